@@ -12,6 +12,7 @@ extern "C" {
 #include <libswresample/swresample.h>
 #include <libavutil/channel_layout.h>
 #include <libavutil/opt.h>
+#include <libavutil/avstring.h>
 }
 
 #include "utils.h"
@@ -65,6 +66,17 @@ struct AVFormatContextDeleter {
   }
 };
 
+/// <div rustbindgen hide></div>
+struct AVIOContextDeleter {
+  void operator()(AVIOContext *context) const {
+    if(context) {
+      // The buffer may have been reallocated by libavformat
+      av_freep(&context->buffer);
+      avio_context_free(&context);
+    }
+  }
+};
+
 /// <div rustbindgen hide></div>
 struct AVFilterInOutDeleter {
   void operator()(AVFilterInOut *inout) const {
@@ -87,9 +99,21 @@ static void print_frame(const AVFrame *frame) {
   fflush(stdout);
 }
 
+/// Size of the buffer open_stream reads into.
+static const int IO_BUFFER_SIZE = 32 * 1024;
+
+/// Must match ResamplerKind in lib.rs
+enum ResamplerKind {
+  RESAMPLER_DEFAULT = 0,
+  RESAMPLER_FAST = 1,
+  RESAMPLER_HIGH_QUALITY = 2
+};
+
 /// <div rustbindgen opaque></div>
 class Decoder {
 private:
+  /// Custom input of open_stream, must be declared before (destroyed after) fmt_ctx which reads from it.
+  std::unique_ptr<AVIOContext, AVIOContextDeleter> io_ctx;
   std::unique_ptr<AVFormatContext, AVFormatContextDeleter> fmt_ctx;
   std::unique_ptr<AVCodecContext, AVCodecContextDeleter> dec_ctx;
   AVFilterContext *buffersink_ctx;
@@ -104,6 +128,20 @@ private:
   std::unique_ptr<SwrContext, SwrContextDeleter> swr;
 
   int audio_stream_index = -1;
+  /// Set once the end of input was reached and the decoder was drained.
+  bool decoder_drained = false;
+  /// One of ResamplerKind, applied when libswresample is initialized.
+  int resampler_kind = RESAMPLER_DEFAULT;
+  /// Input parameters libswresample was initialized with, it is reinitialized if frames change them.
+  int swr_in_rate = 0;
+  int swr_in_format = -1;
+  AVChannelLayout swr_in_ch_layout = {};
+  /// Frames received from the decoder.
+  uint64_t frames_decoded = 0;
+  /// Sizes of the packets read from the input.
+  uint64_t bytes_read = 0;
+  /// Channel layout of the samples passed to frame callbacks, the decoder layout is converted by libswresample.
+  AVChannelLayout out_ch_layout = AV_CHANNEL_LAYOUT_STEREO;
 
 public:
   uint64_t pts = 0;
@@ -216,11 +254,22 @@ public:
   }
 
   int open_input(const char *path) {
-    const AVCodec *dec;
     int ret;
 
+    // Protocol options must be set when opening, HTTP inputs resume at the current offset if the connection drops.
+    // Reconnects are delayed by 0, 1 and 3 seconds, the next delay exceeds reconnect_delay_max.
+    AVDictionary *options = nullptr;
+    av_dict_set(&options, "reconnect", "1", 0);
+    av_dict_set(&options, "reconnect_on_network_error", "1", 0);
+    av_dict_set(&options, "reconnect_delay_max", "4", 0);
+    // Internet radio metadata is stripped from the stream and exposed as icy_metadata_packet
+    av_dict_set(&options, "icy", "1", 0);
+
     AVFormatContext *fmt_ctx_raw = nullptr;
-    if((ret = avformat_open_input(&fmt_ctx_raw, path, nullptr, nullptr)) < 0) {
+    ret = avformat_open_input(&fmt_ctx_raw, path, nullptr, &options);
+    // Contains options not used by the protocol, e.g. all of them for local files
+    av_dict_free(&options);
+    if(ret < 0) {
       av_log(nullptr, AV_LOG_ERROR, "Cannot open input file: %s\n", av_err2str(ret));
       return ret;
     }
@@ -228,6 +277,47 @@ public:
 
     fmt_ctx->flags |= AVIO_FLAG_NONBLOCK;
 
+    return open_decoder();
+  }
+
+  /// Opens an input read through read_callback instead of a URL, e.g. a stream assembled by the caller.
+  /// read_callback follows the read_packet contract of avio_alloc_context, returning AVERROR_EOF at the end.
+  /// The input is not seekable.
+  int open_stream(int (*read_callback)(void *user, uint8_t *buffer, int buffer_size), void *user) {
+    int ret;
+
+    uint8_t *buffer = (uint8_t *)av_malloc(IO_BUFFER_SIZE);
+    if(!buffer)
+      return AVERROR(ENOMEM);
+    AVIOContext *io_ctx_raw = avio_alloc_context(buffer, IO_BUFFER_SIZE, 0, user, read_callback, nullptr, nullptr);
+    if(!io_ctx_raw) {
+      av_free(buffer);
+      return AVERROR(ENOMEM);
+    }
+    io_ctx = std::unique_ptr<AVIOContext, AVIOContextDeleter>(io_ctx_raw);
+
+    AVFormatContext *fmt_ctx_raw = avformat_alloc_context();
+    if(!fmt_ctx_raw)
+      return AVERROR(ENOMEM);
+    fmt_ctx_raw->pb = io_ctx.get();
+    fmt_ctx_raw->flags |= AVFMT_FLAG_CUSTOM_IO;
+
+    // Frees the context on failure
+    ret = avformat_open_input(&fmt_ctx_raw, nullptr, nullptr, nullptr);
+    if(ret < 0) {
+      av_log(nullptr, AV_LOG_ERROR, "Cannot open input stream: %s\n", av_err2str(ret));
+      return ret;
+    }
+    fmt_ctx = std::unique_ptr<AVFormatContext, AVFormatContextDeleter>(fmt_ctx_raw);
+
+    return open_decoder();
+  }
+
+  /// Finds the audio stream of the opened input and opens its decoder.
+  int open_decoder() {
+    const AVCodec *dec;
+    int ret;
+
     if((ret = avformat_find_stream_info(fmt_ctx.get(), nullptr)) < 0) {
       av_log(nullptr, AV_LOG_ERROR, "Cannot find stream information\n");
       return ret;
@@ -241,12 +331,6 @@ public:
     }
     audio_stream_index = ret;
 
-    ret = av_opt_set_int(fmt_ctx.get(), "reconnect", 1, AV_OPT_SEARCH_CHILDREN);
-    if(ret < 0) {
-      av_log(nullptr, AV_LOG_ERROR, "Cannot set reconnect\n");
-      // return ret;
-    }
-
     /* create decoding context */
     dec_ctx = std::unique_ptr<AVCodecContext, AVCodecContextDeleter>(avcodec_alloc_context3(dec));
     if(!dec_ctx)
@@ -268,7 +352,6 @@ public:
     frame = std::unique_ptr<AVFrame, AVFrameDeleter>(av_frame_alloc());
     out_frame = std::unique_ptr<AVFrame, AVFrameDeleter>(av_frame_alloc());
     filter_frame = std::unique_ptr<AVFrame, AVFrameDeleter>(av_frame_alloc());
-    swr = std::unique_ptr<SwrContext, SwrContextDeleter>(swr_alloc());
 
     if(!packet || !frame || !filter_frame) {
       fprintf(stderr, "Could not allocate frame or packet\n");
@@ -283,59 +366,67 @@ public:
 
   Decoder(const Decoder &other) = delete;
 
-  int read_frame(void (*frame_callback)(float *data, int data_length, void* user), void* user) {
-    int ret;
-    if((ret = av_read_frame(fmt_ctx.get(), packet.get())) < 0) {
-      av_log(nullptr, AV_LOG_ERROR, "Error while av_read_frame\n");
-      goto end;
-    }
+  ~Decoder() {
+    av_channel_layout_uninit(&swr_in_ch_layout);
+  }
 
-    if(packet->stream_index == audio_stream_index) {
-      ret = avcodec_send_packet(dec_ctx.get(), packet.get());
-      if(ret < 0) {
-        av_log(nullptr, AV_LOG_ERROR, "Error while sending a packet to the decoder\n");
-        goto end;
+  /// Receives all frames available from the decoder, resamples them and passes them to frame_callback.
+  /// Returns AVERROR(EAGAIN) if the decoder needs more input, or AVERROR_EOF once it is fully drained.
+  int receive_frames(void (*frame_callback)(float *data, int data_length, void* user), void* user) {
+    int ret = 0;
+    while(ret >= 0) {
+      ret = avcodec_receive_frame(dec_ctx.get(), frame.get());
+      in_pts += frame->nb_samples;
+      if(ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) {
+        // av_log(nullptr, AV_LOG_ERROR, "AGAIN or EOF while avcodec_receive_frame\n");
+        break;
+      } else if(ret < 0) {
+        av_log(nullptr, AV_LOG_ERROR, "Error while receiving a frame from the decoder\n");
+        return ret;
       }
+      frames_decoded++;
 
-      while(ret >= 0) {
-        ret = avcodec_receive_frame(dec_ctx.get(), frame.get());
-        in_pts += frame->nb_samples;
-        if(ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) {
-          // av_log(nullptr, AV_LOG_ERROR, "AGAIN or EOF while avcodec_receive_frame\n");
+      AVFrame *process_frame;
+      if(enable_filter_graph) {
+        /* push the audio data from decoded frame into the filtergraph */
+        if(av_buffersrc_add_frame_flags(buffersrc_ctx, frame.get(), AV_BUFFERSRC_FLAG_KEEP_REF) < 0) {
+          av_log(nullptr, AV_LOG_ERROR, "Error while feeding the audio filtergraph\n");
           break;
-        } else if(ret < 0) {
-          av_log(nullptr, AV_LOG_ERROR, "Error while receiving a frame from the decoder\n");
-          goto end;
         }
 
-        AVFrame *process_frame;
+        process_frame = filter_frame.get();
+      } else {
+        process_frame = frame.get();
+      }
+
+      /* pull filtered audio from the filtergraph */
+      while(true) {
         if(enable_filter_graph) {
-          /* push the audio data from decoded frame into the filtergraph */
-          if(av_buffersrc_add_frame_flags(buffersrc_ctx, frame.get(), AV_BUFFERSRC_FLAG_KEEP_REF) < 0) {
-            av_log(nullptr, AV_LOG_ERROR, "Error while feeding the audio filtergraph\n");
-            break;
+          ret = av_buffersink_get_frame(buffersink_ctx, filter_frame.get());
+          if(ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) break;
+          if(ret < 0) {
+            av_log(nullptr, AV_LOG_ERROR, "Error while av_buffersink_get_frame\n");
+            return ret;
           }
-
-          process_frame = filter_frame.get();
-        } else {
-          process_frame = frame.get();
         }
 
-        /* pull filtered audio from the filtergraph */
-        while(true) {
-          if(enable_filter_graph) {
-            ret = av_buffersink_get_frame(buffersink_ctx, filter_frame.get());
-            if(ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) break;
-            if(ret < 0) {
-              av_log(nullptr, AV_LOG_ERROR, "Error while av_buffersink_get_frame\n");
-              goto end;
-            }
-          }
-
+        float *data;
+        int n;
+        if(!swr && is_output_format(process_frame)) {
+          // Already 48 kHz interleaved float, e.g. a float WAV or the output of the filter graph
+          pts += process_frame->nb_samples;
+          n = process_frame->nb_samples * process_frame->ch_layout.nb_channels;
+          data = reinterpret_cast<float *>(process_frame->extended_data[0]);
+        } else {
           out_frame->format = AV_SAMPLE_FMT_FLT;
-          out_frame->ch_layout = AV_CHANNEL_LAYOUT_STEREO;
+          out_frame->ch_layout = out_ch_layout;
           out_frame->sample_rate = 48000;
-          if(!swr_is_initialized(swr.get())) {
+          if(resampler_initialized() && resampler_input_changed(process_frame)) {
+            // E.g. chained Ogg streams, samples still buffered in the resampler are dropped
+            fprintf(stderr, "Input format changed, reinitializing libswresample\n");
+            swr_close(swr.get());
+          }
+          if(!resampler_initialized()) {
             fprintf(
               stderr,
               "Initializing libswresample: rate=%d, sample_fmt=%s\n",
@@ -343,6 +434,7 @@ public:
               av_get_sample_fmt_name((AVSampleFormat)process_frame->format)
             );
 
+            // Allocated lazily, frames already in the output format do not need it
             SwrContext *swr_raw = swr.get();
             if((ret = swr_alloc_set_opts2(
               &swr_raw,
@@ -356,60 +448,109 @@ public:
               nullptr
             )) < 0) {
               av_log(nullptr, AV_LOG_ERROR, "Error while swr_alloc_set_opts2\n");
-              goto end;
+              return ret;
+            }
+            if(!swr) swr.reset(swr_raw);
+            if((ret = apply_resampler_options()) < 0) {
+              av_log(nullptr, AV_LOG_ERROR, "Error while setting resampler options\n");
+              return ret;
             }
 
             if((ret = swr_init(swr.get())) < 0) {
               av_log(nullptr, AV_LOG_ERROR, "Error while swr_init\n");
-              goto end;
+              return ret;
+            }
+            swr_in_rate = process_frame->sample_rate;
+            swr_in_format = process_frame->format;
+            av_channel_layout_uninit(&swr_in_ch_layout);
+            if((ret = av_channel_layout_copy(&swr_in_ch_layout, &process_frame->ch_layout)) < 0) {
+              return ret;
             }
           }
 
           // This does the same thing as swr_convert_frame, but without the stupid config_changed call,
           // which returns an AVERROR_INPUT_CHANGED even if it is not a case.
-          int out_num_samples = 48000;
-          // av_rescale_rnd(swr_get_delay(swr.get(), process_frame->sample_rate) + process_frame->nb_samples, out_frame->sample_rate, process_frame->sample_rate, AV_ROUND_UP);
-          // av_log(nullptr, AV_LOG_ERROR, "out=%d in=%d = %d\n", out_frame->sample_rate, process_frame->sample_rate, out_num_samples);
-          out_frame->nb_samples = out_num_samples;
-          // out_frame->nb_samples = swr_get_delay(swr.get(), out_frame->sample_rate)
-          //                         + process_frame->nb_samples * (int64_t)out_frame->sample_rate / process_frame->sample_rate
-          //                         + 3;
+          // Frames may have any size (e.g. a short last frame, or long frames of PCM inputs), size the output
+          // for this frame and the samples still buffered in the resampler, otherwise the rest stays buffered.
+          int out_num_samples = swr_get_out_samples(swr.get(), process_frame->nb_samples);
+          if(out_num_samples < 0) {
+            av_log(nullptr, AV_LOG_ERROR, "Error while swr_get_out_samples\n");
+            return out_num_samples;
+          }
+          // av_frame_get_buffer fails for empty frames
+          out_frame->nb_samples = FFMAX(out_num_samples, 1);
           if((ret = av_frame_get_buffer(out_frame.get(), 0)) < 0) {
             av_log(nullptr, AV_LOG_ERROR, "Error while av_frame_get_buffer\n");
-            goto end;
+            return ret;
           }
           if((ret = swr_convert(swr.get(), out_frame->extended_data, out_frame->nb_samples,
                                 (const uint8_t **)process_frame->extended_data, process_frame->nb_samples)) < 0) {
             av_log(nullptr, AV_LOG_ERROR, "Error while swr_convert\n");
-            goto end;
+            return ret;
           }
           out_frame->nb_samples = ret;
 
           pts += out_frame->nb_samples;
           // printf("increment pts by %d -> %ld\n", out_frame->nb_samples, pts);
 
-          const int n = out_frame->nb_samples * out_frame->ch_layout.nb_channels;
-          auto data = reinterpret_cast<float *>(out_frame->extended_data[0]);
-          frame_callback(data, n, user);
+          n = out_frame->nb_samples * out_frame->ch_layout.nb_channels;
+          data = reinterpret_cast<float *>(out_frame->extended_data[0]);
+        }
+        frame_callback(data, n, user);
 
-          // print_frame(out_frame.get());
-          av_frame_unref(out_frame.get());
-          av_frame_unref(process_frame);
+        // print_frame(out_frame.get());
+        av_frame_unref(out_frame.get());
+        av_frame_unref(process_frame);
 
-          if(!enable_filter_graph) {
-            break;
-          }
+        if(!enable_filter_graph) {
+          break;
         }
-        av_frame_unref(frame.get());
       }
+      av_frame_unref(frame.get());
+    }
+    return ret;
+  }
+
+  int read_frame(void (*frame_callback)(float *data, int data_length, void* user), void* user) {
+    int ret;
+    if(decoder_drained) return AVERROR_EOF;
+
+    if((ret = av_read_frame(fmt_ctx.get(), packet.get())) < 0) {
+      if(ret != AVERROR_EOF) {
+        av_log(nullptr, AV_LOG_ERROR, "Error while av_read_frame\n");
+        goto end;
+      }
+
+      // Drain frames buffered in the decoder, otherwise the tail of the track is lost
+      decoder_drained = true;
+      if((ret = avcodec_send_packet(dec_ctx.get(), nullptr)) < 0) {
+        av_log(nullptr, AV_LOG_ERROR, "Error while draining the decoder\n");
+        goto end;
+      }
+      if((ret = receive_frames(frame_callback, user)) == AVERROR_EOF) {
+        // Deliver the drained frames, the next call returns AVERROR_EOF
+        ret = 0;
+      }
+      goto end;
+    }
+
+    bytes_read += packet->size;
+    if(packet->stream_index == audio_stream_index) {
+      ret = avcodec_send_packet(dec_ctx.get(), packet.get());
+      if(ret < 0) {
+        av_log(nullptr, AV_LOG_ERROR, "Error while sending a packet to the decoder\n");
+        goto end;
+      }
+
+      ret = receive_frames(frame_callback, user);
     }
-    av_packet_unref(packet.get());
 
     end:
+    av_packet_unref(packet.get());
 
+    // Errors are returned to the caller, which ends the playback of the track
     if(ret < 0 && ret != AVERROR_EOF && ret != AVERROR(EAGAIN)) {
       fprintf(stderr, "Error occurred: %s\n", av_err2string(ret).c_str());
-      exit(1);
     }
 
     return ret;
@@ -419,8 +560,11 @@ public:
   int flush_frame(void (*frame_callback)(float *data, int data_length, void* user), void* user) {
     int ret;
 
+    // Nothing was decoded (e.g. an empty input), there is nothing to flush
+    if(!resampler_initialized()) return AVERROR_EOF;
+
     out_frame->format = AV_SAMPLE_FMT_FLT;
-    out_frame->ch_layout = AV_CHANNEL_LAYOUT_STEREO;
+    out_frame->ch_layout = out_ch_layout;
     out_frame->sample_rate = 48000;
 
     // This does the same thing as swr_convert_frame, but without the stupid config_changed call,
@@ -456,7 +600,6 @@ public:
 
     if(ret < 0 && ret != AVERROR_EOF && ret != AVERROR(EAGAIN)) {
       fprintf(stderr, "Error occurred: %s\n", av_err2string(ret).c_str());
-      exit(1);
     }
 
     return ret;
@@ -486,7 +629,47 @@ public:
     return dec_ctx->time_base.den;
   }
 
+  /// Whether the input supports seeking, HTTP inputs do only if the server accepts range requests.
+  bool is_seekable() {
+    // Demuxers without an AVIOContext do their own I/O and seeking
+    if(!fmt_ctx->pb) return true;
+    return fmt_ctx->pb->seekable & AVIO_SEEKABLE_NORMAL;
+  }
+
+  /// Size of the input in bytes, or a negative value if it is unknown (e.g. live streams).
+  int64_t get_input_size() {
+    if(!fmt_ctx->pb) return AVERROR(ENOSYS);
+    return avio_size(fmt_ctx->pb);
+  }
+
+  /// Seeks to byte `offset` of the input, decoding resumes at the next frame found there.
+  /// `pts` is the estimated position of the offset.
+  int seek_byte(int64_t offset, int64_t pts) {
+    if(!is_seekable()) {
+      av_log(nullptr, AV_LOG_ERROR, "Input is not seekable\n");
+      return AVERROR(ESPIPE);
+    }
+
+    int ret = av_seek_frame(fmt_ctx.get(), -1, offset, AVSEEK_FLAG_BYTE);
+    reset_after_seek(pts);
+
+    av_log(nullptr, AV_LOG_ERROR, "Seek to byte %ld (estimated pts %ld)\n", offset, pts);
+    return ret;
+  }
+
+  void reset_after_seek(int64_t pts) {
+    avcodec_flush_buffers(dec_ctx.get());
+    decoder_drained = false;
+    this->in_pts = pts;
+    this->pts = 0; // TODO(Assasans): How to calculate it?
+  }
+
   int seek(int64_t pts) {
+    if(!is_seekable()) {
+      av_log(nullptr, AV_LOG_ERROR, "Input is not seekable\n");
+      return AVERROR(ESPIPE);
+    }
+
     AVRational decoder_time_base = dec_ctx->time_base;
     AVRational stream_time_base = fmt_ctx->streams[audio_stream_index]->time_base;
 
@@ -496,9 +679,7 @@ public:
       pts * decoder_time_base.num * stream_time_base.den / decoder_time_base.den / stream_time_base.num;
 
     int ret = av_seek_frame(fmt_ctx.get(), audio_stream_index, timestamp, AVSEEK_FLAG_ANY);
-    avcodec_flush_buffers(dec_ctx.get());
-    this->in_pts = pts;
-    this->pts = 0; // TODO(Assasans): How to calculate it?
+    reset_after_seek(pts);
 
     // while(true) {
     //   if((ret = swr_convert_frame(swr.get(), nullptr, nullptr)) < 0) {
@@ -514,12 +695,148 @@ public:
     return ret;
   }
 
+  bool resampler_initialized() {
+    return swr && swr_is_initialized(swr.get());
+  }
+
+  bool resampler_input_changed(const AVFrame *frame) {
+    return frame->sample_rate != swr_in_rate || frame->format != swr_in_format ||
+           av_channel_layout_compare(&frame->ch_layout, &swr_in_ch_layout) != 0;
+  }
+
+  /// Whether frame is already in the format passed to frame callbacks, so it does not need libswresample.
+  bool is_output_format(const AVFrame *frame) {
+    return frame->format == AV_SAMPLE_FMT_FLT && frame->sample_rate == 48000 &&
+           av_channel_layout_compare(&frame->ch_layout, &out_ch_layout) == 0;
+  }
+
+  /// Whether libswresample was needed to convert the decoded frames.
+  bool is_resampling() {
+    return swr != nullptr;
+  }
+
+  uint64_t get_frames_decoded() {
+    return frames_decoded;
+  }
+
+  uint64_t get_bytes_read() {
+    return bytes_read;
+  }
+
+  /// Returns the channel count of the samples passed to frame callbacks.
+  int get_output_channels() {
+    return out_ch_layout.nb_channels;
+  }
+
+  /// Returns the input duration in milliseconds, or -1 if it is unknown (e.g. live streams).
+  /// Stream information is probed with avformat_find_stream_info in open_input.
+  int64_t get_duration_ms() {
+    if(!fmt_ctx) return -1;
+
+    if(fmt_ctx->duration != AV_NOPTS_VALUE) {
+      return av_rescale(fmt_ctx->duration, 1000, AV_TIME_BASE);
+    }
+
+    // Some containers only report the duration of streams
+    if(audio_stream_index >= 0) {
+      AVStream *stream = fmt_ctx->streams[audio_stream_index];
+      if(stream->duration != AV_NOPTS_VALUE) {
+        return av_rescale_q(stream->duration, stream->time_base, AVRational { 1, 1000 });
+      }
+    }
+
+    return -1;
+  }
+
+  /// Returns the bitrate of the input in bits per second, or -1 if it is unknown.
+  int64_t get_bit_rate() {
+    if(!fmt_ctx) return -1;
+
+    if(fmt_ctx->bit_rate > 0) {
+      return fmt_ctx->bit_rate;
+    }
+
+    // Estimated from the file size by some demuxers only, fall back to the codec bitrate
+    if(audio_stream_index >= 0) {
+      int64_t bit_rate = fmt_ctx->streams[audio_stream_index]->codecpar->bit_rate;
+      if(bit_rate > 0) {
+        return bit_rate;
+      }
+    }
+
+    return -1;
+  }
+
+  /// Calls entry_callback for every metadata tag of the input, then of the audio stream (e.g. Vorbis comments).
+  int get_metadata(void (*entry_callback)(const char *key, const char *value, void *user), void *user) {
+    if(!fmt_ctx) return AVERROR(EINVAL);
+
+    AVDictionary *dictionaries[] = {
+      fmt_ctx->metadata,
+      audio_stream_index >= 0 ? fmt_ctx->streams[audio_stream_index]->metadata : nullptr
+    };
+    for(AVDictionary *dictionary : dictionaries) {
+      const AVDictionaryEntry *entry = nullptr;
+      while((entry = av_dict_get(dictionary, "", entry, AV_DICT_IGNORE_SUFFIX))) {
+        entry_callback(entry->key, entry->value, user);
+      }
+    }
+
+    return 0;
+  }
+
+  /// Copies the last ICY metadata packet of internet radio streams (e.g. `StreamTitle='...';`) to `buffer`,
+  /// it is empty if no packet was received yet.
+  int get_icy_metadata(char *buffer, int size) {
+    if(!fmt_ctx || !fmt_ctx->pb || size <= 0) return AVERROR(EINVAL);
+
+    uint8_t *value = nullptr;
+    // Fails for non-HTTP inputs
+    int ret = av_opt_get(fmt_ctx->pb, "icy_metadata_packet", AV_OPT_SEARCH_CHILDREN, &value);
+    if(ret < 0) return ret;
+
+    av_strlcpy(buffer, value ? (const char *)value : "", size);
+    av_free(value);
+    return 0;
+  }
+
+  int set_resampler_kind(int kind) {
+    if(kind < RESAMPLER_DEFAULT || kind > RESAMPLER_HIGH_QUALITY) return AVERROR(EINVAL);
+
+    // Reinitialized with the new options on the next frame
+    if(kind != resampler_kind && resampler_initialized()) {
+      swr_close(swr.get());
+    }
+    resampler_kind = kind;
+    return 0;
+  }
+
+  int apply_resampler_options() {
+    // libswresample defaults
+    int filter_size = 32;
+    int phase_shift = 10;
+    switch(resampler_kind) {
+      case RESAMPLER_FAST:
+        filter_size = 8;
+        phase_shift = 6;
+        break;
+      case RESAMPLER_HIGH_QUALITY:
+        filter_size = 128;
+        phase_shift = 14;
+        break;
+    }
+
+    int ret;
+    if((ret = av_opt_set_int(swr.get(), "filter_size", filter_size, 0)) < 0) return ret;
+    return av_opt_set_int(swr.get(), "phase_shift", phase_shift, 0);
+  }
+
   int set_enable_filter_graph(bool enable) {
     bool changed = enable_filter_graph != enable;
     enable_filter_graph = enable;
 
     int ret = 0;
-    if(changed && swr_is_initialized(swr.get())) {
+    if(changed && resampler_initialized()) {
       swr_close(swr.get());
     }
 
@@ -539,6 +856,10 @@ DLL_EXPORT int decoder_open_input(Decoder *decoder, const char *path) {
   return decoder->open_input(path);
 }
 
+DLL_EXPORT int decoder_open_stream(Decoder *decoder, int (*read_callback)(void *user, uint8_t *buffer, int buffer_size), void *user) {
+  return decoder->open_stream(read_callback, user);
+}
+
 DLL_EXPORT int decoder_init_filters(Decoder *decoder, const char *filters_descr) {
   return decoder->init_filters(filters_descr);
 }
@@ -567,14 +888,70 @@ DLL_EXPORT int decoder_seek(Decoder *decoder, uint64_t pts) {
   return decoder->seek(pts);
 }
 
+DLL_EXPORT int64_t decoder_get_input_size(Decoder *decoder) {
+  return decoder->get_input_size();
+}
+
+DLL_EXPORT int decoder_seek_byte(Decoder *decoder, uint64_t offset, uint64_t pts) {
+  return decoder->seek_byte(offset, pts);
+}
+
+DLL_EXPORT bool decoder_is_seekable(Decoder *decoder) {
+  return decoder->is_seekable();
+}
+
+DLL_EXPORT bool decoder_is_resampling(Decoder *decoder) {
+  return decoder->is_resampling();
+}
+
+DLL_EXPORT uint64_t decoder_get_frames_decoded(Decoder *decoder) {
+  return decoder->get_frames_decoded();
+}
+
+DLL_EXPORT uint64_t decoder_get_bytes_read(Decoder *decoder) {
+  return decoder->get_bytes_read();
+}
+
+DLL_EXPORT int decoder_get_output_channels(Decoder *decoder) {
+  return decoder->get_output_channels();
+}
+
+DLL_EXPORT int64_t decoder_get_duration_ms(Decoder *decoder) {
+  return decoder->get_duration_ms();
+}
+
+DLL_EXPORT int64_t decoder_get_bit_rate(Decoder *decoder) {
+  return decoder->get_bit_rate();
+}
+
+DLL_EXPORT int decoder_get_icy_metadata(Decoder *decoder, char *buffer, int buffer_length) {
+  return decoder->get_icy_metadata(buffer, buffer_length);
+}
+
+DLL_EXPORT int decoder_get_metadata(Decoder *decoder, void (*entry_callback)(const char *key, const char *value, void* user), void* user) {
+  return decoder->get_metadata(entry_callback, user);
+}
+
+DLL_EXPORT int decoder_set_resampler_kind(Decoder *decoder, int kind) {
+  return decoder->set_resampler_kind(kind);
+}
+
 DLL_EXPORT int decoder_set_enable_filter_graph(Decoder *decoder, bool enable) {
   return decoder->set_enable_filter_graph(enable);
 }
 
+/// Whether opening an input failed because of the network or the server, so it may succeed later.
+DLL_EXPORT bool decoder_util_is_transient_error(int error_code) {
+  return error_code == AVERROR(EIO) || error_code == AVERROR(ETIMEDOUT) || error_code == AVERROR(ECONNRESET) ||
+         error_code == AVERROR(ECONNREFUSED) || error_code == AVERROR_HTTP_SERVER_ERROR;
+}
+
 DLL_EXPORT int decoder_util_error_to_string(int error_code, char* buffer, int buffer_length) {
   return av_strerror(error_code, buffer, buffer_length);
 }
 
 DLL_EXPORT const int ERROR_MAX_STRING_SIZE = AV_ERROR_MAX_STRING_SIZE;
+/// ICY metadata blocks are at most 255 * 16 bytes long.
+DLL_EXPORT const int ICY_METADATA_MAX_SIZE = 255 * 16 + 1;
 
 #endif
@@ -1,5 +1,11 @@
-use std::ffi::{c_int, c_void, CStr, CString};
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read};
 use std::slice;
+use std::str::FromStr;
+use std::time::Duration;
 
 mod ffi {
   #![allow(non_upper_case_globals)]
@@ -11,19 +17,144 @@ mod ffi {
 
 pub type RawError = i32;
 
+/// `AVERROR_EOF`, the end of input was reached.
+pub const AVERROR_EOF: RawError = -0x20464f45;
+/// `AVERROR(EAGAIN)`, more input is needed.
+const AVERROR_EAGAIN: RawError = -11;
+/// `AVERROR(EIO)`, reported when the reader of [Decoder::open_reader] fails.
+const AVERROR_EIO: RawError = -5;
+
 macro_rules! result_zero {
   ($result:expr) => {{
     let result = $result;
     if result == 0 {
       Ok(())
     } else {
-      Err(result)
+      Err(DecoderError::new(result))
     }
   }};
 }
 
+/// FFmpeg error code with its description, e.g. `Invalid data found when processing input (-1094995529)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecoderError {
+  code: RawError,
+  message: String
+}
+
+impl DecoderError {
+  pub fn new(code: RawError) -> Self {
+    Self {
+      code,
+      message: Decoder::error_code_to_string(code)
+    }
+  }
+
+  /// Returns the `AVERROR` code.
+  pub fn raw(&self) -> RawError {
+    self.code
+  }
+
+  /// See [Decoder::is_transient_error].
+  pub fn is_transient(&self) -> bool {
+    Decoder::is_transient_error(self.code)
+  }
+}
+
+impl Display for DecoderError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} ({})", self.message, self.code)
+  }
+}
+
+impl Error for DecoderError {}
+
+/// Result of [Decoder::read_frame].
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameResult {
+  /// Decoded samples, never empty.
+  Frame(Vec<f32>),
+  /// Nothing was decoded yet (e.g. more input is needed, or the packet was not audio), read again.
+  Again,
+  /// The end of input was reached, or nothing is left to flush.
+  Eof,
+  /// Reading or decoding failed, see [DecoderError::is_transient] for recoverable errors.
+  Error(DecoderError)
+}
+
+impl FrameResult {
+  /// Maps the return code of `decoder_read_frame` / `decoder_flush_frame` and the samples passed to the callback.
+  fn from_raw(code: RawError, samples: Vec<f32>) -> Self {
+    match code {
+      AVERROR_EOF => Self::Eof,
+      // AVERROR(EAGAIN) is returned after delivering the frames decoded from a packet
+      code if code < 0 && code != AVERROR_EAGAIN => Self::Error(DecoderError::new(code)),
+      _ if samples.is_empty() => Self::Again,
+      _ => Self::Frame(samples)
+    }
+  }
+}
+
+/// Container metadata of an opened input, see [Decoder::read_metadata].
+///
+/// Every field is optional, e.g. raw streams have no tags and live streams have no duration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecoderMetadata {
+  /// Tags with lowercase keys, see [Decoder::metadata].
+  pub tags: HashMap<String, String>,
+  pub duration: Option<Duration>,
+  /// Bits per second.
+  pub bit_rate: Option<u64>
+}
+
+impl DecoderMetadata {
+  /// Returns the value of tag `key`, [None] if it is missing or blank.
+  pub fn tag(&self, key: &str) -> Option<&str> {
+    self.tags.get(key).map(String::as_str).filter(|value| !value.trim().is_empty())
+  }
+
+  pub fn title(&self) -> Option<&str> {
+    self.tag("title")
+  }
+
+  pub fn artist(&self) -> Option<&str> {
+    self.tag("artist").or_else(|| self.tag("album_artist"))
+  }
+}
+
+/// Resampling algorithm used when the input is not 48 kHz, see `filter_size` and `phase_shift`
+/// in libswresample options.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ResamplerKind {
+  /// libswresample defaults.
+  #[default]
+  Default = 0,
+  /// Shorter filter, cheaper but with more aliasing.
+  Fast = 1,
+  /// Longer filter with finer phase resolution.
+  HighQuality = 2
+}
+
+impl FromStr for ResamplerKind {
+  type Err = ();
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.to_ascii_lowercase().as_str() {
+      "default" => Ok(Self::Default),
+      "fast" => Ok(Self::Fast),
+      "high" | "high_quality" => Ok(Self::HighQuality),
+      _ => Err(())
+    }
+  }
+}
+
 pub struct Decoder {
-  decoder: *mut ffi::Decoder
+  decoder: *mut ffi::Decoder,
+  /// Length of the last [Decoder::read_frame] result, used to size the next buffer.
+  read_size_hint: usize,
+  /// Input of [Decoder::open_reader], boxed twice to pass a thin pointer to the read callback.
+  reader: Option<Box<Box<dyn Read + Send>>>
 }
 
 // TODO(Assasans): Not sure...
@@ -33,16 +164,41 @@ unsafe impl Sync for Decoder {}
 impl Decoder {
   pub fn new() -> Self {
     Self {
-      decoder: unsafe { ffi::decoder_alloc() }
+      decoder: unsafe { ffi::decoder_alloc() },
+      read_size_hint: 512,
+      reader: None
     }
   }
 
-  pub fn open_input(&mut self, path: &str) -> Result<(), RawError> {
+  pub fn open_input(&mut self, path: &str) -> Result<(), DecoderError> {
     let path = CString::new(path).unwrap();
     result_zero!(unsafe { ffi::decoder_open_input(self.decoder, path.as_ptr()) })
   }
 
-  pub fn init_filters(&mut self, filters_descr: &str) -> Result<(), RawError> {
+  /// Opens an input read from `reader` instead of a URL, the input is not seekable.
+  ///
+  /// Reads are blocking and happen on the thread calling [Decoder::read_frame].
+  pub fn open_reader(&mut self, reader: Box<dyn Read + Send>) -> Result<(), DecoderError> {
+    extern "C" fn read_callback(user: *mut c_void, buffer: *mut u8, buffer_size: c_int) -> c_int {
+      let reader = unsafe { &mut *(user as *mut Box<dyn Read + Send>) };
+      let buffer = unsafe { slice::from_raw_parts_mut(buffer, buffer_size as usize) };
+      loop {
+        return match reader.read(buffer) {
+          Ok(0) => AVERROR_EOF,
+          Ok(length) => length as c_int,
+          Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+          Err(_) => AVERROR_EIO
+        };
+      }
+    }
+
+    // Lives until the decoder is freed, see Drop
+    let reader = self.reader.insert(Box::new(reader));
+    let user = reader.as_mut() as *mut Box<dyn Read + Send> as *mut c_void;
+    result_zero!(unsafe { ffi::decoder_open_stream(self.decoder, Some(read_callback), user) })
+  }
+
+  pub fn init_filters(&mut self, filters_descr: &str) -> Result<(), DecoderError> {
     let filters_descr = CString::new(filters_descr).unwrap();
     let result = unsafe { ffi::decoder_init_filters(self.decoder, filters_descr.as_ptr()) };
     if result != 0 {
@@ -54,12 +210,19 @@ impl Decoder {
     result_zero!(result)
   }
 
-  pub fn set_enable_filter_graph(&mut self, enable: bool) -> Result<(), RawError> {
+  pub fn set_enable_filter_graph(&mut self, enable: bool) -> Result<(), DecoderError> {
     result_zero!(unsafe { ffi::decoder_set_enable_filter_graph(self.decoder, enable) })
   }
 
-  pub fn read_frame(&mut self, is_flush: bool) -> Option<Vec<f32>> {
-    let mut buffer = Vec::with_capacity(512);
+  /// Selects the resampling algorithm, takes effect from the next decoded frame.
+  pub fn set_resampler_kind(&mut self, kind: ResamplerKind) -> Result<(), DecoderError> {
+    result_zero!(unsafe { ffi::decoder_set_resampler_kind(self.decoder, kind as c_int) })
+  }
+
+  /// Decodes the next packet, or flushes the resampler if `is_flush` is set.
+  pub fn read_frame(&mut self, is_flush: bool) -> FrameResult {
+    // Packets usually decode to the same number of samples, avoid growing the buffer for each of them
+    let mut buffer = Vec::with_capacity(self.read_size_hint);
 
     extern "C" fn frame_callback(data: *mut f32, data_length: c_int, user: *mut c_void) {
       let buffer = unsafe { &mut *(user as *mut Vec<f32>) };
@@ -74,15 +237,14 @@ impl Decoder {
       unsafe { ffi::decoder_read_frame(self.decoder, Some(frame_callback), user) }
     };
 
-    // AVERROR(EAGAIN)
-    if result < 0 && result != -11 {
-      return None;
+    let frame = FrameResult::from_raw(result, buffer);
+    if let FrameResult::Frame(samples) = &frame {
+      self.read_size_hint = samples.len();
     }
-
-    Some(buffer)
+    frame
   }
 
-  pub fn unref_frame(&self) -> Result<(), RawError> {
+  pub fn unref_frame(&self) -> Result<(), DecoderError> {
     result_zero!(unsafe { ffi::decoder_unref_frame(self.decoder) })
   }
 
@@ -94,10 +256,110 @@ impl Decoder {
     unsafe { ffi::decoder_get_decoder_time_base(self.decoder) as u64 }
   }
 
-  pub fn seek(&mut self, pts: u64) -> Result<(), RawError> {
+  pub fn seek(&mut self, pts: u64) -> Result<(), DecoderError> {
     result_zero!(unsafe { ffi::decoder_seek(self.decoder, pts) })
   }
 
+  /// Seeks to byte `offset` of the input, e.g. one returned by [estimate_byte_offset].
+  /// `pts` is the estimated position of the offset, in decoder time base units.
+  pub fn seek_byte(&mut self, offset: u64, pts: u64) -> Result<(), DecoderError> {
+    result_zero!(unsafe { ffi::decoder_seek_byte(self.decoder, offset, pts) })
+  }
+
+  /// Returns the size of the input in bytes, [None] if it is unknown (e.g. live streams).
+  pub fn input_size(&self) -> Option<u64> {
+    let size = unsafe { ffi::decoder_get_input_size(self.decoder) };
+    u64::try_from(size).ok()
+  }
+
+  /// Returns the number of frames received from the codec.
+  pub fn frames_decoded(&self) -> u64 {
+    unsafe { ffi::decoder_get_frames_decoded(self.decoder) }
+  }
+
+  /// Returns the number of encoded bytes read from the input.
+  pub fn bytes_read(&self) -> u64 {
+    unsafe { ffi::decoder_get_bytes_read(self.decoder) }
+  }
+
+  /// Whether [Decoder::seek] is supported, HTTP inputs are seekable only if the server accepts range requests.
+  pub fn is_seekable(&self) -> bool {
+    unsafe { ffi::decoder_is_seekable(self.decoder) }
+  }
+
+  /// Whether decoded frames needed libswresample, it is not allocated for inputs in the output format.
+  pub fn is_resampling(&self) -> bool {
+    unsafe { ffi::decoder_is_resampling(self.decoder) }
+  }
+
+  /// Returns the channel count of the samples returned by [Decoder::read_frame], they are always 48 kHz.
+  pub fn output_channels(&self) -> usize {
+    unsafe { ffi::decoder_get_output_channels(self.decoder) as usize }
+  }
+
+  /// Returns the duration of the opened input, [None] if it is unknown (e.g. live streams).
+  pub fn duration(&self) -> Option<Duration> {
+    let duration = unsafe { ffi::decoder_get_duration_ms(self.decoder) };
+    u64::try_from(duration).ok().map(Duration::from_millis)
+  }
+
+  /// Returns the bitrate of the opened input in bits per second, [None] if it is unknown.
+  pub fn bit_rate(&self) -> Option<u64> {
+    let bit_rate = unsafe { ffi::decoder_get_bit_rate(self.decoder) };
+    u64::try_from(bit_rate).ok()
+  }
+
+  /// Returns the tags, duration and bitrate of the opened input.
+  pub fn read_metadata(&self) -> DecoderMetadata {
+    DecoderMetadata {
+      tags: self.metadata(),
+      duration: self.duration(),
+      bit_rate: self.bit_rate()
+    }
+  }
+
+  /// Returns the metadata tags (e.g. `title`, `artist`, `album`) of the opened input with lowercase keys.
+  ///
+  /// Container tags take precedence over tags of the audio stream.
+  pub fn metadata(&self) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+
+    extern "C" fn entry_callback(key: *const c_char, value: *const c_char, user: *mut c_void) {
+      let metadata = unsafe { &mut *(user as *mut HashMap<String, String>) };
+      let (key, value) = unsafe { (CStr::from_ptr(key), CStr::from_ptr(value)) };
+      metadata
+        .entry(key.to_string_lossy().to_lowercase())
+        .or_insert_with(|| value.to_string_lossy().into_owned());
+    }
+
+    let user = &mut metadata as *mut HashMap<String, String> as *mut c_void;
+    let result = unsafe { ffi::decoder_get_metadata(self.decoder, Some(entry_callback), user) };
+    if result != 0 {
+      return HashMap::new();
+    }
+
+    metadata
+  }
+
+  /// Returns the last ICY metadata packet of internet radio streams, e.g. `StreamTitle='Artist - Title';`.
+  ///
+  /// [None] for other inputs, or if no packet was received yet.
+  pub fn icy_metadata(&self) -> Option<String> {
+    let mut chars = [0; ffi::ICY_METADATA_MAX_SIZE as usize];
+    let result = unsafe { ffi::decoder_get_icy_metadata(self.decoder, chars.as_mut_ptr(), chars.len() as i32) };
+    if result != 0 {
+      return None;
+    }
+
+    let packet = unsafe { CStr::from_ptr(chars.as_ptr()) }.to_string_lossy().into_owned();
+    (!packet.is_empty()).then_some(packet)
+  }
+
+  /// Whether the error is caused by the network or the server, e.g. a connection reset or a 5xx response.
+  pub fn is_transient_error(error: RawError) -> bool {
+    unsafe { ffi::decoder_util_is_transient_error(error) }
+  }
+
   pub fn error_code_to_string(error: RawError) -> String {
     let mut chars = [0; ffi::ERROR_MAX_STRING_SIZE as usize];
     unsafe {
@@ -107,12 +369,242 @@ impl Decoder {
   }
 }
 
+/// Estimates the byte offset of `position` in an input of `size` bytes and `duration`, assuming a constant bitrate.
+///
+/// Used to seek inputs without a seek index, e.g. CBR MP3 or AAC streams. Container headers are not accounted,
+/// so the result is approximate.
+pub fn estimate_byte_offset(position: Duration, duration: Duration, size: u64) -> u64 {
+  if duration.is_zero() {
+    return 0;
+  }
+
+  let position = position.min(duration);
+  (size as u128 * position.as_nanos() / duration.as_nanos()) as u64
+}
+
 impl Drop for Decoder {
   fn drop(&mut self) {
+    // Runs before the fields are dropped, so the reader outlives the decoder reading from it
     unsafe { ffi::decoder_free(self.decoder) };
   }
 }
 
+#[cfg(test)]
+fn wav_sine(sample_rate: u32, samples: u32) -> Vec<u8> {
+  let mut wav = Vec::new();
+  wav.extend_from_slice(b"RIFF");
+  wav.extend_from_slice(&(36 + samples * 2).to_le_bytes());
+  wav.extend_from_slice(b"WAVEfmt ");
+  wav.extend_from_slice(&16u32.to_le_bytes());
+  wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+  wav.extend_from_slice(&1u16.to_le_bytes()); // Mono
+  wav.extend_from_slice(&sample_rate.to_le_bytes());
+  wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+  wav.extend_from_slice(&2u16.to_le_bytes());
+  wav.extend_from_slice(&16u16.to_le_bytes());
+  wav.extend_from_slice(b"data");
+  wav.extend_from_slice(&(samples * 2).to_le_bytes());
+  for index in 0..samples {
+    let sample = (index as f32 / sample_rate as f32 * 440.0 * std::f32::consts::TAU).sin();
+    wav.extend_from_slice(&((sample * i16::MAX as f32 / 2.0) as i16).to_le_bytes());
+  }
+  wav
+}
+
+#[test]
+fn decoder_error_is_readable() {
+  let error = DecoderError::new(AVERROR_EOF);
+  assert_eq!(error.raw(), AVERROR_EOF);
+  assert_eq!(error.to_string(), format!("End of file ({})", AVERROR_EOF));
+  assert!(!error.is_transient());
+}
+
+#[test]
+fn maps_frame_result_codes() {
+  assert_eq!(FrameResult::from_raw(AVERROR_EOF, Vec::new()), FrameResult::Eof);
+  assert_eq!(FrameResult::from_raw(AVERROR_EAGAIN, Vec::new()), FrameResult::Again);
+  assert_eq!(FrameResult::from_raw(0, Vec::new()), FrameResult::Again);
+  // Frames decoded before more input was needed are not lost
+  assert_eq!(FrameResult::from_raw(AVERROR_EAGAIN, vec![0.5; 4]), FrameResult::Frame(vec![0.5; 4]));
+  assert_eq!(FrameResult::from_raw(0, vec![0.5; 4]), FrameResult::Frame(vec![0.5; 4]));
+
+  let FrameResult::Error(error) = FrameResult::from_raw(AVERROR_EIO, vec![0.5; 4]) else {
+    panic!("expected an error");
+  };
+  assert_eq!(error.raw(), AVERROR_EIO);
+  assert!(error.is_transient());
+}
+
+#[test]
+fn decodes_from_reader() {
+  // One second
+  let wav = wav_sine(48000, 48000);
+  let mut decoder = Decoder::new();
+  decoder.open_reader(Box::new(std::io::Cursor::new(wav))).unwrap();
+
+  let mut decoded = 0;
+  for is_flush in [false, true] {
+    loop {
+      match decoder.read_frame(is_flush) {
+        FrameResult::Frame(frame) => decoded += frame.len(),
+        FrameResult::Again => {}
+        FrameResult::Eof => break,
+        FrameResult::Error(error) => panic!("{}", error)
+      }
+    }
+  }
+  // No resampling at 48 kHz, so every input sample is decoded
+  assert_eq!(decoded, 48000 * decoder.output_channels());
+}
+
+/// Decodes all frames of `wav`, starting each read with a buffer of `fixed_capacity` if set.
+#[cfg(test)]
+fn decode_frames(wav: Vec<u8>, fixed_capacity: Option<usize>) -> Vec<Vec<f32>> {
+  let mut decoder = Decoder::new();
+  decoder.open_reader(Box::new(std::io::Cursor::new(wav))).unwrap();
+
+  let mut frames = Vec::new();
+  for is_flush in [false, true] {
+    loop {
+      if let Some(capacity) = fixed_capacity {
+        decoder.read_size_hint = capacity;
+      }
+      match decoder.read_frame(is_flush) {
+        FrameResult::Frame(frame) => {
+          assert_eq!(decoder.read_size_hint, frame.len());
+          frames.push(frame);
+        }
+        FrameResult::Again => {}
+        FrameResult::Eof => break,
+        FrameResult::Error(error) => panic!("{}", error)
+      }
+    }
+  }
+  frames
+}
+
+#[test]
+fn read_size_hint_keeps_decoded_output() {
+  // Resampled, so frames differ in length and are both shorter and longer than the hint
+  let wav = wav_sine(44100, 44100 + 123);
+  let frames = decode_frames(wav.clone(), None);
+  assert!(frames.len() > 1);
+
+  // Previously every read started with a buffer of 512 samples
+  assert_eq!(frames, decode_frames(wav.clone(), Some(512)));
+  assert_eq!(frames, decode_frames(wav, Some(1)));
+}
+
+#[test]
+fn reads_metadata_of_untagged_input() {
+  let path = std::env::temp_dir().join(format!("decoder-untagged-{}.wav", std::process::id()));
+  std::fs::write(&path, wav_sine(48000, 48000)).unwrap();
+
+  let mut decoder = Decoder::new();
+  decoder.open_input(path.to_str().unwrap()).unwrap();
+  let metadata = decoder.read_metadata();
+  std::fs::remove_file(&path).unwrap();
+
+  assert_eq!(metadata.title(), None);
+  assert_eq!(metadata.artist(), None);
+  assert_eq!(metadata.duration, Some(Duration::from_secs(1)));
+  // 16-bit mono
+  assert_eq!(metadata.bit_rate, Some(48000 * 16));
+}
+
+/// Needs the `ffmpeg` executable to create the tagged input.
+#[cfg(feature = "ffmpeg-cli-tests")]
+#[test]
+fn reads_metadata_of_tagged_input() {
+  let path = std::env::temp_dir().join(format!("decoder-tagged-{}.mp3", std::process::id()));
+  let status = std::process::Command::new("ffmpeg")
+    .args(["-y", "-loglevel", "error", "-f", "lavfi", "-i", "sine=frequency=440:duration=2"])
+    .args(["-metadata", "title=Title", "-metadata", "artist=Artist", "-b:a", "128k"])
+    .arg(&path)
+    .status()
+    .unwrap();
+  assert!(status.success());
+
+  let mut decoder = Decoder::new();
+  decoder.open_input(path.to_str().unwrap()).unwrap();
+  let metadata = decoder.read_metadata();
+  std::fs::remove_file(&path).unwrap();
+
+  assert_eq!(metadata.title(), Some("Title"));
+  assert_eq!(metadata.artist(), Some("Artist"));
+  let duration = metadata.duration.unwrap();
+  assert!(duration >= Duration::from_secs(2) && duration < Duration::from_millis(2100), "{:?}", duration);
+  assert_eq!(metadata.bit_rate, Some(128_000));
+}
+
+/// Needs the `ffmpeg` executable to create the input.
+#[cfg(feature = "ffmpeg-cli-tests")]
+#[test]
+fn resamples_across_format_change() {
+  let encode = |name: &str, rate: &str, channels: &str| {
+    let path = std::env::temp_dir().join(format!("decoder-{}-{}.mp3", name, std::process::id()));
+    let status = std::process::Command::new("ffmpeg")
+      .args(["-y", "-loglevel", "error", "-f", "lavfi", "-i", "sine=frequency=440:duration=1"])
+      .args(["-ar", rate, "-ac", channels, "-b:a", "64k"])
+      .arg(&path)
+      .status()
+      .unwrap();
+    assert!(status.success());
+    let mp3 = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    mp3
+  };
+  // Frames of 1152 samples at 44.1 kHz stereo, then frames of 576 samples at 22.05 kHz mono
+  let mut input = encode("stereo", "44100", "2");
+  input.extend(encode("mono", "22050", "1"));
+
+  let mut decoder = Decoder::new();
+  decoder.open_reader(Box::new(std::io::Cursor::new(input))).unwrap();
+  let mut lengths = Vec::new();
+  for is_flush in [false, true] {
+    loop {
+      match decoder.read_frame(is_flush) {
+        FrameResult::Frame(frame) => lengths.push(frame.len()),
+        FrameResult::Again => {}
+        FrameResult::Eof => break,
+        FrameResult::Error(error) => panic!("{}", error)
+      }
+    }
+  }
+
+  let channels = decoder.output_channels();
+  assert!(lengths.iter().all(|length| length % channels == 0));
+  let mut sizes = lengths.clone();
+  sizes.sort();
+  sizes.dedup();
+  assert!(sizes.len() > 2, "frame sizes do not vary: {:?}", sizes);
+
+  // Two seconds at 48 kHz, give or take encoder delay and padding of both parts. A resampler still configured for
+  // the first part would turn the second one into half a second.
+  let mp3_frame = 1152 * 48000 / 44100;
+  let decoded = lengths.iter().sum::<usize>() / channels;
+  assert!(
+    (2 * 48000 - mp3_frame..=2 * 48000 + 6 * mp3_frame).contains(&decoded),
+    "expected about {} frames, got {}",
+    2 * 48000,
+    decoded
+  );
+}
+
+#[test]
+fn estimates_byte_offset_from_duration() {
+  // 128 kbps for 3 minutes
+  let size = 128_000 / 8 * 180;
+  let duration = Duration::from_secs(180);
+
+  assert_eq!(estimate_byte_offset(Duration::ZERO, duration, size), 0);
+  assert_eq!(estimate_byte_offset(Duration::from_secs(90), duration, size), size / 2);
+  assert_eq!(estimate_byte_offset(Duration::from_millis(1500), duration, size), 24_000);
+  // Clamped to the end of the input
+  assert_eq!(estimate_byte_offset(Duration::from_secs(200), duration, size), size);
+  assert_eq!(estimate_byte_offset(Duration::from_secs(1), Duration::ZERO, size), 0);
+}
+
 #[test]
 fn run() {
   use std::io::Write;
@@ -125,7 +617,12 @@ fn run() {
   let stdout = std::io::stdout();
   let mut handle = stdout.lock();
   loop {
-    let frame = decoder.read_frame(false).unwrap();
+    let frame = match decoder.read_frame(false) {
+      FrameResult::Frame(frame) => frame,
+      FrameResult::Again => continue,
+      FrameResult::Eof => break,
+      FrameResult::Error(error) => panic!("{}", error)
+    };
     eprintln!("Frame {} samples", frame.len());
 
     for sample in frame {
@@ -1,40 +1,67 @@
+use std::future::{poll_fn, Future};
 use std::sync::{Arc, RwLock};
+use std::task::Poll;
 
 use tokio::sync::watch::{self, Receiver, Sender};
 
+type Getter<T> = Arc<dyn Fn() -> T + Send + Sync>;
+
+enum Value<T> {
+  Owned {
+    inner: Arc<RwLock<T>>,
+    sender: Arc<Sender<()>>
+  },
+  /// Computed from other flows, see [StateFlow::map] and [StateFlow::combine].
+  Derived {
+    get: Getter<T>,
+    /// Keeps the senders of the source flows alive, so that [Receiver::changed] never fails.
+    senders: Vec<Arc<Sender<()>>>
+  }
+}
+
 // TODO(Assasans): Use watch::[Sender/Receiver]<T>?
 pub struct StateFlow<T> {
-  inner: Arc<RwLock<T>>,
-  sender: Sender<()>,
-  receiver: Receiver<()>
+  value: Value<T>,
+  /// Notified when the value (or the value of any source flow) changes.
+  receivers: Vec<Receiver<()>>
 }
 
 impl<T: Clone> StateFlow<T> {
   pub fn new(value: T) -> Self {
     let (sender, receiver) = watch::channel(());
     Self {
-      inner: Arc::new(RwLock::new(value)),
-      sender,
-      receiver
+      value: Value::Owned {
+        inner: Arc::new(RwLock::new(value)),
+        sender: Arc::new(sender)
+      },
+      receivers: vec![receiver]
     }
   }
 
+  /// Panics if the flow is derived, see [StateFlow::map].
   pub fn set(&self, value: T) {
-    *self.inner.write().unwrap() = value;
-    self.sender.send(()).unwrap() // It is not possible that [receiver] will be dropped
+    let Value::Owned { inner, sender } = &self.value else {
+      panic!("derived state flow can not be set");
+    };
+    *inner.write().unwrap() = value;
+    sender.send(()).unwrap() // It is not possible that [receiver] will be dropped
   }
 
   pub async fn await_change(&self) -> T {
-    let mut receiver = self.receiver.clone();
-    receiver.borrow_and_update();
-    receiver.changed().await.unwrap(); // It is not possible that [receiver] will be dropped
+    let mut receivers = self.receivers.clone();
+    for receiver in &mut receivers {
+      receiver.borrow_and_update();
+    }
+    changed(&mut receivers).await;
 
     self.get()
   }
 
   pub async fn wait_for(&self, block: impl Fn(&T) -> bool) -> T {
-    let mut receiver = self.receiver.clone();
-    receiver.borrow_and_update();
+    let mut receivers = self.receivers.clone();
+    for receiver in &mut receivers {
+      receiver.borrow_and_update();
+    }
 
     // Check if current value matches
     let value = self.get();
@@ -43,7 +70,7 @@ impl<T: Clone> StateFlow<T> {
     }
 
     loop {
-      receiver.changed().await.unwrap(); // It is not possible that [receiver] will be dropped
+      changed(&mut receivers).await;
 
       let value = self.get();
       if block(&value) {
@@ -53,6 +80,91 @@ impl<T: Clone> StateFlow<T> {
   }
 
   pub fn get(&self) -> T {
-    self.inner.read().unwrap().clone()
+    match &self.value {
+      Value::Owned { inner, .. } => inner.read().unwrap().clone(),
+      Value::Derived { get, .. } => get()
+    }
+  }
+}
+
+impl<T: Clone + Send + Sync + 'static> StateFlow<T> {
+  /// Returns a read-only flow of `f` applied to the value, which changes together with this flow.
+  pub fn map<U: Clone, F: Fn(&T) -> U + Send + Sync + 'static>(&self, f: F) -> StateFlow<U> {
+    let (get, senders) = self.parts();
+    StateFlow {
+      value: Value::Derived {
+        get: Arc::new(move || f(&get())),
+        senders
+      },
+      receivers: self.receivers.clone()
+    }
+  }
+
+  /// Returns a read-only flow of `f` applied to the values of `a` and `b`, which changes when either of them does.
+  pub fn combine<U, V, F>(a: &StateFlow<T>, b: &StateFlow<U>, f: F) -> StateFlow<V>
+  where
+    U: Clone + Send + Sync + 'static,
+    V: Clone,
+    F: Fn(&T, &U) -> V + Send + Sync + 'static
+  {
+    let (get_a, mut senders) = a.parts();
+    let (get_b, senders_b) = b.parts();
+    senders.extend(senders_b);
+    StateFlow {
+      value: Value::Derived {
+        get: Arc::new(move || f(&get_a(), &get_b())),
+        senders
+      },
+      receivers: a.receivers.iter().chain(&b.receivers).cloned().collect()
+    }
   }
+
+  fn parts(&self) -> (Getter<T>, Vec<Arc<Sender<()>>>) {
+    match &self.value {
+      Value::Owned { inner, sender } => {
+        let inner = inner.clone();
+        (Arc::new(move || inner.read().unwrap().clone()), vec![sender.clone()])
+      }
+      Value::Derived { get, senders } => (get.clone(), senders.clone())
+    }
+  }
+}
+
+/// Waits until any of `receivers` is notified.
+async fn changed(receivers: &mut [Receiver<()>]) {
+  let mut futures = receivers
+    .iter_mut()
+    .map(|receiver| Box::pin(receiver.changed()))
+    .collect::<Vec<_>>();
+  poll_fn(|cx| {
+    for future in &mut futures {
+      if let Poll::Ready(result) = future.as_mut().poll(cx) {
+        result.unwrap(); // It is not possible that [sender] will be dropped
+        return Poll::Ready(());
+      }
+    }
+    Poll::Pending
+  })
+  .await
+}
+
+#[tokio::test]
+async fn derived_flows_follow_sources() {
+  let state = StateFlow::new(1);
+  let paused = StateFlow::new(false);
+  let doubled = state.map(|value| value * 2);
+  let ready = StateFlow::combine(&doubled, &paused, |value, paused| *value > 2 && !paused);
+  assert_eq!(doubled.get(), 2);
+  assert!(!ready.get());
+
+  let waiter = tokio::spawn(async move { ready.wait_for(|ready| *ready).await });
+  tokio::task::yield_now().await;
+  state.set(2);
+  paused.set(true);
+  tokio::task::yield_now().await;
+  assert!(!waiter.is_finished());
+
+  paused.set(false);
+  assert!(waiter.await.unwrap());
+  assert_eq!(doubled.get(), 4);
 }
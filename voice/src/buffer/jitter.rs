@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+/// Number of inter-write intervals used to estimate jitter.
+const WINDOW_SIZE: usize = 256;
+/// How often the thresholds are re-evaluated.
+const ADJUST_INTERVAL: Duration = Duration::from_secs(5);
+/// Thresholds are only changed if the target deviates from the current low threshold by more than this fraction.
+const ADJUST_TOLERANCE: f32 = 0.2;
+const PERCENTILE: f32 = 0.95;
+
+/// Estimates [`SampleBuffer`](super::SampleBuffer) thresholds from the arrival times of written sample blocks.
+#[derive(Debug)]
+pub struct JitterController {
+  samples_per_second: usize,
+  min_low_threshold: usize,
+  max_high_threshold: usize,
+
+  intervals: VecDeque<Duration>,
+  last_write: Option<Instant>,
+  last_adjust: Option<Instant>
+}
+
+impl JitterController {
+  pub fn new(samples_per_second: usize, min_low_threshold: usize, max_high_threshold: usize) -> Self {
+    assert!(min_low_threshold <= max_high_threshold);
+
+    Self {
+      samples_per_second,
+      min_low_threshold,
+      max_high_threshold,
+
+      intervals: VecDeque::with_capacity(WINDOW_SIZE),
+      last_write: None,
+      last_adjust: None
+    }
+  }
+
+  /// Records the start of a write. The interval is measured from the end of the previous write,
+  /// so that time spent waiting for a corked buffer is not counted as jitter.
+  pub fn observe_write(&mut self, now: Instant) {
+    if let Some(last_write) = self.last_write {
+      if self.intervals.len() == WINDOW_SIZE {
+        self.intervals.pop_front();
+      }
+      self.intervals.push_back(now.saturating_duration_since(last_write));
+    }
+  }
+
+  /// Records the end of a write.
+  pub fn finish_write(&mut self, now: Instant) {
+    self.last_write = Some(now);
+  }
+
+  /// Returns the 95th percentile of observed inter-write intervals.
+  pub fn percentile_interval(&self) -> Option<Duration> {
+    if self.intervals.is_empty() {
+      return None;
+    }
+
+    let mut intervals = self.intervals.iter().copied().collect::<Vec<_>>();
+    intervals.sort_unstable();
+    let index = ((intervals.len() - 1) as f32 * PERCENTILE).round() as usize;
+    Some(intervals[index])
+  }
+
+  /// Returns the `(low, high)` thresholds the buffer should be able to absorb the measured jitter with.
+  pub fn target_thresholds(&self) -> Option<(usize, usize)> {
+    let interval = self.percentile_interval()?;
+
+    // Keep at least two worst-case write intervals buffered
+    let low = (interval.as_secs_f32() * 2.0 * self.samples_per_second as f32) as usize;
+    let low = low.clamp(self.min_low_threshold, self.max_high_threshold / 2);
+    let high = (low * 2).min(self.max_high_threshold);
+    Some((low, high))
+  }
+
+  /// Returns new thresholds at most once per 5 seconds, if the target differs enough from `low_threshold`.
+  pub fn poll(&mut self, now: Instant, low_threshold: usize) -> Option<(usize, usize)> {
+    match self.last_adjust {
+      Some(last_adjust) if now.saturating_duration_since(last_adjust) < ADJUST_INTERVAL => return None,
+      Some(_) => {}
+      None => {
+        self.last_adjust = Some(now);
+        return None;
+      }
+    }
+    self.last_adjust = Some(now);
+
+    let (low, high) = self.target_thresholds()?;
+    let deviation = (low as f32 - low_threshold as f32).abs() / low_threshold.max(1) as f32;
+    if deviation <= ADJUST_TOLERANCE {
+      return None;
+    }
+
+    debug!(
+      "jitter: p95 write interval {:?}, adjusting thresholds: low {} -> {}, high -> {}",
+      self.percentile_interval(),
+      low_threshold,
+      low,
+      high
+    );
+    Some((low, high))
+  }
+}
+
+#[test]
+fn adjusts_only_after_interval_and_tolerance() {
+  let mut controller = JitterController::new(1000, 100, 4000);
+  let start = Instant::now();
+
+  for index in 0..10 {
+    let time = start + Duration::from_millis(500 * index);
+    controller.observe_write(time);
+    controller.finish_write(time);
+  }
+  assert_eq!(controller.percentile_interval(), Some(Duration::from_millis(500)));
+  assert_eq!(controller.target_thresholds(), Some((1000, 2000)));
+
+  // First poll only starts the adjustment period
+  assert_eq!(controller.poll(start, 100), None);
+  assert_eq!(controller.poll(start + Duration::from_secs(1), 100), None);
+  assert_eq!(controller.poll(start + ADJUST_INTERVAL, 100), Some((1000, 2000)));
+  // Within tolerance of the current threshold
+  assert_eq!(controller.poll(start + ADJUST_INTERVAL * 2, 900), None);
+}
@@ -0,0 +1,399 @@
+pub mod jitter;
+
+use std::cmp::min;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use tokio::sync::watch::{Receiver, Sender};
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, trace, warn};
+use utils::state_flow::StateFlow;
+
+use self::jitter::JitterController;
+
+pub struct SampleBuffer<T> {
+  capacity: usize,
+  low_threshold: AtomicUsize,
+  high_threshold: AtomicUsize,
+  jitter: std::sync::Mutex<Option<JitterController>>,
+  overwrite_on_full: AtomicBool,
+  dropped_samples: AtomicUsize,
+  last_drop_warning: std::sync::Mutex<Option<Instant>>,
+  is_corked: StateFlow<bool>,
+  is_closed: AtomicBool,
+  /// Incremented by [Self::clear], see [Self::write_epoch].
+  epoch: AtomicU64,
+  write_performed: (Sender<()>, Receiver<()>),
+
+  producer: Mutex<HeapProducer<T>>,
+  consumer: Mutex<HeapConsumer<T>>,
+
+  length: AtomicUsize
+}
+
+impl<T: Copy> SampleBuffer<T> {
+  pub fn new(capacity: usize, low_threshold: usize, high_threshold: usize) -> Self {
+    assert!(low_threshold <= high_threshold);
+    assert!(low_threshold <= capacity);
+    assert!(high_threshold <= capacity);
+
+    let buffer = HeapRb::<T>::new(capacity);
+    let (producer, consumer) = buffer.split();
+
+    Self {
+      capacity,
+      low_threshold: AtomicUsize::new(low_threshold),
+      high_threshold: AtomicUsize::new(high_threshold),
+      jitter: std::sync::Mutex::new(None),
+      overwrite_on_full: AtomicBool::new(false),
+      dropped_samples: AtomicUsize::new(0),
+      last_drop_warning: std::sync::Mutex::new(None),
+      is_corked: StateFlow::new(false),
+      is_closed: AtomicBool::new(false),
+      epoch: AtomicU64::new(0),
+      write_performed: watch::channel(()),
+
+      producer: Mutex::new(producer),
+      consumer: Mutex::new(consumer),
+
+      length: AtomicUsize::new(0)
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.length.load(Ordering::Relaxed)
+  }
+
+  /// Adjusts thresholds automatically based on the jitter measured by `controller`.
+  pub fn with_jitter_controller(self, controller: JitterController) -> Self {
+    *self.jitter.lock().unwrap() = Some(controller);
+    self
+  }
+
+  pub fn low_threshold(&self) -> usize {
+    self.low_threshold.load(Ordering::Relaxed)
+  }
+
+  pub fn high_threshold(&self) -> usize {
+    self.high_threshold.load(Ordering::Relaxed)
+  }
+
+  pub fn set_thresholds(&self, low_threshold: usize, high_threshold: usize) {
+    assert!(low_threshold <= high_threshold);
+    assert!(high_threshold <= self.capacity);
+
+    self.low_threshold.store(low_threshold, Ordering::Relaxed);
+    self.high_threshold.store(high_threshold, Ordering::Relaxed);
+
+    if self.len() <= low_threshold && self.is_corked.get() {
+      self.is_corked.set(false);
+      debug!("set_thresholds: buffer uncorked: {} <= {}", self.len(), low_threshold);
+    }
+  }
+
+  /// If enabled, [`Self::write`] never blocks: when the buffer is full, the oldest samples are dropped instead.
+  ///
+  /// Useful for live streams, where falling behind is worse than skipping audio.
+  pub fn set_overwrite_on_full(&self, enabled: bool) {
+    self.overwrite_on_full.store(enabled, Ordering::Relaxed);
+    if enabled && self.is_corked.get() {
+      self.is_corked.set(false);
+      debug!("set_overwrite_on_full: buffer uncorked");
+    }
+  }
+
+  /// Returns the total number of samples dropped in overwrite-on-full mode.
+  pub fn dropped_samples(&self) -> usize {
+    self.dropped_samples.load(Ordering::Relaxed)
+  }
+
+  /// Returns the maximum number of samples the buffer can hold.
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Returns how full the buffer is, in percent of [`Self::capacity`].
+  pub fn fill_percent(&self) -> f32 {
+    self.len() as f32 / self.capacity() as f32 * 100.0
+  }
+
+  /// Returns the number of samples that can be read without waiting for a write.
+  pub fn available_to_read(&self) -> usize {
+    self.len()
+  }
+
+  /// Marks the end of the stream: no more samples will be written.
+  ///
+  /// Wakes up pending [`Self::wait_for`] calls and corked writers, which then fail.
+  pub fn close(&self) {
+    self.is_closed.store(true, Ordering::Release);
+    self.is_corked.set(false);
+    // It is not possible that [self.write_performed.1] will be dropped
+    self.write_performed.0.send(()).unwrap();
+    debug!("close: buffer closed with {} samples left", self.len());
+  }
+
+  /// Allows writing again after [`Self::close`].
+  pub fn reopen(&self) {
+    self.is_closed.store(false, Ordering::Release);
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.is_closed.load(Ordering::Acquire)
+  }
+
+  /// Returns `true` if the buffer is closed and fewer than `size` samples are left,
+  /// i.e. reading `size` samples would never complete.
+  pub fn is_drained(&self, size: usize) -> bool {
+    // Checked before the length, all writes happen before [Self::close]
+    self.is_closed() && self.len() < size
+  }
+
+  /// Waits until at least `size` samples are available, or the buffer is closed.
+  pub async fn wait_for(&self, size: usize) -> Result<()> {
+    trace!("waiting for at least {} samples to be available...", size);
+    loop {
+      let length = self.len();
+      if length >= size || self.is_closed() {
+        break;
+      }
+
+      let mut write_performed = self.write_performed.1.clone();
+      write_performed.borrow_and_update();
+      debug!("insufficient buffer length: {} < {}", length, size);
+      write_performed.changed().await.unwrap(); // It is not possible that [self.write_performed.0] will be dropped
+    }
+
+    Ok(())
+  }
+
+  /// Returns the number of times the buffer was cleared.
+  pub fn epoch(&self) -> u64 {
+    self.epoch.load(Ordering::Acquire)
+  }
+
+  pub async fn write(&self, data: &[T]) -> Result<()> {
+    self.write_epoch(data, self.epoch()).await.map(|_| ())
+  }
+
+  /// Writes `data` produced before the buffer was cleared for the `epoch`-th time, see [Self::epoch].
+  ///
+  /// Returns `false` if the buffer was cleared since, the rest of `data` is dropped then.
+  /// Used to discard samples that were produced before a seek, but written after it.
+  pub async fn write_epoch(&self, data: &[T], epoch: u64) -> Result<bool> {
+    trace!("writing {} samples", data.len());
+    if self.is_closed() {
+      bail!("write to closed buffer");
+    }
+    self.observe_write();
+    let overwrite = self.overwrite_on_full.load(Ordering::Relaxed);
+    if !overwrite {
+      self.is_corked.wait_for(|it| *it == false).await;
+    }
+
+    let mut producer = self.producer.lock().await;
+    let mut written = 0;
+    while written < data.len() {
+      // Held while pushing, so that [Self::clear] cannot run between the epoch check and the push
+      let mut consumer = self.consumer.lock().await;
+      if self.epoch() != epoch {
+        debug!("write: dropped {} samples of epoch {} < {}", data.len() - written, epoch, self.epoch());
+        return Ok(false);
+      }
+
+      if overwrite && producer.free_len() == 0 {
+        let count = min(data.len() - written, consumer.len());
+        let dropped = consumer.skip(count);
+        self.length.store(consumer.len(), Ordering::Release);
+
+        self.record_dropped(dropped);
+      }
+
+      let end = min(written + producer.free_len(), data.len());
+      producer.push_slice(&data[written..end]);
+      let len = producer.len();
+      self.length.store(len, Ordering::Release);
+      drop(consumer);
+      self.write_performed.0.send(())?;
+      trace!("written {written}..{end} ({}) samples", end - written);
+      written = end;
+
+      if !overwrite && len >= self.high_threshold() {
+        self.is_corked.set(true);
+        debug!("write: buffer corked: {} >= {}", len, self.high_threshold());
+      }
+
+      if self.is_corked.get() {
+        self.is_corked.wait_for(|it| *it == false).await;
+        trace!("write: buffer uncorked: {} <= {}", producer.len(), self.low_threshold());
+      }
+
+      if self.is_closed() {
+        bail!("buffer closed during write");
+      }
+    }
+
+    if let Some(jitter) = self.jitter.lock().unwrap().as_mut() {
+      jitter.finish_write(Instant::now());
+    }
+
+    Ok(true)
+  }
+
+  pub async fn read(&self, data: &mut [T]) -> Result<()> {
+    trace!("reading {} samples", data.len());
+    self.wait_for(data.len()).await?;
+    if self.len() < data.len() {
+      bail!("buffer closed with {} < {} samples left", self.len(), data.len());
+    }
+
+    let mut consumer = self.consumer.lock().await;
+    assert!(consumer.len() >= data.len());
+    consumer.pop_slice(data);
+    self.length.fetch_sub(data.len(), Ordering::AcqRel);
+
+    if consumer.len() <= self.low_threshold() && self.is_corked.get() {
+      self.is_corked.set(false);
+      debug!("read: buffer uncorked: {} <= {}", consumer.len(), self.low_threshold());
+    }
+
+    Ok(())
+  }
+
+  /// Copies the next `data.len()` samples into `data` without consuming them.
+  pub async fn peek(&self, data: &mut [T]) -> Result<()> {
+    trace!("peeking {} samples", data.len());
+    self.wait_for(data.len()).await?;
+    if self.len() < data.len() {
+      bail!("buffer closed with {} < {} samples left", self.len(), data.len());
+    }
+
+    let consumer = self.consumer.lock().await;
+    assert!(consumer.len() >= data.len());
+
+    let (head, tail) = consumer.as_slices();
+    let from_head = min(head.len(), data.len());
+    data[..from_head].copy_from_slice(&head[..from_head]);
+    data[from_head..].copy_from_slice(&tail[..data.len() - from_head]);
+
+    Ok(())
+  }
+
+  pub async fn flush(&self) -> Vec<T> {
+    let mut consumer = self.consumer.lock().await;
+
+    let data = consumer.pop_iter().collect::<Vec<T>>();
+    self.length.store(0, Ordering::Release);
+    self.is_corked.set(false);
+    debug!("flush: buffer uncorked: {} <= {}", consumer.len(), self.low_threshold());
+
+    data
+  }
+
+  fn record_dropped(&self, dropped: usize) {
+    let total = self.dropped_samples.fetch_add(dropped, Ordering::Relaxed) + dropped;
+
+    let mut last_warning = self.last_drop_warning.lock().unwrap();
+    if last_warning.map_or(true, |last_warning| last_warning.elapsed() >= Duration::from_secs(1)) {
+      warn!("buffer full, dropped {} oldest samples (total: {})", dropped, total);
+      *last_warning = Some(Instant::now());
+    }
+  }
+
+  fn observe_write(&self) {
+    let mut jitter = self.jitter.lock().unwrap();
+    if let Some(jitter) = jitter.as_mut() {
+      let now = Instant::now();
+      jitter.observe_write(now);
+      if let Some((low, high)) = jitter.poll(now, self.low_threshold()) {
+        self.set_thresholds(low, high);
+      }
+    }
+  }
+
+  pub async fn clear(&self) -> () {
+    let mut consumer = self.consumer.lock().await;
+    consumer.clear();
+    self.epoch.fetch_add(1, Ordering::AcqRel);
+
+    self.length.store(0, Ordering::Release);
+    self.is_corked.set(false);
+    debug!("clear: buffer uncorked: {} <= {}", consumer.len(), self.low_threshold());
+  }
+}
+
+#[tokio::test]
+async fn peek_does_not_consume() {
+  let buffer = SampleBuffer::<u32>::new(8, 2, 6);
+  buffer.write(&[1, 2, 3, 4]).await.unwrap();
+
+  let mut peeked = [0; 3];
+  buffer.peek(&mut peeked).await.unwrap();
+  assert_eq!(peeked, [1, 2, 3]);
+  assert_eq!(buffer.available_to_read(), 4);
+
+  let mut read = [0; 4];
+  buffer.read(&mut read).await.unwrap();
+  assert_eq!(read, [1, 2, 3, 4]);
+  assert_eq!(buffer.available_to_read(), 0);
+}
+
+#[tokio::test]
+async fn peek_across_wrap_around() {
+  let buffer = SampleBuffer::<u32>::new(4, 0, 4);
+  buffer.write(&[1, 2, 3]).await.unwrap();
+
+  let mut read = [0; 2];
+  buffer.read(&mut read).await.unwrap();
+  buffer.write(&[4, 5]).await.unwrap();
+
+  let mut peeked = [0; 3];
+  buffer.peek(&mut peeked).await.unwrap();
+  assert_eq!(peeked, [3, 4, 5]);
+
+  let mut read = [0; 3];
+  buffer.read(&mut read).await.unwrap();
+  assert_eq!(read, peeked);
+}
+
+#[tokio::test]
+async fn close_wakes_up_reader() {
+  let buffer = std::sync::Arc::new(SampleBuffer::<u32>::new(8, 2, 6));
+  buffer.write(&[1, 2]).await.unwrap();
+
+  let reader = {
+    let buffer = buffer.clone();
+    tokio::spawn(async move {
+      buffer.wait_for(4).await.unwrap();
+      buffer.is_drained(4)
+    })
+  };
+  buffer.close();
+
+  assert!(reader.await.unwrap());
+  assert!(!buffer.is_drained(2));
+  assert!(buffer.write(&[3]).await.is_err());
+
+  let mut read = [0; 4];
+  assert!(buffer.read(&mut read).await.is_err());
+  assert_eq!(buffer.flush().await, [1, 2]);
+}
+
+#[tokio::test]
+async fn drops_writes_of_previous_epoch() {
+  let buffer = SampleBuffer::<u32>::new(8, 2, 6);
+  let epoch = buffer.epoch();
+  buffer.write(&[1, 2]).await.unwrap();
+
+  // Produced before the clear, written after it
+  buffer.clear().await;
+  assert!(!buffer.write_epoch(&[3, 4], epoch).await.unwrap());
+  assert_eq!(buffer.available_to_read(), 0);
+
+  assert!(buffer.write_epoch(&[5, 6], buffer.epoch()).await.unwrap());
+  let mut read = [0; 2];
+  buffer.read(&mut read).await.unwrap();
+  assert_eq!(read, [5, 6]);
+}
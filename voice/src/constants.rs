@@ -5,5 +5,31 @@ pub const SAMPLE_RATE: usize = 48000;
 pub const CHUNK_DURATION: Duration = Duration::from_millis(20);
 pub const TIMESTAMP_STEP: usize = SAMPLE_RATE / (1000 / CHUNK_DURATION.as_millis() as usize);
 
+/// Larger voice gateway messages are dropped, real ones are at most a few kilobytes.
+pub const MAX_GATEWAY_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Maximum duration of a single voice connection handshake step.
+pub const VOICE_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A source that has not returned samples for this long is considered stalled.
+pub const SOURCE_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default duration of the fade-out when playback is stopped, see [`VoiceConnection::fade_out`](crate::VoiceConnection::fade_out).
+pub const DEFAULT_FADE_OUT_DURATION: Duration = Duration::from_millis(150);
+
+/// Default threshold of the limiter in dBFS, see [`VoiceConnection::set_limiter`](crate::VoiceConnection::set_limiter).
+pub const DEFAULT_LIMITER_THRESHOLD: f32 = -1.0;
+pub const DEFAULT_LIMITER_RELEASE: Duration = Duration::from_millis(50);
+
+/// Duration of the fade-out before silence frames are sent when pausing, resuming fades in over [CHUNK_DURATION].
+pub const PAUSE_FADE_DURATION: Duration = Duration::from_millis(10);
+
 pub const OPUS_SILENCE_FRAME: [u8; 3] = [0xF8, 0xFF, 0xFE];
 pub const OPUS_SILENCE_FRAMES: u8 = 5;
+
+/// Default DTX threshold in dBFS.
+pub const DTX_DEFAULT_THRESHOLD: f32 = -60.0;
+/// Audio must stay below the DTX threshold for this long before DTX is activated.
+pub const DTX_SILENCE_DURATION: Duration = Duration::from_millis(100);
+/// While DTX is active, a silence frame is sent every this many packets.
+pub const DTX_SILENCE_FRAME_INTERVAL: usize = 5;
@@ -0,0 +1,105 @@
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{SampleRate, StreamConfig};
+use opus::{Channels, Decoder};
+use ringbuf::{HeapProducer, HeapRb};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::constants::{CHANNEL_COUNT, CHUNK_DURATION, SAMPLE_RATE, TIMESTAMP_STEP};
+use crate::sink::{frame_to_pcm, VoiceSink};
+use crate::AudioFrame;
+
+/// Output latency, also the amount of audio buffered ahead of the device.
+const BUFFER_FRAMES: usize = 5;
+
+/// Plays audio through the default local output device, for testing without a Discord connection.
+pub struct CpalSink {
+  producer: HeapProducer<f32>,
+  decoder: Decoder,
+  /// Dropping it stops the output stream.
+  _stop: mpsc::Sender<()>
+}
+
+impl CpalSink {
+  pub fn new() -> Result<Self> {
+    let (producer, mut consumer) = HeapRb::<f32>::new(TIMESTAMP_STEP * CHANNEL_COUNT * BUFFER_FRAMES).split();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let (ready_tx, ready_rx) = mpsc::channel::<Result<()>>();
+
+    // cpal::Stream is not Send on every platform, so it lives on its own thread
+    thread::spawn(move || {
+      let stream = (|| {
+        let device = cpal::default_host()
+          .default_output_device()
+          .context("no output device")?;
+        debug!("using output device {:?}", device.name());
+
+        let config = StreamConfig {
+          channels: CHANNEL_COUNT as u16,
+          sample_rate: SampleRate(SAMPLE_RATE as u32),
+          buffer_size: cpal::BufferSize::Default
+        };
+        let stream = device.build_output_stream(
+          &config,
+          move |data: &mut [f32], _| {
+            let read = consumer.pop_slice(data);
+            // Underrun
+            data[read..].fill(0.0);
+          },
+          |error| warn!("output stream error: {:?}", error),
+          None
+        )?;
+        stream.play()?;
+        Ok(stream)
+      })();
+
+      match stream {
+        Ok(_stream) => {
+          let _ = ready_tx.send(Ok(()));
+          // Returns when the sink is dropped
+          let _ = stop_rx.recv();
+        }
+        Err(error) => {
+          let _ = ready_tx.send(Err(error));
+        }
+      }
+    });
+    ready_rx.recv().map_err(|_| anyhow!("output thread exited"))??;
+
+    Ok(Self {
+      producer,
+      decoder: Decoder::new(SAMPLE_RATE as u32, Channels::Stereo)?,
+      _stop: stop_tx
+    })
+  }
+
+  async fn push(&mut self, mut samples: &[f32]) {
+    // The device consumes samples in real time, which paces the playback loop
+    while !samples.is_empty() {
+      let written = self.producer.push_slice(samples);
+      samples = &samples[written..];
+      if !samples.is_empty() {
+        time::sleep(CHUNK_DURATION / 4).await;
+      }
+    }
+  }
+}
+
+#[async_trait]
+impl VoiceSink for CpalSink {
+  async fn send(&mut self, frame: AudioFrame) -> Result<()> {
+    let samples = frame_to_pcm(&mut self.decoder, frame)?;
+    self.push(&samples).await;
+    Ok(())
+  }
+
+  async fn skip(&mut self) -> Result<()> {
+    self.push(&[0.0; TIMESTAMP_STEP * CHANNEL_COUNT]).await;
+    Ok(())
+  }
+}
@@ -0,0 +1,261 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use flume::{Receiver, Sender};
+use tracing::{debug, warn};
+
+use crate::constants::CHANNEL_COUNT;
+use crate::provider::{to_stereo, SampleProvider, SampleProviderHandle};
+
+struct Incoming {
+  provider: Box<dyn SampleProvider>,
+  /// Fade length in interleaved samples.
+  length: usize
+}
+
+struct Fade {
+  next: Box<dyn SampleProvider>,
+  /// Samples of the next provider not mixed yet.
+  pending: VecDeque<f32>,
+  next_finished: bool,
+  length: usize,
+  position: usize
+}
+
+/// Mixes the tail of the current sample provider with the head of the next one, see [CrossfadeHandle::start].
+///
+/// Once the fade is complete or the current provider ends, the next provider becomes the current one
+/// and [CrossfadeHandle::switched] resolves. The provider only ends when the last provider ends.
+/// Both providers are converted to stereo before mixing.
+pub struct CrossfadeSampleProvider {
+  current: Box<dyn SampleProvider>,
+  current_finished: bool,
+  fade: Option<Fade>,
+  incoming: Arc<Mutex<Option<Incoming>>>,
+  fading: Arc<AtomicBool>,
+  switched_tx: Sender<usize>
+}
+
+/// Controls a [CrossfadeSampleProvider] while it is locked by the playback loop.
+#[derive(Clone)]
+pub struct CrossfadeHandle {
+  incoming: Arc<Mutex<Option<Incoming>>>,
+  fading: Arc<AtomicBool>,
+  switched_rx: Receiver<usize>
+}
+
+impl CrossfadeSampleProvider {
+  pub fn new(current: Box<dyn SampleProvider>) -> (Self, CrossfadeHandle) {
+    let incoming = Arc::new(Mutex::new(None));
+    let fading = Arc::new(AtomicBool::new(false));
+    let (switched_tx, switched_rx) = flume::unbounded();
+
+    let provider = Self {
+      current,
+      current_finished: false,
+      fade: None,
+      incoming: incoming.clone(),
+      fading: fading.clone(),
+      switched_tx
+    };
+    let handle = CrossfadeHandle {
+      incoming,
+      fading,
+      switched_rx
+    };
+    (provider, handle)
+  }
+
+  /// Makes the next provider current, returning its samples that were pulled but not mixed yet.
+  fn switch(&mut self) -> Vec<f32> {
+    let fade = self.fade.take().unwrap();
+    debug!("crossfade finished after {} of {} samples", fade.position, fade.length);
+
+    self.current = fade.next;
+    self.current_finished = fade.next_finished;
+    self.fading.store(false, Ordering::Release);
+    // The handle may be dropped, the switch does not depend on it
+    let _ = self.switched_tx.send(fade.position);
+
+    fade.pending.into()
+  }
+
+  /// Pulls from the next provider until `count` samples are pending. Returns `false` if it failed.
+  fn fill_pending(fade: &mut Fade, count: usize) -> bool {
+    while !fade.next_finished && fade.pending.len() < count {
+      match fade.next.get_samples() {
+        Ok(Some(samples)) if samples.is_empty() => break,
+        Ok(Some(samples)) => fade.pending.extend(to_stereo(samples, fade.next.spec().channels)),
+        Ok(None) => fade.next_finished = true,
+        Err(error) => {
+          warn!("next sample provider failed during crossfade: {:?}", error);
+          return false;
+        }
+      }
+    }
+    true
+  }
+}
+
+impl SampleProvider for CrossfadeSampleProvider {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    if self.current_finished {
+      return Ok(None);
+    }
+
+    if self.fade.is_none() {
+      if let Some(incoming) = self.incoming.lock().unwrap().take() {
+        debug!("starting crossfade over {} samples", incoming.length);
+        self.fade = Some(Fade {
+          next: incoming.provider,
+          pending: VecDeque::new(),
+          next_finished: false,
+          length: incoming.length.max(CHANNEL_COUNT),
+          position: 0
+        });
+      }
+    }
+
+    let channels = self.current.spec().channels;
+    let Some(samples) = self.current.get_samples()?.map(|samples| to_stereo(samples, channels)) else {
+      if self.fade.is_none() {
+        return Ok(None);
+      }
+      // Ended before the fade completed, e.g. the duration was inaccurate
+      let samples = self.switch();
+      return Ok(if samples.is_empty() && self.current_finished { None } else { Some(samples) });
+    };
+
+    let Some(fade) = &mut self.fade else {
+      return Ok(Some(samples));
+    };
+    if !Self::fill_pending(fade, samples.len()) {
+      // Hard cut to the next track when the current one ends
+      self.fade = None;
+      self.fading.store(false, Ordering::Release);
+      return Ok(Some(samples));
+    }
+
+    let mut samples = samples;
+    let frames = (fade.length / CHANNEL_COUNT) as f32;
+    for (index, sample) in samples.iter_mut().enumerate() {
+      // Equal-power ramps keep the perceived loudness constant
+      let progress = (((fade.position + index) / CHANNEL_COUNT) as f32 / frames).min(1.0) * FRAC_PI_2;
+      let next = fade.pending.pop_front().unwrap_or(0.0);
+      // Correlated signals may sum above full scale
+      *sample = (*sample * progress.cos() + next * progress.sin()).clamp(-1.0, 1.0);
+    }
+    fade.position += samples.len();
+
+    if fade.position >= fade.length {
+      samples.extend(self.switch());
+    }
+    Ok(Some(samples))
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    self.current.get_handle()
+  }
+}
+
+impl CrossfadeHandle {
+  /// Starts mixing in `next` over `length` interleaved samples.
+  ///
+  /// Returns `false` if a crossfade is already in progress.
+  pub fn start(&self, next: Box<dyn SampleProvider>, length: usize) -> bool {
+    if self.fading.swap(true, Ordering::AcqRel) {
+      return false;
+    }
+    *self.incoming.lock().unwrap() = Some(Incoming { provider: next, length });
+    true
+  }
+
+  pub fn is_fading(&self) -> bool {
+    self.fading.load(Ordering::Acquire)
+  }
+
+  /// Whether the [CrossfadeSampleProvider] was dropped, e.g. replaced by another track.
+  pub fn is_closed(&self) -> bool {
+    self.switched_rx.is_disconnected()
+  }
+
+  /// Waits until the next provider becomes the current one, returning how many of its samples were mixed in.
+  ///
+  /// Returns [None] if the [CrossfadeSampleProvider] was dropped.
+  pub async fn switched(&self) -> Option<usize> {
+    self.switched_rx.recv_async().await.ok()
+  }
+}
+
+#[cfg(test)]
+use crate::provider::test::TestProvider;
+
+#[cfg(test)]
+fn drain(provider: &mut CrossfadeSampleProvider) -> Vec<f32> {
+  let mut output = Vec::new();
+  while let Some(samples) = provider.get_samples().unwrap() {
+    output.extend(samples);
+  }
+  output
+}
+
+#[test]
+fn crossfade_mixes_and_switches_once() {
+  use std::f32::consts::FRAC_PI_4;
+
+  let (mut provider, handle) = CrossfadeSampleProvider::new(Box::new(TestProvider::new(1.0).block(100).blocks(5)));
+
+  // Plain passthrough before the fade is started
+  assert_eq!(provider.get_samples().unwrap().unwrap(), vec![1.0; 100]);
+
+  assert!(handle.start(Box::new(TestProvider::new(0.5).block(100).blocks(10)), 400));
+  assert!(!handle.start(Box::new(TestProvider::new(0.0).block(100).blocks(1)), 400));
+  let output = drain(&mut provider);
+
+  // The last 400 samples of the current provider overlap with the first 400 of the next one
+  assert_eq!(output.len(), 400 + 600);
+  assert_eq!(output[0], 1.0);
+  assert!((output[200] - (FRAC_PI_4.cos() + 0.5 * FRAC_PI_4.sin())).abs() < 1e-6);
+  assert!(output[400..].iter().all(|sample| *sample == 0.5));
+  assert!(!handle.is_fading());
+
+  assert_eq!(handle.switched_rx.try_recv(), Ok(400));
+  assert!(handle.switched_rx.try_recv().is_err());
+}
+
+#[test]
+fn crossfade_switches_when_current_ends_early() {
+  let (mut provider, handle) = CrossfadeSampleProvider::new(Box::new(TestProvider::new(1.0).block(100).blocks(2)));
+  handle.start(Box::new(TestProvider::new(0.5).block(100).blocks(3)), 1000);
+
+  let output = drain(&mut provider);
+  // Nothing of the next provider is lost
+  assert_eq!(output.len(), 300);
+  assert_eq!(handle.switched_rx.try_recv(), Ok(200));
+}
+
+#[test]
+fn crossfade_clamps_mixed_samples() {
+  let (mut provider, handle) = CrossfadeSampleProvider::new(Box::new(TestProvider::new(0.9).block(100).blocks(4)));
+  handle.start(Box::new(TestProvider::new(-0.9).block(100).blocks(2)), 400);
+  let mut opposite = drain(&mut provider);
+  opposite.truncate(400);
+
+  let (mut provider, handle) = CrossfadeSampleProvider::new(Box::new(TestProvider::new(0.9).block(100).blocks(4)));
+  handle.start(Box::new(TestProvider::new(0.9).block(100).blocks(4)), 400);
+  let mixed = drain(&mut provider);
+
+  // Gains of both ramps sum above 1 in the middle of the fade
+  assert!(mixed[..400].iter().all(|sample| (0.9..=1.0).contains(sample)));
+  assert!(mixed[..400].iter().any(|sample| *sample == 1.0));
+  // Opposite signals cancel out, but are never clamped
+  assert!(opposite.iter().all(|sample| sample.abs() <= 0.9));
+}
@@ -0,0 +1,52 @@
+use std::io;
+
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+/// Errors of the voice connection. Converts into [anyhow::Error] through [std::error::Error].
+#[derive(Debug, Error)]
+pub enum VoiceError {
+  #[error("timed out connecting to the voice server")]
+  ConnectTimeout,
+  #[error("UDP IP discovery failed")]
+  IpDiscoveryFailed,
+  #[error("voice cipher is not initialized")]
+  CipherNotInitialized,
+  #[error("voice UDP socket error: {0}")]
+  UdpSocketError(#[from] io::Error),
+  #[error("voice gateway error: {0}")]
+  WebSocketError(#[from] tungstenite::Error),
+  #[error("opus error: {0}")]
+  OpusError(#[from] opus::Error),
+  #[error("already connected")]
+  AlreadyConnected,
+  #[error("not connected")]
+  NotConnected,
+  /// The voice gateway connection task exited.
+  #[error("voice gateway connection closed")]
+  GatewayClosed,
+  #[error("unexpected voice gateway packet: {0}")]
+  UnexpectedPacket(String),
+  #[error("invalid voice gateway packet: {0}")]
+  InvalidPacket(#[from] serde_json::Error),
+  #[error("failed to encrypt voice packet")]
+  EncryptionFailed,
+  /// Packet size and buffer size.
+  #[error("voice packet of {0} bytes does not fit into the {1} byte buffer")]
+  PacketTooLarge(usize, usize),
+  #[error("voice cipher mode is not supported")]
+  UnsupportedCipherMode,
+  #[error("invalid RTCP packet: {0}")]
+  InvalidRtcpPacket(&'static str)
+}
+
+/// Errors of a [SampleProvider](crate::provider::SampleProvider), they end the playback of the current track.
+///
+/// The end of the track is not an error, `get_samples` returns [None] instead.
+#[derive(Debug, Error)]
+pub enum SampleProviderError {
+  #[error("I/O error: {0}")]
+  IoError(#[from] io::Error),
+  #[error("decode error: {0}")]
+  DecodeError(String)
+}
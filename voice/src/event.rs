@@ -1,10 +1,11 @@
 use std::net::IpAddr;
 
-use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use super::opcode::GatewayOpcode;
 use super::GatewayPacket;
+use crate::error::VoiceError;
 
 #[derive(Clone, Debug)]
 pub enum GatewayEvent {
@@ -17,7 +18,9 @@ pub enum GatewayEvent {
   HeartbeatAck(u64),
   Resume(Resume),
   Hello(Hello),
-  Resumed
+  Resumed,
+  /// Opcodes without an event, including undocumented ones (e.g. 11, 12, 18). `data` is [Value::Null] if missing.
+  Unknown { opcode: u8, data: Value }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -46,6 +49,8 @@ pub struct Ready {
   pub ssrc: u32,
   pub ip: String,
   pub port: u16,
+  /// Missing in ready packets of resumed sessions.
+  #[serde(default)]
   pub modes: Vec<String>
 }
 
@@ -58,8 +63,13 @@ pub struct SessionDescription {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Speaking {
   pub speaking: u8,
+  /// Not sent by the gateway.
+  #[serde(default)]
   pub delay: u32,
-  pub ssrc: u32
+  pub ssrc: u32,
+  /// Only sent by the gateway.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub user_id: Option<String>
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -87,7 +97,8 @@ impl From<&GatewayEvent> for GatewayOpcode {
       HeartbeatAck(_) => GatewayOpcode::HeartbeatAck,
       Resume(_) => GatewayOpcode::Resume,
       Hello(_) => GatewayOpcode::Hello,
-      Resumed => GatewayOpcode::Resumed
+      Resumed => GatewayOpcode::Resumed,
+      Unknown { opcode, .. } => GatewayOpcode::from(*opcode)
     }
   }
 }
@@ -99,13 +110,15 @@ impl From<GatewayEvent> for GatewayOpcode {
 }
 
 impl TryFrom<GatewayPacket> for GatewayEvent {
-  type Error = anyhow::Error; // TODO
+  type Error = VoiceError;
 
   fn try_from(packet: GatewayPacket) -> Result<GatewayEvent, Self::Error> {
     use serde_json::from_value;
     use GatewayOpcode::*;
 
-    let data = packet.data.context("no packet data");
+    let data = packet
+      .data
+      .ok_or_else(|| VoiceError::UnexpectedPacket(format!("no data for opcode {}", packet.opcode)));
     match packet.opcode {
       Identify => Ok(GatewayEvent::Identify(from_value(data?)?)),
       SelectProtocol => Ok(GatewayEvent::SelectProtocol(from_value(data?)?)),
@@ -117,18 +130,22 @@ impl TryFrom<GatewayPacket> for GatewayEvent {
       Resume => Ok(GatewayEvent::Resume(from_value(data?)?)),
       Hello => Ok(GatewayEvent::Hello(from_value(data?)?)),
       Resumed => Ok(GatewayEvent::Resumed),
-      _ => Err(anyhow::anyhow!("Unsupported opcode: {}", packet.opcode))
+      opcode @ (ClientDisconnect | Unknown(_)) => Ok(GatewayEvent::Unknown {
+        opcode: opcode.into(),
+        data: data.unwrap_or_default()
+      })
     }
   }
 }
 
 impl TryFrom<GatewayEvent> for GatewayPacket {
-  type Error = anyhow::Error; // TODO
+  type Error = VoiceError;
 
   fn try_from(event: GatewayEvent) -> Result<GatewayPacket, Self::Error> {
     use GatewayEvent::*;
     Ok(GatewayPacket {
       opcode: (&event).into(),
+      seq: None,
       data: match event {
         Identify(identify) => Some(serde_json::to_value(identify)?),
         SelectProtocol(select_protocol) => Some(serde_json::to_value(select_protocol)?),
@@ -139,8 +156,91 @@ impl TryFrom<GatewayEvent> for GatewayPacket {
         HeartbeatAck(nonce) => Some(serde_json::to_value(nonce)?),
         Resume(resume) => Some(serde_json::to_value(resume)?),
         Hello(hello) => Some(serde_json::to_value(hello)?),
-        Resumed => None
+        Resumed => None,
+        Unknown { data, .. } => Some(data)
       }
     })
   }
 }
+
+#[test]
+fn invalid_packets_are_typed_errors() {
+  let packet = GatewayPacket::new(GatewayOpcode::Unknown(18), None::<serde_json::Value>);
+  assert!(matches!(
+    GatewayEvent::try_from(packet),
+    Ok(GatewayEvent::Unknown { opcode: 18, data: Value::Null })
+  ));
+
+  let packet = GatewayPacket::new(GatewayOpcode::Hello, serde_json::json!({ "heartbeat_interval": "soon" }));
+  assert!(matches!(GatewayEvent::try_from(packet), Err(VoiceError::InvalidPacket(_))));
+
+  let packet = GatewayPacket::new(GatewayOpcode::Ready, None::<serde_json::Value>);
+  assert!(matches!(GatewayEvent::try_from(packet), Err(VoiceError::UnexpectedPacket(_))));
+}
+
+#[test]
+fn parses_captured_gateway_payloads() {
+  let payloads = [
+    r#"{"op":2,"d":{"ssrc":1,"ip":"127.0.0.1","port":50000,"modes":["xsalsa20_poly1305","xsalsa20_poly1305_suffix","xsalsa20_poly1305_lite"],"heartbeat_interval":1,"experiments":["fixed_keyframe_interval"]}}"#,
+    // Ready of a resumed session
+    r#"{"op":2,"d":{"ssrc":1,"ip":"127.0.0.1","port":50000}}"#,
+    r#"{"op":4,"d":{"video_codec":"H264","secret_key":[1,2,3],"mode":"xsalsa20_poly1305_suffix","media_session_id":"2f4c","audio_codec":"opus"}}"#,
+    r#"{"op":5,"d":{"user_id":"123","ssrc":5,"speaking":1}}"#,
+    r#"{"op":6,"d":1501184119561}"#,
+    r#"{"op":8,"d":{"v":4,"heartbeat_interval":13750.0}}"#,
+    r#"{"op":11,"d":{"user_ids":["123"]}}"#,
+    r#"{"op":12,"d":{"video_ssrc":0,"user_id":"123","streams":[],"audio_ssrc":5}}"#,
+    r#"{"op":13,"d":{"user_id":"123"}}"#,
+    r#"{"op":18,"d":{"user_id":"123","flags":2},"seq":7}"#,
+    r#"{"op":20,"d":{"user_id":"123","platform":0}}"#
+  ];
+
+  for payload in payloads {
+    let packet = serde_json::from_str::<GatewayPacket>(payload).unwrap();
+    // Packets are passed through as is, including the sequence number
+    assert_eq!(
+      serde_json::to_value(&packet).unwrap(),
+      serde_json::from_str::<Value>(payload).unwrap(),
+      "{}",
+      payload
+    );
+    GatewayEvent::try_from(packet).unwrap_or_else(|error| panic!("{}: {}", payload, error));
+  }
+
+  let packet = serde_json::from_str::<GatewayPacket>(payloads[1]).unwrap();
+  assert!(matches!(GatewayEvent::try_from(packet), Ok(GatewayEvent::Ready(Ready { modes, .. })) if modes.is_empty()));
+}
+
+#[test]
+fn unknown_events_round_trip() {
+  for payload in [
+    r#"{"op":11,"d":{"user_ids":["123"]}}"#,
+    r#"{"op":12,"d":{"video_ssrc":0,"user_id":"123","streams":[],"audio_ssrc":5}}"#,
+    r#"{"op":18,"d":{"user_id":"123","flags":2}}"#
+  ] {
+    let event = GatewayEvent::try_from(serde_json::from_str::<GatewayPacket>(payload).unwrap()).unwrap();
+    assert!(matches!(event, GatewayEvent::Unknown { .. }), "{:?}", event);
+
+    let packet = GatewayPacket::try_from(event).unwrap();
+    assert_eq!(serde_json::to_value(&packet).unwrap(), serde_json::from_str::<Value>(payload).unwrap());
+  }
+}
+
+#[test]
+fn serializes_ipv6_select_protocol_address() {
+  let select_protocol = SelectProtocol {
+    protocol: "udp".to_owned(),
+    data: SelectProtocolData {
+      address: "2001:db8::7".parse().unwrap(),
+      port: 50004,
+      mode: "xsalsa20_poly1305_suffix".to_owned()
+    }
+  };
+  assert_eq!(
+    serde_json::to_value(&select_protocol).unwrap(),
+    serde_json::json!({
+      "protocol": "udp",
+      "data": { "address": "2001:db8::7", "port": 50004, "mode": "xsalsa20_poly1305_suffix" }
+    })
+  );
+}
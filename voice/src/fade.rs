@@ -0,0 +1,117 @@
+use std::f32::consts::FRAC_PI_2;
+use std::time::Duration;
+
+use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+/// Linear gain ramp from full volume to silence over a fixed number of frames.
+#[derive(Debug)]
+pub(crate) struct FadeOut {
+  /// Fade length in frames.
+  length: usize,
+  position: usize
+}
+
+impl FadeOut {
+  pub fn new(duration: Duration) -> Self {
+    Self {
+      length: ((duration.as_secs_f64() * SAMPLE_RATE as f64) as usize).max(1),
+      position: 0
+    }
+  }
+
+  /// Attenuates interleaved stereo `samples`, samples after the end of the fade are silenced.
+  pub fn apply(&mut self, samples: &mut [f32]) {
+    for frame in samples.chunks_mut(CHANNEL_COUNT) {
+      let gain = 1.0 - (self.position.min(self.length) as f32 / self.length as f32);
+      for sample in frame {
+        *sample *= gain;
+      }
+      self.position += 1;
+    }
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.position >= self.length
+  }
+}
+
+/// Equal-power gain ramp between full volume and silence, avoids clicks when pausing and resuming.
+#[derive(Debug)]
+pub(crate) struct GainRamp {
+  fade_in: bool,
+  /// Ramp length in frames.
+  length: usize,
+  position: usize
+}
+
+impl GainRamp {
+  pub fn fade_in(duration: Duration) -> Self {
+    Self::new(true, duration)
+  }
+
+  pub fn fade_out(duration: Duration) -> Self {
+    Self::new(false, duration)
+  }
+
+  fn new(fade_in: bool, duration: Duration) -> Self {
+    Self {
+      fade_in,
+      length: ((duration.as_secs_f64() * SAMPLE_RATE as f64) as usize).max(1),
+      position: 0
+    }
+  }
+
+  pub fn is_fade_in(&self) -> bool {
+    self.fade_in
+  }
+
+  /// Applies the ramp to interleaved stereo `samples`, samples after the end of the ramp keep the final gain.
+  pub fn apply(&mut self, samples: &mut [f32]) {
+    for frame in samples.chunks_mut(CHANNEL_COUNT) {
+      let progress = (self.position + 1).min(self.length) as f32 / self.length as f32;
+      let gain = if self.fade_in {
+        (progress * FRAC_PI_2).sin()
+      } else {
+        (progress * FRAC_PI_2).cos()
+      };
+      for sample in frame {
+        *sample *= gain;
+      }
+      self.position += 1;
+    }
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.position >= self.length
+  }
+}
+
+#[test]
+fn fades_out_linearly() {
+  let mut fade = FadeOut::new(Duration::from_millis(1));
+  let mut samples = vec![1.0; 60 * CHANNEL_COUNT];
+  fade.apply(&mut samples);
+
+  assert!(fade.is_finished());
+  assert_eq!(samples[0], 1.0);
+  assert_eq!(samples[24 * CHANNEL_COUNT], 0.5);
+  assert!(samples[48 * CHANNEL_COUNT..].iter().all(|sample| *sample == 0.0));
+}
+
+#[test]
+fn ramps_with_equal_power() {
+  let mut fade_out = GainRamp::fade_out(Duration::from_millis(1));
+  let mut samples = vec![1.0; 60 * CHANNEL_COUNT];
+  fade_out.apply(&mut samples);
+  assert!(fade_out.is_finished());
+  assert!(samples.windows(2).all(|pair| pair[1] <= pair[0]));
+  assert!(samples[47 * CHANNEL_COUNT..].iter().all(|sample| sample.abs() < 1e-6));
+
+  let mut fade_in = GainRamp::fade_in(Duration::from_millis(1));
+  let mut samples = vec![1.0; 60 * CHANNEL_COUNT];
+  fade_in.apply(&mut samples);
+  assert!(samples.windows(2).all(|pair| pair[1] >= pair[0]));
+  assert!(samples[47 * CHANNEL_COUNT..].iter().all(|sample| *sample == 1.0));
+  // -3 dB at the midpoint instead of -6 dB of a linear ramp
+  assert!((samples[23 * CHANNEL_COUNT] - 0.5f32.sqrt()).abs() < 0.01);
+}
@@ -1,60 +1,82 @@
 pub mod buffer;
 pub mod close_code;
 pub mod constants;
+pub mod crossfade;
+#[cfg(feature = "cpal")]
+pub mod cpal_sink;
+pub mod error;
 pub mod event;
+pub mod metrics;
 pub mod opcode;
 pub mod provider;
+pub mod receive;
+pub mod rtcp;
+pub mod sink;
 pub mod udp;
+pub mod wav;
 pub mod ws;
+mod fade;
+mod limiter;
 mod rms;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::io;
+use std::future::Future;
+use std::mem;
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
-use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, PoisonError, Weak};
 use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use discortp::discord::{IpDiscoveryPacket, IpDiscoveryType, MutableIpDiscoveryPacket};
-use discortp::rtcp::report::{MutableReceiverReportPacket, ReportBlockPacket};
-use discortp::rtp::{MutableRtpPacket, RtpType};
-use discortp::MutablePacket;
 use ebur128::{EbuR128, Mode};
 use flume::{Receiver, Sender};
 pub use event::*;
 pub use opcode::*;
 use opus::{Application, Bitrate, Channels, Encoder};
-use rand::random;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::select;
 use tokio::sync::{Mutex, RwLock};
-use tokio::time::{interval, Interval};
+use tokio::task::JoinHandle;
+use tokio::time::{self, interval, Interval};
 use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tracing::*;
 use utils::state_flow::StateFlow;
-use xsalsa20poly1305::aead::generic_array::GenericArray;
-use xsalsa20poly1305::{AeadInPlace, Key, KeyInit, XSalsa20Poly1305, TAG_SIZE};
+use xsalsa20poly1305::{Key, KeyInit, XSalsa20Poly1305};
 
+use crate::buffer::jitter::JitterController;
 use crate::buffer::SampleBuffer;
 use crate::close_code::GatewayCloseCode;
 use crate::constants::{
-  CHANNEL_COUNT, CHUNK_DURATION, OPUS_SILENCE_FRAME, OPUS_SILENCE_FRAMES, SAMPLE_RATE, TIMESTAMP_STEP
+  CHANNEL_COUNT, CHUNK_DURATION, DEFAULT_FADE_OUT_DURATION, DEFAULT_LIMITER_RELEASE, DEFAULT_LIMITER_THRESHOLD,
+  DTX_DEFAULT_THRESHOLD, DTX_SILENCE_DURATION, DTX_SILENCE_FRAME_INTERVAL, OPUS_SILENCE_FRAME, OPUS_SILENCE_FRAMES,
+  PAUSE_FADE_DURATION, SAMPLE_RATE, TIMESTAMP_STEP, VOICE_CONNECT_TIMEOUT
 };
-use crate::provider::{SampleProvider, SampleProviderHandle};
+use crate::error::VoiceError;
+use crate::fade::{FadeOut, GainRamp};
+use crate::limiter::Limiter;
+use crate::metrics::VoiceMetrics;
+use crate::provider::{to_stereo, ProviderSpec, SampleProvider, SampleProviderHandle};
+use crate::receive::{run_receive_loop, ReceivedAudio, VoiceReceiver};
+use crate::rtcp::{ReportBlock, RtcpReports, RtcpStats};
+use crate::sink::{UdpSinkCommand, UdpVoiceSink, VoiceSink};
 use crate::rms::RMS;
-use crate::udp::UdpVoiceConnection;
+use crate::udp::{build_voice_packet, rtp_buffer_size, RtpHeader, UdpVoiceConnection, RTP_HEADER_SIZE};
 use crate::ws::{VoiceConnectionMode, WebSocketVoiceConnection};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GatewayPacket {
   #[serde(rename = "op")]
   opcode: GatewayOpcode,
-  #[serde(rename = "d")]
-  data: Option<Value>
+  #[serde(rename = "d", default)]
+  data: Option<Value>,
+  /// Sequence number of the packet, sent by newer gateway versions. Kept as is.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  seq: Option<u64>
 }
 
 impl GatewayPacket {
@@ -64,19 +86,54 @@ impl GatewayPacket {
   {
     Self {
       opcode,
-      data: data.into()
+      data: data.into(),
+      seq: None
     }
   }
+
+  pub fn seq(&self) -> Option<u64> {
+    self.seq
+  }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Encryption mode of voice packets, negotiated with `SelectProtocol`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[non_exhaustive]
-enum VoiceCipherMode {
+pub enum VoiceCipherMode {
   Normal,
   Suffix,
   Lite
 }
 
+impl VoiceCipherMode {
+  pub const ALL: [VoiceCipherMode; 3] = [VoiceCipherMode::Normal, VoiceCipherMode::Suffix, VoiceCipherMode::Lite];
+
+  /// Returns the name used by the voice gateway.
+  pub fn name(&self) -> &'static str {
+    match self {
+      VoiceCipherMode::Normal => "xsalsa20_poly1305",
+      VoiceCipherMode::Suffix => "xsalsa20_poly1305_suffix",
+      VoiceCipherMode::Lite => "xsalsa20_poly1305_lite"
+    }
+  }
+
+  /// Whether voice packets can be sent in this mode, `lite` is only supported for receiving.
+  pub fn is_supported(&self) -> bool {
+    *self != VoiceCipherMode::Lite
+  }
+}
+
+impl FromStr for VoiceCipherMode {
+  type Err = VoiceError;
+
+  fn from_str(name: &str) -> Result<Self, Self::Err> {
+    Self::ALL
+      .into_iter()
+      .find(|mode| mode.name() == name)
+      .ok_or(VoiceError::UnsupportedCipherMode)
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct VoiceConnectionOptions {
   pub user_id: u64,
@@ -85,15 +142,41 @@ pub struct VoiceConnectionOptions {
 
   pub endpoint: String,
   pub token: String,
-  pub session_id: String
+  pub session_id: String,
+
+  /// Whether to receive and decode audio of other users, see [VoiceConnection::received_audio].
+  pub receive: bool
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 struct IpDiscoveryResult {
   pub address: IpAddr,
   pub port: u16
 }
 
+impl IpDiscoveryResult {
+  /// Parses an IP discovery response, the address is a null-terminated IPv4 or IPv6 string.
+  fn parse(packet: &[u8]) -> Result<Self, VoiceError> {
+    let view = IpDiscoveryPacket::new(packet).ok_or(VoiceError::IpDiscoveryFailed)?;
+    if view.get_pkt_type() != IpDiscoveryType::Response {
+      warn!("Expected IP discovery response, got: {:?}", view.get_pkt_type());
+      return Err(VoiceError::IpDiscoveryFailed);
+    }
+
+    let address = view.get_address_raw();
+    let null_index = address.iter().position(|&b| b == 0).unwrap_or(address.len());
+    let address = std::str::from_utf8(&address[..null_index])
+      .ok()
+      .and_then(|it| IpAddr::from_str(it).ok())
+      .ok_or(VoiceError::IpDiscoveryFailed)?;
+
+    Ok(Self {
+      address,
+      port: view.get_port()
+    })
+  }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum VoiceConnectionState {
   Disconnected,
@@ -109,61 +192,169 @@ pub enum AudioFrame {
 
 #[derive(Debug)]
 pub enum VoiceConnectionEvent {
-  RmsPeak(f32)
+  RmsPeak(f32),
+  /// Old and new state.
+  StateChanged(VoiceConnectionState, VoiceConnectionState),
+  GatewayClosed(GatewayCloseCode),
+  Reconnecting { attempt: u32 },
+  Reconnected,
+  /// The sample provider failed or panicked, playback of the current track was ended.
+  SampleProviderError(String),
+  /// The sample provider did not return samples for [`SOURCE_STALL_TIMEOUT`](crate::constants::SOURCE_STALL_TIMEOUT)
+  /// and the sample buffer is running low.
+  /// Emitted once per stall.
+  SourceStalled
+}
+
+/// How [`VoiceConnection::run_playback_loop`] finished.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum PlaybackLoopExit {
+  /// The track ended or [`VoiceConnection::stop_udp_loop`] was set.
+  Finished,
+  /// Stopped by [`VoiceConnection::disconnect`], nothing was flushed.
+  Disconnected
 }
 
 pub struct VoiceConnection {
   pub ws: RwLock<Option<WebSocketVoiceConnection>>,
+  guild_id: AtomicU64,
   ws_heartbeat_interval: Mutex<Option<Interval>>,
+  /// UDP socket while the UDP loop is not running. The loop takes it on start and returns it on finish.
   pub udp: Mutex<Option<UdpVoiceConnection>>,
+  udp_commands_tx: Sender<UdpSinkCommand>,
+  udp_commands_rx: Receiver<UdpSinkCommand>,
+  playback_stop_tx: Sender<()>,
+  playback_stop_rx: Receiver<()>,
+  rtp_sequence: AtomicU16,
+  rtp_timestamp: AtomicU32,
   cipher: Mutex<Option<XSalsa20Poly1305>>,
-  cipher_mode: VoiceCipherMode,
+  /// Requested on the next [Self::connect], see [Self::set_cipher_mode].
+  cipher_mode: std::sync::Mutex<VoiceCipherMode>,
   opus_encoder: Mutex<Encoder>,
+  /// Bitrate set with [Self::set_bitrate] in bits per second, `0` for the encoder default.
+  bitrate: AtomicU32,
   pub sample_provider: std::sync::Mutex<Option<Box<dyn SampleProvider>>>,
   pub sample_provider_handle: Mutex<Option<Box<dyn SampleProviderHandle>>>,
   pub state: StateFlow<VoiceConnectionState>,
   paused: StateFlow<bool>,
+  /// Whether the playback loop is sending audio: playing and not paused.
+  pub ready_to_play: StateFlow<bool>,
   silence_frames_left: AtomicU8,
+  /// Threshold in dBFS (as [f32] bits) below which audio is considered silence.
+  dtx_threshold: AtomicU32,
+  /// Set while only occasional silence frames are sent instead of audio.
+  pub dtx_active: AtomicBool,
+  /// Linear gain (as [f32] bits) applied to samples before encoding.
+  volume: AtomicU32,
+  /// Applied after the volume, see [Self::set_limiter].
+  limiter: std::sync::Mutex<Option<Limiter>>,
   pub sample_buffer: SampleBuffer<f32>,
+  playback_base: std::sync::Mutex<Duration>,
+  /// Position the next playback loop starts at, see [`Self::set_start_position`].
+  start_position: std::sync::Mutex<Duration>,
+  samples_sent: AtomicU64,
+  /// Media seconds played per second of output, see [`Self::set_playback_speed`].
+  playback_speed: std::sync::Mutex<f64>,
   pub rms: std::sync::Mutex<RMS<f32>>,
   pub ebur128: std::sync::Mutex<EbuR128>,
   pub stop_udp_loop: AtomicBool,
+  /// If set together with [`Self::stop_udp_loop`], the playback loop fades out before stopping,
+  /// see [`Self::set_fade_out_duration`].
+  pub fade_out: AtomicBool,
+  fade_out_duration: std::sync::Mutex<Duration>,
+  /// Applied to the next packets after pausing or resuming, see [Self::set_paused].
+  pause_ramp: std::sync::Mutex<Option<GainRamp>>,
+  reconnect_attempt: AtomicU32,
   events_tx: Sender<VoiceConnectionEvent>,
+  /// Lossy: if nobody reads events, the oldest ones are dropped.
   pub events: Receiver<VoiceConnectionEvent>,
+  /// Reception statistics of the sent audio from RTCP receiver reports.
+  rtcp_reports: Arc<std::sync::Mutex<RtcpReports>>,
+  metrics: VoiceMetrics,
+  /// SSRC to user ID mapping from `Speaking` events.
+  ssrc_users: Arc<std::sync::RwLock<HashMap<u32, u64>>>,
+  receive_task: std::sync::Mutex<Option<JoinHandle<()>>>,
+  received_audio_tx: Sender<ReceivedAudio>,
+  /// Audio of other users, if enabled with [VoiceConnectionOptions::receive]. Dropped if not read in time.
+  pub received_audio: Receiver<ReceivedAudio>
 }
 
 impl VoiceConnection {
   pub fn new() -> Result<Self> {
     let (events_tx, events_rx) = flume::bounded(16);
+    let (udp_commands_tx, udp_commands_rx) = flume::unbounded();
+    let (playback_stop_tx, playback_stop_rx) = flume::unbounded();
+    let (received_audio_tx, received_audio_rx) = flume::bounded(256);
+    let state = StateFlow::new(VoiceConnectionState::Disconnected);
+    let paused = StateFlow::new(false);
+    let ready_to_play = StateFlow::combine(&state, &paused, |state, paused| {
+      *state == VoiceConnectionState::Playing && !paused
+    });
 
     Ok(Self {
       ws: RwLock::new(None),
+      guild_id: AtomicU64::new(0),
       ws_heartbeat_interval: Mutex::new(None),
       udp: Mutex::new(None),
+      udp_commands_tx,
+      udp_commands_rx,
+      playback_stop_tx,
+      playback_stop_rx,
+      rtp_sequence: AtomicU16::new(0),
+      rtp_timestamp: AtomicU32::new(0),
       cipher: Mutex::new(None),
-      cipher_mode: VoiceCipherMode::Suffix,
+      cipher_mode: std::sync::Mutex::new(VoiceCipherMode::Suffix),
       opus_encoder: Mutex::new(Encoder::new(48000, Channels::Stereo, Application::Audio)?),
+      bitrate: AtomicU32::new(0),
       sample_provider: std::sync::Mutex::new(None),
       sample_provider_handle: Mutex::new(None),
-      state: StateFlow::new(VoiceConnectionState::Disconnected),
-      paused: StateFlow::new(false),
+      state,
+      paused,
+      ready_to_play,
       silence_frames_left: AtomicU8::new(0),
-      sample_buffer: SampleBuffer::new(SAMPLE_RATE * 3, SAMPLE_RATE, SAMPLE_RATE * 2),
+      dtx_threshold: AtomicU32::new(DTX_DEFAULT_THRESHOLD.to_bits()),
+      dtx_active: AtomicBool::new(false),
+      volume: AtomicU32::new(1f32.to_bits()),
+      limiter: std::sync::Mutex::new(Some(Limiter::new(DEFAULT_LIMITER_THRESHOLD, DEFAULT_LIMITER_RELEASE))),
+      sample_buffer: SampleBuffer::new(SAMPLE_RATE * 3, SAMPLE_RATE, SAMPLE_RATE * 2).with_jitter_controller(
+        JitterController::new(SAMPLE_RATE * CHANNEL_COUNT, SAMPLE_RATE / 4, SAMPLE_RATE * 3)
+      ),
+      playback_base: std::sync::Mutex::new(Duration::ZERO),
+      start_position: std::sync::Mutex::new(Duration::ZERO),
+      samples_sent: AtomicU64::new(0),
+      playback_speed: std::sync::Mutex::new(1.0),
       rms: std::sync::Mutex::new(RMS::new(((SAMPLE_RATE * CHANNEL_COUNT) as f32 * 5.0) as usize)),
       ebur128: std::sync::Mutex::new(EbuR128::new(CHANNEL_COUNT as u32, SAMPLE_RATE as u32, Mode::M | Mode::S | Mode::I | Mode::TRUE_PEAK).unwrap()),
       stop_udp_loop: AtomicBool::new(false),
+      fade_out: AtomicBool::new(false),
+      fade_out_duration: std::sync::Mutex::new(DEFAULT_FADE_OUT_DURATION),
+      pause_ramp: std::sync::Mutex::new(None),
+      reconnect_attempt: AtomicU32::new(0),
       events_tx,
-      events: events_rx
+      events: events_rx,
+      rtcp_reports: Default::default(),
+      metrics: VoiceMetrics::new(),
+      ssrc_users: Default::default(),
+      receive_task: std::sync::Mutex::new(None),
+      received_audio_tx,
+      received_audio: received_audio_rx
     })
   }
 
-  pub async fn connect(&self, options: VoiceConnectionOptions) -> Result<()> {
+  /// Connects to the voice server. A playing connection may be moved to another server,
+  /// the running UDP loop picks up the new socket.
+  #[instrument(name = "voice", skip_all, fields(guild_id = options.guild_id))]
+  pub async fn connect(&self, options: VoiceConnectionOptions) -> Result<(), VoiceError> {
+    self.guild_id.store(options.guild_id, Ordering::Relaxed);
+    if self.state.get() == VoiceConnectionState::Connected {
+      return Err(VoiceError::AlreadyConnected);
+    }
+    if self.state.get() == VoiceConnectionState::Disconnected {
+      self.reset().await?;
+    }
+
     if let Some(bitrate) = options.bitrate {
-      self
-        .opus_encoder
-        .lock()
-        .await
-        .set_bitrate(Bitrate::Bits(i32::try_from(bitrate)?))?;
+      self.set_bitrate(bitrate).await?;
     }
     debug!("using bitrate {:?}", self.opus_encoder.lock().await.get_bitrate());
 
@@ -171,63 +362,104 @@ impl VoiceConnection {
     // self.opus_encoder.lock().await.set_packet_loss_perc(50)?;
 
     debug!("connecting to gateway {}", options.endpoint);
-    *self.ws.write().await = Some(WebSocketVoiceConnection::new(VoiceConnectionMode::New(options.clone())).await?);
+    let ws = with_connect_timeout(WebSocketVoiceConnection::new(VoiceConnectionMode::New(options.clone()))).await?;
+    *self.ws.write().await = Some(ws);
 
     let ws = self.ws.read().await;
-    let ws = ws.as_ref().context("no voice gateway connection")?;
+    let ws = ws.as_ref().ok_or(VoiceError::NotConnected)?;
 
-    let hello = ws.hello.as_ref().context("no voice hello packet")?;
-    let ready = ws.ready.as_ref().context("no voice ready packet")?;
+    // Both are set by a successful handshake
+    let hello = ws.hello.as_ref().ok_or(VoiceError::NotConnected)?;
+    let ready = ws.ready.as_ref().ok_or(VoiceError::NotConnected)?;
 
     *self.ws_heartbeat_interval.lock().await =
       Some(interval(Duration::from_millis(hello.heartbeat_interval.round() as u64)));
 
     debug!("connecting to udp {}", options.endpoint);
-    *self.udp.lock().await = Some(UdpVoiceConnection::new(ready).await?);
+    *self.udp.lock().await = Some(UdpVoiceConnection::new(ready, options.bitrate).await?);
 
     let ip = self.discover_udp_ip(ready).await?;
     debug!("public ip: {:?}", ip);
 
+    let cipher_mode = self.cipher_mode();
+    if !ready.modes.is_empty() && !ready.modes.iter().any(|mode| mode == cipher_mode.name()) {
+      warn!("cipher mode {} is not advertised by the server: {:?}", cipher_mode.name(), ready.modes);
+      return Err(VoiceError::UnsupportedCipherMode);
+    }
+
     ws.send(
       GatewayEvent::SelectProtocol(SelectProtocol {
         protocol: "udp".to_owned(),
         data: SelectProtocolData {
           address: ip.address,
           port: ip.port,
-          mode: "xsalsa20_poly1305_suffix".to_owned()
+          mode: cipher_mode.name().to_owned()
         }
       })
       .try_into()?
     )
     .await?;
 
-    let session_description = loop {
-      // Ignore undocumented opcode 18
-      let event: GatewayEvent = match ws.receive().await?.try_into() {
-        Ok(event) => event,
-        Err(_) => continue
-      };
-
-      match event {
-        GatewayEvent::SessionDescription(description) => break description,
-        other => {
-          warn!("Expected SessionDescription packet, got: {:?}", other);
-          return Err(anyhow!("Invalid packet")); // TODO
+    let session_description = with_connect_timeout(async {
+      loop {
+        let event: GatewayEvent = ws.receive().await?.try_into()?;
+        match event {
+          GatewayEvent::SessionDescription(description) => return Ok(description),
+          // Undocumented opcodes, e.g. 18 (client flags), are sent at any time
+          GatewayEvent::Unknown { opcode, .. } => debug!("ignoring opcode {} before SessionDescription", opcode),
+          other => {
+            warn!("Expected SessionDescription packet, got: {:?}", other);
+            return Err(VoiceError::UnexpectedPacket(format!("{:?}", other)));
+          }
         }
       }
-    };
+    })
+    .await?;
 
     let key = Key::from_slice(&session_description.secret_key);
-    *self.cipher.lock().await = Some(XSalsa20Poly1305::new(&key));
+    let cipher = XSalsa20Poly1305::new(&key);
+    *self.cipher.lock().await = Some(cipher.clone());
+
+    self.stop_receiving();
+    if options.receive {
+      let udp = self.udp.lock().await;
+      let socket = udp.as_ref().ok_or(VoiceError::NotConnected)?.socket.clone();
+      let receiver = VoiceReceiver::new(cipher.clone(), cipher_mode);
+      *self.receive_task.lock().unwrap() = Some(tokio::spawn(
+        run_receive_loop(
+          socket,
+          receiver,
+          self.ssrc_users.clone(),
+          self.received_audio_tx.clone(),
+          ready.ssrc,
+          self.rtcp_reports.clone()
+        )
+        .in_current_span()
+      ));
+    }
 
-    self.state.set(VoiceConnectionState::Connected);
+    if self.state.get() == VoiceConnectionState::Playing {
+      debug!("handing new UDP socket over to running UDP loop");
+      let udp = self.udp.lock().await.take().ok_or(VoiceError::NotConnected)?;
+      self.send_udp_command(UdpSinkCommand::Rebind(udp));
+      self.send_udp_command(UdpSinkCommand::UpdateCipher(cipher));
+    } else {
+      self.set_state(VoiceConnectionState::Connected);
+    }
 
     Ok(())
   }
 
-  pub async fn disconnect(&self) -> Result<()> {
-    self.state.set(VoiceConnectionState::Disconnected);
+  pub async fn disconnect(&self) -> Result<(), VoiceError> {
+    let was_playing = self.state.get() == VoiceConnectionState::Playing;
+    self.set_state(VoiceConnectionState::Disconnected);
+    self.stop_receiving();
+    self.ssrc_users.write().unwrap().clear();
     *self.udp.lock().await = None;
+    if was_playing {
+      // Receiver is owned by self, so sending never fails
+      let _ = self.playback_stop_tx.send(());
+    }
 
     let mut ws_lock = self.ws.write().await;
     if let Some(ref ws) = *ws_lock {
@@ -246,129 +478,214 @@ impl VoiceConnection {
     Ok(())
   }
 
-  pub fn is_connected(&self) -> bool {
-    self.state.get() != VoiceConnectionState::Disconnected
+  /// Resets the state left by a previous connection, so that the connection can be reused after [Self::disconnect].
+  ///
+  /// Called by [Self::connect] if disconnected.
+  pub async fn reset(&self) -> Result<(), VoiceError> {
+    *self.cipher.lock().await = None;
+    self.clear_sample_buffer(Duration::ZERO).await;
+    self.silence_frames_left.store(0, Ordering::Relaxed);
+    self.stop_udp_loop.store(false, Ordering::Relaxed);
+    self.fade_out.store(false, Ordering::Relaxed);
+    *self.pause_ramp.lock().unwrap() = None;
+    self.set_state(VoiceConnectionState::Disconnected);
+
+    Ok(())
   }
 
-  async fn discover_udp_ip(&self, ready: &Ready) -> Result<IpDiscoveryResult> {
-    let mut udp_guard = self.udp.lock().await;
-    let udp = udp_guard.as_mut().context("no voice UDP socket")?;
+  /// Guild of the last [`Self::connect`], `0` before connecting. Recorded in the `voice` tracing spans.
+  pub fn guild_id(&self) -> u64 {
+    self.guild_id.load(Ordering::Relaxed)
+  }
 
-    let mut buffer = [0; IpDiscoveryPacket::const_packet_size()];
-    let mut view = MutableIpDiscoveryPacket::new(&mut buffer).unwrap();
-    view.set_pkt_type(IpDiscoveryType::Request);
-    view.set_length(70);
-    view.set_ssrc(ready.ssrc);
-    udp.socket.send(&buffer).await?;
+  pub fn cipher_mode(&self) -> VoiceCipherMode {
+    *self.cipher_mode.lock().unwrap()
+  }
 
-    let (length, _address) = udp.socket.recv_from(&mut buffer).await?;
-    let view = IpDiscoveryPacket::new(&buffer[..length]).unwrap();
-    if view.get_pkt_type() != IpDiscoveryType::Response {
-      return Err(anyhow!("Invalid response")); // TODO
+  /// Sets the cipher mode requested on the next [Self::connect], e.g. to debug voice issues.
+  /// Fails if connected or if packets can not be sent in `mode`.
+  pub fn set_cipher_mode(&self, mode: VoiceCipherMode) -> Result<(), VoiceError> {
+    if self.is_connected() {
+      return Err(VoiceError::AlreadyConnected);
     }
-
-    let null_index = view.get_address_raw().iter().position(|&b| b == 0).unwrap();
-
-    Ok(IpDiscoveryResult {
-      address: std::str::from_utf8(&view.get_address_raw()[..null_index]).map(|it| IpAddr::from_str(it))??,
-      port: view.get_port()
-    })
+    if !mode.is_supported() {
+      return Err(VoiceError::UnsupportedCipherMode);
+    }
+    *self.cipher_mode.lock().unwrap() = mode;
+    Ok(())
   }
 
-  pub async fn recv_rtcp_stats(&self, udp: &mut UdpVoiceConnection) -> Result<()> {
-    let mut buffer = [0; 4096];
-    let (length, _address) = match udp.socket.try_recv_from(&mut buffer) {
-      Ok((length, address)) => (length, address),
-      Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
-      Err(error) => return Err(anyhow::anyhow!(error))
-    };
-
-    let mut nonce_bytes = [0; 24];
-    nonce_bytes.copy_from_slice(&buffer[length - 24..length]);
-    let nonce = GenericArray::from_slice(&nonce_bytes);
+  pub fn is_connected(&self) -> bool {
+    self.state.get() != VoiceConnectionState::Disconnected
+  }
 
-    let mut view = MutableReceiverReportPacket::new(&mut buffer[..length - 24]).unwrap();
+  fn set_state(&self, state: VoiceConnectionState) {
+    let old = self.state.get();
+    self.state.set(state);
+    if old != state {
+      self.emit(VoiceConnectionEvent::StateChanged(old, state));
+    }
+  }
 
-    let mut tag_bytes = [0; TAG_SIZE];
-    tag_bytes.copy_from_slice(&view.payload_mut()[..TAG_SIZE]);
-    let tag = GenericArray::from_slice(&tag_bytes);
+  /// Sends `event` without blocking, dropping the oldest event if the channel is full.
+  fn emit(&self, event: VoiceConnectionEvent) {
+    let mut event = event;
+    loop {
+      match self.events_tx.try_send(event) {
+        Ok(()) => return,
+        Err(flume::TrySendError::Full(returned)) => {
+          if let Ok(dropped) = self.events.try_recv() {
+            trace!("events channel full, dropped {:?}", dropped);
+          }
+          event = returned;
+        }
+        // Receiver is owned by self
+        Err(flume::TrySendError::Disconnected(_)) => unreachable!()
+      }
+    }
+  }
 
-    let cipher_guard = self.cipher.lock().await;
-    let cipher = cipher_guard.as_ref().context("no voice cipher")?;
+  /// Returns the RTP sequence number and timestamp of the last sent voice packet.
+  pub fn rtp_state(&self) -> (u16, u32) {
+    (self.rtp_sequence.load(Ordering::Relaxed), self.rtp_timestamp.load(Ordering::Relaxed))
+  }
 
-    let data = &mut view.payload_mut()[TAG_SIZE..];
+  /// Packet loss and jitter of the sent audio, from RTCP reports of the voice server.
+  pub fn rtcp_stats(&self) -> RtcpStats {
+    self.rtcp_reports.lock().unwrap().local()
+  }
 
-    cipher.decrypt_in_place_detached(nonce, b"", data, tag).unwrap();
+  /// RTCP statistics of every source reported by the voice server, including the sent audio.
+  pub fn rtcp_reports(&self) -> RtcpReports {
+    self.rtcp_reports.lock().unwrap().clone()
+  }
 
-    // TODO(Assasans): Support view.rx_report_count != 1
-    let report = ReportBlockPacket::new(data).unwrap();
-    debug!("{report:?}");
+  /// Totals of the audio sent since the connection was created.
+  pub fn metrics(&self) -> &VoiceMetrics {
+    &self.metrics
+  }
 
-    Ok(())
+  /// Accounts RTCP report blocks, e.g. from [`UdpVoiceSink`]. `ssrc` is the SSRC of the sent audio.
+  pub(crate) fn add_rtcp_reports(&self, ssrc: u32, blocks: &[ReportBlock]) {
+    self.rtcp_reports.lock().unwrap().add(ssrc, blocks);
   }
 
-  pub async fn send_voice_packet(&self, ready: &Ready, udp: &mut UdpVoiceConnection, frame: AudioFrame) -> Result<()> {
-    let cipher_guard = self.cipher.lock().await;
-    let cipher = cipher_guard.as_ref().context("no voice cipher")?;
+  /// Whether audio of other users is being received, see [VoiceConnectionOptions::receive].
+  pub fn is_receiving(&self) -> bool {
+    self
+      .receive_task
+      .lock()
+      .unwrap()
+      .as_ref()
+      .map_or(false, |task| !task.is_finished())
+  }
 
-    let rtp_buffer_length = udp.rtp_buffer.len();
-    let mut view = MutableRtpPacket::new(&mut *udp.rtp_buffer).unwrap();
-    view.set_version(2);
-    view.set_payload_type(RtpType::Unassigned(0x78));
+  fn stop_receiving(&self) {
+    if let Some(task) = self.receive_task.lock().unwrap().take() {
+      task.abort();
+    }
+  }
 
-    view.set_sequence(udp.sequence);
-    udp.sequence += 1;
+  fn send_udp_command(&self, command: UdpSinkCommand) {
+    // Receiver is owned by self, so sending never fails
+    let _ = self.udp_commands_tx.send(command);
+  }
 
-    view.set_timestamp(udp.timestamp);
-    udp.timestamp += TIMESTAMP_STEP as u32;
+  async fn discover_udp_ip(&self, ready: &Ready) -> Result<IpDiscoveryResult, VoiceError> {
+    let mut udp_guard = self.udp.lock().await;
+    let udp = udp_guard.as_mut().ok_or(VoiceError::NotConnected)?;
 
+    let mut buffer = [0; IpDiscoveryPacket::const_packet_size()];
+    let mut view = MutableIpDiscoveryPacket::new(&mut buffer).unwrap();
+    view.set_pkt_type(IpDiscoveryType::Request);
+    view.set_length(70);
     view.set_ssrc(ready.ssrc);
+    udp.socket.send(&buffer).await?;
+
+    let receive = async { udp.socket.recv_from(&mut buffer).await.map_err(VoiceError::from) };
+    let (length, _address) = with_connect_timeout(receive).await?;
+    IpDiscoveryResult::parse(&buffer[..length])
+  }
 
-    let payload = view.payload_mut();
+  /// Changes the encoder bitrate in bits per second, e.g. when the channel bitrate changes.
+  ///
+  /// The encoder lock is held while encoding a frame, so the change applies between frames.
+  pub async fn set_bitrate(&self, bitrate: u32) -> Result<(), VoiceError> {
+    let mut encoder = self.opus_encoder.lock().await;
+    encoder.set_bitrate(Bitrate::Bits(i32::try_from(bitrate).unwrap_or(i32::MAX)))?;
+    self.bitrate.store(bitrate, Ordering::Relaxed);
+    debug!("set bitrate to {}", bitrate);
+    Ok(())
+  }
 
-    assert_eq!(self.cipher_mode, VoiceCipherMode::Suffix); // TODO: Implement rest
-    let nonce_bytes = random::<[u8; 24]>();
-    let nonce = GenericArray::from_slice(&nonce_bytes);
+  /// Returns the bitrate the encoder is configured with in bits per second.
+  pub async fn encoder_bitrate(&self) -> Option<u32> {
+    match self.opus_encoder.lock().await.get_bitrate() {
+      Ok(Bitrate::Bits(bitrate)) => u32::try_from(bitrate).ok(),
+      _ => None
+    }
+  }
 
-    let size = match frame {
-      AudioFrame::Opus(data) => {
-        payload[TAG_SIZE..TAG_SIZE + data.len()].copy_from_slice(&data);
-        data.len()
-      }
-      AudioFrame::Pcm(data) => self.opus_encoder.lock().await.encode_float(
-        &data,
-        &mut payload[TAG_SIZE..TAG_SIZE + rtp_buffer_length - 12 - nonce_bytes.len()]
-      )?
+  /// Waits for the packet deadline and sends `frame`, see [build_voice_packet] for the packet layout.
+  pub async fn send_voice_packet(
+    &self,
+    ready: &Ready,
+    udp: &mut UdpVoiceConnection,
+    cipher: &XSalsa20Poly1305,
+    frame: AudioFrame
+  ) -> Result<(), VoiceError> {
+    let header = RtpHeader {
+      sequence: udp.sequence,
+      timestamp: udp.timestamp,
+      ssrc: ready.ssrc
     };
+    // The bitrate may have been raised after the socket was created
+    let bitrate = self.bitrate.load(Ordering::Relaxed);
+    let buffer_size = rtp_buffer_size((bitrate > 0).then_some(bitrate));
+    if udp.rtp_buffer.len() < buffer_size {
+      udp.rtp_buffer.resize(buffer_size, 0);
+    }
 
-    payload[TAG_SIZE + size..TAG_SIZE + size + nonce_bytes.len()].copy_from_slice(&nonce_bytes);
+    let samples = match &frame {
+      AudioFrame::Pcm(samples) => samples.len(),
+      AudioFrame::Opus(_) => TIMESTAMP_STEP * CHANNEL_COUNT
+    };
+    let packet_size = {
+      let mut encoder = self.opus_encoder.lock().await;
+      build_voice_packet(&mut udp.rtp_buffer, frame, header, &mut encoder, cipher, self.cipher_mode())?
+    };
 
-    let tag = cipher.encrypt_in_place_detached(nonce, b"", &mut payload[TAG_SIZE..TAG_SIZE + size]);
-    match tag {
-      Ok(tag) => {
-        payload[..TAG_SIZE].copy_from_slice(tag.as_slice());
+    self.rtp_sequence.store(udp.sequence.0 .0, Ordering::Relaxed);
+    udp.sequence += 1;
+    udp.packets_sent = udp.packets_sent.wrapping_add(1);
+    udp.octets_sent = udp.octets_sent.wrapping_add((packet_size - RTP_HEADER_SIZE) as u32);
+    self.rtp_timestamp.store(udp.timestamp.0 .0, Ordering::Relaxed);
+    udp.timestamp += TIMESTAMP_STEP as u32;
 
-        spin_sleep::sleep(udp.deadline - Instant::now());
-        let delta = Instant::now().saturating_duration_since(udp.deadline);
-        udp.deadline = Instant::now() + CHUNK_DURATION;
-        udp
-          .socket
-          .send(&udp.rtp_buffer[..12 + TAG_SIZE + size + nonce_bytes.len()])
-          .await?;
+    spin_sleep::sleep(udp.deadline.saturating_duration_since(Instant::now()));
+    let delta = Instant::now().saturating_duration_since(udp.deadline);
+    udp.deadline = Instant::now() + CHUNK_DURATION;
+    udp.socket.send(&udp.rtp_buffer[..packet_size]).await?;
+    self.metrics.add_packet(samples, packet_size);
 
-        if delta > CHUNK_DURATION {
-          warn!("Voice packet deadline exceeded by {:?}", delta - CHUNK_DURATION);
-        }
-      }
-      Err(error) => {
-        return Err(anyhow!(error));
-      }
+    if delta > CHUNK_DURATION {
+      warn!("Voice packet deadline exceeded by {:?}", delta - CHUNK_DURATION);
     }
 
     Ok(())
   }
 
+  /// Pausing fades out over [PAUSE_FADE_DURATION] before sending silence frames, resuming fades in over a packet.
   pub fn set_paused(&self, is_paused: bool) {
+    if self.paused.get() != is_paused {
+      let mut pause_ramp = self.pause_ramp.lock().unwrap();
+      *pause_ramp = match pause_ramp.take() {
+        // Resumed before the fade-out was played, the audio never stopped
+        Some(ramp) if !is_paused && !ramp.is_fade_in() => None,
+        _ if is_paused => Some(GainRamp::fade_out(PAUSE_FADE_DURATION)),
+        _ => Some(GainRamp::fade_in(CHUNK_DURATION))
+      };
+    }
     self.paused.set(is_paused);
     self.rms.lock().unwrap().reset();
     if is_paused {
@@ -382,6 +699,97 @@ impl VoiceConnection {
     self.paused.get()
   }
 
+  pub fn dtx_threshold(&self) -> f32 {
+    f32::from_bits(self.dtx_threshold.load(Ordering::Relaxed))
+  }
+
+  /// Sets the level in dBFS below which audio is replaced with silence frames (DTX), see [DTX_SILENCE_DURATION].
+  pub fn set_dtx_threshold(&self, threshold: f32) {
+    self.dtx_threshold.store(threshold.to_bits(), Ordering::Relaxed);
+  }
+
+  pub fn volume(&self) -> f32 {
+    f32::from_bits(self.volume.load(Ordering::Relaxed))
+  }
+
+  /// Sets the linear gain applied to played audio, takes effect on the next packet.
+  pub fn set_volume(&self, volume: f32) {
+    self.volume.store(volume.to_bits(), Ordering::Relaxed);
+  }
+
+  /// Enables the limiter with the threshold in dBFS and the release time, or disables it with [None].
+  /// Keeps volumes above 100% from clipping.
+  pub fn set_limiter(&self, threshold: Option<f32>, release: Duration) {
+    *self.limiter.lock().unwrap() = threshold.map(|threshold| Limiter::new(threshold, release));
+  }
+
+  /// Sets the duration of the fade-out requested with [`Self::fade_out`], [`Duration::ZERO`] disables it.
+  pub fn set_fade_out_duration(&self, duration: Duration) {
+    *self.fade_out_duration.lock().unwrap() = duration;
+  }
+
+  /// Waits for the packet deadline without sending anything, keeping RTP timestamps continuous.
+  pub(crate) fn skip_voice_packet(&self, udp: &mut UdpVoiceConnection) {
+    spin_sleep::sleep(udp.deadline.saturating_duration_since(Instant::now()));
+    udp.deadline = Instant::now() + CHUNK_DURATION;
+    udp.timestamp += TIMESTAMP_STEP as u32;
+  }
+
+  /// Incremented every time [`Self::sample_buffer`] is cleared, see [`SampleBuffer::epoch`].
+  pub fn buffer_epoch(&self) -> u64 {
+    self.sample_buffer.epoch()
+  }
+
+  /// Discards buffered samples and restarts playback position tracking from `base`.
+  ///
+  /// Must be called instead of [`SampleBuffer::clear`], e.g. after seeking the sample provider to `base`.
+  /// Samples produced before are dropped, even if they are written to the buffer later.
+  pub async fn clear_sample_buffer(&self, base: Duration) {
+    self.sample_buffer.clear().await;
+
+    *self.playback_base.lock().unwrap() = base;
+    self.samples_sent.store(0, Ordering::Release);
+  }
+
+  /// Sets the [`Self::playback_position`] the next playback loop starts at,
+  /// e.g. if the sample provider was seeked before. Applies to one loop only.
+  pub fn set_start_position(&self, position: Duration) {
+    *self.start_position.lock().unwrap() = position;
+  }
+
+  /// Restarts playback position tracking from `base` without discarding buffered samples,
+  /// e.g. when the sample provider switched to the next track.
+  pub fn rebase_playback_position(&self, base: Duration) {
+    *self.playback_base.lock().unwrap() = base;
+    self.samples_sent.store(0, Ordering::Release);
+  }
+
+  /// Returns the position of the last sent sample, not including samples that are still buffered.
+  pub fn playback_position(&self) -> Duration {
+    let base = *self.playback_base.lock().unwrap();
+    let frames = self.samples_sent.load(Ordering::Acquire) / CHANNEL_COUNT as u64;
+    let sent = Duration::from_nanos(frames.saturating_mul(1_000_000_000) / SAMPLE_RATE as u64);
+    let speed = *self.playback_speed.lock().unwrap();
+
+    base.saturating_add(sent.mul_f64(speed))
+  }
+
+  /// Sets how fast the sample provider plays the media, e.g. `1.25` if a filter speeds it up by 25%,
+  /// so that [`Self::playback_position`] stays in media time. Samples sent before are accounted with the old speed.
+  pub fn set_playback_speed(&self, speed: f64) {
+    let position = self.playback_position();
+    *self.playback_speed.lock().unwrap() = speed;
+    self.rebase_playback_position(position);
+  }
+
+  /// Accounts `count` sent samples, unless the buffer they were read from was cleared since `epoch`.
+  fn add_samples_sent(&self, epoch: u64, count: usize) {
+    if self.buffer_epoch() == epoch {
+      self.samples_sent.fetch_add(count as u64, Ordering::AcqRel);
+    }
+  }
+
+  #[instrument(name = "voice", skip_all, fields(guild_id = me.upgrade().map_or(0, |me| me.guild_id())))]
   pub async fn run_ws_loop(me: Weak<Self>) -> Result<()> {
     let (read, close) = {
       let me = me.upgrade().context("voice connection dropped")?;
@@ -407,6 +815,14 @@ impl VoiceConnection {
           match TryInto::<GatewayEvent>::try_into(event) {
             Ok(event) => {
               debug!("<< {:?}", event);
+              if let GatewayEvent::Speaking(Speaking { ssrc, user_id: Some(user_id), .. }) = event {
+                match user_id.parse::<u64>() {
+                  Ok(user_id) => {
+                    me.ssrc_users.write().unwrap().insert(ssrc, user_id);
+                  }
+                  Err(error) => warn!("invalid speaking user id {}: {}", user_id, error)
+                }
+              }
             }
 
             Err(error) => {
@@ -436,6 +852,7 @@ impl VoiceConnection {
     if let Some(frame) = frame {
       if let Some(me) = me.upgrade() {
         let code: GatewayCloseCode = frame.code.into();
+        me.emit(VoiceConnectionEvent::GatewayClosed(code));
         if code.can_reconnect() {
           me.reconnect_ws().await?;
         } else {
@@ -451,164 +868,902 @@ impl VoiceConnection {
   }
 
   pub async fn reconnect_ws(&self) -> Result<()> {
+    let attempt = self.reconnect_attempt.fetch_add(1, Ordering::Relaxed) + 1;
+    self.emit(VoiceConnectionEvent::Reconnecting { attempt });
+
     let mut ws = self.ws.write().await;
-    let old_ws = ws.take().expect("no voice gateway connection");
+    let old_ws = ws.as_ref().context("no voice gateway connection")?;
+    let mode = VoiceConnectionMode::Resume {
+      options: old_ws.options.clone(),
+      ready: old_ws.ready.clone().context("no voice ready packet")?
+    };
 
-    debug!("reconnecting to voice gateway...");
-    *ws = Some(
-      WebSocketVoiceConnection::new(VoiceConnectionMode::Resume {
-        options: old_ws.options,
-        ready: old_ws.ready.context("no voice ready packet")?
-      })
-      .await?
-    );
+    debug!("reconnecting to voice gateway (attempt {})...", attempt);
+    // Keep the old connection on failure, so that reconnecting can be retried
+    *ws = Some(WebSocketVoiceConnection::new(mode).await?);
+
+    self.reconnect_attempt.store(0, Ordering::Relaxed);
+    self.emit(VoiceConnectionEvent::Reconnected);
     Ok(())
   }
 
+  #[instrument(name = "voice", skip_all, fields(guild_id = me.guild_id()))]
   pub async fn run_udp_loop(me: Arc<Self>) -> Result<()> {
-    const PACKET_SIZE: usize = TIMESTAMP_STEP * CHANNEL_COUNT;
-    let finished = Arc::new(AtomicBool::new(false));
-
-    let clone = me.clone();
-    let finished_clone = finished.clone();
-
     let ready = {
       let ws = me.ws.read().await;
       let ws = ws.as_ref().context("no voice gateway connection")?;
       ws.ready.clone().context("no voice ready packet")?
     };
 
+    Self::run_udp_loop_with(me, ready).await
+  }
+
+  async fn run_udp_loop_with(me: Arc<Self>, ready: Ready) -> Result<()> {
+    // Discard commands addressed to a previous loop
+    me.udp_commands_rx.drain();
+    let udp = me.udp.lock().await.take().context("no voice UDP socket")?;
+    let cipher = me.cipher.lock().await.clone().ok_or(VoiceError::CipherNotInitialized)?;
+    let mut sink = UdpVoiceSink::new(me.clone(), ready, udp, cipher, me.udp_commands_rx.clone());
+
+    let result = Self::run_playback_loop(me.clone(), &mut sink).await;
+    // The socket is gone if disconnected, a new one may already be in the slot
+    if !matches!(result, Ok(PlaybackLoopExit::Disconnected)) {
+      *me.udp.lock().await = Some(sink.into_udp());
+    }
+    result.map(|_| ())
+  }
+
+  /// Plays `provider` into `sink` without a voice connection, e.g. [`NullSink`](crate::sink::NullSink)
+  /// or [`WavSink`](crate::sink::WavSink) for testing the playback pipeline.
+  pub async fn play_to_sink(
+    me: Arc<Self>,
+    provider: Box<dyn SampleProvider>,
+    sink: &mut dyn VoiceSink
+  ) -> Result<PlaybackLoopExit> {
+    me.set_sample_provider(provider).await;
+    Self::run_playback_loop(me, sink).await
+  }
+
+  /// Replaces the sample provider and its handle, returning the previous provider.
+  ///
+  /// May be called while playing, e.g. to wrap the current provider into a
+  /// [`MixerSampleProvider`](crate::provider::mixer::MixerSampleProvider).
+  /// The playback loop continues with the new provider.
+  pub async fn set_sample_provider(&self, provider: Box<dyn SampleProvider>) -> Option<Box<dyn SampleProvider>> {
+    *self.sample_provider_handle.lock().await = Some(provider.get_handle());
+    self
+      .sample_provider
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .replace(provider)
+  }
+
+  /// Plays the current sample provider into `sink` until it ends, or the loop is stopped.
+  pub async fn run_playback_loop(me: Arc<Self>, sink: &mut dyn VoiceSink) -> Result<PlaybackLoopExit> {
+    const PACKET_SIZE: usize = TIMESTAMP_STEP * CHANNEL_COUNT;
+
+    let clone = me.clone();
+
+    // Discard stop requests addressed to a previous loop
+    me.playback_stop_rx.drain();
+    me.fade_out.store(false, Ordering::Relaxed);
+    if let Some(limiter) = me.limiter.lock().unwrap().as_mut() {
+      limiter.reset();
+    }
+
+    let start_position = mem::take(&mut *me.start_position.lock().unwrap());
+    me.clear_sample_buffer(start_position).await;
+    me.sample_buffer.reopen();
+
     // TODO(Assasans): Seems like a hack...
     let (_udp_drop_tx, udp_drop_rx) = flume::bounded::<()>(0);
-    tokio::task::spawn(async move {
-      loop {
-        let clone2 = clone.clone();
-        let samples = tokio::task::spawn_blocking(move || {
-          let mut sample_provider = clone2.sample_provider.lock().unwrap();
-          let sample_provider = sample_provider.as_mut().context("no sample provider set").unwrap();
-          sample_provider.get_samples()
-        }).await.unwrap();
-
-        match samples {
-          Some(data) => {
-            // debug!("got {} samples", data.len());
+    let io = async move {
+      let result: Result<()> = async {
+        let mut last_spec = ProviderSpec::default();
+        loop {
+          // Do not read ahead while paused, buffered samples are kept so that playback resumes where it was paused
+          if clone.paused.get() {
+            debug!("IO task paused");
             select! {
-              result = clone.sample_buffer.write(&data) => {
-                result.unwrap();
-              }
+              _ = clone.paused.wait_for(|paused| *paused == false) => debug!("IO task resumed"),
 
               _ = udp_drop_rx.recv_async() => {
                 debug!("UDP loop exited, aborting IO task");
-                break;
+                return Ok(());
               }
             }
           }
-          None => {
-            debug!("got sample provider eof");
-            break;
+
+          let clone2 = clone.clone();
+          // Captured before reading, so that samples read before a seek are dropped
+          let epoch = clone.buffer_epoch();
+          let (samples, spec) = tokio::task::spawn_blocking(move || {
+            // Poisoned if a previous provider panicked, the provider is replaced for every track anyway
+            let mut sample_provider = clone2.sample_provider.lock().unwrap_or_else(PoisonError::into_inner);
+            let sample_provider = sample_provider.as_mut().context("no sample provider set")?;
+            anyhow::Ok((sample_provider.get_samples()?, sample_provider.spec()))
+          })
+          .await
+          .map_err(|error| anyhow!("sample provider panicked: {}", error))??;
+
+          if spec != last_spec {
+            if spec.channels != CHANNEL_COUNT {
+              info!("converting {} channels to stereo", spec.channels);
+            }
+            if spec.sample_rate != SAMPLE_RATE {
+              warn!("sample provider returns {} Hz, expected {} Hz", spec.sample_rate, SAMPLE_RATE);
+            }
+            last_spec = spec;
+          }
+
+          match samples.map(|data| to_stereo(data, spec.channels)) {
+            Some(data) => {
+              // debug!("got {} samples", data.len());
+              select! {
+                result = clone.sample_buffer.write_epoch(&data, epoch) => {
+                  result?;
+                }
+
+                _ = udp_drop_rx.recv_async() => {
+                  debug!("UDP loop exited, aborting IO task");
+                  return Ok(());
+                }
+              }
+            }
+            None => {
+              debug!("got sample provider eof");
+              return Ok(());
+            }
           }
         }
       }
-      finished_clone.store(true, Ordering::Release);
-    });
+      .await;
+
+      // The UDP loop drains the buffer until it is closed and empty, so every written sample is sent exactly once
+      clone.sample_buffer.close();
+      result
+    };
+    let mut io_task = tokio::task::spawn(io.in_current_span());
 
     debug!("waiting for jitter buffer to fill halfway");
-    me.sample_buffer.wait_for(me.sample_buffer.low_threshold).await?;
-    debug!("jitter buffer filled halfway");
+    // The IO task may finish (short track or provider error) before the buffer is filled
+    let mut io_result = None;
+    select! {
+      result = me.sample_buffer.wait_for(me.sample_buffer.low_threshold()) => {
+        result?;
+        debug!("jitter buffer filled halfway");
+      }
+      result = &mut io_task => io_result = Some(result)
+    }
 
-    me.state.set(VoiceConnectionState::Playing);
+    let stall_watchdog = tokio::spawn(Self::watch_source_stalls(me.clone()).in_current_span());
+    let mut stopped = false;
+    let result: Result<()> = async {
+      me.set_state(VoiceConnectionState::Playing);
 
-    {
-      let mut udp_lock = me.udp.lock().await;
-      let udp = udp_lock.as_mut().context("no voice UDP socket")?;
-      udp.deadline = Instant::now();
-    }
-    loop {
-      if me.stop_udp_loop.load(Ordering::Relaxed) {
-        debug!("stop udp loop");
-        break;
-      }
+      sink.reset();
+      let mut silent_for = Duration::ZERO;
+      let mut dtx_packets = 0;
+      let mut fade_out: Option<FadeOut> = None;
+      me.dtx_active.store(false, Ordering::Relaxed);
+      loop {
+        if me.stop_udp_loop.load(Ordering::Relaxed) {
+          // Silence frames are already sent while paused
+          if fade_out.is_none() && me.fade_out.swap(false, Ordering::Relaxed) && !me.paused.get() {
+            let duration = *me.fade_out_duration.lock().unwrap();
+            if !duration.is_zero() {
+              debug!("fading out over {:?} before stopping", duration);
+              fade_out = Some(FadeOut::new(duration));
+            }
+          }
 
-      let mut udp_lock = me.udp.lock().await;
-      let udp = match udp_lock.as_mut() {
-        Some(udp) => udp,
-        None => {
-          warn!("no voice UDP socket, possibly voice gateway was closed by remote");
-          me.sample_buffer.clear().await;
-          me.state.set(VoiceConnectionState::Disconnected);
+          if fade_out.as_ref().map_or(true, FadeOut::is_finished) {
+            debug!("stop udp loop");
+            break;
+          }
+        }
 
+        if me.playback_stop_rx.try_recv().is_ok() {
           // Early return instead of break to prevent flushing to nonexistent connection
+          stopped = true;
           return Ok(());
         }
-      };
 
-      if me.paused.get() && me.silence_frames_left.load(Ordering::Relaxed) > 0 {
-        me.silence_frames_left.fetch_sub(1, Ordering::SeqCst);
-        me.send_voice_packet(&ready, udp, AudioFrame::Opus(OPUS_SILENCE_FRAME.to_vec()))
-          .await?;
-        if me.silence_frames_left.load(Ordering::Relaxed) == 0 {
-          debug!("waiting for unpause...");
-          me.paused.wait_for(|paused| *paused == false).await;
-          debug!("unpaused");
-        }
-      } else {
-        // if let Ok(true) = me.jitter_buffer_reset.compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed) {
-        //   debug!("reset sample buffer (was: {})", consumer.len());
-        //   consumer.clear();
-        //   me.jitter_buffer_size.store(0, Ordering::Relaxed);
-        //   _ = dtx.try_send(()); // Unblock IO task
-        //   continue;
-        // }
-
-        if finished.load(Ordering::Acquire) {
-          debug!("got finished == true");
-          break;
-        }
+        // Buffered samples are faded out before silence frames are sent
+        let pausing = me.paused.get() && {
+          let mut pause_ramp = me.pause_ramp.lock().unwrap();
+          if me.sample_buffer.len() < PACKET_SIZE {
+            *pause_ramp = None;
+          }
+          pause_ramp.is_some()
+        };
+        if me.paused.get() && !pausing && me.silence_frames_left.load(Ordering::Relaxed) > 0 {
+          me.silence_frames_left.fetch_sub(1, Ordering::SeqCst);
+          sink.send(AudioFrame::Opus(OPUS_SILENCE_FRAME.to_vec())).await?;
+          if me.silence_frames_left.load(Ordering::Relaxed) == 0 {
+            debug!("waiting for unpause...");
+            me.ready_to_play.wait_for(|ready| *ready).await;
+            debug!("unpaused");
+          }
+        } else {
+          // if let Ok(true) = me.jitter_buffer_reset.compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed) {
+          //   debug!("reset sample buffer (was: {})", consumer.len());
+          //   consumer.clear();
+          //   me.jitter_buffer_size.store(0, Ordering::Relaxed);
+          //   _ = dtx.try_send(()); // Unblock IO task
+          //   continue;
+          // }
+
+          me.sample_buffer.wait_for(PACKET_SIZE).await?;
+          if me.sample_buffer.is_drained(PACKET_SIZE) {
+            debug!("sample buffer closed, {} samples left", me.sample_buffer.len());
+            break;
+          }
+
+          let epoch = me.buffer_epoch();
+          let mut data = vec![0f32; PACKET_SIZE];
+          me.sample_buffer.read(&mut data).await?;
+          let volume = me.volume();
+          if volume != 1.0 {
+            for sample in &mut data {
+              *sample *= volume;
+            }
+          }
+          if let Some(fade_out) = &mut fade_out {
+            fade_out.apply(&mut data);
+          }
+          {
+            let mut pause_ramp = me.pause_ramp.lock().unwrap();
+            if let Some(ramp) = pause_ramp.as_mut() {
+              ramp.apply(&mut data);
+              if ramp.is_finished() {
+                *pause_ramp = None;
+              }
+            }
+          }
+          if let Some(limiter) = me.limiter.lock().unwrap().as_mut() {
+            if limiter.apply(&mut data) {
+              me.metrics.limited_packets.fetch_add(1, Ordering::Relaxed);
+            }
+          }
+          // debug!("sending {} samples", PACKET_SIZE);
 
-        let mut data = vec![0f32; PACKET_SIZE];
-        me.sample_buffer.read(&mut data).await?;
-        // debug!("sending {} samples", PACKET_SIZE);
+          let packet_rms = {
+            let mut rms = me.rms.lock().unwrap();
+            for sample in &data {
+              rms.add_sample(*sample);
+            }
+            rms.calculate_rms(PACKET_SIZE)
+          };
 
-        {
-          let mut rms = me.rms.lock().unwrap();
-          for sample in &data {
-            rms.add_sample(*sample);
+          {
+            let mut ebur128 = me.ebur128.lock().unwrap();
+            ebur128.add_frames_f32(&data).unwrap();
           }
+
+          if 20.0 * packet_rms.log10() < me.dtx_threshold() {
+            silent_for += CHUNK_DURATION;
+          } else {
+            silent_for = Duration::ZERO;
+            if me.dtx_active.swap(false, Ordering::Relaxed) {
+              debug!("DTX deactivated");
+            }
+          }
+
+          if silent_for > DTX_SILENCE_DURATION && !me.dtx_active.swap(true, Ordering::Relaxed) {
+            debug!("DTX activated after {:?} of silence", silent_for);
+            dtx_packets = 0;
+          }
+
+          if me.dtx_active.load(Ordering::Relaxed) {
+            if dtx_packets % DTX_SILENCE_FRAME_INTERVAL == 0 {
+              sink.send(AudioFrame::Opus(OPUS_SILENCE_FRAME.to_vec())).await?;
+            } else {
+              sink.skip().await?;
+            }
+            dtx_packets += 1;
+          } else {
+            sink.send(AudioFrame::Pcm(data)).await?;
+          }
+          me.add_samples_sent(epoch, PACKET_SIZE);
+          // samples.copy_within(PACKET_SIZE..got, 0);
+          // got -= PACKET_SIZE;
         }
+        sink.tick().await?;
+      }
 
-        {
-          let mut ebur128 = me.ebur128.lock().unwrap();
-          ebur128.add_frames_f32(&data).unwrap();
+      // Flush
+      if !me.stop_udp_loop.load(Ordering::Relaxed) {
+        let epoch = me.buffer_epoch();
+        let data = me.sample_buffer.flush().await;
+        for chunk in data.chunks(PACKET_SIZE) {
+          debug!("flushing {} (total: {}) samples...", chunk.len(), data.len());
+          let length = chunk.len();
+          let mut chunk = chunk.to_vec();
+          chunk.resize(PACKET_SIZE, 0f32); // Pad with zeros to make sure opus_encode_float does not fail
+          sink.send(AudioFrame::Pcm(chunk)).await?;
+          me.add_samples_sent(epoch, length);
         }
+      }
 
-        me.send_voice_packet(&ready, udp, AudioFrame::Pcm(data)).await?;
-        // samples.copy_within(PACKET_SIZE..got, 0);
-        // got -= PACKET_SIZE;
+      Ok(())
+    }
+    .await;
+    stall_watchdog.abort();
+    if io_result.is_none() && !me.sample_buffer.is_closed() {
+      // Otherwise the IO task could close the buffer of the next loop
+      io_task.abort();
+    }
+
+    if stopped {
+      warn!("UDP loop stopped, possibly voice gateway was closed by remote");
+      me.clear_sample_buffer(Duration::ZERO).await;
+      me.set_state(VoiceConnectionState::Disconnected);
+      return Ok(PlaybackLoopExit::Disconnected);
+    }
+
+    result?;
+
+    let io_result = match io_result {
+      Some(result) => result,
+      // Closed, so awaiting does not block
+      None if me.sample_buffer.is_closed() => io_task.await,
+      // Aborted
+      None => Ok(Ok(()))
+    };
+    if let Err(error) = io_result.map_err(anyhow::Error::from).and_then(|result| result) {
+      warn!("sample provider error: {:?}", error);
+      me.emit(VoiceConnectionEvent::SampleProviderError(error.to_string()));
+    }
+
+    debug!("play loop finished");
+    me.clear_sample_buffer(Duration::ZERO).await;
+    me.set_state(VoiceConnectionState::Connected);
+    Ok(PlaybackLoopExit::Finished)
+  }
+
+  /// Emits [`VoiceConnectionEvent::SourceStalled`] when the sample provider stalls while the buffer is running low.
+  async fn watch_source_stalls(me: Arc<Self>) {
+    let mut interval = interval(Duration::from_secs(1));
+    let mut emitted = false;
+    loop {
+      interval.tick().await;
+
+      // The provider is not read from while paused
+      if !me.ready_to_play.get() {
+        emitted = false;
+        continue;
+      }
+
+      let stats = me.sample_provider_handle.lock().await.as_ref().and_then(|handle| handle.stats());
+      if !stats.is_some_and(|stats| stats.is_stalled) {
+        emitted = false;
+      } else if !emitted && me.sample_buffer.len() < me.sample_buffer.low_threshold() {
+        warn!("sample provider stalled: {:?}", stats);
+        me.emit(VoiceConnectionEvent::SourceStalled);
+        emitted = true;
       }
-      // me.recv_rtcp_stats(udp).await?;
+    }
+  }
+}
+
+/// Fails with [VoiceError::ConnectTimeout] if a connection step takes longer than [VOICE_CONNECT_TIMEOUT].
+async fn with_connect_timeout<T>(future: impl Future<Output = Result<T, VoiceError>>) -> Result<T, VoiceError> {
+  time::timeout(VOICE_CONNECT_TIMEOUT, future)
+    .await
+    .map_err(|_| VoiceError::ConnectTimeout)?
+}
 
-      if Instant::now() >= udp.heartbeat_time + Duration::from_millis(5000) {
-        udp.send_keepalive(&ready).await?;
+#[tokio::test]
+async fn playback_position_after_backwards_seek() {
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  connection.clear_sample_buffer(Duration::from_secs(10)).await;
+  connection.add_samples_sent(connection.buffer_epoch(), SAMPLE_RATE * CHANNEL_COUNT);
+  assert_eq!(connection.playback_position(), Duration::from_secs(11));
+
+  // Buffer more than a second of audio, the writer blocks until the buffer is uncorked
+  let writer = {
+    let connection = connection.clone();
+    tokio::spawn(async move {
+      let data = vec![0f32; SAMPLE_RATE * CHANNEL_COUNT + SAMPLE_RATE / 2];
+      connection.sample_buffer.write(&data).await.unwrap();
+    })
+  };
+  connection.sample_buffer.wait_for(SAMPLE_RATE * CHANNEL_COUNT).await.unwrap();
+
+  // Packet read before the seek, but accounted after it
+  let epoch = connection.buffer_epoch();
+  let mut data = vec![0f32; TIMESTAMP_STEP * CHANNEL_COUNT];
+  connection.sample_buffer.read(&mut data).await.unwrap();
+
+  connection.clear_sample_buffer(Duration::from_secs(2)).await;
+  connection.add_samples_sent(epoch, data.len());
+  assert_eq!(connection.playback_position(), Duration::from_secs(2));
+
+  connection.add_samples_sent(connection.buffer_epoch(), data.len());
+  assert_eq!(connection.playback_position(), Duration::from_secs(2) + CHUNK_DURATION);
+
+  writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn playback_position_follows_speed() {
+  let connection = VoiceConnection::new().unwrap();
+  connection.clear_sample_buffer(Duration::from_secs(10)).await;
+  connection.add_samples_sent(connection.buffer_epoch(), SAMPLE_RATE * CHANNEL_COUNT);
+
+  connection.set_playback_speed(1.5);
+  assert_eq!(connection.playback_position(), Duration::from_secs(11));
+  connection.add_samples_sent(connection.buffer_epoch(), SAMPLE_RATE * CHANNEL_COUNT * 2);
+  assert_eq!(connection.playback_position(), Duration::from_secs(14));
+
+  // Seeking keeps the speed
+  connection.clear_sample_buffer(Duration::from_secs(2)).await;
+  connection.add_samples_sent(connection.buffer_epoch(), SAMPLE_RATE * CHANNEL_COUNT);
+  assert_eq!(connection.playback_position(), Duration::from_millis(3500));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn udp_loop_pacing_unaffected_by_held_connection_locks() {
+  use crate::provider::test::TestProvider;
+
+  const PACKETS: usize = 50;
+
+  let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+  receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  let ready = Ready {
+    ssrc: 1,
+    ip: "127.0.0.1".to_owned(),
+    port: receiver.local_addr().unwrap().port(),
+    modes: vec![]
+  };
+  let arrivals = std::thread::spawn(move || {
+    let mut buffer = [0; 2048];
+    (0..PACKETS)
+      .map(|_| {
+        receiver.recv(&mut buffer).unwrap();
+        Instant::now()
+      })
+      .collect::<Vec<_>>()
+  });
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  *connection.udp.lock().await = Some(UdpVoiceConnection::new(&ready, None).await.unwrap());
+  *connection.cipher.lock().await = Some(XSalsa20Poly1305::new(Key::from_slice(&[0; 32])));
+  *connection.sample_provider.lock().unwrap() = Some(Box::new(TestProvider::new(0.0)));
+  // Disable DTX, otherwise most silent packets are not sent
+  connection.set_dtx_threshold(f32::NEG_INFINITY);
+
+  let udp_loop = tokio::spawn(VoiceConnection::run_udp_loop_with(connection.clone(), ready));
+  connection.state.wait_for(|state| *state == VoiceConnectionState::Playing).await;
+
+  // Hold the locks taken by [VoiceConnection::reconnect_ws] and [VoiceConnection::connect] for several frames
+  {
+    let _ws = connection.ws.write().await;
+    let _udp = connection.udp.lock().await;
+    let _cipher = connection.cipher.lock().await;
+    tokio::time::sleep(CHUNK_DURATION * 10).await;
+  }
+
+  let arrivals = tokio::task::spawn_blocking(move || arrivals.join().unwrap()).await.unwrap();
+  connection.stop_udp_loop.store(true, Ordering::Relaxed);
+  udp_loop.await.unwrap().unwrap();
+
+  for window in arrivals.windows(2) {
+    let gap = window[1] - window[0];
+    assert!(gap <= CHUNK_DURATION * 2, "packet pacing delayed by {:?}", gap - CHUNK_DURATION);
+  }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn udp_loop_ends_track_on_sample_provider_error() {
+  use crate::provider::test::TestProvider;
+
+  // Packets are never read, but the socket must exist for sends to succeed
+  let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+  let ready = Ready {
+    ssrc: 1,
+    ip: "127.0.0.1".to_owned(),
+    port: receiver.local_addr().unwrap().port(),
+    modes: vec![]
+  };
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  *connection.udp.lock().await = Some(UdpVoiceConnection::new(&ready, None).await.unwrap());
+  *connection.cipher.lock().await = Some(XSalsa20Poly1305::new(Key::from_slice(&[0; 32])));
+  *connection.sample_provider.lock().unwrap() = Some(Box::new(TestProvider::new(0.0).fail_after(3)));
+
+  // Must not hang waiting for samples that never arrive
+  tokio::time::timeout(Duration::from_secs(5), VoiceConnection::run_udp_loop_with(connection.clone(), ready))
+    .await
+    .expect("UDP loop hung")
+    .unwrap();
+
+  assert_eq!(connection.state.get(), VoiceConnectionState::Connected);
+  let error = connection.events.drain().find_map(|event| match event {
+    VoiceConnectionEvent::SampleProviderError(error) => Some(error),
+    _ => None
+  });
+  assert_eq!(error.as_deref(), Some("decoding failed"));
+  assert!(connection.udp.lock().await.is_some());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn playback_loop_sends_every_sample_to_sink() {
+  use crate::provider::test::TestProvider;
+  use crate::sink::NullSink;
+
+  const PACKETS: usize = 10;
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  let provider = Box::new(TestProvider::new(0.5).blocks(PACKETS));
+
+  let mut sink = NullSink::default();
+  let playback = VoiceConnection::play_to_sink(connection.clone(), provider, &mut sink);
+  let exit = tokio::time::timeout(Duration::from_secs(5), playback)
+    .await
+    .expect("playback loop hung")
+    .unwrap();
+
+  assert_eq!(exit, PlaybackLoopExit::Finished);
+  assert_eq!(sink.samples, PACKETS * TIMESTAMP_STEP * CHANNEL_COUNT);
+  assert_eq!(sink.silence_frames, 0);
+  assert_eq!(sink.skipped, 0);
+  assert_eq!(connection.state.get(), VoiceConnectionState::Connected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn playback_loop_upmixes_mono_provider() {
+  use crate::provider::test::TestProvider;
+  use crate::sink::NullSink;
+
+  const PACKETS: usize = 10;
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  let mut sink = NullSink::default();
+  let provider = Box::new(TestProvider::new(0.5).mono().blocks(PACKETS));
+  let playback = VoiceConnection::play_to_sink(connection.clone(), provider, &mut sink);
+  let exit = tokio::time::timeout(Duration::from_secs(5), playback)
+    .await
+    .expect("playback loop hung")
+    .unwrap();
+
+  // Sent as stereo with the same duration as the source, not at half speed
+  assert_eq!(exit, PlaybackLoopExit::Finished);
+  assert_eq!(sink.samples, PACKETS * TIMESTAMP_STEP * CHANNEL_COUNT);
+  assert_eq!(connection.playback_position(), CHUNK_DURATION * PACKETS as u32);
+}
+
+#[tokio::test]
+async fn set_bitrate_reconfigures_encoder() {
+  let connection = VoiceConnection::new().unwrap();
+  connection.set_bitrate(128_000).await.unwrap();
+  assert_eq!(connection.encoder_bitrate().await, Some(128_000));
+
+  // Between frames of a playing connection
+  connection.set_bitrate(64_000).await.unwrap();
+  assert_eq!(connection.encoder_bitrate().await, Some(64_000));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn playback_loop_sends_track_tail_exactly_once() {
+  use crate::provider::test::TestProvider;
+  use crate::sink::NullSink;
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  // Exactly 100 ms of audio in blocks not aligned to packets
+  let provider = Box::new(TestProvider::new(0.5).block(700 * CHANNEL_COUNT).samples(SAMPLE_RATE / 10 * CHANNEL_COUNT));
+
+  let mut sink = NullSink::default();
+  let playback = VoiceConnection::play_to_sink(connection.clone(), provider, &mut sink);
+  let exit = tokio::time::timeout(Duration::from_secs(5), playback)
+    .await
+    .expect("playback loop hung")
+    .unwrap();
+
+  assert_eq!(exit, PlaybackLoopExit::Finished);
+  assert_eq!(sink.samples, 5 * TIMESTAMP_STEP * CHANNEL_COUNT);
+  assert_eq!(connection.playback_position(), Duration::from_millis(100));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn playback_loop_drops_samples_read_before_seek() {
+  use std::sync::mpsc;
+
+  use async_trait::async_trait;
+
+  use crate::provider::test::TestProvider;
+
+  const PACKET_SIZE: usize = TIMESTAMP_STEP * CHANNEL_COUNT;
+  const PACKETS: usize = 10;
+
+  #[derive(Default)]
+  struct RecordingSink {
+    samples: Vec<f32>
+  }
+
+  #[async_trait]
+  impl VoiceSink for RecordingSink {
+    async fn send(&mut self, frame: AudioFrame) -> Result<()> {
+      if let AudioFrame::Pcm(samples) = frame {
+        self.samples.extend(samples);
       }
+      Ok(())
     }
 
-    // Flush
-    if !me.stop_udp_loop.load(Ordering::Relaxed) {
-      let data = me.sample_buffer.flush().await;
-      for chunk in data.chunks(PACKET_SIZE) {
-        debug!("flushing {} (total: {}) samples...", chunk.len(), data.len());
-        let mut chunk = chunk.to_vec();
-        chunk.resize(PACKET_SIZE, 0f32); // Pad with zeros to make sure opus_encode_float does not fail
+    async fn skip(&mut self) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  let (reading_tx, reading_rx) = mpsc::channel();
+  let (seeked_tx, seeked_rx) = mpsc::channel();
+  // One packet of `1.0` read "before the seek", then packets of `0.5`
+  let provider = Box::new(TestProvider::new(0.5).blocks(PACKETS).first_block(move || {
+    reading_tx.send(()).unwrap();
+    seeked_rx.recv().unwrap();
+    vec![1.0; PACKET_SIZE]
+  }));
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  let playback = {
+    let connection = connection.clone();
+    tokio::spawn(async move {
+      let mut sink = RecordingSink::default();
+      VoiceConnection::play_to_sink(connection, provider, &mut sink).await.map(|_| sink)
+    })
+  };
+
+  // Seek while the provider is returning pre-seek samples
+  tokio::task::spawn_blocking(move || reading_rx.recv().unwrap()).await.unwrap();
+  connection.clear_sample_buffer(Duration::from_secs(5)).await;
+  seeked_tx.send(()).unwrap();
+
+  let sink = tokio::time::timeout(Duration::from_secs(5), playback)
+    .await
+    .expect("playback loop hung")
+    .unwrap()
+    .unwrap();
+  assert_eq!(sink.samples.len(), PACKETS * PACKET_SIZE);
+  assert!(sink.samples.iter().all(|sample| *sample == 0.5));
+}
 
-        let mut udp = me.udp.lock().await;
-        let udp = udp.as_mut().context("no voice UDP socket")?;
-        me.send_voice_packet(&ready, udp, AudioFrame::Pcm(chunk)).await?;
+#[tokio::test]
+async fn reset_clears_previous_connection_state() {
+  let connection = VoiceConnection::new().unwrap();
+  *connection.cipher.lock().await = Some(XSalsa20Poly1305::new(&Key::default()));
+  connection.sample_buffer.write(&[0.5; 4]).await.unwrap();
+  connection.silence_frames_left.store(OPUS_SILENCE_FRAMES, Ordering::Relaxed);
+  connection.stop_udp_loop.store(true, Ordering::Relaxed);
+  connection.disconnect().await.unwrap();
+
+  connection.reset().await.unwrap();
+  assert!(connection.cipher.lock().await.is_none());
+  assert_eq!(connection.sample_buffer.len(), 0);
+  assert_eq!(connection.silence_frames_left.load(Ordering::Relaxed), 0);
+  assert!(!connection.stop_udp_loop.load(Ordering::Relaxed));
+  assert_eq!(connection.state.get(), VoiceConnectionState::Disconnected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn playback_loop_does_not_read_ahead_while_paused() {
+  use crate::provider::test::TestProvider;
+  use crate::sink::NullSink;
+
+  const PACKETS: usize = 10;
+
+  let provider = Box::new(TestProvider::new(0.5).blocks(PACKETS));
+  let reads = provider.reads();
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  connection.set_paused(true);
+
+  let playback = {
+    let connection = connection.clone();
+    tokio::spawn(async move {
+      let mut sink = NullSink::default();
+      VoiceConnection::play_to_sink(connection, provider, &mut sink).await.map(|_| sink)
+    })
+  };
+
+  // Without the gate, the buffer would be filled up to its cork threshold
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  assert_eq!(reads.load(Ordering::Relaxed), 0);
+  assert_eq!(connection.sample_buffer.len(), 0);
+
+  connection.set_paused(false);
+  let sink = tokio::time::timeout(Duration::from_secs(5), playback)
+    .await
+    .expect("playback loop hung")
+    .unwrap()
+    .unwrap();
+  assert_eq!(sink.samples, PACKETS * TIMESTAMP_STEP * CHANNEL_COUNT);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn playback_loop_fades_out_on_stop() {
+  use async_trait::async_trait;
+
+  use crate::provider::test::TestProvider;
+
+  /// Records the peak of every packet.
+  #[derive(Default)]
+  struct PeakSink {
+    peaks: Vec<f32>
+  }
+
+  #[async_trait]
+  impl VoiceSink for PeakSink {
+    async fn send(&mut self, frame: AudioFrame) -> Result<()> {
+      if let AudioFrame::Pcm(samples) = frame {
+        self.peaks.push(samples.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs())));
       }
+      Ok(())
     }
 
-    debug!("play loop finished");
-    me.sample_buffer.clear().await;
-    me.state.set(VoiceConnectionState::Connected);
-    Ok(())
+    async fn skip(&mut self) -> Result<()> {
+      Ok(())
+    }
   }
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  let playback = {
+    let connection = connection.clone();
+    tokio::spawn(async move {
+      let mut sink = PeakSink::default();
+      let provider = Box::new(TestProvider::new(0.5));
+      VoiceConnection::play_to_sink(connection, provider, &mut sink).await.map(|_| sink)
+    })
+  };
+
+  connection.state.wait_for(|state| *state == VoiceConnectionState::Playing).await;
+  connection.fade_out.store(true, Ordering::Relaxed);
+  connection.stop_udp_loop.store(true, Ordering::Relaxed);
+
+  let sink = tokio::time::timeout(Duration::from_secs(5), playback)
+    .await
+    .expect("playback loop hung")
+    .unwrap()
+    .unwrap();
+  let fade_packets = DEFAULT_FADE_OUT_DURATION.as_millis().div_ceil(CHUNK_DURATION.as_millis()) as usize;
+  let (played, faded) = sink.peaks.split_at(sink.peaks.len() - fade_packets);
+  assert!(played.iter().all(|peak| *peak == 0.5));
+  assert!(faded.windows(2).all(|pair| pair[1] < pair[0]), "not attenuated: {:?}", faded);
+  assert!(faded[0] <= 0.5 && faded[fade_packets - 1] < 0.05);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn playback_loop_ramps_on_pause_and_resume() {
+  use async_trait::async_trait;
+
+  use crate::provider::test::TestProvider;
+
+  /// Records the left channel handed to the encoder, silence frames are recorded as [None].
+  #[derive(Clone, Default)]
+  struct CapturingSink {
+    frames: Arc<std::sync::Mutex<Vec<Option<Vec<f32>>>>>
+  }
+
+  #[async_trait]
+  impl VoiceSink for CapturingSink {
+    async fn send(&mut self, frame: AudioFrame) -> Result<()> {
+      let frame = match frame {
+        AudioFrame::Pcm(samples) => Some(samples.iter().step_by(CHANNEL_COUNT).copied().collect()),
+        AudioFrame::Opus(_) => None
+      };
+      self.frames.lock().unwrap().push(frame);
+      // Paced like a real connection, so that the test can pause in the middle of playback
+      tokio::time::sleep(Duration::from_millis(1)).await;
+      Ok(())
+    }
+
+    async fn skip(&mut self) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  let sink = CapturingSink::default();
+  let frames = sink.frames.clone();
+  let playback = {
+    let connection = connection.clone();
+    let mut sink = sink.clone();
+    let provider = Box::new(TestProvider::new(0.5));
+    tokio::spawn(async move { VoiceConnection::play_to_sink(connection, provider, &mut sink).await })
+  };
+  let wait_for_frames = |count: usize, silent: bool| {
+    let frames = frames.clone();
+    async move {
+      while frames.lock().unwrap().iter().filter(|frame| frame.is_none() == silent).count() < count {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+      }
+    }
+  };
+
+  tokio::time::timeout(Duration::from_secs(5), async {
+    wait_for_frames(5, false).await;
+    connection.set_paused(true);
+    wait_for_frames(OPUS_SILENCE_FRAMES as usize, true).await;
+    let played = frames.lock().unwrap().iter().filter(|frame| frame.is_some()).count();
+    connection.set_paused(false);
+    wait_for_frames(played + 5, false).await;
+  })
+  .await
+  .expect("playback loop hung");
+  connection.stop_udp_loop.store(true, Ordering::Relaxed);
+  playback.await.unwrap().unwrap();
+
+  let frames = frames.lock().unwrap();
+  let first_silent = frames.iter().position(Option::is_none).unwrap();
+  let last_silent = frames.iter().rposition(Option::is_none).unwrap();
+  assert_eq!(last_silent - first_silent + 1, OPUS_SILENCE_FRAMES as usize);
+
+  let fade_out = frames[first_silent - 1].as_ref().unwrap();
+  let fade_in = frames[last_silent + 1].as_ref().unwrap();
+  assert!(fade_out.windows(2).all(|pair| pair[1] <= pair[0]), "fade-out is not monotonic");
+  assert!(fade_in.windows(2).all(|pair| pair[1] >= pair[0]), "fade-in is not monotonic");
+  assert!(fade_out.last().unwrap().abs() < 1e-6 && (fade_in.last().unwrap() - 0.5).abs() < 1e-6);
+
+  // Silence frames decode to silence
+  let signal = frames
+    .iter()
+    .flat_map(|frame| frame.clone().unwrap_or_else(|| vec![0.0]))
+    .collect::<Vec<_>>();
+  let max_step = signal.windows(2).fold(0.0, |max: f32, pair| max.max((pair[1] - pair[0]).abs()));
+  assert!(max_step <= 0.1, "discontinuity of {}", max_step);
+}
+
+#[test]
+fn rejects_unsupported_cipher_modes() {
+  let connection = VoiceConnection::new().unwrap();
+  assert!(matches!(
+    "xsalsa20_poly1305_unknown".parse::<VoiceCipherMode>(),
+    Err(VoiceError::UnsupportedCipherMode)
+  ));
+  assert!(matches!(
+    connection.set_cipher_mode(VoiceCipherMode::Lite),
+    Err(VoiceError::UnsupportedCipherMode)
+  ));
+  assert_eq!(connection.cipher_mode(), VoiceCipherMode::Suffix);
+
+  connection.set_cipher_mode("xsalsa20_poly1305".parse().unwrap()).unwrap();
+  assert_eq!(connection.cipher_mode(), VoiceCipherMode::Normal);
+
+  connection.state.set(VoiceConnectionState::Connected);
+  assert!(matches!(
+    connection.set_cipher_mode(VoiceCipherMode::Suffix),
+    Err(VoiceError::AlreadyConnected)
+  ));
+}
+
+#[test]
+fn parses_ip_discovery_responses() {
+  let response = |address: &str, port: u16| {
+    let mut packet = [0; IpDiscoveryPacket::const_packet_size()];
+    packet[0..2].copy_from_slice(&2u16.to_be_bytes()); // Response
+    packet[2..4].copy_from_slice(&70u16.to_be_bytes());
+    packet[4..8].copy_from_slice(&42u32.to_be_bytes());
+    packet[8..8 + address.len()].copy_from_slice(address.as_bytes());
+    packet[72..74].copy_from_slice(&port.to_be_bytes());
+    packet
+  };
+
+  assert_eq!(IpDiscoveryResult::parse(&response("203.0.113.7", 50004)).unwrap(), IpDiscoveryResult {
+    address: "203.0.113.7".parse().unwrap(),
+    port: 50004
+  });
+  assert_eq!(
+    IpDiscoveryResult::parse(&response("2001:db8:85a3::8a2e:370:7334", 50004)).unwrap(),
+    IpDiscoveryResult {
+      address: "2001:db8:85a3::8a2e:370:7334".parse().unwrap(),
+      port: 50004
+    }
+  );
+  assert!(matches!(
+    IpDiscoveryResult::parse(&response("not an address", 50004)),
+    Err(VoiceError::IpDiscoveryFailed)
+  ));
+
+  let mut request = response("203.0.113.7", 50004);
+  request[0..2].copy_from_slice(&1u16.to_be_bytes());
+  assert!(matches!(IpDiscoveryResult::parse(&request), Err(VoiceError::IpDiscoveryFailed)));
 }
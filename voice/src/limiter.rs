@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+/// Peak limiter with instant attack, keeps samples within the threshold after volume is applied.
+///
+/// The gain recovers exponentially over the release time once the signal is below the threshold.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+  /// Linear threshold.
+  threshold: f32,
+  /// Per-frame coefficient of the gain recovery.
+  release: f32,
+  gain: f32
+}
+
+impl Limiter {
+  pub fn new(threshold_db: f32, release: Duration) -> Self {
+    let release_frames = (release.as_secs_f32() * SAMPLE_RATE as f32).max(1.0);
+    Self {
+      threshold: 10f32.powf(threshold_db / 20.0),
+      release: 1.0 - (-1.0 / release_frames).exp(),
+      gain: 1.0
+    }
+  }
+
+  /// Attenuates interleaved stereo `samples`, returns whether the gain was reduced.
+  pub fn apply(&mut self, samples: &mut [f32]) -> bool {
+    let mut engaged = false;
+    for frame in samples.chunks_mut(CHANNEL_COUNT) {
+      let peak = frame.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs()));
+      let required = if peak > self.threshold { self.threshold / peak } else { 1.0 };
+      self.gain = required.min(self.gain + (1.0 - self.gain) * self.release);
+      if self.gain < 1.0 {
+        engaged = true;
+        for sample in frame {
+          *sample *= self.gain;
+        }
+      }
+    }
+    engaged
+  }
+
+  pub fn reset(&mut self) {
+    self.gain = 1.0;
+  }
+}
+
+#[test]
+fn limits_over_unity_signal() {
+  let mut limiter = Limiter::new(-1.0, Duration::from_millis(50));
+  let threshold = 10f32.powf(-1.0 / 20.0);
+
+  let mut samples = (0..SAMPLE_RATE * CHANNEL_COUNT / 10)
+    .map(|index| 2.0 * (index as f32 / 20.0).sin())
+    .collect::<Vec<_>>();
+  assert!(limiter.apply(&mut samples));
+  assert!(samples.iter().all(|sample| sample.abs() <= threshold));
+  assert!(samples.iter().any(|sample| sample.abs() > threshold * 0.9));
+
+  // Quiet signal is untouched once the gain has recovered
+  let mut quiet = vec![0.25; SAMPLE_RATE * CHANNEL_COUNT];
+  limiter.apply(&mut quiet);
+  assert_eq!(quiet.last(), Some(&0.25));
+}
@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+/// Totals of the audio transmitted by a voice connection since it was created.
+#[derive(Debug)]
+pub struct VoiceMetrics {
+  pub packets_sent: AtomicU64,
+  /// Interleaved samples, silence frames count as a whole frame.
+  pub samples_sent: AtomicU64,
+  /// Bytes of RTP packets, including headers.
+  pub bytes_sent: AtomicU64,
+  /// Packets attenuated by the limiter.
+  pub limited_packets: AtomicU64,
+  pub start_time: Instant
+}
+
+impl VoiceMetrics {
+  pub fn new() -> Self {
+    Self {
+      packets_sent: AtomicU64::new(0),
+      samples_sent: AtomicU64::new(0),
+      bytes_sent: AtomicU64::new(0),
+      limited_packets: AtomicU64::new(0),
+      start_time: Instant::now()
+    }
+  }
+
+  pub(crate) fn add_packet(&self, samples: usize, bytes: usize) {
+    self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    self.samples_sent.fetch_add(samples as u64, Ordering::Relaxed);
+    self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  /// Returns the duration of the sent audio.
+  pub fn samples_sent_duration(&self) -> Duration {
+    let samples = self.samples_sent.load(Ordering::Relaxed);
+    Duration::from_secs_f64(samples as f64 / (SAMPLE_RATE * CHANNEL_COUNT) as f64)
+  }
+}
+
+impl Default for VoiceMetrics {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[test]
+fn converts_samples_to_duration() {
+  let metrics = VoiceMetrics::new();
+  metrics.add_packet(SAMPLE_RATE * CHANNEL_COUNT, 100);
+  metrics.add_packet(SAMPLE_RATE * CHANNEL_COUNT / 2, 50);
+
+  assert_eq!(metrics.samples_sent_duration(), Duration::from_millis(1500));
+  assert_eq!(metrics.packets_sent.load(Ordering::Relaxed), 2);
+  assert_eq!(metrics.bytes_sent.load(Ordering::Relaxed), 150);
+}
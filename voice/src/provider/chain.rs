@@ -0,0 +1,96 @@
+use std::any::Any;
+use std::collections::VecDeque;
+
+use anyhow::Result;
+
+use crate::provider::{ProviderSpec, SampleProvider, SampleProviderHandle};
+
+/// Plays multiple sample providers one after another without gaps, e.g. an announcement before a track.
+///
+/// Segments are removed once they end, and the chain ends when no segments are left.
+#[derive(Default)]
+pub struct ChainSampleProvider {
+  segments: VecDeque<Box<dyn SampleProvider>>
+}
+
+struct ChainSampleProviderHandle;
+
+impl ChainSampleProvider {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Plays `provider` before the remaining segments.
+  pub fn push_front(&mut self, provider: Box<dyn SampleProvider>) {
+    self.segments.push_front(provider);
+  }
+
+  /// Plays `provider` after the remaining segments.
+  pub fn push_back(&mut self, provider: Box<dyn SampleProvider>) {
+    self.segments.push_back(provider);
+  }
+
+  pub fn len(&self) -> usize {
+    self.segments.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.segments.is_empty()
+  }
+}
+
+impl SampleProvider for ChainSampleProvider {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    while let Some(segment) = self.segments.front_mut() {
+      if let Some(samples) = segment.get_samples()? {
+        return Ok(Some(samples));
+      }
+      self.segments.pop_front();
+    }
+    Ok(None)
+  }
+
+  /// Spec of the segment that is playing, segments may differ in channel count.
+  fn spec(&self) -> ProviderSpec {
+    self.segments.front().map(|segment| segment.spec()).unwrap_or_default()
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  /// Returns the handle of the last segment, which is usually the main content (e.g. the track after an
+  /// announcement) and stays the same while the segments before it are playing.
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    match self.segments.back() {
+      Some(segment) => segment.get_handle(),
+      None => Box::new(ChainSampleProviderHandle)
+    }
+  }
+}
+
+impl SampleProviderHandle for ChainSampleProviderHandle {
+  fn as_any(&self) -> &(dyn Any + Sync + Send) {
+    self
+  }
+}
+
+#[test]
+fn chain_plays_segments_in_order() {
+  use crate::provider::test::CountingProvider;
+
+  let mut provider = ChainSampleProvider::new();
+  provider.push_back(Box::new(CountingProvider { next: 250, end: 400 }));
+  provider.push_back(Box::new(CountingProvider { next: 400, end: 400 }));
+  provider.push_front(Box::new(CountingProvider { next: 0, end: 250 }));
+  assert_eq!(provider.len(), 3);
+
+  let mut output = Vec::new();
+  while let Some(samples) = provider.get_samples().unwrap() {
+    output.extend(samples);
+  }
+
+  // No gaps between segments, and empty segments are skipped
+  assert_eq!(output, (0..400).map(|sample| sample as f32).collect::<Vec<_>>());
+  assert!(provider.is_empty());
+}
@@ -0,0 +1,83 @@
+use std::any::Any;
+
+use anyhow::Result;
+
+use crate::provider::{ProviderSpec, SampleProvider, SampleProviderHandle};
+
+/// Gain of a [GainSampleProvider].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gain {
+  Linear(f32),
+  Db(f32)
+}
+
+impl Gain {
+  pub fn to_linear(self) -> f32 {
+    match self {
+      Gain::Linear(gain) => gain,
+      Gain::Db(db) => 10f32.powf(db / 20.0)
+    }
+  }
+}
+
+/// Multiplies the samples of `inner` by a gain, e.g. to normalize the loudness of a single track.
+///
+/// Unlike [VoiceConnection::set_volume](crate::VoiceConnection::set_volume), only this provider is affected.
+pub struct GainSampleProvider<P: SampleProvider> {
+  inner: P,
+  gain: f32
+}
+
+impl<P: SampleProvider> GainSampleProvider<P> {
+  pub fn new(inner: P, gain: Gain) -> Self {
+    Self {
+      inner,
+      gain: gain.to_linear()
+    }
+  }
+
+  pub fn from_db(inner: P, db: f32) -> Self {
+    Self::new(inner, Gain::Db(db))
+  }
+
+  /// Returns the linear gain.
+  pub fn gain(&self) -> f32 {
+    self.gain
+  }
+
+  pub fn set_gain(&mut self, gain: Gain) {
+    self.gain = gain.to_linear();
+  }
+}
+
+impl<P: SampleProvider + 'static> SampleProvider for GainSampleProvider<P> {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    let mut samples = self.inner.get_samples()?;
+    if self.gain != 1.0 {
+      for sample in samples.iter_mut().flatten() {
+        *sample *= self.gain;
+      }
+    }
+    Ok(samples)
+  }
+
+  fn spec(&self) -> ProviderSpec {
+    self.inner.spec()
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    self.inner.get_handle()
+  }
+}
+
+#[test]
+fn gain_converts_db() {
+  assert_eq!(Gain::Linear(0.5).to_linear(), 0.5);
+  assert_eq!(Gain::Db(0.0).to_linear(), 1.0);
+  assert!((Gain::Db(-6.0).to_linear() - 0.501).abs() < 0.001);
+  assert!((Gain::Db(20.0).to_linear() - 10.0).abs() < 0.001);
+}
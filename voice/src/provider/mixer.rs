@@ -0,0 +1,161 @@
+use std::any::Any;
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use tracing::{debug, warn};
+
+use crate::provider::{to_stereo, SampleProvider, SampleProviderHandle};
+
+/// Identifies a channel of a [MixerSampleProvider], returned by [MixerSampleProvider::add_channel].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MixerChannelId(usize);
+
+pub struct MixerChannel {
+  id: MixerChannelId,
+  provider: Box<dyn SampleProvider>,
+  gain: f32,
+  /// Stereo samples pulled from the provider but not mixed yet.
+  pending: VecDeque<f32>,
+  finished: bool
+}
+
+/// Overlays multiple sample providers, e.g. a sound effect over the music.
+///
+/// Channels are removed once their provider ends, and the mixer ends when no channels are left.
+/// Use [VoiceConnection::set_sample_provider](crate::VoiceConnection::set_sample_provider) to play it,
+/// and [SampleProvider::as_any] to access it while it is playing.
+#[derive(Default)]
+pub struct MixerSampleProvider {
+  channels: Vec<MixerChannel>,
+  next_id: usize
+}
+
+struct MixerSampleProviderHandle;
+
+impl MixerSampleProvider {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn add_channel(&mut self, provider: Box<dyn SampleProvider>, gain: f32) -> MixerChannelId {
+    let id = MixerChannelId(self.next_id);
+    self.next_id += 1;
+    self.channels.push(MixerChannel {
+      id,
+      provider,
+      gain,
+      pending: VecDeque::new(),
+      finished: false
+    });
+    id
+  }
+
+  /// Returns the provider of the channel, [None] if it was removed or has ended.
+  pub fn remove_channel(&mut self, id: MixerChannelId) -> Option<Box<dyn SampleProvider>> {
+    let index = self.channels.iter().position(|channel| channel.id == id)?;
+    Some(self.channels.remove(index).provider)
+  }
+
+  /// Returns `false` if the channel was removed or has ended.
+  pub fn set_channel_gain(&mut self, id: MixerChannelId, gain: f32) -> bool {
+    match self.channels.iter_mut().find(|channel| channel.id == id) {
+      Some(channel) => {
+        channel.gain = gain;
+        true
+      }
+      None => false
+    }
+  }
+
+  pub fn channel_count(&self) -> usize {
+    self.channels.len()
+  }
+}
+
+impl SampleProvider for MixerSampleProvider {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    for channel in &mut self.channels {
+      if !channel.pending.is_empty() || channel.finished {
+        continue;
+      }
+
+      match channel.provider.get_samples() {
+        Ok(Some(samples)) => channel
+          .pending
+          .extend(to_stereo(samples, channel.provider.spec().channels)),
+        Ok(None) => channel.finished = true,
+        Err(error) => {
+          // Do not end the other channels
+          warn!("mixer channel {:?} failed: {:?}", channel.id, error);
+          channel.pending.clear();
+          channel.finished = true;
+        }
+      }
+    }
+
+    self.channels.retain(|channel| {
+      let ended = channel.finished && channel.pending.is_empty();
+      if ended {
+        debug!("mixer channel {:?} ended", channel.id);
+      }
+      !ended
+    });
+
+    // Only mix as many samples as every channel has, an empty vector requests a retry
+    let Some(length) = self.channels.iter().map(|channel| channel.pending.len()).min() else {
+      return Ok(None);
+    };
+    let mut samples = vec![0f32; length];
+    for channel in &mut self.channels {
+      for (sample, pending) in samples.iter_mut().zip(channel.pending.drain(..length)) {
+        *sample += pending * channel.gain;
+      }
+    }
+    for sample in &mut samples {
+      *sample = sample.clamp(-1.0, 1.0);
+    }
+
+    Ok(Some(samples))
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  /// Returns the handle of the first channel, which is usually the music.
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    match self.channels.first() {
+      Some(channel) => channel.provider.get_handle(),
+      None => Box::new(MixerSampleProviderHandle)
+    }
+  }
+}
+
+impl SampleProviderHandle for MixerSampleProviderHandle {
+  fn as_any(&self) -> &(dyn Any + Sync + Send) {
+    self
+  }
+}
+
+#[cfg(test)]
+use crate::provider::test::TestProvider;
+
+#[test]
+fn mixer_sums_channels_with_gain_and_clipping() {
+  let mut mixer = MixerSampleProvider::new();
+  let music = mixer.add_channel(Box::new(TestProvider::new(0.5).block(100).blocks(3)), 1.0);
+  let effect = mixer.add_channel(Box::new(TestProvider::new(0.5).block(60).blocks(1)), 0.5);
+
+  // Limited by the shorter chunk of the effect
+  assert_eq!(mixer.get_samples().unwrap().unwrap(), vec![0.75; 60]);
+  // The rest of the music chunk, the effect ended
+  assert_eq!(mixer.get_samples().unwrap().unwrap(), vec![0.5; 40]);
+  assert!(!mixer.set_channel_gain(effect, 1.0));
+  assert_eq!(mixer.channel_count(), 1);
+
+  assert!(mixer.set_channel_gain(music, 4.0));
+  assert_eq!(mixer.get_samples().unwrap().unwrap(), vec![1.0; 100]);
+
+  assert!(mixer.remove_channel(music).is_some());
+  assert_eq!(mixer.get_samples().unwrap(), None);
+}
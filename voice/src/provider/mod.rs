@@ -0,0 +1,150 @@
+pub mod chain;
+pub mod gain;
+pub mod mixer;
+#[cfg(test)]
+pub(crate) mod test;
+pub mod trim;
+
+use std::any::Any;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE, SOURCE_STALL_TIMEOUT};
+
+/// Layout of the samples returned by [`SampleProvider::get_samples`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProviderSpec {
+  pub channels: usize,
+  pub sample_rate: usize
+}
+
+impl Default for ProviderSpec {
+  fn default() -> Self {
+    Self {
+      channels: CHANNEL_COUNT,
+      sample_rate: SAMPLE_RATE
+    }
+  }
+}
+
+/// Audio sample provider for [`VoiceConnection`](crate::VoiceConnection).
+pub trait SampleProvider: Sync + Send {
+  /// The provided samples are returned in interleaved 32-bit floating point PCM format,
+  /// laid out as described by [`SampleProvider::spec`].
+  ///
+  /// If there are no additional samples available at the moment, this function will return [`None`].
+  /// If there are no samples currently available but could potentially become available later, this function returns an empty vector.
+  /// An error, e.g. a [`SampleProviderError`](crate::error::SampleProviderError), ends the playback of the current
+  /// track and is reported as [`VoiceConnectionEvent::SampleProviderError`](crate::VoiceConnectionEvent).
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>>;
+
+  /// Layout of the samples, non-stereo samples are converted to stereo by the playback loop.
+  fn spec(&self) -> ProviderSpec {
+    ProviderSpec::default()
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send);
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle>;
+}
+
+/// Allows wrapping boxed providers, e.g. `GainSampleProvider<Box<dyn SampleProvider>>`.
+///
+/// [`SampleProvider::as_any`] returns the boxed provider, so it can be downcast as if it was not boxed.
+impl<P: SampleProvider + ?Sized> SampleProvider for Box<P> {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    (**self).get_samples()
+  }
+
+  fn spec(&self) -> ProviderSpec {
+    (**self).spec()
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    (**self).as_any()
+  }
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    (**self).get_handle()
+  }
+}
+
+/// Converts interleaved samples with `channels` channels to stereo.
+///
+/// Mono is duplicated to both channels. More than two channels are averaged into left and right
+/// by alternating them, which keeps the front pair in place for common layouts.
+pub fn to_stereo(samples: Vec<f32>, channels: usize) -> Vec<f32> {
+  match channels {
+    0 | CHANNEL_COUNT => samples,
+    1 => samples.iter().flat_map(|sample| [*sample, *sample]).collect(),
+    _ => {
+      let left_count = channels.div_ceil(2) as f32;
+      let right_count = (channels / 2) as f32;
+      samples
+        .chunks_exact(channels)
+        .flat_map(|frame| {
+          let left = frame.iter().step_by(2).sum::<f32>() / left_count;
+          let right = frame.iter().skip(1).step_by(2).sum::<f32>() / right_count;
+          [left, right]
+        })
+        .collect()
+    }
+  }
+}
+
+/// Decoder health of a [`SampleProvider`], see [`SampleProviderHandle::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProviderStats {
+  pub frames_decoded: u64,
+  /// Bytes of encoded media read from the source.
+  pub bytes_read: u64,
+  /// When the provider last returned samples, [None] before the first read.
+  pub last_read_at: Option<Instant>,
+  /// No samples were returned for [`SOURCE_STALL_TIMEOUT`], e.g. the network is slow.
+  pub is_stalled: bool
+}
+
+impl ProviderStats {
+  /// Computes [`ProviderStats::is_stalled`] from `last_read_at`.
+  pub fn new(frames_decoded: u64, bytes_read: u64, last_read_at: Option<Instant>) -> Self {
+    Self {
+      frames_decoded,
+      bytes_read,
+      last_read_at,
+      is_stalled: last_read_at.is_some_and(|last_read_at| last_read_at.elapsed() > SOURCE_STALL_TIMEOUT)
+    }
+  }
+}
+
+/// Audio sample provider handle for [`SampleProvider`].
+///
+/// Used to communicate with a locked [`SampleProvider`] during playback.
+pub trait SampleProviderHandle: Sync + Send {
+  fn as_any(&self) -> &(dyn Any + Sync + Send);
+
+  /// Returns [None] if the provider does not track its decoder health.
+  ///
+  /// Must not block on the provider, it is called while the provider may be waiting for the source.
+  fn stats(&self) -> Option<ProviderStats> {
+    None
+  }
+}
+
+#[test]
+fn to_stereo_converts_channel_layouts() {
+  assert_eq!(to_stereo(vec![0.1, 0.2], 1), vec![0.1, 0.1, 0.2, 0.2]);
+  assert_eq!(to_stereo(vec![0.1, 0.2], 2), vec![0.1, 0.2]);
+  assert_eq!(to_stereo(vec![0.2, 0.4, 0.6, 0.8, 0.2, 0.4], 6), vec![(0.2 + 0.6 + 0.2) / 3.0, (0.4 + 0.8 + 0.4) / 3.0]);
+}
+
+#[test]
+fn stats_are_stalled_after_timeout() {
+  use std::time::Duration;
+
+  assert!(!ProviderStats::new(0, 0, None).is_stalled);
+  assert!(!ProviderStats::new(10, 4096, Some(Instant::now())).is_stalled);
+
+  let long_ago = Instant::now().checked_sub(SOURCE_STALL_TIMEOUT + Duration::from_secs(1)).unwrap();
+  assert!(ProviderStats::new(10, 4096, Some(long_ago)).is_stalled);
+}
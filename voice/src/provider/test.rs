@@ -0,0 +1,150 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+
+use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE, TIMESTAMP_STEP};
+use crate::provider::{ProviderSpec, SampleProvider, SampleProviderHandle};
+
+type FirstBlock = Box<dyn FnOnce() -> Vec<f32> + Send>;
+
+/// Provider for tests returning blocks of a constant sample, by default one stereo packet per call and without end.
+pub(crate) struct TestProvider {
+  value: f32,
+  block: usize,
+  /// Samples left until the end of the track, [None] if endless.
+  samples_left: Option<usize>,
+  channels: usize,
+  /// Number of calls that succeed before [SampleProvider::get_samples] fails.
+  fail_after: Option<usize>,
+  /// Returned by the first call instead of `value`, without counting towards the end of the track.
+  first_block: Mutex<Option<FirstBlock>>,
+  reads: Arc<AtomicUsize>
+}
+
+struct TestProviderHandle;
+
+impl TestProvider {
+  pub fn new(value: f32) -> Self {
+    Self {
+      value,
+      block: TIMESTAMP_STEP * CHANNEL_COUNT,
+      samples_left: None,
+      channels: CHANNEL_COUNT,
+      fail_after: None,
+      first_block: Mutex::new(None),
+      reads: Default::default()
+    }
+  }
+
+  /// Returns `samples` samples per call.
+  pub fn block(mut self, samples: usize) -> Self {
+    self.block = samples;
+    self
+  }
+
+  /// Returns mono packets instead of stereo ones.
+  pub fn mono(mut self) -> Self {
+    self.channels = 1;
+    self.block = TIMESTAMP_STEP;
+    self
+  }
+
+  /// Ends after `count` blocks, the block size must be set before.
+  pub fn blocks(self, count: usize) -> Self {
+    let samples = count * self.block;
+    self.samples(samples)
+  }
+
+  /// Ends after `samples` samples, the last block is shorter if needed.
+  pub fn samples(mut self, samples: usize) -> Self {
+    self.samples_left = Some(samples);
+    self
+  }
+
+  /// Fails with `decoding failed` after `calls` successful calls.
+  pub fn fail_after(mut self, calls: usize) -> Self {
+    self.fail_after = Some(calls);
+    self
+  }
+
+  /// Returns the result of `block` from the first call, e.g. to wait for the test to seek during a read.
+  pub fn first_block(self, block: impl FnOnce() -> Vec<f32> + Send + 'static) -> Self {
+    *self.first_block.lock().unwrap() = Some(Box::new(block));
+    self
+  }
+
+  /// Counter of [SampleProvider::get_samples] calls.
+  pub fn reads(&self) -> Arc<AtomicUsize> {
+    self.reads.clone()
+  }
+}
+
+impl SampleProvider for TestProvider {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    let calls = self.reads.fetch_add(1, Ordering::Relaxed);
+    if self.fail_after.is_some_and(|limit| calls >= limit) {
+      return Err(anyhow!("decoding failed"));
+    }
+    if let Some(block) = self.first_block.get_mut().unwrap().take() {
+      return Ok(Some(block()));
+    }
+
+    let len = match self.samples_left {
+      Some(0) => return Ok(None),
+      Some(left) => left.min(self.block),
+      None => self.block
+    };
+    if let Some(left) = &mut self.samples_left {
+      *left -= len;
+    }
+    Ok(Some(vec![self.value; len]))
+  }
+
+  fn spec(&self) -> ProviderSpec {
+    ProviderSpec {
+      channels: self.channels,
+      sample_rate: SAMPLE_RATE
+    }
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    Box::new(TestProviderHandle)
+  }
+}
+
+impl SampleProviderHandle for TestProviderHandle {
+  fn as_any(&self) -> &(dyn Any + Sync + Send) {
+    self
+  }
+}
+
+/// Provider for tests returning consecutive sample values from `next` until `end`, in chunks of 100.
+pub(crate) struct CountingProvider {
+  pub next: usize,
+  pub end: usize
+}
+
+impl SampleProvider for CountingProvider {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    if self.next >= self.end {
+      return Ok(None);
+    }
+    let chunk = (self.next..(self.next + 100).min(self.end)).map(|sample| sample as f32).collect::<Vec<_>>();
+    self.next += chunk.len();
+    Ok(Some(chunk))
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    Box::new(TestProviderHandle)
+  }
+}
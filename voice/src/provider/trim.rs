@@ -0,0 +1,105 @@
+use std::any::Any;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::provider::{ProviderSpec, SampleProvider, SampleProviderHandle};
+
+/// Skips the first `skip_samples` samples of `inner` and ends after `take_samples` samples, e.g. to play a clip.
+///
+/// Sample counts are interleaved samples in the layout of `inner`.
+pub struct TrimSampleProvider<P: SampleProvider> {
+  inner: P,
+  skip_samples: usize,
+  take_samples: Option<usize>,
+  skipped: usize,
+  taken: usize
+}
+
+impl<P: SampleProvider> TrimSampleProvider<P> {
+  pub fn new(inner: P, skip_samples: usize, take_samples: Option<usize>) -> Self {
+    Self {
+      inner,
+      skip_samples,
+      take_samples,
+      skipped: 0,
+      taken: 0
+    }
+  }
+
+  /// Plays `inner` from `start` until `end`, or until it ends if `end` is [None].
+  pub fn with_range(inner: P, start: Duration, end: Option<Duration>) -> Self {
+    let spec = inner.spec();
+    let to_samples = |duration: Duration| (duration.as_secs_f64() * spec.sample_rate as f64) as usize * spec.channels;
+
+    let skip_samples = to_samples(start);
+    let take_samples = end.map(|end| to_samples(end).saturating_sub(skip_samples));
+    Self::new(inner, skip_samples, take_samples)
+  }
+
+  pub fn inner(&self) -> &P {
+    &self.inner
+  }
+}
+
+impl<P: SampleProvider + 'static> SampleProvider for TrimSampleProvider<P> {
+  fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+    if self.take_samples.is_some_and(|take| self.taken >= take) {
+      return Ok(None);
+    }
+
+    let Some(mut samples) = self.inner.get_samples()? else {
+      return Ok(None);
+    };
+
+    if self.skipped < self.skip_samples {
+      let skip = (self.skip_samples - self.skipped).min(samples.len());
+      self.skipped += skip;
+      // Empty if the whole chunk is skipped, which requests a retry
+      samples.drain(..skip);
+    }
+
+    if let Some(take) = self.take_samples {
+      samples.truncate(take - self.taken);
+    }
+    self.taken += samples.len();
+
+    Ok(Some(samples))
+  }
+
+  fn spec(&self) -> ProviderSpec {
+    self.inner.spec()
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    self.inner.get_handle()
+  }
+}
+
+#[test]
+fn trim_skips_and_takes_across_chunks() {
+  use crate::provider::test::CountingProvider;
+
+  let mut provider = TrimSampleProvider::new(CountingProvider { next: 0, end: 1000 }, 250, Some(300));
+  let mut output = Vec::new();
+  while let Some(samples) = provider.get_samples().unwrap() {
+    output.extend(samples);
+  }
+  assert_eq!(output, (250..550).map(|sample| sample as f32).collect::<Vec<_>>());
+
+  // Until the end of the inner provider
+  let mut provider = TrimSampleProvider::with_range(
+    CountingProvider { next: 0, end: 96_000 },
+    Duration::from_millis(500),
+    None
+  );
+  let mut count = 0;
+  while let Some(samples) = provider.get_samples().unwrap() {
+    count += samples.len();
+  }
+  assert_eq!(count, 48_000);
+}
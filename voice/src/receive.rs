@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use opus::{Channels, Decoder};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace, warn};
+use xsalsa20poly1305::aead::generic_array::GenericArray;
+use xsalsa20poly1305::{AeadInPlace, XSalsa20Poly1305, TAG_SIZE};
+
+use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+use crate::rtcp::{is_rtcp, read_report_blocks, RtcpReports};
+use crate::udp::{NONCE_SIZE, RTP_HEADER_SIZE};
+use crate::VoiceCipherMode;
+
+/// Maximum Opus frame duration (120 ms) in interleaved samples.
+const MAX_DECODED_SAMPLES: usize = SAMPLE_RATE * 120 / 1000 * CHANNEL_COUNT;
+/// Size of the nonce appended to the payload in `xsalsa20_poly1305_lite` mode.
+const LITE_NONCE_SIZE: usize = 4;
+/// RTP header extension identifier used by Discord (RFC 5285 one-byte header).
+const RTP_EXTENSION_ONE_BYTE: u16 = 0xBEDE;
+
+/// Decoded audio of a single received voice packet.
+#[derive(Debug, Clone)]
+pub struct ReceivedAudio {
+  /// Speaking user, [None] if no `Speaking` event was received for the SSRC yet.
+  pub user_id: Option<u64>,
+  pub ssrc: u32,
+  /// Interleaved stereo samples.
+  pub pcm: Vec<f32>,
+  /// RTP timestamp, in samples per channel.
+  pub timestamp: u32
+}
+
+struct SsrcState {
+  decoder: Decoder,
+  last_sequence: u16
+}
+
+/// Decrypts and decodes incoming voice packets, one Opus decoder per SSRC.
+pub struct VoiceReceiver {
+  cipher: XSalsa20Poly1305,
+  cipher_mode: VoiceCipherMode,
+  sources: HashMap<u32, SsrcState>,
+  pcm: Vec<f32>
+}
+
+impl VoiceReceiver {
+  pub(crate) fn new(cipher: XSalsa20Poly1305, cipher_mode: VoiceCipherMode) -> Self {
+    Self {
+      cipher,
+      cipher_mode,
+      sources: HashMap::new(),
+      pcm: vec![0.0; MAX_DECODED_SAMPLES]
+    }
+  }
+
+  /// Processes a single UDP datagram. Returns [None] for non-voice (e.g. RTCP) and duplicate packets.
+  pub fn process(&mut self, packet: &mut [u8], users: &HashMap<u32, u64>) -> Result<Option<ReceivedAudio>> {
+    if packet.len() < RTP_HEADER_SIZE || packet[0] >> 6 != 2 {
+      return Ok(None);
+    }
+    if is_rtcp(packet) {
+      trace!("ignoring RTCP packet");
+      return Ok(None);
+    }
+
+    let has_extension = packet[0] & 0x10 != 0;
+    let csrc_count = (packet[0] & 0x0F) as usize;
+    let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+    let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+    let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+
+    let header_size = RTP_HEADER_SIZE + csrc_count * 4;
+    let payload = self.decrypt(packet, header_size)?;
+
+    let payload = if has_extension {
+      skip_extension(payload).context("invalid RTP header extension")?
+    } else {
+      payload
+    };
+
+    let source = match self.sources.entry(ssrc) {
+      std::collections::hash_map::Entry::Occupied(entry) => {
+        let source = entry.into_mut();
+        // Drop duplicates and late packets, reordering is not supported
+        if (sequence.wrapping_sub(source.last_sequence) as i16) <= 0 {
+          trace!(ssrc, sequence, "dropping duplicate or late packet");
+          return Ok(None);
+        }
+        source.last_sequence = sequence;
+        source
+      }
+      std::collections::hash_map::Entry::Vacant(entry) => {
+        debug!(ssrc, "new voice source");
+        entry.insert(SsrcState {
+          decoder: Decoder::new(SAMPLE_RATE as u32, Channels::Stereo)?,
+          last_sequence: sequence
+        })
+      }
+    };
+
+    let samples = source.decoder.decode_float(payload, &mut self.pcm, false)?;
+    Ok(Some(ReceivedAudio {
+      user_id: users.get(&ssrc).copied(),
+      ssrc,
+      pcm: self.pcm[..samples * CHANNEL_COUNT].to_vec(),
+      timestamp
+    }))
+  }
+
+  /// Decrypts the payload in place, returning the plaintext.
+  fn decrypt<'a>(&self, packet: &'a mut [u8], header_size: usize) -> Result<&'a [u8]> {
+    let mut nonce = [0; NONCE_SIZE];
+    let end = match self.cipher_mode {
+      VoiceCipherMode::Normal => {
+        nonce[..RTP_HEADER_SIZE].copy_from_slice(&packet[..RTP_HEADER_SIZE]);
+        packet.len()
+      }
+      VoiceCipherMode::Suffix => {
+        let end = packet.len().checked_sub(NONCE_SIZE).context("packet too small")?;
+        nonce.copy_from_slice(&packet[end..]);
+        end
+      }
+      VoiceCipherMode::Lite => {
+        let end = packet.len().checked_sub(LITE_NONCE_SIZE).context("packet too small")?;
+        nonce[..LITE_NONCE_SIZE].copy_from_slice(&packet[end..]);
+        end
+      }
+    };
+    if end < header_size + TAG_SIZE {
+      return Err(anyhow!("packet too small"));
+    }
+
+    let (tag, data) = packet[header_size..end].split_at_mut(TAG_SIZE);
+    self
+      .cipher
+      .decrypt_in_place_detached(GenericArray::from_slice(&nonce), b"", data, GenericArray::from_slice(tag))
+      .map_err(|_| anyhow!("failed to decrypt voice packet"))?;
+
+    Ok(&packet[header_size + TAG_SIZE..end])
+  }
+}
+
+/// Skips the (encrypted, so parsed after decryption) RTP header extension.
+fn skip_extension(payload: &[u8]) -> Option<&[u8]> {
+  let profile = u16::from_be_bytes([*payload.first()?, *payload.get(1)?]);
+  let length = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]) as usize;
+  if profile != RTP_EXTENSION_ONE_BYTE {
+    debug!("unknown RTP header extension profile {:#06x}", profile);
+  }
+  payload.get(4 + length * 4..)
+}
+
+/// Receives voice packets from `socket` until it is closed or `tx` is dropped.
+///
+/// RTCP report blocks are accounted per source in `rtcp_reports`, `ssrc` is the SSRC of the sent audio.
+/// Undecodable packets are logged and skipped. If `tx` is full, received audio is dropped.
+pub(crate) async fn run_receive_loop(
+  socket: Arc<UdpSocket>,
+  mut receiver: VoiceReceiver,
+  users: Arc<std::sync::RwLock<HashMap<u32, u64>>>,
+  tx: flume::Sender<ReceivedAudio>,
+  ssrc: u32,
+  rtcp_reports: Arc<std::sync::Mutex<RtcpReports>>
+) {
+  let mut buffer = [0; 4096];
+  loop {
+    let length = match socket.recv(&mut buffer).await {
+      Ok(length) => length,
+      Err(error) => {
+        warn!("voice receive error: {:?}", error);
+        return;
+      }
+    };
+
+    if is_rtcp(&buffer[..length]) {
+      match read_report_blocks(&mut buffer[..length], &receiver.cipher, receiver.cipher_mode) {
+        Ok(blocks) => rtcp_reports.lock().unwrap().add(ssrc, &blocks),
+        Err(error) => debug!("failed to process received RTCP packet: {:?}", error)
+      }
+      continue;
+    }
+
+    // Guard must not be held across awaits
+    let result = {
+      let users = users.read().unwrap();
+      receiver.process(&mut buffer[..length], &users)
+    };
+    match result {
+      Ok(Some(audio)) => {
+        if let Err(flume::TrySendError::Disconnected(_)) = tx.try_send(audio) {
+          return;
+        }
+      }
+      Ok(None) => {}
+      Err(error) => debug!("failed to process received voice packet: {:?}", error)
+    }
+  }
+}
+
+#[cfg(test)]
+fn encrypt_packet(cipher: &XSalsa20Poly1305, sequence: u16, ssrc: u32, opus: &[u8]) -> Vec<u8> {
+  use rand::random;
+
+  let mut packet = vec![0x80, 0x78];
+  packet.extend_from_slice(&sequence.to_be_bytes());
+  packet.extend_from_slice(&(sequence as u32 * 960).to_be_bytes());
+  packet.extend_from_slice(&ssrc.to_be_bytes());
+
+  let nonce = random::<[u8; NONCE_SIZE]>();
+  let mut payload = opus.to_vec();
+  let tag = cipher
+    .encrypt_in_place_detached(GenericArray::from_slice(&nonce), b"", &mut payload)
+    .unwrap();
+  packet.extend_from_slice(&tag);
+  packet.extend_from_slice(&payload);
+  packet.extend_from_slice(&nonce);
+  packet
+}
+
+#[test]
+fn receive_decodes_and_skips_duplicates() {
+  use xsalsa20poly1305::KeyInit;
+
+  use crate::constants::{OPUS_SILENCE_FRAME, TIMESTAMP_STEP};
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let mut receiver = VoiceReceiver::new(cipher.clone(), VoiceCipherMode::Suffix);
+  let users = HashMap::from([(42, 1234)]);
+
+  let mut packet = encrypt_packet(&cipher, 10, 42, &OPUS_SILENCE_FRAME);
+  let audio = receiver.process(&mut packet.clone(), &users).unwrap().unwrap();
+  assert_eq!(audio.user_id, Some(1234));
+  assert_eq!(audio.ssrc, 42);
+  assert_eq!(audio.timestamp, 9600);
+  assert_eq!(audio.pcm.len(), TIMESTAMP_STEP * CHANNEL_COUNT);
+
+  // Duplicate
+  assert!(receiver.process(&mut packet, &users).unwrap().is_none());
+
+  // Unknown SSRC
+  let mut packet = encrypt_packet(&cipher, 1, 43, &OPUS_SILENCE_FRAME);
+  assert_eq!(receiver.process(&mut packet, &users).unwrap().unwrap().user_id, None);
+}
+
+#[test]
+fn receive_rejects_garbage() {
+  use xsalsa20poly1305::KeyInit;
+
+  use crate::constants::OPUS_SILENCE_FRAME;
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let mut receiver = VoiceReceiver::new(cipher, VoiceCipherMode::Suffix);
+  let users = HashMap::new();
+
+  // Encrypted with another key
+  let other = XSalsa20Poly1305::new(&[8; 32].into());
+  let mut packet = encrypt_packet(&other, 1, 42, &OPUS_SILENCE_FRAME);
+  assert!(receiver.process(&mut packet, &users).is_err());
+
+  // Truncated
+  assert!(receiver.process(&mut packet[..RTP_HEADER_SIZE + 4], &users).is_err());
+
+  // Not RTP
+  assert!(receiver.process(&mut [0; 8], &users).unwrap().is_none());
+  let mut rtcp = [0x80, 201, 0, 2, 0, 0, 0, 42, 0, 0, 0, 0];
+  assert!(receiver.process(&mut rtcp, &users).unwrap().is_none());
+}
@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::slice;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::random;
+use xsalsa20poly1305::aead::generic_array::GenericArray;
+use xsalsa20poly1305::{AeadInPlace, XSalsa20Poly1305, TAG_SIZE};
+
+use crate::error::VoiceError;
+use crate::udp::NONCE_SIZE;
+use crate::VoiceCipherMode;
+
+/// Size of the unencrypted RTCP header: version, report count, packet type, length and sender SSRC.
+pub const RTCP_HEADER_SIZE: usize = 8;
+/// How often sender reports are sent while playing.
+pub const SENDER_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+const PACKET_TYPE_SENDER_REPORT: u8 = 200;
+const PACKET_TYPE_RECEIVER_REPORT: u8 = 201;
+const SENDER_INFO_SIZE: usize = 20;
+const REPORT_BLOCK_SIZE: usize = 24;
+/// Seconds between the NTP (1900) and Unix (1970) epochs.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Reception statistics of a single source from a sender or receiver report, see RFC 3550 section 6.4.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportBlock {
+  /// Source the statistics are about.
+  pub ssrc: u32,
+  /// Fraction of packets lost since the previous report, `256` is 100%.
+  pub fraction_lost: u8,
+  pub cumulative_lost: i32,
+  pub highest_sequence: u32,
+  /// Interarrival jitter in RTP timestamp units.
+  pub jitter: u32,
+  pub last_sender_report: u32,
+  pub delay_since_last_sender_report: u32
+}
+
+impl ReportBlock {
+  fn parse(data: &[u8; REPORT_BLOCK_SIZE]) -> Self {
+    let u32_at = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+    // 24-bit signed
+    let cumulative_lost = i32::from_be_bytes([data[5], data[6], data[7], 0]) >> 8;
+
+    Self {
+      ssrc: u32_at(0),
+      fraction_lost: data[4],
+      cumulative_lost,
+      highest_sequence: u32_at(8),
+      jitter: u32_at(12),
+      last_sender_report: u32_at(16),
+      delay_since_last_sender_report: u32_at(20)
+    }
+  }
+
+  /// Returns the round-trip time to the receiver at `now`, if it received a sender report.
+  ///
+  /// Computed from the middle 32 bits of NTP timestamps (1/65536 seconds), see RFC 3550 section 6.4.1.
+  pub fn round_trip_time(&self, now: SystemTime) -> Option<Duration> {
+    if self.last_sender_report == 0 {
+      return None;
+    }
+
+    let now = (ntp_timestamp(now) >> 16) as u32;
+    let round_trip = now
+      .wrapping_sub(self.last_sender_report)
+      .wrapping_sub(self.delay_since_last_sender_report);
+    // Negative if the clock jumped or the report is bogus
+    if round_trip > u32::MAX / 2 {
+      return None;
+    }
+    Some(Duration::from_secs_f64(round_trip as f64 / 65536.0))
+  }
+}
+
+/// Packet loss and jitter of a source, as reported by the voice server.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RtcpStats {
+  /// Report blocks about the source.
+  pub reports: u64,
+  /// Fraction of packets lost (`0.0..=1.0`) in the last report.
+  pub fraction_lost: f32,
+  /// Mean of [Self::fraction_lost] over all reports.
+  pub average_fraction_lost: f32,
+  pub cumulative_lost: i32,
+  /// Interarrival jitter in the last report, in RTP timestamp units.
+  pub jitter: u32,
+  pub max_jitter: u32,
+  /// Extended highest sequence number received in the last report.
+  pub highest_sequence: u32,
+  /// Round-trip time of the last report that referenced a sender report.
+  pub round_trip_time: Option<Duration>
+}
+
+impl RtcpStats {
+  /// Accounts the blocks of `blocks` that are about `ssrc`, blocks about other sources are ignored.
+  pub fn add(&mut self, ssrc: u32, blocks: &[ReportBlock]) {
+    self.add_at(ssrc, blocks, SystemTime::now());
+  }
+
+  /// Like [Self::add], with reports received at `now`.
+  pub fn add_at(&mut self, ssrc: u32, blocks: &[ReportBlock], now: SystemTime) {
+    for block in blocks.iter().filter(|block| block.ssrc == ssrc) {
+      let fraction_lost = block.fraction_lost as f32 / 256.0;
+      self.reports += 1;
+      self.fraction_lost = fraction_lost;
+      self.average_fraction_lost += (fraction_lost - self.average_fraction_lost) / self.reports as f32;
+      self.cumulative_lost = block.cumulative_lost;
+      self.jitter = block.jitter;
+      self.max_jitter = self.max_jitter.max(block.jitter);
+      self.highest_sequence = block.highest_sequence;
+      if let Some(round_trip_time) = block.round_trip_time(now) {
+        self.round_trip_time = Some(round_trip_time);
+      }
+    }
+  }
+}
+
+/// [RtcpStats] of every source that received reports are about, by SSRC.
+#[derive(Debug, Clone, Default)]
+pub struct RtcpReports {
+  /// SSRC of the sent audio.
+  local_ssrc: u32,
+  sources: HashMap<u32, RtcpStats>
+}
+
+impl RtcpReports {
+  /// Accounts every block of `blocks` under its source, `local_ssrc` is the SSRC of the sent audio.
+  pub fn add(&mut self, local_ssrc: u32, blocks: &[ReportBlock]) {
+    self.add_at(local_ssrc, blocks, SystemTime::now());
+  }
+
+  /// Like [Self::add], with reports received at `now`.
+  pub fn add_at(&mut self, local_ssrc: u32, blocks: &[ReportBlock], now: SystemTime) {
+    self.local_ssrc = local_ssrc;
+    for block in blocks {
+      self
+        .sources
+        .entry(block.ssrc)
+        .or_default()
+        .add_at(block.ssrc, slice::from_ref(block), now);
+    }
+  }
+
+  /// Statistics of the sent audio.
+  pub fn local(&self) -> RtcpStats {
+    self.get(self.local_ssrc)
+  }
+
+  pub fn get(&self, ssrc: u32) -> RtcpStats {
+    self.sources.get(&ssrc).copied().unwrap_or_default()
+  }
+
+  pub fn sources(&self) -> impl Iterator<Item = (u32, &RtcpStats)> {
+    self.sources.iter().map(|(ssrc, stats)| (*ssrc, stats))
+  }
+}
+
+/// Sender information of a sender report, see RFC 3550 section 6.4.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderReport {
+  pub ssrc: u32,
+  /// Wall clock time of [Self::rtp_timestamp], see [ntp_timestamp].
+  pub ntp_timestamp: u64,
+  pub rtp_timestamp: u32,
+  pub packet_count: u32,
+  /// Payload bytes sent, not including RTP headers.
+  pub octet_count: u32
+}
+
+/// Converts `time` to a 64-bit NTP timestamp (32.32 fixed point seconds since 1900).
+pub fn ntp_timestamp(time: SystemTime) -> u64 {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let seconds = since_epoch.as_secs() + NTP_UNIX_OFFSET;
+  let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+  (seconds << 32) | fraction
+}
+
+/// Whether `packet` is an RTCP packet, which shares the socket with RTP voice packets.
+pub fn is_rtcp(packet: &[u8]) -> bool {
+  // RTCP packet types 200-204 collide with RTP payload types 72-76 with the marker bit set
+  packet.len() >= 2 && packet[0] >> 6 == 2 && (200..=204).contains(&packet[1])
+}
+
+/// Writes the encrypted sender report to the start of `buffer` and returns its size.
+///
+/// Like voice packets, the header is sent in plain text and the rest is encrypted.
+pub(crate) fn build_sender_report(
+  buffer: &mut [u8],
+  report: &SenderReport,
+  cipher: &XSalsa20Poly1305,
+  mode: VoiceCipherMode
+) -> Result<usize, VoiceError> {
+  let nonce_size = match mode {
+    VoiceCipherMode::Normal => 0,
+    VoiceCipherMode::Suffix => NONCE_SIZE,
+    VoiceCipherMode::Lite => return Err(VoiceError::UnsupportedCipherMode)
+  };
+  let size = RTCP_HEADER_SIZE + TAG_SIZE + SENDER_INFO_SIZE + nonce_size;
+  if buffer.len() < size {
+    return Err(VoiceError::PacketTooLarge(size, buffer.len()));
+  }
+
+  // Length in 32-bit words minus one, of the unencrypted packet
+  let length = ((RTCP_HEADER_SIZE + SENDER_INFO_SIZE) / 4 - 1) as u16;
+  buffer[0] = 0x80; // Version 2, no report blocks
+  buffer[1] = PACKET_TYPE_SENDER_REPORT;
+  buffer[2..4].copy_from_slice(&length.to_be_bytes());
+  buffer[4..8].copy_from_slice(&report.ssrc.to_be_bytes());
+
+  let mut nonce = [0; NONCE_SIZE];
+  match mode {
+    VoiceCipherMode::Normal => nonce[..RTCP_HEADER_SIZE].copy_from_slice(&buffer[..RTCP_HEADER_SIZE]),
+    _ => nonce = random()
+  }
+
+  let data = &mut buffer[RTCP_HEADER_SIZE + TAG_SIZE..RTCP_HEADER_SIZE + TAG_SIZE + SENDER_INFO_SIZE];
+  data[0..8].copy_from_slice(&report.ntp_timestamp.to_be_bytes());
+  data[8..12].copy_from_slice(&report.rtp_timestamp.to_be_bytes());
+  data[12..16].copy_from_slice(&report.packet_count.to_be_bytes());
+  data[16..20].copy_from_slice(&report.octet_count.to_be_bytes());
+  let tag = cipher
+    .encrypt_in_place_detached(GenericArray::from_slice(&nonce), b"", data)
+    .map_err(|_| VoiceError::EncryptionFailed)?;
+  buffer[RTCP_HEADER_SIZE..RTCP_HEADER_SIZE + TAG_SIZE].copy_from_slice(tag.as_slice());
+  buffer[size - nonce_size..size].copy_from_slice(&nonce[..nonce_size]);
+
+  Ok(size)
+}
+
+/// Decrypts `packet` in place and returns all report blocks of a sender or receiver report.
+///
+/// Other RTCP packet types have no report blocks. Malformed and truncated packets are rejected.
+pub(crate) fn read_report_blocks(
+  packet: &mut [u8],
+  cipher: &XSalsa20Poly1305,
+  mode: VoiceCipherMode
+) -> Result<Vec<ReportBlock>, VoiceError> {
+  if !is_rtcp(packet) || packet.len() < RTCP_HEADER_SIZE {
+    return Err(VoiceError::InvalidRtcpPacket("not an RTCP packet"));
+  }
+  let count = (packet[0] & 0x1F) as usize;
+  let blocks_offset = match packet[1] {
+    PACKET_TYPE_SENDER_REPORT => SENDER_INFO_SIZE,
+    PACKET_TYPE_RECEIVER_REPORT => 0,
+    _ => return Ok(Vec::new())
+  };
+
+  let body = decrypt_rtcp(packet, cipher, mode)?;
+  let blocks = body
+    .get(blocks_offset..blocks_offset + count * REPORT_BLOCK_SIZE)
+    .ok_or(VoiceError::InvalidRtcpPacket("truncated report blocks"))?;
+
+  Ok(
+    blocks
+      .chunks_exact(REPORT_BLOCK_SIZE)
+      .map(|block| ReportBlock::parse(block.try_into().unwrap()))
+      .collect()
+  )
+}
+
+/// Decrypts the body after the RTCP header in place, returning the plaintext.
+fn decrypt_rtcp<'a>(
+  packet: &'a mut [u8],
+  cipher: &XSalsa20Poly1305,
+  mode: VoiceCipherMode
+) -> Result<&'a [u8], VoiceError> {
+  let mut nonce = [0; NONCE_SIZE];
+  let end = match mode {
+    VoiceCipherMode::Normal => {
+      nonce[..RTCP_HEADER_SIZE].copy_from_slice(&packet[..RTCP_HEADER_SIZE]);
+      packet.len()
+    }
+    VoiceCipherMode::Suffix => {
+      let end = packet
+        .len()
+        .checked_sub(NONCE_SIZE)
+        .ok_or(VoiceError::InvalidRtcpPacket("packet too small"))?;
+      nonce.copy_from_slice(&packet[end..]);
+      end
+    }
+    VoiceCipherMode::Lite => return Err(VoiceError::UnsupportedCipherMode)
+  };
+  if end < RTCP_HEADER_SIZE + TAG_SIZE {
+    return Err(VoiceError::InvalidRtcpPacket("packet too small"));
+  }
+
+  let (tag, data) = packet[RTCP_HEADER_SIZE..end].split_at_mut(TAG_SIZE);
+  cipher
+    .decrypt_in_place_detached(GenericArray::from_slice(&nonce), b"", data, GenericArray::from_slice(tag))
+    .map_err(|_| VoiceError::InvalidRtcpPacket("failed to decrypt"))?;
+
+  Ok(&packet[RTCP_HEADER_SIZE + TAG_SIZE..end])
+}
+
+#[cfg(test)]
+fn encrypt_receiver_report(cipher: &XSalsa20Poly1305, blocks: &[ReportBlock]) -> Vec<u8> {
+  let mut body = Vec::new();
+  for block in blocks {
+    body.extend_from_slice(&block.ssrc.to_be_bytes());
+    body.push(block.fraction_lost);
+    body.extend_from_slice(&block.cumulative_lost.to_be_bytes()[1..]);
+    for value in [
+      block.highest_sequence,
+      block.jitter,
+      block.last_sender_report,
+      block.delay_since_last_sender_report
+    ] {
+      body.extend_from_slice(&value.to_be_bytes());
+    }
+  }
+
+  let length = ((RTCP_HEADER_SIZE + body.len()) / 4 - 1) as u16;
+  let mut packet = vec![0x80 | blocks.len() as u8, PACKET_TYPE_RECEIVER_REPORT];
+  packet.extend_from_slice(&length.to_be_bytes());
+  packet.extend_from_slice(&7u32.to_be_bytes());
+
+  let nonce = random::<[u8; NONCE_SIZE]>();
+  let tag = cipher
+    .encrypt_in_place_detached(GenericArray::from_slice(&nonce), b"", &mut body)
+    .unwrap();
+  packet.extend_from_slice(&tag);
+  packet.extend_from_slice(&body);
+  packet.extend_from_slice(&nonce);
+  packet
+}
+
+#[test]
+fn reads_all_report_blocks() {
+  use xsalsa20poly1305::KeyInit;
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let block = |ssrc, fraction_lost, jitter| ReportBlock {
+    ssrc,
+    fraction_lost,
+    cumulative_lost: -3,
+    highest_sequence: 1000,
+    jitter,
+    last_sender_report: 0,
+    delay_since_last_sender_report: 0
+  };
+  let blocks = [block(42, 64, 100), block(43, 0, 5), block(42, 0, 20)];
+
+  let mut packet = encrypt_receiver_report(&cipher, &blocks);
+  let read = read_report_blocks(&mut packet, &cipher, VoiceCipherMode::Suffix).unwrap();
+  assert_eq!(read, blocks);
+
+  let mut stats = RtcpStats::default();
+  stats.add(42, &read);
+  assert_eq!(stats.reports, 2);
+  assert_eq!(stats.fraction_lost, 0.0);
+  assert_eq!(stats.average_fraction_lost, 0.125);
+  assert_eq!(stats.cumulative_lost, -3);
+  assert_eq!((stats.jitter, stats.max_jitter), (20, 100));
+}
+
+#[test]
+fn accounts_report_blocks_per_source() {
+  use xsalsa20poly1305::KeyInit;
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let block = |ssrc, fraction_lost, highest_sequence| ReportBlock {
+    ssrc,
+    fraction_lost,
+    cumulative_lost: 0,
+    highest_sequence,
+    jitter: 10,
+    last_sender_report: 0,
+    delay_since_last_sender_report: 0
+  };
+  let mut packet = encrypt_receiver_report(&cipher, &[block(42, 128, 1000), block(43, 0, 2000)]);
+  let blocks = read_report_blocks(&mut packet, &cipher, VoiceCipherMode::Suffix).unwrap();
+  assert_eq!(blocks.len(), 2);
+
+  let mut reports = RtcpReports::default();
+  reports.add(42, &blocks);
+  reports.add(42, &[block(43, 64, 2050)]);
+  assert_eq!(reports.sources().count(), 2);
+  assert_eq!(reports.local().reports, 1);
+  assert_eq!(reports.local().fraction_lost, 0.5);
+  assert_eq!(reports.local().highest_sequence, 1000);
+  assert_eq!(reports.get(43).reports, 2);
+  assert_eq!(reports.get(43).average_fraction_lost, 0.125);
+  assert_eq!(reports.get(43).highest_sequence, 2050);
+  assert_eq!(reports.get(44), RtcpStats::default());
+}
+
+#[test]
+fn rejects_malformed_rtcp_packets() {
+  use xsalsa20poly1305::KeyInit;
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let block = ReportBlock {
+    ssrc: 42,
+    fraction_lost: 0,
+    cumulative_lost: 0,
+    highest_sequence: 0,
+    jitter: 0,
+    last_sender_report: 0,
+    delay_since_last_sender_report: 0
+  };
+  let packet = encrypt_receiver_report(&cipher, &[block, block]);
+
+  // Every truncation fails without panicking
+  for length in 0..packet.len() {
+    let mut truncated = packet[..length].to_vec();
+    assert!(read_report_blocks(&mut truncated, &cipher, VoiceCipherMode::Suffix).is_err());
+  }
+
+  // More report blocks than the packet has
+  let mut packet = packet;
+  packet[0] = 0x80 | 3;
+  assert!(matches!(
+    read_report_blocks(&mut packet, &cipher, VoiceCipherMode::Suffix),
+    Err(VoiceError::InvalidRtcpPacket("truncated report blocks"))
+  ));
+}
+
+#[test]
+fn builds_decryptable_sender_reports() {
+  use xsalsa20poly1305::KeyInit;
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let report = SenderReport {
+    ssrc: 42,
+    ntp_timestamp: ntp_timestamp(UNIX_EPOCH + Duration::from_millis(1500)),
+    rtp_timestamp: 9600,
+    packet_count: 3,
+    octet_count: 300
+  };
+  assert_eq!(report.ntp_timestamp, ((NTP_UNIX_OFFSET + 1) << 32) | (1 << 31));
+
+  for mode in [VoiceCipherMode::Suffix, VoiceCipherMode::Normal] {
+    let mut buffer = [0; 128];
+    let size = build_sender_report(&mut buffer, &report, &cipher, mode).unwrap();
+    let packet = &mut buffer[..size];
+    assert!(is_rtcp(packet));
+    assert_eq!(&packet[4..8], &42u32.to_be_bytes());
+
+    // Sender reports without report blocks
+    assert_eq!(read_report_blocks(packet, &cipher, mode).unwrap(), Vec::new());
+    let data = &packet[RTCP_HEADER_SIZE + TAG_SIZE..RTCP_HEADER_SIZE + TAG_SIZE + SENDER_INFO_SIZE];
+    assert_eq!(&data[0..8], &report.ntp_timestamp.to_be_bytes());
+    assert_eq!(&data[8..12], &9600u32.to_be_bytes());
+    assert_eq!(&data[12..16], &3u32.to_be_bytes());
+    assert_eq!(&data[16..20], &300u32.to_be_bytes());
+  }
+}
+
+#[test]
+fn parses_captured_receiver_report_block() {
+  // Decrypted report block of a receiver report from the voice server
+  let data: [u8; REPORT_BLOCK_SIZE] = [
+    0x00, 0x01, 0xe2, 0x40, // SSRC 123456
+    0x0d, // 5% lost
+    0x00, 0x00, 0x2a, // 42 lost
+    0x00, 0x00, 0x1f, 0x40, // Highest sequence 8000
+    0x00, 0x00, 0x01, 0x2c, // Jitter 300
+    0x12, 0x34, 0x80, 0x00, // Last sender report
+    0x00, 0x00, 0x80, 0x00 // 0.5 s since the last sender report
+  ];
+  let block = ReportBlock::parse(&data);
+  assert_eq!(block, ReportBlock {
+    ssrc: 123456,
+    fraction_lost: 13,
+    cumulative_lost: 42,
+    highest_sequence: 8000,
+    jitter: 300,
+    last_sender_report: 0x1234_8000,
+    delay_since_last_sender_report: 0x8000
+  });
+
+  // Received 0.625 s after the sender report was sent, 0.5 s of which the server held it
+  let sent = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+  let received = sent + Duration::from_millis(625);
+  let block = ReportBlock {
+    last_sender_report: (ntp_timestamp(sent) >> 16) as u32,
+    ..block
+  };
+  let mut stats = RtcpStats::default();
+  stats.add_at(123456, &[block], received);
+  assert_eq!(stats.round_trip_time, Some(Duration::from_millis(125)));
+  assert_eq!(stats.highest_sequence, 8000);
+
+  // Without a sender report there is no round-trip time
+  assert_eq!(ReportBlock { last_sender_report: 0, ..block }.round_trip_time(received), None);
+}
@@ -0,0 +1,294 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use flume::Receiver;
+use opus::{Channels, Decoder};
+use tracing::{debug, warn};
+use xsalsa20poly1305::XSalsa20Poly1305;
+
+use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE, TIMESTAMP_STEP};
+use crate::rtcp::{
+  build_sender_report, is_rtcp, ntp_timestamp, read_report_blocks, SenderReport, SENDER_REPORT_INTERVAL
+};
+use crate::udp::UdpVoiceConnection;
+use crate::wav::{write_wav_header, write_wav_samples};
+use crate::{AudioFrame, Ready, VoiceConnection};
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(5000);
+
+/// Destination of audio frames played by [`VoiceConnection::run_playback_loop`].
+///
+/// Implementations are responsible for pacing: [`VoiceSink::send`] and [`VoiceSink::skip`] should return
+/// no earlier than one [`CHUNK_DURATION`](crate::constants::CHUNK_DURATION) after the previous frame.
+#[async_trait]
+pub trait VoiceSink: Send {
+  /// Called right before the first frame is sent.
+  fn reset(&mut self) {}
+
+  async fn send(&mut self, frame: AudioFrame) -> Result<()>;
+
+  /// Skips a frame (e.g. during DTX), keeping the timing continuous.
+  async fn skip(&mut self) -> Result<()>;
+
+  /// Called after every frame, for periodic housekeeping.
+  async fn tick(&mut self) -> Result<()> {
+    Ok(())
+  }
+}
+
+/// Control messages for a running [`UdpVoiceSink`], which owns the UDP socket and the cipher.
+pub(crate) enum UdpSinkCommand {
+  Rebind(UdpVoiceConnection),
+  UpdateCipher(XSalsa20Poly1305)
+}
+
+/// Sends encrypted RTP packets to the Discord voice server.
+///
+/// The sink owns the UDP socket and the cipher, so control paths (e.g. voice gateway reconnect)
+/// never contend with it for a lock. They are updated through [`UdpSinkCommand`]s instead.
+pub struct UdpVoiceSink {
+  connection: Arc<VoiceConnection>,
+  ready: Ready,
+  udp: UdpVoiceConnection,
+  cipher: XSalsa20Poly1305,
+  commands: Receiver<UdpSinkCommand>,
+  last_sender_report: Instant,
+  /// [`UdpVoiceConnection::packets_sent`] when the last sender report was due.
+  last_sender_report_packets: u32
+}
+
+impl UdpVoiceSink {
+  pub(crate) fn new(
+    connection: Arc<VoiceConnection>,
+    ready: Ready,
+    udp: UdpVoiceConnection,
+    cipher: XSalsa20Poly1305,
+    commands: Receiver<UdpSinkCommand>
+  ) -> Self {
+    Self {
+      connection,
+      ready,
+      udp,
+      cipher,
+      commands,
+      last_sender_report: Instant::now(),
+      last_sender_report_packets: 0
+    }
+  }
+
+  pub fn into_udp(self) -> UdpVoiceConnection {
+    self.udp
+  }
+
+  fn apply_commands(&mut self) {
+    while let Ok(command) = self.commands.try_recv() {
+      match command {
+        UdpSinkCommand::Rebind(udp) => {
+          debug!("UDP sink: rebinding socket");
+          // Keep pacing and sender report counts continuous across the socket change, the SSRC stays the same
+          let (deadline, packets_sent, octets_sent) = (self.udp.deadline, self.udp.packets_sent, self.udp.octets_sent);
+          self.udp = udp;
+          self.udp.deadline = deadline;
+          self.udp.packets_sent = packets_sent;
+          self.udp.octets_sent = octets_sent;
+        }
+        UdpSinkCommand::UpdateCipher(cipher) => {
+          debug!("UDP sink: updating cipher");
+          self.cipher = cipher;
+        }
+      }
+    }
+  }
+
+  /// Reads pending RTCP packets, unless the receive task reads the socket.
+  fn recv_rtcp(&mut self) -> Result<()> {
+    if self.connection.is_receiving() {
+      return Ok(());
+    }
+
+    let mut buffer = [0; 1500];
+    loop {
+      let length = match self.udp.socket.try_recv(&mut buffer) {
+        Ok(length) => length,
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+        Err(error) => return Err(error.into())
+      };
+      if !is_rtcp(&buffer[..length]) {
+        continue;
+      }
+
+      match read_report_blocks(&mut buffer[..length], &self.cipher, self.connection.cipher_mode()) {
+        Ok(blocks) => self.connection.add_rtcp_reports(self.ready.ssrc, &blocks),
+        Err(error) => debug!("failed to process RTCP packet: {:?}", error)
+      }
+    }
+  }
+
+  /// Sends a sender report with the RTP timestamp of the current time in the outgoing stream.
+  async fn send_sender_report(&mut self) -> Result<()> {
+    // The next packet has udp.timestamp and is sent at udp.deadline
+    let now = Instant::now();
+    let ahead = self.udp.deadline.saturating_duration_since(now);
+    let report = SenderReport {
+      ssrc: self.ready.ssrc,
+      ntp_timestamp: ntp_timestamp(SystemTime::now()),
+      rtp_timestamp: self.udp.timestamp.0 .0.wrapping_sub((ahead.as_secs_f64() * SAMPLE_RATE as f64) as u32),
+      packet_count: self.udp.packets_sent,
+      octet_count: self.udp.octets_sent
+    };
+
+    let mut buffer = [0; 128];
+    let size = build_sender_report(&mut buffer, &report, &self.cipher, self.connection.cipher_mode())?;
+    self.udp.socket.send(&buffer[..size]).await?;
+    self.last_sender_report = now;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl VoiceSink for UdpVoiceSink {
+  fn reset(&mut self) {
+    self.udp.deadline = Instant::now();
+  }
+
+  async fn send(&mut self, frame: AudioFrame) -> Result<()> {
+    self.apply_commands();
+    self
+      .connection
+      .send_voice_packet(&self.ready, &mut self.udp, &self.cipher, frame)
+      .await?;
+    Ok(())
+  }
+
+  async fn skip(&mut self) -> Result<()> {
+    self.apply_commands();
+    self.connection.skip_voice_packet(&mut self.udp);
+    Ok(())
+  }
+
+  async fn tick(&mut self) -> Result<()> {
+    if Instant::now() >= self.udp.heartbeat_time + KEEPALIVE_INTERVAL {
+      self.udp.send_keepalive(&self.ready).await?;
+    }
+
+    // Malformed packets are skipped, so only socket errors end up here
+    if let Err(error) = self.recv_rtcp() {
+      warn!("failed to receive RTCP packets: {:?}", error);
+    }
+    if Instant::now() >= self.last_sender_report + SENDER_REPORT_INTERVAL {
+      // Only active senders send sender reports (RFC 3550 section 6.4), e.g. not after the silence frames of a pause
+      if self.udp.packets_sent != self.last_sender_report_packets {
+        self.send_sender_report().await?;
+      } else {
+        self.last_sender_report = Instant::now();
+      }
+      self.last_sender_report_packets = self.udp.packets_sent;
+    }
+    Ok(())
+  }
+}
+
+/// Decodes `frame` to interleaved PCM samples.
+pub(crate) fn frame_to_pcm(decoder: &mut Decoder, frame: AudioFrame) -> Result<Vec<f32>> {
+  Ok(match frame {
+    AudioFrame::Pcm(samples) => samples,
+    AudioFrame::Opus(data) => {
+      let mut samples = vec![0.0; TIMESTAMP_STEP * CHANNEL_COUNT];
+      let decoded = decoder.decode_float(&data, &mut samples, false)?;
+      samples.truncate(decoded * CHANNEL_COUNT);
+      samples
+    }
+  })
+}
+
+/// Discards audio as fast as it is played, only counting it. Useful in tests.
+#[derive(Debug, Default)]
+pub struct NullSink {
+  /// PCM samples sent, including the padding of the last frame.
+  pub samples: usize,
+  /// Opus silence frames sent while paused or during DTX.
+  pub silence_frames: usize,
+  pub skipped: usize
+}
+
+#[async_trait]
+impl VoiceSink for NullSink {
+  async fn send(&mut self, frame: AudioFrame) -> Result<()> {
+    match frame {
+      AudioFrame::Pcm(samples) => self.samples += samples.len(),
+      AudioFrame::Opus(_) => self.silence_frames += 1
+    }
+    Ok(())
+  }
+
+  async fn skip(&mut self) -> Result<()> {
+    self.skipped += 1;
+    Ok(())
+  }
+}
+
+/// Writes the played (pre-encoding) audio to a WAV file as fast as it is played.
+///
+/// Opus frames are decoded and skipped frames are written as silence, so the file has the duration of the playback.
+/// The header is only valid after [`WavSink::finish`].
+pub struct WavSink<W: Write + Seek + Send> {
+  out: W,
+  decoder: Decoder,
+  samples: usize
+}
+
+impl WavSink<BufWriter<File>> {
+  pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    Self::new(BufWriter::new(File::create(path)?))
+  }
+}
+
+impl<W: Write + Seek + Send> WavSink<W> {
+  pub fn new(mut out: W) -> Result<Self> {
+    // Rewritten with the actual size in finish
+    write_wav_header(&mut out, SAMPLE_RATE as u32, CHANNEL_COUNT as u16, 0)?;
+    Ok(Self {
+      out,
+      decoder: Decoder::new(SAMPLE_RATE as u32, Channels::Stereo)?,
+      samples: 0
+    })
+  }
+
+  /// Interleaved samples written so far.
+  pub fn samples(&self) -> usize {
+    self.samples
+  }
+
+  /// Writes the final header and returns the writer.
+  pub fn finish(mut self) -> Result<W> {
+    let data_size = (self.samples * 2) as u32;
+    self.out.seek(SeekFrom::Start(0))?;
+    write_wav_header(&mut self.out, SAMPLE_RATE as u32, CHANNEL_COUNT as u16, data_size)?;
+    self.out.seek(SeekFrom::End(0))?;
+    self.out.flush()?;
+    Ok(self.out)
+  }
+
+  fn write(&mut self, samples: &[f32]) -> Result<()> {
+    write_wav_samples(&mut self.out, samples)?;
+    self.samples += samples.len();
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl<W: Write + Seek + Send> VoiceSink for WavSink<W> {
+  async fn send(&mut self, frame: AudioFrame) -> Result<()> {
+    let samples = frame_to_pcm(&mut self.decoder, frame)?;
+    self.write(&samples)
+  }
+
+  async fn skip(&mut self) -> Result<()> {
+    self.write(&[0.0; TIMESTAMP_STEP * CHANNEL_COUNT])
+  }
+}
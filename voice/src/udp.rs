@@ -1,43 +1,164 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::Instant;
 
-use anyhow::Result;
 use discortp::discord::MutableKeepalivePacket;
+use discortp::rtp::{MutableRtpPacket, RtpType};
 use discortp::wrap::{Wrap16, Wrap32};
+use discortp::MutablePacket;
+use opus::Encoder;
 use rand::random;
-use tokio::net::UdpSocket;
+use tokio::net::{lookup_host, UdpSocket};
 use tracing::debug;
+use xsalsa20poly1305::aead::generic_array::GenericArray;
+use xsalsa20poly1305::{AeadInPlace, XSalsa20Poly1305, TAG_SIZE};
 
 use super::Ready;
+use crate::constants::CHUNK_DURATION;
+use crate::error::VoiceError;
+use crate::{AudioFrame, VoiceCipherMode};
+
+/// Size of the RTP header written by [`VoiceConnection::send_voice_packet`](crate::VoiceConnection::send_voice_packet).
+pub const RTP_HEADER_SIZE: usize = 12;
+/// Size of the nonce appended to the payload in `xsalsa20_poly1305_suffix` mode.
+pub const NONCE_SIZE: usize = 24;
+/// Default RTP buffer size (typical Ethernet MTU minus IP and UDP headers).
+pub const DEFAULT_RTP_BUFFER_SIZE: usize = 1460;
+
+/// Returns the maximum size of an encrypted RTP packet carrying an Opus frame
+/// of `duration_ms` milliseconds encoded at `bitrate_bps` bits per second.
+pub fn max_opus_frame_size(bitrate_bps: u32, duration_ms: u32) -> usize {
+  let payload = bitrate_bps as usize * duration_ms as usize / 8000;
+  payload + RTP_HEADER_SIZE + TAG_SIZE + NONCE_SIZE
+}
+
+/// Returns the RTP buffer size for frames encoded at `bitrate_bps`, at least [DEFAULT_RTP_BUFFER_SIZE].
+pub fn rtp_buffer_size(bitrate_bps: Option<u32>) -> usize {
+  match bitrate_bps {
+    Some(bitrate) => DEFAULT_RTP_BUFFER_SIZE.max(max_opus_frame_size(bitrate, CHUNK_DURATION.as_millis() as u32)),
+    None => DEFAULT_RTP_BUFFER_SIZE
+  }
+}
+
+/// RTP header fields of a voice packet.
+#[derive(Debug, Clone, Copy)]
+pub struct RtpHeader {
+  pub sequence: Wrap16,
+  pub timestamp: Wrap32,
+  pub ssrc: u32
+}
+
+/// Writes the encrypted voice packet of `frame` to the start of `buffer`, encoding PCM frames with `encoder`.
+///
+/// Returns the packet size, or [VoiceError::PacketTooLarge] if the Opus frame does not fit into `buffer`.
+pub(crate) fn build_voice_packet(
+  buffer: &mut [u8],
+  frame: AudioFrame,
+  header: RtpHeader,
+  encoder: &mut Encoder,
+  cipher: &XSalsa20Poly1305,
+  mode: VoiceCipherMode
+) -> Result<usize, VoiceError> {
+  let nonce_size = match mode {
+    VoiceCipherMode::Normal => 0,
+    VoiceCipherMode::Suffix => NONCE_SIZE,
+    VoiceCipherMode::Lite => return Err(VoiceError::UnsupportedCipherMode)
+  };
+  let max_payload_size = buffer
+    .len()
+    .checked_sub(RTP_HEADER_SIZE + TAG_SIZE + nonce_size)
+    .ok_or(VoiceError::PacketTooLarge(RTP_HEADER_SIZE + TAG_SIZE + nonce_size, buffer.len()))?;
+
+  let mut view = MutableRtpPacket::new(buffer).unwrap();
+  view.set_version(2);
+  view.set_payload_type(RtpType::Unassigned(0x78));
+  view.set_sequence(header.sequence);
+  view.set_timestamp(header.timestamp);
+  view.set_ssrc(header.ssrc);
+
+  // Normal mode uses the RTP header as the nonce, suffix mode appends a random one
+  let mut nonce = [0; NONCE_SIZE];
+  match mode {
+    VoiceCipherMode::Normal => nonce[..RTP_HEADER_SIZE].copy_from_slice(&view.packet()[..RTP_HEADER_SIZE]),
+    _ => nonce = random()
+  }
+
+  let payload = view.payload_mut();
+  let data = &mut payload[TAG_SIZE..TAG_SIZE + max_payload_size];
+  let size = match frame {
+    AudioFrame::Opus(frame) => {
+      if frame.len() > max_payload_size {
+        let capacity = RTP_HEADER_SIZE + TAG_SIZE + max_payload_size + nonce_size;
+        return Err(VoiceError::PacketTooLarge(capacity - max_payload_size + frame.len(), capacity));
+      }
+      data[..frame.len()].copy_from_slice(&frame);
+      frame.len()
+    }
+    AudioFrame::Pcm(frame) => encoder.encode_float(&frame, data)?
+  };
+  payload[TAG_SIZE + size..TAG_SIZE + size + nonce_size].copy_from_slice(&nonce[..nonce_size]);
+
+  let tag = cipher
+    .encrypt_in_place_detached(GenericArray::from_slice(&nonce), b"", &mut payload[TAG_SIZE..TAG_SIZE + size])
+    .map_err(|_| VoiceError::EncryptionFailed)?;
+  payload[..TAG_SIZE].copy_from_slice(tag.as_slice());
+
+  Ok(RTP_HEADER_SIZE + TAG_SIZE + size + nonce_size)
+}
+
+/// Returns the unspecified address (`0.0.0.0` or `::`) of the address family of `remote`, with any port.
+pub fn bind_address(remote: &SocketAddr) -> SocketAddr {
+  match remote {
+    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into()
+  }
+}
 
 #[derive(Debug)]
 pub struct UdpVoiceConnection {
-  pub socket: UdpSocket,
+  /// Shared with the receive task, if receiving is enabled.
+  pub socket: Arc<UdpSocket>,
   pub heartbeat_time: Instant,
 
   pub sequence: Wrap16,
   pub timestamp: Wrap32,
   pub deadline: Instant,
+  /// Voice packets and payload bytes sent, for RTCP sender reports.
+  pub packets_sent: u32,
+  pub octets_sent: u32,
 
   pub rtp_buffer: Vec<u8>
 }
 
 impl UdpVoiceConnection {
-  pub async fn new(ready: &Ready) -> Result<Self> {
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect((ready.ip.clone(), ready.port)).await?;
+  pub async fn new(ready: &Ready, bitrate: Option<u32>) -> Result<Self, VoiceError> {
+    // The voice server may be IPv6-only, so the socket is bound to the address family of its address
+    let remote = lookup_host((ready.ip.as_str(), ready.port))
+      .await?
+      .next()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "voice server address did not resolve"))?;
+    let socket = UdpSocket::bind(bind_address(&remote)).await?;
+    socket.connect(remote).await?;
+    debug!("bound UDP socket {:?} to {}", socket.local_addr(), remote);
+
+    let rtp_buffer_size = rtp_buffer_size(bitrate);
+    debug!("using RTP buffer size {}", rtp_buffer_size);
 
     Ok(Self {
-      socket,
+      socket: Arc::new(socket),
       sequence: random::<u16>().into(),
       timestamp: random::<u32>().into(),
       heartbeat_time: Instant::now(),
       deadline: Instant::now(),
+      packets_sent: 0,
+      octets_sent: 0,
 
-      rtp_buffer: vec![0; 1460]
+      rtp_buffer: vec![0; rtp_buffer_size]
     })
   }
 
-  pub async fn send_keepalive(&mut self, ready: &Ready) -> Result<()> {
+  pub async fn send_keepalive(&mut self, ready: &Ready) -> Result<(), VoiceError> {
     let mut buffer = [0; MutableKeepalivePacket::minimum_packet_size()];
     let mut view = MutableKeepalivePacket::new(&mut buffer).unwrap();
     view.set_ssrc(ready.ssrc);
@@ -49,3 +170,120 @@ impl UdpVoiceConnection {
     Ok(())
   }
 }
+
+#[cfg(test)]
+fn test_header() -> RtpHeader {
+  RtpHeader {
+    sequence: 10u16.into(),
+    timestamp: 9600u32.into(),
+    ssrc: 42
+  }
+}
+
+#[test]
+fn builds_decryptable_voice_packets() {
+  use std::collections::HashMap;
+
+  use discortp::rtp::RtpPacket;
+  use opus::{Application, Channels};
+  use xsalsa20poly1305::KeyInit;
+
+  use crate::constants::{CHANNEL_COUNT, OPUS_SILENCE_FRAME, SAMPLE_RATE, TIMESTAMP_STEP};
+  use crate::receive::VoiceReceiver;
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let mut encoder = Encoder::new(SAMPLE_RATE as u32, Channels::Stereo, Application::Audio).unwrap();
+  let mut buffer = vec![0; DEFAULT_RTP_BUFFER_SIZE];
+  let mode = VoiceCipherMode::Suffix;
+
+  // Opus frames are sent as is
+  let frame = AudioFrame::Opus(OPUS_SILENCE_FRAME.to_vec());
+  let size = build_voice_packet(&mut buffer, frame, test_header(), &mut encoder, &cipher, mode).unwrap();
+  assert_eq!(size, RTP_HEADER_SIZE + TAG_SIZE + OPUS_SILENCE_FRAME.len() + NONCE_SIZE);
+
+  let packet = RtpPacket::new(&buffer[..size]).unwrap();
+  assert_eq!(packet.get_version(), 2);
+  assert_eq!(packet.get_payload_type(), RtpType::Unassigned(0x78));
+  assert_eq!(packet.get_sequence(), 10u16.into());
+  assert_eq!(packet.get_timestamp(), 9600u32.into());
+  assert_eq!(packet.get_ssrc(), 42);
+
+  let mut payload = buffer[RTP_HEADER_SIZE + TAG_SIZE..size - NONCE_SIZE].to_vec();
+  let tag = GenericArray::clone_from_slice(&buffer[RTP_HEADER_SIZE..RTP_HEADER_SIZE + TAG_SIZE]);
+  let nonce = GenericArray::clone_from_slice(&buffer[size - NONCE_SIZE..size]);
+  cipher.decrypt_in_place_detached(&nonce, b"", &mut payload, &tag).unwrap();
+  assert_eq!(payload, OPUS_SILENCE_FRAME);
+
+  // PCM frames are encoded, in both modes
+  let pcm = (0..TIMESTAMP_STEP * CHANNEL_COUNT)
+    .map(|index| (index as f32 * 0.01).sin() * 0.5)
+    .collect::<Vec<_>>();
+  for mode in [VoiceCipherMode::Suffix, VoiceCipherMode::Normal] {
+    let frame = AudioFrame::Pcm(pcm.clone());
+    let size = build_voice_packet(&mut buffer, frame, test_header(), &mut encoder, &cipher, mode).unwrap();
+
+    let mut receiver = VoiceReceiver::new(cipher.clone(), mode);
+    let audio = receiver.process(&mut buffer[..size], &HashMap::new()).unwrap().unwrap();
+    assert_eq!(audio.ssrc, 42);
+    assert_eq!(audio.pcm.len(), pcm.len());
+  }
+}
+
+#[test]
+fn rejects_frames_larger_than_buffer() {
+  use opus::{Application, Bitrate, Channels};
+  use xsalsa20poly1305::KeyInit;
+
+  use crate::constants::{CHANNEL_COUNT, SAMPLE_RATE, TIMESTAMP_STEP};
+
+  let cipher = XSalsa20Poly1305::new(&[7; 32].into());
+  let mut encoder = Encoder::new(SAMPLE_RATE as u32, Channels::Stereo, Application::Audio).unwrap();
+  let mut buffer = vec![0; DEFAULT_RTP_BUFFER_SIZE];
+  let mode = VoiceCipherMode::Suffix;
+
+  // The largest frame that fits
+  let max_frame = DEFAULT_RTP_BUFFER_SIZE - RTP_HEADER_SIZE - TAG_SIZE - NONCE_SIZE;
+  let frame = AudioFrame::Opus(vec![1; max_frame]);
+  let size = build_voice_packet(&mut buffer, frame, test_header(), &mut encoder, &cipher, mode).unwrap();
+  assert_eq!(size, DEFAULT_RTP_BUFFER_SIZE);
+
+  let frame = AudioFrame::Opus(vec![1; max_frame + 1]);
+  let result = build_voice_packet(&mut buffer, frame, test_header(), &mut encoder, &cipher, mode);
+  assert!(matches!(
+    result,
+    Err(VoiceError::PacketTooLarge(size, DEFAULT_RTP_BUFFER_SIZE)) if size == DEFAULT_RTP_BUFFER_SIZE + 1
+  ));
+
+  // Noise at the maximum bitrate is encoded within the buffer
+  encoder.set_bitrate(Bitrate::Max).unwrap();
+  let noise = (0..TIMESTAMP_STEP * CHANNEL_COUNT)
+    .map(|_| random::<f32>() * 2.0 - 1.0)
+    .collect::<Vec<_>>();
+  let frame = AudioFrame::Pcm(noise);
+  let size = build_voice_packet(&mut buffer, frame, test_header(), &mut encoder, &cipher, mode).unwrap();
+  assert!(size <= DEFAULT_RTP_BUFFER_SIZE);
+}
+
+#[tokio::test]
+async fn binds_to_address_family_of_voice_server() {
+  use std::net::{SocketAddrV4, SocketAddrV6};
+
+  assert_eq!(
+    bind_address(&SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 50000).into()),
+    "0.0.0.0:0".parse::<SocketAddr>().unwrap()
+  );
+  assert_eq!(
+    bind_address(&SocketAddrV6::new("2001:db8::1".parse().unwrap(), 50000, 0, 0).into()),
+    "[::]:0".parse::<SocketAddr>().unwrap()
+  );
+
+  let ready = Ready {
+    ssrc: 1,
+    ip: "127.0.0.1".to_owned(),
+    port: 50000,
+    modes: Vec::new()
+  };
+  let udp = UdpVoiceConnection::new(&ready, None).await.unwrap();
+  assert!(udp.socket.local_addr().unwrap().is_ipv4());
+  assert_eq!(udp.socket.peer_addr().unwrap(), "127.0.0.1:50000".parse::<SocketAddr>().unwrap());
+}
@@ -0,0 +1,40 @@
+use std::io::{self, Write};
+
+pub const WAV_HEADER_SIZE: usize = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Writes a 16-bit PCM WAV header for `data_size` bytes of samples.
+pub fn write_wav_header(out: &mut impl Write, sample_rate: u32, channels: u16, data_size: u32) -> io::Result<()> {
+  let block_align = channels * BITS_PER_SAMPLE / 8;
+
+  out.write_all(b"RIFF")?;
+  out.write_all(&(36 + data_size).to_le_bytes())?;
+  out.write_all(b"WAVEfmt ")?;
+  out.write_all(&16u32.to_le_bytes())?;
+  out.write_all(&1u16.to_le_bytes())?; // PCM
+  out.write_all(&channels.to_le_bytes())?;
+  out.write_all(&sample_rate.to_le_bytes())?;
+  out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+  out.write_all(&block_align.to_le_bytes())?;
+  out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+  out.write_all(b"data")?;
+  out.write_all(&data_size.to_le_bytes())
+}
+
+/// Writes interleaved samples as 16-bit PCM, clipping them to `-1.0..=1.0`.
+pub fn write_wav_samples(out: &mut impl Write, samples: &[f32]) -> io::Result<()> {
+  for sample in samples {
+    out.write_all(&((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())?;
+  }
+  Ok(())
+}
+
+/// Encodes interleaved samples as a 16-bit PCM WAV file.
+pub fn encode_wav(samples: &[f32], sample_rate: u32, channels: u16) -> Vec<u8> {
+  let data_size = (samples.len() * 2) as u32;
+  let mut wav = Vec::with_capacity(WAV_HEADER_SIZE + data_size as usize);
+  // Writing to a Vec does not fail
+  write_wav_header(&mut wav, sample_rate, channels, data_size).unwrap();
+  write_wav_samples(&mut wav, samples).unwrap();
+  wav
+}
@@ -1,15 +1,16 @@
 use std::time::SystemTime;
 
-use anyhow::{anyhow, Context, Result};
 use flume::{Receiver, Sender};
 use futures_util::{SinkExt, StreamExt};
 use tokio::select;
 use tokio_tungstenite::connect_async;
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{debug, warn};
+use tracing::{debug, warn, Instrument};
 
 use super::{GatewayEvent, GatewayPacket, Hello, Identify, Ready, Resume, Speaking, VoiceConnectionOptions};
+use crate::constants::MAX_GATEWAY_MESSAGE_SIZE;
+use crate::error::VoiceError;
 
 pub struct WebSocketVoiceConnection {
   pub read: Receiver<GatewayPacket>,
@@ -31,7 +32,7 @@ pub enum VoiceConnectionMode {
 }
 
 impl WebSocketVoiceConnection {
-  pub async fn new(mode: VoiceConnectionMode) -> Result<Self> {
+  pub async fn new(mode: VoiceConnectionMode) -> Result<Self, VoiceError> {
     let options = match &mode {
       VoiceConnectionMode::New(options) => options,
       VoiceConnectionMode::Resume { options, .. } => options
@@ -46,7 +47,7 @@ impl WebSocketVoiceConnection {
     let (close_rx_tx, close_rx_rx) = flume::unbounded();
 
     // WebSocket IO task
-    tokio::spawn(async move {
+    let io = async move {
       // [read_tx], [write_rx], [close_rx_tx], [close_tx_rx] are moved into this task
       loop {
         select! {
@@ -56,8 +57,9 @@ impl WebSocketVoiceConnection {
                 let message = message.unwrap();
                 match message {
                   Message::Text(json) => {
-                    debug!("< {}", json);
-                    read_tx.send_async(serde_json::from_str(&json).unwrap()).await.unwrap();
+                    if let Some(packet) = parse_gateway_message(&json) {
+                      read_tx.send_async(packet).await.ok();
+                    }
                   }
 
                   Message::Close(frame) => {
@@ -91,7 +93,8 @@ impl WebSocketVoiceConnection {
           }
         }
       }
-    });
+    };
+    tokio::spawn(io.in_current_span());
 
     let mut me = Self {
       read: read_rx,
@@ -125,9 +128,10 @@ impl WebSocketVoiceConnection {
                 break;
               }
             }
+            GatewayEvent::Unknown { opcode, .. } => debug!("ignoring opcode {} during handshake", opcode),
             other => {
               warn!("Expected Ready or Hello packet, got: {:?}", other);
-              return Err(anyhow!("Invalid packet")); // TODO
+              return Err(VoiceError::UnexpectedPacket(format!("{:?}", other)));
             }
           }
         }
@@ -157,9 +161,10 @@ impl WebSocketVoiceConnection {
                 break;
               }
             }
+            GatewayEvent::Unknown { opcode, .. } => debug!("ignoring opcode {} while resuming", opcode),
             other => {
               warn!("Expected Resumed or Hello packet, got: {:?}", other);
-              return Err(anyhow!("Invalid packet")); // TODO
+              return Err(VoiceError::UnexpectedPacket(format!("{:?}", other)));
             }
           }
         }
@@ -173,15 +178,16 @@ impl WebSocketVoiceConnection {
     Ok(me)
   }
 
-  pub async fn send_speaking(&self, speaking: bool) -> Result<()> {
-    let ready = self.ready.as_ref().context("no voice ready packet")?;
+  pub async fn send_speaking(&self, speaking: bool) -> Result<(), VoiceError> {
+    let ready = self.ready.as_ref().ok_or(VoiceError::NotConnected)?;
 
     self
       .send(
         GatewayEvent::Speaking(Speaking {
           speaking: if speaking { 1 } else { 0 },
           delay: 0,
-          ssrc: ready.ssrc
+          ssrc: ready.ssrc,
+          user_id: None
         })
         .try_into()?
       )
@@ -190,7 +196,7 @@ impl WebSocketVoiceConnection {
     Ok(())
   }
 
-  pub async fn send_identify(&self) -> Result<()> {
+  pub async fn send_identify(&self) -> Result<(), VoiceError> {
     self
       .send(
         GatewayEvent::Identify(Identify {
@@ -205,7 +211,7 @@ impl WebSocketVoiceConnection {
     Ok(())
   }
 
-  pub async fn send_resume(&self) -> Result<()> {
+  pub async fn send_resume(&self) -> Result<(), VoiceError> {
     self
       .send(
         GatewayEvent::Resume(Resume {
@@ -219,8 +225,12 @@ impl WebSocketVoiceConnection {
     Ok(())
   }
 
-  pub async fn send_heartbeat(&self) -> Result<()> {
-    let nonce = u64::try_from(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis())?;
+  pub async fn send_heartbeat(&self) -> Result<(), VoiceError> {
+    // Any unique value works as a nonce, even if the clock is off
+    let nonce = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis() as u64;
 
     self.send(GatewayEvent::Heartbeat(nonce).try_into()?).await?;
     debug!("Sent gateway heartbeat");
@@ -228,21 +238,54 @@ impl WebSocketVoiceConnection {
     Ok(())
   }
 
-  pub async fn send(&self, packet: GatewayPacket) -> Result<()> {
-    self.write.send_async(packet).await?;
-    Ok(())
+  pub async fn send(&self, packet: GatewayPacket) -> Result<(), VoiceError> {
+    self
+      .write
+      .send_async(packet)
+      .await
+      .map_err(|_| VoiceError::GatewayClosed)
   }
 
-  pub async fn receive(&self) -> Result<GatewayPacket> {
-    Ok(self.read.recv_async().await?)
+  pub async fn receive(&self) -> Result<GatewayPacket, VoiceError> {
+    self.read.recv_async().await.map_err(|_| VoiceError::GatewayClosed)
   }
 
-  pub async fn close(&self, frame: CloseFrame<'_>) -> Result<()> {
-    self.close_tx.send_async(frame.into_owned()).await?;
-    Ok(())
+  pub async fn close(&self, frame: CloseFrame<'_>) -> Result<(), VoiceError> {
+    self
+      .close_tx
+      .send_async(frame.into_owned())
+      .await
+      .map_err(|_| VoiceError::GatewayClosed)
   }
 
   pub fn is_closed(&self) -> bool {
     self.read.is_disconnected()
   }
 }
+
+/// Parses a text message of the voice gateway, dropping oversized and malformed ones with a warning.
+fn parse_gateway_message(json: &str) -> Option<GatewayPacket> {
+  if json.len() > MAX_GATEWAY_MESSAGE_SIZE {
+    warn!("Dropped oversized voice gateway message ({} bytes)", json.len());
+    return None;
+  }
+
+  debug!("< {}", json);
+  match serde_json::from_str::<GatewayPacket>(json) {
+    Ok(packet) => Some(packet),
+    Err(error) => {
+      warn!("Failed to parse voice gateway packet: {}: {}", error, json);
+      None
+    }
+  }
+}
+
+#[test]
+fn drops_invalid_gateway_messages() {
+  assert!(parse_gateway_message(r#"{"op": 8, "d": {"heartbeat_interval": 41250.0}}"#).is_some());
+  assert!(parse_gateway_message("{\"op\": 8,").is_none());
+  assert!(parse_gateway_message("[]").is_none());
+
+  let oversized = format!(r#"{{"op": 8, "d": "{}"}}"#, "a".repeat(MAX_GATEWAY_MESSAGE_SIZE));
+  assert!(parse_gateway_message(&oversized).is_none());
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+
+use crate::state::get_player_or_fail;
+use crate::util::check_dj_permission;
+use crate::{AnyError, PoiseContext};
+
+/// Play a media file or URL before the rest of the current track
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
+pub async fn announce(
+  ctx: PoiseContext<'_>,
+  #[description = "Media file or URL to play"] source: String
+) -> Result<(), AnyError> {
+  ctx.reply("Processing...").await?;
+
+  let player = get_player_or_fail!(ctx);
+
+  let _guard = player.command_lock.lock().await;
+  if let Err(error) = player.play_announcement(&source).await {
+    ctx.reply(format!("Failed to play announcement: {}", error)).await?;
+    return Ok(());
+  }
+  ctx.reply("Ok").await?;
+
+  Ok(())
+}
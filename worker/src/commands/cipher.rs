@@ -0,0 +1,40 @@
+use anyhow::Result;
+use voice::VoiceCipherMode;
+
+use crate::commands::get_or_create_player;
+use crate::{AnyError, PoiseContext};
+
+/// Show or force the voice encryption mode used on the next connection
+#[poise::command(prefix_command, slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn cipher(
+  ctx: PoiseContext<'_>,
+  #[description = "Mode name, e.g. `xsalsa20_poly1305_suffix`"] mode: Option<String>
+) -> Result<(), AnyError> {
+  let player = get_or_create_player(ctx).await?;
+
+  if let Some(mode) = mode {
+    let result = mode
+      .parse::<VoiceCipherMode>()
+      .and_then(|mode| player.connection.set_cipher_mode(mode));
+    if let Err(error) = result {
+      ctx.reply(format!("Failed to set cipher mode `{}`: {}", mode, error)).await?;
+      return Ok(());
+    }
+  }
+
+  let server_modes = {
+    let ws = player.connection.ws.read().await;
+    match ws.as_ref().and_then(|ws| ws.ready.as_ref()) {
+      Some(ready) => ready.modes.iter().map(|mode| format!("`{}`", mode)).collect::<Vec<_>>().join(", "),
+      None => "unknown (never connected)".to_owned()
+    }
+  };
+  ctx
+    .reply(format!(
+      "Cipher mode: `{}`\nServer modes: {}",
+      player.connection.cipher_mode().name(),
+      server_modes
+    ))
+    .await?;
+  Ok(())
+}
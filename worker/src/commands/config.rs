@@ -0,0 +1,72 @@
+use anyhow::Result;
+use serenity::all::AutocompleteChoice;
+
+use crate::db::{save_config, CONFIG_KEYS};
+use crate::util::command_names;
+use crate::{AnyError, PoiseContext};
+
+/// Change bot settings for this server
+#[poise::command(
+  prefix_command,
+  slash_command,
+  guild_only,
+  aliases("settings"),
+  required_permissions = "MANAGE_GUILD"
+)]
+pub async fn config(
+  ctx: PoiseContext<'_>,
+  #[description = "Setting name"]
+  #[autocomplete = "autocomplete_key"]
+  key: String,
+  #[description = "New value, `off` to disable"]
+  #[rest]
+  value: String
+) -> Result<(), AnyError> {
+  set_config_value(ctx, &key, &value).await
+}
+
+/// Sets and persists a config value, and replies with the change.
+pub(crate) async fn set_config_value(ctx: PoiseContext<'_>, key: &str, value: &str) -> Result<(), AnyError> {
+  let guild_id = ctx.guild_id().unwrap();
+  let state = ctx.data();
+
+  // Keep the in-memory config of a running player in sync, it is the source of truth if present
+  let player = state.players.read().await.get(&guild_id).cloned();
+  let mut config = match &player {
+    Some(player) => player.config.read().unwrap().clone(),
+    None => state.config(guild_id).await?
+  };
+
+  let old = config.get(key);
+  let result = old.and_then(|old| config.set(key, value).map(|_| old)).and_then(|old| {
+    // Registered names are only known here, the config itself only checks the syntax
+    if key == "dj-commands" {
+      config.check_dj_commands(&command_names(&ctx.framework().options().commands))?;
+    }
+    Ok(old)
+  });
+  let (old, new) = match result {
+    Ok(old) => (old, config.get(key)?),
+    Err(error) => {
+      ctx.reply(error.to_string()).await?;
+      return Ok(());
+    }
+  };
+
+  save_config(&state.db, &config).await?;
+  state.invalidate_config(guild_id).await;
+  if let Some(player) = player {
+    player.set_config(config).await?;
+  }
+
+  ctx.reply(format!("`{}`: {} -> {}", key, old, new)).await?;
+  Ok(())
+}
+
+async fn autocomplete_key(_ctx: PoiseContext<'_>, partial: &str) -> Vec<AutocompleteChoice> {
+  CONFIG_KEYS
+    .iter()
+    .filter(|key| key.starts_with(partial))
+    .map(|key| AutocompleteChoice::new(*key, *key))
+    .collect()
+}
@@ -0,0 +1,19 @@
+use anyhow::Result;
+
+use crate::commands::set_config_value;
+use crate::{AnyError, PoiseContext};
+
+/// Show or change the overlap between consecutive tracks
+#[poise::command(prefix_command, slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn crossfade(
+  ctx: PoiseContext<'_>,
+  #[description = "Overlap in seconds (0-10), `0` to disable"] seconds: Option<String>
+) -> Result<(), AnyError> {
+  if let Some(seconds) = seconds {
+    return set_config_value(ctx, "crossfade", &seconds).await;
+  }
+
+  let config = ctx.data().config(ctx.guild_id().unwrap()).await?;
+  ctx.reply(format!("Crossfade: {}", config.get("crossfade")?)).await?;
+  Ok(())
+}
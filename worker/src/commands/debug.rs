@@ -1,18 +1,25 @@
 use std::fmt::Display;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
 
 use anyhow::Result;
 use poise::CreateReply;
 use serenity::all::CreateEmbed;
 use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
 
-use crate::{AnyError, PoiseContext};
+use crate::logs::to_code_blocks;
 use crate::player::Player;
+use crate::providers::{get_metadata, MediaMetadata};
 use crate::state::get_player_or_fail;
+use crate::util::samples_to_duration;
+use crate::{AnyError, PoiseContext};
 use crate::voice::ffmpeg::FFmpegSampleProviderHandle;
 
-#[poise::command(prefix_command, track_edits, slash_command)]
+/// Number of log events shown by `debug logs` by default.
+const DEFAULT_LOG_TAIL: usize = 20;
+const MESSAGE_LENGTH_LIMIT: usize = 2000;
+
+#[poise::command(prefix_command, track_edits, slash_command, subcommands("debug_logs"))]
 pub async fn debug(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
   ctx.reply("Processing...").await?;
 
@@ -20,47 +27,84 @@ pub async fn debug(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
 
   let mut embed = CreateEmbed::default().title("Debug information");
 
-  let track = player.queue.get_current().upgrade().unwrap();
-  embed = embed.field(
-    "Track",
-    format!("provider: `{:?}`\ncreator: `{:?}`", track.provider, track.creator),
-    false
-  );
+  match player.queue.get_current().and_then(|track| track.upgrade()) {
+    Some(track) => {
+      embed = embed.field(
+        "Track",
+        format!("provider: `{:?}`\ncreator: `{:?}`", track.provider, track.creator),
+        false
+      );
+
+      let metadata = track.metadata().await;
+      if let Some(thumbnail) = get_metadata!(metadata, MediaMetadata::Thumbnail(url) => url) {
+        embed = embed.thumbnail(thumbnail);
+      }
+    }
+    None => embed = embed.field("Track", "queue is empty", false)
+  }
 
   {
     let handle = player.connection.sample_provider_handle.lock().await;
     let handle = handle.as_ref().unwrap();
+    if let Some(stats) = handle.stats() {
+      let last_read = stats
+        .last_read_at
+        .map_or_else(|| "never".to_owned(), |at| format!("{:?} ago", at.elapsed()));
+      embed = embed.field(
+        "Source",
+        format!(
+          "frames decoded: `{}`, read: `{}` bytes\nlast read: {}",
+          stats.frames_decoded,
+          stats.bytes_read,
+          wrap_warning(format!("`{}`", last_read), stats.is_stalled)
+        ),
+        false
+      );
+    }
+
     let handle = handle.as_any();
     if let Some(handle) = handle.downcast_ref::<FFmpegSampleProviderHandle>() {
-      // TODO(Assasans): Make get_frame_pts return raw PTS (samples count)?
       let decoder_pts = handle.get_frame_pts().unwrap();
-      let buffer_length = player.connection.sample_buffer.len() * 1000 / 2 / 48000;
-      let buffer_length = Duration::from_millis(buffer_length as u64);
-      let pts = decoder_pts - buffer_length;
+      let buffer_length = samples_to_duration(player.connection.sample_buffer.available_to_read());
+      let pts = player.connection.playback_position();
+
+      let buffer = &player.connection.sample_buffer;
 
       embed = embed.field(
         "Decoder",
         format!(
-          "pts: `{:?}` (decoder: `{:?}`, buffered: `{:?}`)",
-          pts, decoder_pts, buffer_length
+          "pts: `{:?}` (decoder: `{:?}`, buffered: `{:?}`)\nbuffer: `{}` / `{}` samples (`{:.1}%`, dropped: `{}`)",
+          pts,
+          decoder_pts,
+          buffer_length,
+          buffer.available_to_read(),
+          buffer.capacity(),
+          buffer.fill_percent(),
+          buffer.dropped_samples()
         ),
         false
       );
     }
   }
 
+  {
+    let channel = player.channel_bitrate().map_or("unknown".to_owned(), |bitrate| format!("{} kbps", bitrate / 1000));
+    let encoder = match player.connection.encoder_bitrate().await {
+      Some(bitrate) => format!("{} kbps", bitrate / 1000),
+      None => "unknown".to_owned()
+    };
+    let setting = player.config.read().unwrap().get("bitrate").unwrap();
+    embed = embed.field(
+      "Bitrate",
+      format!("channel: `{}`, encoder: `{}` (setting: `{}`)", channel, encoder, setting),
+      false
+    );
+  }
+
   {
     let rms = player.connection.rms.lock().unwrap();
     let ebur128 = player.connection.ebur128.lock().unwrap();
 
-    fn wrap_warning(value: impl Display, is_warning: bool) -> String {
-      if is_warning {
-        format!("__{}__ :warning:", value)
-      } else {
-        format!("{}", value)
-      }
-    }
-
     let get_rms = |ms| {
       let rms = rms.calculate_rms(SAMPLE_RATE * CHANNEL_COUNT * ms / 1000);
       let rms_db = 20.0 * (rms / 1.0).log10();
@@ -87,16 +131,36 @@ pub async fn debug(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
     let lufs_i = ebur128.loudness_global().unwrap();
     let lufs_target = -10.0;
 
+    let metrics = player.connection.metrics();
+    let limited_packets = metrics.limited_packets.load(Ordering::Relaxed);
+    let limited_percent = limited_packets as f64 / metrics.packets_sent.load(Ordering::Relaxed).max(1) as f64 * 100.0;
+
     embed = embed.field(
       "Audio levels",
       format!(
-        "{}\nCurrent: {}\nTrue Peak: {}\nMomentary loudness: {}\nShort-term loudness: {}\nIntegrated loudness: {}",
+        "{}\nCurrent: {}\nTrue Peak: {}\nMomentary loudness: {}\nShort-term loudness: {}\nIntegrated loudness: {}\n\
+         Limiter engaged: {}",
         rms,
         wrap_warning(format!("`{:.2} dBTP`", current_true_peak), current_true_peak >= 0.0),
         wrap_warning(format!("`{:.2} dBTP`", true_peak), true_peak >= 0.0),
         wrap_warning(format!("`{:.1} LUFS`", lufs_m), lufs_m > lufs_target),
         wrap_warning(format!("`{:.1} LUFS`", lufs_s), lufs_s > lufs_target),
         wrap_warning(format!("`{:.1} LUFS`", lufs_i), lufs_i > lufs_target),
+        wrap_warning(format!("`{}` packets (`{:.1}%`)", limited_packets, limited_percent), limited_percent > 10.0),
+      ),
+      true
+    );
+  }
+
+  {
+    let filters = player.filters.read().unwrap().clone();
+    embed = embed.field(
+      "Filters",
+      format!(
+        "preset: `{}`\nspeed: `{}x`\npitch: `{:+}` semitones",
+        filters.filters.as_ref().map_or("none", |filters| filters.name.as_str()),
+        filters.speed,
+        filters.pitch
       ),
       true
     );
@@ -112,6 +176,8 @@ pub async fn debug(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
     false
   );
 
+  embed = embed.field("Connection status", format!("`{}`", player.get_status()), false);
+
   {
     let ws = player.connection.ws.read().await;
     if let Some(ws) = ws.as_ref() {
@@ -125,18 +191,86 @@ pub async fn debug(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
     }
   }
 
+  if player.connection.is_connected() {
+    let (sequence, timestamp) = player.connection.rtp_state();
+    let rtcp = player.connection.rtcp_stats();
+    embed = embed.field(
+      "UdpVoiceConnection",
+      format!(
+        "sequence: `{}` (received: `{}`)\ntimestamp: `{}`\n\
+         loss: `{:.1}%` (average: `{:.1}%`, reports: `{}`)\njitter: `{}` (max: `{}`)\nround-trip: {}",
+        sequence,
+        rtcp.highest_sequence,
+        timestamp,
+        rtcp.fraction_lost * 100.0,
+        rtcp.average_fraction_lost * 100.0,
+        rtcp.reports,
+        rtcp.jitter,
+        rtcp.max_jitter,
+        match rtcp.round_trip_time {
+          Some(round_trip_time) => format!("`{} ms`", round_trip_time.as_millis()),
+          None => "unknown".to_owned()
+        }
+      ),
+      true
+    );
+  }
+
   {
-    let udp = player.connection.udp.lock().await;
-    if let Some(udp) = udp.as_ref() {
-      embed = embed.field(
-        "UdpVoiceConnection",
-        format!("sequence: `{}`\ntimestamp: `{}`", udp.sequence.0 .0, udp.timestamp.0 .0),
-        true
-      );
-    }
+    let metrics = player.connection.metrics();
+    let bytes_sent = metrics.bytes_sent.load(Ordering::Relaxed);
+    let elapsed = metrics.start_time.elapsed();
+    embed = embed.field(
+      "Transmission statistics",
+      format!(
+        "packets: `{}`\nsamples: `{}` (`{:?}` of audio)\nbytes: `{}` (average: `{:.1} kbps`)\n\
+         uptime: `{:?}`",
+        metrics.packets_sent.load(Ordering::Relaxed),
+        metrics.samples_sent.load(Ordering::Relaxed),
+        metrics.samples_sent_duration(),
+        bytes_sent,
+        bytes_sent as f64 * 8.0 / elapsed.as_secs_f64().max(1.0) / 1000.0,
+        elapsed
+      ),
+      true
+    );
   }
 
   ctx.send(ctx.reply_builder(CreateReply::default().embed(embed))).await?;
 
   Ok(())
 }
+
+/// Show recent log events of this server
+#[poise::command(prefix_command, slash_command, rename = "logs", guild_only)]
+pub async fn debug_logs(
+  ctx: PoiseContext<'_>,
+  #[description = "Number of events, defaults to 20"]
+  #[min = 1]
+  count: Option<usize>
+) -> Result<(), AnyError> {
+  let logs = &ctx.data().logs;
+  if logs.capacity() == 0 {
+    ctx.reply("Log recording is disabled (`MOSAIK_LOG_BUFFER_SIZE` is 0)").await?;
+    return Ok(());
+  }
+
+  let lines = logs.tail(ctx.guild_id().unwrap(), count.unwrap_or(DEFAULT_LOG_TAIL));
+  if lines.is_empty() {
+    ctx.reply("No log events recorded").await?;
+    return Ok(());
+  }
+
+  for block in to_code_blocks(&lines, MESSAGE_LENGTH_LIMIT) {
+    ctx.reply(block).await?;
+  }
+  Ok(())
+}
+
+fn wrap_warning(value: impl Display, is_warning: bool) -> String {
+  if is_warning {
+    format!("__{}__ :warning:", value)
+  } else {
+    format!("{}", value)
+  }
+}
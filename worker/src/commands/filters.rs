@@ -1,47 +1,136 @@
+use std::fmt::Write;
+
 use anyhow::Result;
-use decoder::Decoder;
+use serenity::all::AutocompleteChoice;
 use tracing::error;
 
+use crate::filters_presets::{get_filter_preset, FILTER_PRESETS};
+use crate::player::ActiveFilters;
 use crate::state::get_player_or_fail;
-use crate::voice::ffmpeg::FFmpegSampleProviderHandle;
+use crate::util::check_dj_permission;
 use crate::{AnyError, PoiseContext};
 
-#[poise::command(prefix_command, track_edits, slash_command)]
-pub async fn filters(
+/// Manage audio filters
+#[poise::command(
+  prefix_command,
+  slash_command,
+  check = "check_dj_permission",
+  subcommands("filters_preset", "filters_custom", "filters_off", "filters_status")
+)]
+pub async fn filters(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
+  let mut fmt = String::from(concat!(
+    "Usage: `filters preset <name> [intensity]`, `filters custom <graph>`, `filters off`, `filters status`\n\n",
+    "Presets:\n"
+  ));
+  for preset in FILTER_PRESETS {
+    fmt.write_fmt(format_args!("`{}` - {}\n", preset.name, preset.description)).unwrap();
+  }
+  ctx.reply(fmt).await?;
+
+  Ok(())
+}
+
+/// Apply a filter preset
+#[poise::command(prefix_command, track_edits, slash_command, rename = "preset", check = "check_dj_permission")]
+pub async fn filters_preset(
   ctx: PoiseContext<'_>,
-  #[description = "Specific command to show help about"]
-  #[autocomplete = "poise::builtins::autocomplete_command"]
-  filters: String
+  #[description = "Preset name"]
+  #[autocomplete = "autocomplete_filter"]
+  name: String,
+  #[description = "Intensity (0-2), or gains for the equalizer"]
+  #[rest]
+  parameters: Option<String>
 ) -> Result<(), AnyError> {
-  ctx.reply("Processing...").await?;
+  let preset = match get_filter_preset(&name) {
+    Some(preset) => preset,
+    None => {
+      ctx.reply(format!("Unknown preset `{}`", name)).await?;
+      return Ok(());
+    }
+  };
 
+  let graph = match preset.build(parameters.as_deref()) {
+    Ok(graph) => graph,
+    Err(error) => {
+      ctx.reply(format!("Invalid preset parameters: {}", error)).await?;
+      return Ok(());
+    }
+  };
+
+  set_filters(ctx, Some(ActiveFilters {
+    name: preset.name.to_owned(),
+    graph
+  }))
+  .await
+}
+
+/// Apply a custom FFmpeg filter graph
+#[poise::command(prefix_command, track_edits, slash_command, rename = "custom", check = "check_dj_permission")]
+pub async fn filters_custom(
+  ctx: PoiseContext<'_>,
+  #[description = "FFmpeg filter graph"]
+  #[rest]
+  graph: String
+) -> Result<(), AnyError> {
+  set_filters(ctx, Some(ActiveFilters {
+    name: "custom".to_owned(),
+    graph
+  }))
+  .await
+}
+
+/// Disable filters
+#[poise::command(prefix_command, track_edits, slash_command, rename = "off", check = "check_dj_permission")]
+pub async fn filters_off(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
+  set_filters(ctx, None).await
+}
+
+/// Show active filters
+#[poise::command(prefix_command, track_edits, slash_command, rename = "status")]
+pub async fn filters_status(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
   let player = get_player_or_fail!(ctx);
 
-  let handle = player.connection.sample_provider_handle.lock().await;
-  let handle = handle.as_ref().unwrap();
-  let handle = handle.as_any();
-  if let Some(handle) = handle.downcast_ref::<FFmpegSampleProviderHandle>() {
-    if filters == "bypass" {
-      handle.set_enable_filter_graph(false).unwrap();
-      ctx.reply("Disabled filter graph").await?;
-    } else {
-      match handle.init_filters(&filters) {
-        Ok(()) => {
-          handle.set_enable_filter_graph(true).unwrap();
-          ctx.reply(format!("Set filter graph: `{}`", filters)).await?;
-        }
-        Err(error) => {
-          let description = Decoder::error_code_to_string(error);
-          error!("failed to init filters: {:?} ({})", error, description);
-          ctx
-            .reply(format!("Failed to set filter graph: `{:?} ({})`", error, description))
-            .await?;
-        }
-      }
+  let state = player.filters.read().unwrap().clone();
+  let name = state.filters.as_ref().map_or("none", |filters| filters.name.as_str());
+  match state.graph() {
+    Some(graph) => {
+      ctx
+        .reply(format!(
+          "Active filters: `{}`, speed: `{}x`, pitch: `{:+}` semitones\n```\n{}\n```",
+          name, state.speed, state.pitch, graph
+        ))
+        .await?
     }
-  } else {
-    ctx.reply("Unsupported sample provider").await?;
-  }
+    None => ctx.reply("No filters active").await?
+  };
+
+  Ok(())
+}
+
+async fn autocomplete_filter(_ctx: PoiseContext<'_>, partial: &str) -> Vec<AutocompleteChoice> {
+  let partial = partial.to_lowercase();
+  FILTER_PRESETS
+    .iter()
+    .filter(|preset| preset.name.starts_with(&partial))
+    .map(|preset| AutocompleteChoice::new(format!("{} - {}", preset.name, preset.description), preset.name))
+    .collect()
+}
+
+async fn set_filters(ctx: PoiseContext<'_>, filters: Option<ActiveFilters>) -> Result<(), AnyError> {
+  let player = get_player_or_fail!(ctx);
+
+  let _guard = player.command_lock.lock().await;
+  // Speed and pitch are kept
+  match player.update_filters(|state| state.filters = filters).await {
+    Ok(state) => match state.graph() {
+      Some(graph) => ctx.reply(format!("Set filter graph: `{}`", graph)).await?,
+      None => ctx.reply("Disabled filter graph").await?
+    },
+    Err(error) => {
+      error!("failed to set filters: {:?}", error);
+      ctx.reply(format!("Failed to set filter graph: `{}`", error)).await?
+    }
+  };
 
   Ok(())
 }
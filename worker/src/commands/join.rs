@@ -0,0 +1,45 @@
+use serenity::all::{ChannelType, GuildChannel};
+use tracing::info;
+
+use crate::commands::{author_voice_channel, get_or_create_player};
+use crate::util::check_dj_permission;
+use crate::{AnyError, PoiseContext, VOICE_MANAGER};
+
+/// Join a voice channel without playing anything
+#[poise::command(prefix_command, slash_command, guild_only, check = "check_dj_permission")]
+pub async fn join(
+  ctx: PoiseContext<'_>,
+  #[description = "Voice channel to join, defaults to yours"]
+  #[channel_types("Voice", "Stage")]
+  channel: Option<GuildChannel>
+) -> Result<(), AnyError> {
+  let channel_id = match channel {
+    Some(channel) if matches!(channel.kind, ChannelType::Voice | ChannelType::Stage) => channel.id,
+    Some(channel) => {
+      ctx.reply(format!("<#{}> is not a voice channel", channel.id)).await?;
+      return Ok(());
+    }
+    None => author_voice_channel(ctx)?
+  };
+
+  let player = get_or_create_player(ctx).await?;
+  player.set_text_channel_id(ctx.channel_id());
+  player.set_context(ctx.serenity_context().clone()).await;
+
+  if player.connection.is_connected() {
+    if player.get_channel() == Some(channel_id) {
+      ctx.reply(format!("Already connected to <#{}>", channel_id)).await?;
+      return Ok(());
+    }
+
+    info!("moving to voice channel {}", channel_id);
+    player.move_to(channel_id).await?;
+  } else {
+    info!("joining voice channel {}", channel_id);
+    player.set_channel(channel_id);
+    player.connect(VOICE_MANAGER.get().unwrap().as_ref(), ctx.cache()).await?;
+  }
+
+  ctx.reply(format!("Joined <#{}>", channel_id)).await?;
+  Ok(())
+}
@@ -1,11 +1,11 @@
 use anyhow::{Context, Result};
 use tracing::debug;
-use voice::VoiceConnectionState;
 
 use crate::state::get_player_or_fail;
+use crate::util::check_dj_permission;
 use crate::{AnyError, PoiseContext};
 
-#[poise::command(prefix_command, track_edits, slash_command)]
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
 pub async fn jump(
   ctx: PoiseContext<'_>,
   #[description = "Specific command to show help about"]
@@ -17,6 +17,7 @@ pub async fn jump(
   let player = get_player_or_fail!(ctx);
 
   debug!("jump: {}", position);
+  let _guard = player.command_lock.lock().await;
   let current_position = player.queue.position();
 
   let position = match position.chars().nth(0).context("no first position character")? {
@@ -25,11 +26,7 @@ pub async fn jump(
     _ => position.parse::<usize>()?
   };
 
-  if player.connection.state.get() == VoiceConnectionState::Playing {
-    player.stop().await?;
-  }
-  player.queue.set_position(position);
-  player.play().await?;
+  player.jump(position).await?;
 
   ctx
     .reply(format!("Jumped to track {:?} (was: {:?})", position, current_position))
@@ -1,6 +1,9 @@
 use crate::{include_and_export, AnyError, PoiseContext};
 
-include_and_export!(play pause filters seek queue debug jump);
+include_and_export!(
+  play pause filters seek queue queue_persist debug jump search record config join announce playlist speed mode
+  crossfade cipher
+);
 
 /// Show this help menu
 #[poise::command(prefix_command, track_edits, slash_command)]
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use serenity::all::AutocompleteChoice;
+
+use crate::player::queue::{LoopPlayMode, NormalPlayMode, PlayMode, RandomPlayMode};
+use crate::state::get_player_or_fail;
+use crate::util::check_dj_permission;
+use crate::{AnyError, PoiseContext};
+
+const MODES: &[(&str, &str)] = &[
+  ("normal", "play the queue once"),
+  ("loop", "repeat the queue"),
+  ("random", "random order, no repeats until every track was played")
+];
+
+/// Change the order the queue is played in
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
+pub async fn mode(
+  ctx: PoiseContext<'_>,
+  #[description = "Play mode"]
+  #[autocomplete = "autocomplete_mode"]
+  mode: String
+) -> Result<(), AnyError> {
+  let player = get_player_or_fail!(ctx);
+
+  let queue = Arc::downgrade(&player.queue);
+  let play_mode: Box<dyn PlayMode> = match mode.to_ascii_lowercase().as_str() {
+    "normal" => Box::new(NormalPlayMode::new(queue)),
+    "loop" => Box::new(LoopPlayMode::new(queue)),
+    "random" => Box::new(RandomPlayMode::new(queue)),
+    _ => {
+      ctx.reply(format!("Unknown mode `{}`, expected `normal`, `loop` or `random`", mode)).await?;
+      return Ok(());
+    }
+  };
+
+  player.queue.set_mode(play_mode);
+  ctx.reply(format!("Play mode: `{}`", mode.to_ascii_lowercase())).await?;
+
+  Ok(())
+}
+
+async fn autocomplete_mode(_ctx: PoiseContext<'_>, partial: &str) -> Vec<AutocompleteChoice> {
+  MODES
+    .iter()
+    .filter(|(mode, _)| mode.starts_with(partial))
+    .map(|(mode, description)| AutocompleteChoice::new(format!("{} ({})", mode, description), *mode))
+    .collect()
+}
@@ -1,15 +1,20 @@
 use anyhow::Result;
 
 use crate::state::get_player_or_fail;
+use crate::util::check_dj_permission;
 use crate::{AnyError, PoiseContext};
 
-#[poise::command(prefix_command, track_edits, slash_command)]
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
 pub async fn pause(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
   ctx.reply("Processing...").await?;
 
   let player = get_player_or_fail!(ctx);
 
-  player.connection.set_paused(!player.connection.is_paused());
+  let paused = !player.connection.is_paused();
+  player.connection.set_paused(paused);
+  if !paused {
+    player.cancel_idle_timer();
+  }
   ctx.reply("Ok").await?;
 
   Ok(())
@@ -1,119 +1,269 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use serenity::all::ShardId;
-use tracing::{error, info};
+use futures_util::{stream, StreamExt};
+use poise::CreateReply;
+use serenity::all::ChannelId;
+use tracing::{error, info, warn};
 use voice::VoiceConnectionState;
 
+use crate::player::queue::QueueError;
 use crate::player::track::Track;
 use crate::player::Player;
 use crate::providers::{
-  FFmpegMediaProvider, MediaProvider, SberzvukMediaProvider, VkMediaProvider, YtDlpMediaProvider
+  FFmpegMediaProvider, HlsMediaProvider, HttpContext, MediaProvider, SberzvukMediaProvider, SourceError,
+  VkMediaProvider, YtDlpError, YtDlpMediaProvider
 };
+use crate::util::{check_dj_permission, parse_clip_range};
 use crate::{AnyError, PoiseContext, pretty_print_error, VOICE_MANAGER};
-use crate::provider_predictor::{MediaProviderPredictor, PredictedProvider};
-use crate::providers::factory::{MediaProviderFactory, YtDlpPlaylistMediaProviderFactory};
+use crate::provider_predictor::{parse_explicit_provider, MediaProviderPredictor, PredictedProvider};
+use crate::providers::factory::{MediaProviderFactory, MediaProviderStream, YtDlpPlaylistMediaProviderFactory};
 
-#[poise::command(prefix_command, track_edits, slash_command)]
+/// Playlist loading progress is reported every this many tracks.
+const PLAYLIST_PROGRESS_INTERVAL: usize = 25;
+
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
 pub async fn play(
   ctx: PoiseContext<'_>,
   #[description = "Specific command to show help about"]
   #[autocomplete = "poise::builtins::autocomplete_command"]
-  source: String
+  source: String,
+  #[description = "Play right after the current track"] next: Option<bool>,
+  #[description = "Start position in seconds"]
+  #[min = 0]
+  start: Option<f64>,
+  #[description = "End position in seconds"]
+  #[min = 0]
+  end: Option<f64>,
+  #[description = "Clip range, e.g. `1:23:45-1:25:00`, `1:30-` or `-2:00`"] range: Option<String>
 ) -> Result<(), AnyError> {
+  let (range_start, range_end) = match range.as_deref().map(parse_clip_range).transpose() {
+    Ok(range) => range.unwrap_or_default(),
+    Err(error) => {
+      ctx.reply(error.to_string()).await?;
+      return Ok(());
+    }
+  };
+  let start = start.map(Duration::from_secs_f64).or(range_start);
+  let end = end.map(Duration::from_secs_f64).or(range_end);
+  if let (Some(start), Some(end)) = (start, end) {
+    if end <= start {
+      ctx.reply("End position must be after the start position").await?;
+      return Ok(());
+    }
+  }
+
+  play_source(ctx, source, next.unwrap_or(false), (start, end)).await
+}
+
+/// Play right after the current track
+#[poise::command(prefix_command, track_edits, check = "check_dj_permission")]
+pub async fn playnext(ctx: PoiseContext<'_>, #[rest] source: String) -> Result<(), AnyError> {
+  play_source(ctx, source, true, (None, None)).await
+}
+
+/// `clip` is the start and end position to play, see [Track::with_clip].
+async fn play_source(
+  ctx: PoiseContext<'_>,
+  source: String,
+  next: bool,
+  clip: (Option<Duration>, Option<Duration>)
+) -> Result<(), AnyError> {
+  let (provider, input) = match parse_explicit_provider(&source)? {
+    Some((provider, input)) => (provider, input.to_owned()),
+    None => {
+      let mut prediction = MediaProviderPredictor::new().predict(&source);
+      info!("prediction: {:?}", prediction);
+      (prediction.remove(0).provider, source)
+    }
+  };
+
   ctx.reply("Processing...").await?;
 
-  let author = ctx.author();
-  let guild_id = ctx.guild_id().unwrap();
+  let player = join_author_channel(ctx).await?;
 
-  // TODO: The fuck
-  let voice_state = ctx
-    .guild()
-    .unwrap()
-    .voice_states
-    .get(&author.id)
-    .map(|it| it.to_owned());
-  if voice_state.is_none() {
-    ctx.reply("You are not in a voice channel").await.unwrap();
-  }
-  let channel_id = voice_state.unwrap().channel_id;
+  let (providers, is_playlist) = create_providers(provider, input, &ctx.data().http).await?;
+  enqueue(ctx, &player, providers, is_playlist, next, clip).await
+}
 
-  info!("connecting");
+/// Creates the media providers of `input`, and whether they are a playlist.
+pub(crate) async fn create_providers(
+  provider: PredictedProvider,
+  input: String,
+  http: &HttpContext
+) -> Result<(MediaProviderStream, bool)> {
+  Ok(match provider {
+    PredictedProvider::FFmpeg => (single_provider(Box::new(FFmpegMediaProvider::new(input))), false),
+    PredictedProvider::YtDlp => (single_provider(Box::new(YtDlpMediaProvider::new(input))), false),
+    PredictedProvider::YtDlpPlaylist => {
+      let mut factory = YtDlpPlaylistMediaProviderFactory::new(input);
+      (factory.get_media_providers_stream().await?, true)
+    }
+    PredictedProvider::Sberzvuk(id) => (single_provider(Box::new(SberzvukMediaProvider::new(id, http.clone()))), false),
+    PredictedProvider::Vk { owner_id, track_id } => {
+      (single_provider(Box::new(VkMediaProvider::new(owner_id, track_id, http.clone()))), false)
+    }
+    PredictedProvider::Hls => (single_provider(Box::new(HlsMediaProvider::new(input, http.clone()))), false),
+  })
+}
 
-  let state = ctx.data();
-  let mut players = state.players.write().await;
-  let player = players
-    .entry(guild_id)
-    .or_insert_with(|| Arc::new(Player::new(state.clone(), guild_id)));
+/// Joins the voice channel of the command author, creating a player for the guild if needed.
+pub async fn join_author_channel(ctx: PoiseContext<'_>) -> Result<Arc<Player>> {
+  let channel_id = author_voice_channel(ctx)?;
+
+  info!("connecting");
 
-  player.set_channel(channel_id.unwrap());
+  let player = get_or_create_player(ctx).await?;
+  player.set_channel(channel_id);
   player.set_text_channel_id(ctx.channel_id());
   player.set_context(ctx.serenity_context().clone()).await;
   if !player.connection.is_connected() {
-    let shard_id = ShardId(guild_id.shard_id(ctx.cache()));
-    let shard_manager = ctx.framework().shard_manager();
-    let shards = shard_manager.runners.lock().await;
-    let shard = shards.get(&shard_id).unwrap();
-
-    player
-      .connect(VOICE_MANAGER.get().unwrap().as_ref(), ctx.cache(), &shard.runner_tx)
-      .await?;
+    let voice_manager = VOICE_MANAGER.get().context("no voice manager")?;
+    player.connect(voice_manager.as_ref(), ctx.cache()).await?;
   }
 
   // TODO(Assasans): Internal code
   {
     let ws = player.connection.ws.read().await;
-    ws.as_ref().unwrap().send_speaking(true).await?;
+    let ws = ws.as_ref().context("voice gateway is not connected")?;
+    ws.send_speaking(true).await?;
   }
 
-  let predictor = MediaProviderPredictor::new();
-  let splitted = source.split_once(':').and_then(|splitted| {
-    if ["ffmpeg", "yt-dlp", "yt-dlp-playlist", "zvuk", "vk"].contains(&splitted.0) {
-      Some(splitted)
-    } else {
-      None
-    }
-  });
-  let mut providers: Vec<Box<dyn MediaProvider>> = if let Some((provider, input)) = splitted {
-    match provider {
-      "ffmpeg" => vec![Box::new(FFmpegMediaProvider::new(input.to_owned()))],
-      "yt-dlp" => vec![Box::new(YtDlpMediaProvider::new(input.to_owned()))],
-      "yt-dlp-playlist" => {
-        let mut factory = YtDlpPlaylistMediaProviderFactory::new(input.to_owned());
-        factory.init().await.unwrap();
-        factory.get_media_providers().await.unwrap()
-      },
-      "zvuk" => vec![Box::new(SberzvukMediaProvider::new(input.parse::<i64>()?))],
-      "vk" => {
-        let (owner_id, track_id) = input.split_once('_').unwrap();
-        vec![Box::new(VkMediaProvider::new(owner_id.parse::<i64>()?, track_id.parse::<i64>()?))]
-      }
-      _ => todo!("media provider {} is not implemented", provider)
+  Ok(player)
+}
+
+/// Returns the voice channel the command author is currently in.
+pub fn author_voice_channel(ctx: PoiseContext<'_>) -> Result<ChannelId> {
+  // Cache guard must not be held across awaits
+  ctx
+    .guild()
+    .context("no guild cached")?
+    .voice_states
+    .get(&ctx.author().id)
+    .and_then(|voice_state| voice_state.channel_id)
+    .context("You are not in a voice channel")
+}
+
+/// Returns the player of the guild, creating it with the saved guild config if needed.
+pub async fn get_or_create_player(ctx: PoiseContext<'_>) -> Result<Arc<Player>> {
+  let guild_id = ctx.guild_id().context("This command can only be used in a server")?;
+
+  let state = ctx.data();
+  let existing = state.players.read().await.get(&guild_id).cloned();
+  Ok(match existing {
+    Some(player) => player,
+    None => {
+      let config = state.config(guild_id).await?;
+      let mut players = state.players.write().await;
+      // Do not block other commands while connecting and loading media, so the lock is released right away
+      players
+        .entry(guild_id)
+        .or_insert_with(|| Arc::new(Player::new(state.clone(), guild_id, config)))
+        .clone()
     }
-  } else {
-    let prediction = predictor.predict(&source);
-    info!("prediction: {:?}", prediction);
-
-    match prediction[0].provider {
-      PredictedProvider::FFmpeg => vec![Box::new(FFmpegMediaProvider::new(source))],
-      PredictedProvider::YtDlp => vec![Box::new(YtDlpMediaProvider::new(source))],
-      PredictedProvider::YtDlpPlaylist => {
-        let mut factory = YtDlpPlaylistMediaProviderFactory::new(source);
-        factory.init().await.unwrap();
-        factory.get_media_providers().await.unwrap()
+  })
+}
+
+pub fn single_provider(provider: Box<dyn MediaProvider>) -> MediaProviderStream {
+  stream::iter([Ok(provider)]).boxed()
+}
+
+/// Initializes and enqueues media providers as they arrive, starting playback if the player is idle.
+///
+/// If `next` is set, tracks are inserted contiguously right after the current one instead of appended.
+/// `clip` is applied to every track, see [Track::with_clip].
+pub async fn enqueue(
+  ctx: PoiseContext<'_>,
+  player: &Arc<Player>,
+  mut providers: MediaProviderStream,
+  is_playlist: bool,
+  next: bool,
+  clip: (Option<Duration>, Option<Duration>)
+) -> Result<(), AnyError> {
+  let author = ctx.author();
+  let mut insert_index = None;
+
+  let mut progress = None;
+  let mut added = 0;
+  let mut total = 0;
+  // Playlists are truncated once the queue limit is reached, remaining items are only counted
+  let capacity = player.queue.remaining_capacity();
+  let mut is_full = false;
+  while let Some(provider) = providers.next().await {
+    let mut provider = match provider {
+      Ok(provider) => provider,
+      Err(error) => {
+        error!("failed to load media providers: {:?}", error);
+
+        if let Some(error) = error.downcast_ref::<YtDlpError>().filter(|error| error.is_classified()) {
+          ctx.reply(error.to_string()).await?;
+          break;
+        }
+        ctx
+          .reply(format!("Failed to load media providers:```ansi\n{}\n```", pretty_print_error(error)))
+          .await?;
+        break;
       }
+    };
+    total += 1;
+
+    if is_playlist && (is_full || capacity.is_some_and(|capacity| added >= capacity)) {
+      is_full = true;
+      continue;
     }
-  };
 
-  for mut provider in providers {
-    match provider.init().await {
+    let init = {
+      let _permit = ctx.data().init_permits.acquire().await?;
+      provider.init().await
+    };
+    match init {
       Ok(_) => {
-        let track = Track::new(provider, Some(author.id));
-        let (track, position) = player.queue.push(track);
+        let track = Track::new(provider, Some(author.id)).with_clip(clip.0, clip.1);
+        let result = if next {
+          let index = insert_index.unwrap_or_else(|| player.queue.position() + 1);
+          player.queue.insert(index, track)
+        } else {
+          player.queue.push(track)
+        };
+        let (track, position) = match result {
+          Ok(queued) => queued,
+          Err(error) => {
+            is_full |= matches!(error, QueueError::Full(_));
+            if !is_playlist {
+              ctx.reply(error.to_string()).await?;
+            }
+            continue;
+          }
+        };
+        insert_index = Some(position + 1);
+        added += 1;
 
-        if player.connection.state.get() != VoiceConnectionState::Playing {
-          player.queue.set_position(position);
-          player.play().await.unwrap();
+        {
+          let _guard = player.command_lock.lock().await;
+          if player.connection.state.get() != VoiceConnectionState::Playing {
+            player.queue.set_position(position);
+            if let Err(error) = player.play().await {
+              match error.downcast_ref::<SourceError>() {
+                Some(error) => {
+                  ctx.reply(error.to_string()).await?;
+                  continue;
+                }
+                None => return Err(error)
+              }
+            }
+          }
+        }
+
+        if is_playlist {
+          if added % PLAYLIST_PROGRESS_INTERVAL == 0 {
+            let reply = CreateReply::default().content(format!("Added {} tracks to queue...", added));
+            match progress {
+              Some(ref handle) => handle.edit(ctx, reply).await?,
+              None => progress = Some(ctx.send(reply).await?)
+            }
+          }
+          continue;
         }
 
         let metadata = track.provider.get_metadata().await?;
@@ -129,11 +279,15 @@ pub async fn play(
             track.provider, metadata_string
           ))
           .await
-          .unwrap();
+          .context("failed to reply with added track")?;
       }
       Err(error) => {
         error!("failed to init track: {:?}", error);
 
+        if let Some(error) = error.downcast_ref::<YtDlpError>().filter(|error| error.is_classified()) {
+          ctx.reply(error.to_string()).await?;
+          continue;
+        }
         ctx
           .reply(format!(
             "Failed to init provider `{:?}`:```ansi\n{}\n```",
@@ -141,7 +295,23 @@ pub async fn play(
             pretty_print_error(error)
           ))
           .await
-          .unwrap();
+          .context("failed to reply with provider error")?;
+      }
+    }
+  }
+
+  if is_playlist {
+    let content = if is_full {
+      warn!("queue limit reached, added {} of {} tracks", added, total);
+      format!("Added {} of {} tracks (queue limit reached)", added, total)
+    } else {
+      format!("Added {} tracks to queue", added)
+    };
+    let reply = CreateReply::default().content(content);
+    match progress {
+      Some(ref handle) => handle.edit(ctx, reply).await?,
+      None => {
+        ctx.send(reply).await?;
       }
     }
   }
@@ -0,0 +1,153 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use futures_util::{stream, StreamExt};
+use tracing::warn;
+
+use crate::commands::{enqueue, join_author_channel};
+use crate::playlist::{Playlist, PlaylistEntry};
+use crate::providers::{get_metadata, MediaMetadata};
+use crate::state::get_player_or_fail;
+use crate::util::check_dj_permission;
+use crate::{AnyError, PoiseContext};
+
+/// Save and load queues as playlists
+#[poise::command(
+  prefix_command,
+  slash_command,
+  subcommands("playlist_save", "playlist_load", "playlist_list", "playlist_delete")
+)]
+pub async fn playlist(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
+  ctx
+    .reply("Usage: `playlist save <name>`, `playlist load <name>`, `playlist list`, `playlist delete <name>`")
+    .await?;
+  Ok(())
+}
+
+/// Save the current queue as a playlist
+#[poise::command(prefix_command, slash_command, rename = "save")]
+pub async fn playlist_save(
+  ctx: PoiseContext<'_>,
+  #[description = "Playlist name"] name: String
+) -> Result<(), AnyError> {
+  let player = get_player_or_fail!(ctx);
+
+  let tracks = {
+    let tracks = player.queue.tracks.read().unwrap();
+    tracks.iter().cloned().collect::<Vec<_>>()
+  };
+
+  let mut playlist = Playlist::default();
+  let mut skipped = 0;
+  for track in &tracks {
+    let Some(source) = &track.source else {
+      skipped += 1;
+      continue;
+    };
+    let title = match track.provider.get_metadata().await {
+      Ok(metadata) => get_metadata!(metadata, MediaMetadata::Title(title) => title.clone()),
+      Err(_) => None
+    };
+    match PlaylistEntry::from_source(source, title) {
+      Some(entry) => playlist.entries.push(entry),
+      None => skipped += 1
+    }
+  }
+
+  if playlist.entries.is_empty() {
+    ctx.reply("Nothing to save, the queue has no saveable tracks").await?;
+    return Ok(());
+  }
+  if let Err(error) = ctx.data().playlists.save(ctx.author().id.get(), &name, &playlist).await {
+    ctx.reply(format!("Failed to save playlist: {}", error)).await?;
+    return Ok(());
+  }
+
+  let mut reply = format!("Saved {} tracks to `{}`", playlist.entries.len(), name);
+  if skipped > 0 {
+    write!(reply, ", skipped {} tracks that cannot be saved", skipped).unwrap();
+  }
+  ctx.reply(reply).await?;
+
+  Ok(())
+}
+
+/// Add the tracks of a saved playlist to the queue
+#[poise::command(prefix_command, slash_command, rename = "load", check = "check_dj_permission")]
+pub async fn playlist_load(
+  ctx: PoiseContext<'_>,
+  #[description = "Playlist name"] name: String
+) -> Result<(), AnyError> {
+  let playlist = match ctx.data().playlists.load(ctx.author().id.get(), &name).await {
+    Ok(Some(playlist)) => playlist,
+    Ok(None) => {
+      ctx.reply(format!("No playlist named `{}`", name)).await?;
+      return Ok(());
+    }
+    Err(error) => {
+      ctx.reply(format!("Failed to load playlist: {}", error)).await?;
+      return Ok(());
+    }
+  };
+
+  enqueue_entries(ctx, &name, &playlist.entries).await
+}
+
+/// Enqueues the `entries` of the saved playlist or queue `name`, skipping entries that cannot be restored.
+pub(crate) async fn enqueue_entries(
+  ctx: PoiseContext<'_>,
+  name: &str,
+  entries: &[PlaylistEntry]
+) -> Result<(), AnyError> {
+  ctx.reply("Processing...").await?;
+
+  let mut providers = Vec::new();
+  for entry in entries {
+    match entry.create_provider(&ctx.data().http) {
+      Ok(provider) => providers.push(Ok(provider)),
+      Err(error) => warn!("skipping playlist entry {:?}: {:?}", entry, error)
+    }
+  }
+
+  let restored = providers.len();
+  if restored > 0 {
+    let player = join_author_channel(ctx).await?;
+    enqueue(ctx, &player, stream::iter(providers).boxed(), true, false, (None, None)).await?;
+  }
+
+  ctx
+    .reply(format!("Restored {} tracks from `{}`, skipped {}", restored, name, entries.len() - restored))
+    .await?;
+
+  Ok(())
+}
+
+/// List your saved playlists
+#[poise::command(prefix_command, slash_command, rename = "list")]
+pub async fn playlist_list(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
+  let names = ctx.data().playlists.list(ctx.author().id.get()).await?;
+  if names.is_empty() {
+    ctx.reply("You have no saved playlists").await?;
+    return Ok(());
+  }
+
+  let names = names.iter().map(|name| format!("`{}`", name)).collect::<Vec<_>>();
+  ctx.reply(format!("Saved playlists: {}", names.join(", "))).await?;
+
+  Ok(())
+}
+
+/// Delete a saved playlist
+#[poise::command(prefix_command, slash_command, rename = "delete")]
+pub async fn playlist_delete(
+  ctx: PoiseContext<'_>,
+  #[description = "Playlist name"] name: String
+) -> Result<(), AnyError> {
+  match ctx.data().playlists.delete(ctx.author().id.get(), &name).await {
+    Ok(true) => ctx.reply(format!("Deleted playlist `{}`", name)).await?,
+    Ok(false) => ctx.reply(format!("No playlist named `{}`", name)).await?,
+    Err(error) => ctx.reply(format!("Failed to delete playlist: {}", error)).await?
+  };
+
+  Ok(())
+}
@@ -1,33 +1,76 @@
 use std::fmt::Write;
-use std::time::Duration;
 
 use anyhow::Result;
+use futures_util::{stream, StreamExt};
+use voice::VoiceConnectionState;
 
+use crate::commands::{queue_load, queue_save, set_config_value};
 use crate::providers::{get_metadata, MediaMetadata};
 use crate::state::get_player_or_fail;
-use crate::voice::ffmpeg::FFmpegSampleProviderHandle;
+use crate::util::{check_dj_permission, samples_to_duration};
 use crate::{AnyError, PoiseContext};
 
-#[poise::command(prefix_command, track_edits, slash_command)]
+/// Number of tracks whose metadata is fetched at once.
+const METADATA_CONCURRENCY: usize = 8;
+
+/// Show the queue
+#[poise::command(
+  prefix_command,
+  track_edits,
+  slash_command,
+  subcommands("queue_show", "queue_dedup", "queue_limit", "queue_save", "queue_load")
+)]
 pub async fn queue(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
+  show_queue(ctx).await
+}
+
+/// Show the queue
+#[poise::command(prefix_command, track_edits, slash_command, rename = "show")]
+pub async fn queue_show(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
+  show_queue(ctx).await
+}
+
+/// Remove tracks that are queued more than once
+#[poise::command(prefix_command, slash_command, rename = "dedup", check = "check_dj_permission")]
+pub async fn queue_dedup(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
+  let player = get_player_or_fail!(ctx);
+
+  // The position may change
+  let _guard = player.command_lock.lock().await;
+  let removed = player.queue.remove_duplicates().await;
+  ctx.reply(format!("Removed {} duplicate tracks", removed)).await?;
+
+  Ok(())
+}
+
+/// Show or change the maximum number of queued tracks
+#[poise::command(prefix_command, slash_command, rename = "limit", guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn queue_limit(
+  ctx: PoiseContext<'_>,
+  #[description = "Maximum number of tracks, `off` to remove the limit"] limit: Option<String>
+) -> Result<(), AnyError> {
+  if let Some(limit) = limit {
+    return set_config_value(ctx, "queue-limit", &limit).await;
+  }
+
+  let config = ctx.data().config(ctx.guild_id().unwrap()).await?;
+  ctx.reply(format!("Queue limit: {}", config.get("queue-limit")?)).await?;
+  Ok(())
+}
+
+async fn show_queue(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
   ctx.reply("Processing...").await?;
 
   let player = get_player_or_fail!(ctx);
   let mut fmt = String::new();
   let mut index = 0;
 
-  let handle = player.connection.sample_provider_handle.lock().await;
-  let handle = handle.as_ref().unwrap();
-  let handle = handle.as_any();
-  if let Some(handle) = handle.downcast_ref::<FFmpegSampleProviderHandle>() {
-    // TODO(Assasans): Make get_frame_pts return raw PTS (samples count)?
-    let mut pts = handle.get_frame_pts().unwrap();
-    let buffer_length = player.connection.sample_buffer.len() * 1000 / 2 / 48000;
-    let buffer_length = Duration::from_millis(buffer_length as u64);
-    pts -= buffer_length;
+  if player.connection.state.get() == VoiceConnectionState::Playing {
+    let position = player.connection.playback_position();
+    let buffer_length = samples_to_duration(player.connection.sample_buffer.available_to_read());
 
     fmt
-      .write_fmt(format_args!("pts: {:?} (buffer {:?})\n\n", pts, buffer_length))
+      .write_fmt(format_args!("pts: {:?} (buffer {:?})\n\n", position, buffer_length))
       .unwrap();
   }
 
@@ -35,22 +78,33 @@ pub async fn queue(ctx: PoiseContext<'_>) -> Result<(), AnyError> {
     let tracks = player.queue.tracks.read().unwrap();
     tracks.iter().map(|it| it.clone()).collect::<Vec<_>>()
   };
-  for track in &tracks {
-    let metadata = track.provider.get_metadata().await.unwrap();
+  // Uncached providers may call external APIs, so metadata of several tracks is fetched at once
+  let metadata = stream::iter(&tracks)
+    .map(|track| track.metadata())
+    .buffered(METADATA_CONCURRENCY)
+    .collect::<Vec<_>>()
+    .await;
+  for (track, metadata) in tracks.iter().zip(metadata) {
     let title =
       get_metadata!(metadata, MediaMetadata::Title(id) => id.as_str()).unwrap_or("**provider not supported**");
+    let artist = get_metadata!(metadata, MediaMetadata::Artist(artist) => artist)
+      .map(|artist| format!("{} - ", artist))
+      .unwrap_or(String::new());
     let duration = get_metadata!(metadata, MediaMetadata::Duration(duration) => duration)
       .map(|duration| format!(" [{:?}]", duration))
       .unwrap_or(String::new());
+    let clip = track.format_clip().map(|clip| format!(" [{}]", clip)).unwrap_or_default();
     let is_current = index == player.queue.position();
 
     fmt
       .write_fmt(format_args!(
-        "{}. {}{}{}\n",
+        "{}. {}{}{}{}{}\n",
         index + 1,
         if is_current { ":arrow_forward: " } else { "" },
+        artist,
         title,
-        duration
+        duration,
+        clip
       ))
       .unwrap();
     index += 1;
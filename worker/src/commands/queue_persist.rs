@@ -0,0 +1,79 @@
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+
+use crate::commands::enqueue_entries;
+use crate::playlist::{Playlist, PlaylistEntry, MAX_SAVED_QUEUE_LENGTH};
+use crate::state::get_player_or_fail;
+use crate::util::check_dj_permission;
+use crate::{AnyError, PoiseContext};
+
+/// Save the queue of this server
+#[poise::command(prefix_command, slash_command, rename = "save", guild_only, check = "check_dj_permission")]
+pub async fn queue_save(
+  ctx: PoiseContext<'_>,
+  #[description = "Queue name"] name: String
+) -> Result<(), AnyError> {
+  let guild_id = ctx.guild_id().context("no guild_id")?;
+  let player = get_player_or_fail!(ctx);
+
+  let sources = {
+    let tracks = player.queue.tracks.read().unwrap();
+    tracks.iter().map(|track| track.source.clone()).collect::<Vec<_>>()
+  };
+
+  let mut queue = Playlist::default();
+  let mut skipped = 0;
+  for source in &sources {
+    match source.as_deref().and_then(|source| PlaylistEntry::from_source(source, None)) {
+      Some(entry) => queue.entries.push(entry),
+      None => skipped += 1
+    }
+  }
+  let truncated = queue.entries.len().saturating_sub(MAX_SAVED_QUEUE_LENGTH);
+  queue.entries.truncate(MAX_SAVED_QUEUE_LENGTH);
+
+  if queue.entries.is_empty() {
+    ctx.reply("Nothing to save, the queue has no saveable tracks").await?;
+    return Ok(());
+  }
+  if let Err(error) = ctx.data().saved_queues.save(guild_id.get(), &name, &queue).await {
+    ctx.reply(format!("Failed to save queue: {}", error)).await?;
+    return Ok(());
+  }
+
+  let mut reply = format!("Saved {} tracks to `{}`", queue.entries.len(), name);
+  if skipped > 0 {
+    write!(reply, ", skipped {} tracks that cannot be saved", skipped).unwrap();
+  }
+  if truncated > 0 {
+    write!(reply, ", {} tracks over the limit of {} were not saved", truncated, MAX_SAVED_QUEUE_LENGTH).unwrap();
+  }
+  ctx.reply(reply).await?;
+
+  Ok(())
+}
+
+/// Add the tracks of a saved queue of this server to the queue
+#[poise::command(prefix_command, slash_command, rename = "load", guild_only, check = "check_dj_permission")]
+pub async fn queue_load(
+  ctx: PoiseContext<'_>,
+  #[description = "Queue name"] name: String
+) -> Result<(), AnyError> {
+  let guild_id = ctx.guild_id().context("no guild_id")?;
+  let mut queue = match ctx.data().saved_queues.load(guild_id.get(), &name).await {
+    Ok(Some(queue)) => queue,
+    Ok(None) => {
+      ctx.reply(format!("No saved queue named `{}`", name)).await?;
+      return Ok(());
+    }
+    Err(error) => {
+      ctx.reply(format!("Failed to load queue: {}", error)).await?;
+      return Ok(());
+    }
+  };
+  // Saved queues may be edited by hand
+  queue.entries.truncate(MAX_SAVED_QUEUE_LENGTH);
+
+  enqueue_entries(ctx, &name, &queue.entries).await
+}
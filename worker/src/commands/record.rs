@@ -0,0 +1,79 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use poise::CreateReply;
+use serenity::all::CreateAttachment;
+use tokio::time;
+use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+use crate::state::get_player_or_fail;
+use crate::util::encode_wav;
+use crate::{AnyError, PoiseContext};
+
+/// Keeps the WAV file below the Discord attachment size limit.
+const MAX_RECORD_SECONDS: u64 = 60;
+/// Audio of a user arriving this much later than expected starts a new talk spurt instead of continuing the previous one.
+const MAX_DRIFT: Duration = Duration::from_millis(100);
+
+/// Record audio of other users in the voice channel
+#[poise::command(prefix_command, track_edits, slash_command)]
+pub async fn record(
+  ctx: PoiseContext<'_>,
+  #[description = "Duration in seconds"] seconds: u64
+) -> Result<(), AnyError> {
+  let player = get_player_or_fail!(ctx);
+  if !player.connection.is_receiving() {
+    ctx
+      .reply("Receiving audio is disabled, set `MOSAIK_RECEIVE_AUDIO=1` to enable it.")
+      .await?;
+    return Ok(());
+  }
+
+  let duration = Duration::from_secs(seconds.clamp(1, MAX_RECORD_SECONDS));
+  ctx.reply(format!("Recording for {:?}...", duration)).await?;
+
+  // Discard audio received before the command
+  let received = &player.connection.received_audio;
+  received.drain().for_each(drop);
+
+  let start = Instant::now();
+  let mut chunks = Vec::new();
+  while let Ok(Ok(audio)) = time::timeout(duration.saturating_sub(start.elapsed()), received.recv_async()).await {
+    chunks.push((start.elapsed(), audio.ssrc, audio.pcm));
+  }
+
+  let pcm = mix(&chunks, duration);
+  let sources = chunks.iter().map(|(_, ssrc, _)| ssrc).collect::<HashSet<_>>().len();
+  ctx
+    .send(
+      CreateReply::default()
+        .content(format!("Recorded {} packets from {} sources", chunks.len(), sources))
+        .attachment(CreateAttachment::bytes(encode_wav(&pcm), "recording.wav"))
+    )
+    .await?;
+
+  Ok(())
+}
+
+/// Mixes interleaved chunks placed by arrival time, keeping chunks of the same source contiguous.
+fn mix(chunks: &[(Duration, u32, Vec<f32>)], duration: Duration) -> Vec<f32> {
+  let to_samples = |duration: Duration| (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize * CHANNEL_COUNT;
+
+  let mut output = vec![0.0; to_samples(duration)];
+  let mut cursors = HashMap::new();
+  for (arrived, ssrc, pcm) in chunks {
+    let arrived = to_samples(*arrived);
+    let cursor = cursors.entry(*ssrc).or_insert(arrived);
+    if arrived.abs_diff(*cursor) > to_samples(MAX_DRIFT) {
+      *cursor = arrived;
+    }
+
+    for (output, sample) in output.iter_mut().skip(*cursor).zip(pcm) {
+      *output += sample;
+    }
+    *cursor += pcm.len();
+  }
+
+  output
+}
@@ -0,0 +1,104 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use anyhow::Result;
+use poise::CreateReply;
+use serenity::all::{
+  ButtonStyle, ComponentInteractionCollector, CreateActionRow, CreateButton, CreateEmbed, CreateInteractionResponse,
+  CreateInteractionResponseMessage
+};
+
+use crate::commands::{enqueue, join_author_channel, single_provider};
+use crate::providers::search::{SearchProvider, YtDlpSearchProvider};
+use crate::util::{check_dj_permission, format_duration};
+use crate::{AnyError, PoiseContext};
+
+const SEARCH_RESULT_LIMIT: usize = 5;
+const SEARCH_SELECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Search for a track and choose which result to play
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
+pub async fn search(
+  ctx: PoiseContext<'_>,
+  #[description = "Search query"]
+  #[rest]
+  query: String
+) -> Result<(), AnyError> {
+  ctx.reply("Processing...").await?;
+
+  let provider = YtDlpSearchProvider::new();
+  let results = provider.search(&query, SEARCH_RESULT_LIMIT).await?;
+  if results.is_empty() {
+    ctx.reply("Nothing found").await?;
+    return Ok(());
+  }
+
+  let mut fmt = String::new();
+  for (index, result) in results.iter().enumerate() {
+    let author = result.author.as_ref().map(|author| format!(" by {}", author)).unwrap_or_default();
+    let duration = result
+      .duration
+      .map(|duration| format!(" `[{}]`", format_duration(duration)))
+      .unwrap_or_default();
+    fmt
+      .write_fmt(format_args!("**{}.** [{}]({}){}{}\n", index + 1, result.title, result.url, author, duration))
+      .unwrap();
+  }
+  let embed = CreateEmbed::default().title(format!("Search results for `{}`", query));
+
+  let prefix = format!("{}:search:", ctx.id());
+  let buttons = (0..results.len())
+    .map(|index| {
+      CreateButton::new(format!("{}{}", prefix, index))
+        .label(format!("{}", index + 1))
+        .style(ButtonStyle::Secondary)
+    })
+    .collect::<Vec<_>>();
+
+  let reply = ctx
+    .send(
+      CreateReply::default()
+        .embed(embed.clone().description(fmt.clone()))
+        .components(vec![CreateActionRow::Buttons(buttons)])
+    )
+    .await?;
+
+  let author_id = ctx.author().id;
+  let filter_prefix = prefix.clone();
+  let interaction = ComponentInteractionCollector::new(ctx)
+    .author_id(author_id)
+    .filter(move |interaction| interaction.data.custom_id.starts_with(&filter_prefix))
+    .timeout(SEARCH_SELECTION_TIMEOUT)
+    .await;
+
+  let interaction = match interaction {
+    Some(interaction) => interaction,
+    None => {
+      reply
+        .edit(
+          ctx,
+          CreateReply::default()
+            .embed(embed.description(format!("{}\nSelection expired", fmt)))
+            .components(vec![])
+        )
+        .await?;
+      return Ok(());
+    }
+  };
+
+  let index = interaction.data.custom_id[prefix.len()..].parse::<usize>()?;
+  let result = &results[index];
+  interaction
+    .create_response(
+      ctx,
+      CreateInteractionResponse::UpdateMessage(
+        CreateInteractionResponseMessage::new()
+          .embed(embed.description(format!("{}\nSelected: **{}**", fmt, result.title)))
+          .components(vec![])
+      )
+    )
+    .await?;
+
+  let player = join_author_channel(ctx).await?;
+  enqueue(ctx, &player, single_provider(provider.get_media_provider(result)), false, false, (None, None)).await
+}
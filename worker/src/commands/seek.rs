@@ -1,17 +1,25 @@
-use std::time::Duration;
-
-use anyhow::{Context, Result};
+use anyhow::Result;
+use serenity::all::AutocompleteChoice;
 use tracing::debug;
 
+use crate::providers::{get_metadata, MediaMetadata};
 use crate::state::get_player_or_fail;
-use crate::voice::ffmpeg::FFmpegSampleProviderHandle;
+use crate::util::{check_dj_permission, parse_position};
 use crate::{AnyError, PoiseContext};
 
-#[poise::command(prefix_command, track_edits, slash_command)]
+const POSITION_SUGGESTIONS: &[(&str, &str)] = &[
+  ("+10", "forward 10 seconds"),
+  ("-10", "back 10 seconds"),
+  ("+30", "forward 30 seconds"),
+  ("-30", "back 30 seconds"),
+  ("0", "beginning")
+];
+
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
 pub async fn seek(
   ctx: PoiseContext<'_>,
-  #[description = "Specific command to show help about"]
-  #[autocomplete = "poise::builtins::autocomplete_command"]
+  #[description = "Position, e.g. `1:30`, `+10` or `50%`"]
+  #[autocomplete = "autocomplete_position"]
   position: String
 ) -> Result<(), AnyError> {
   ctx.reply("Processing...").await?;
@@ -19,28 +27,46 @@ pub async fn seek(
   let player = get_player_or_fail!(ctx);
 
   debug!("seek: {}", position);
-  let handle = player.connection.sample_provider_handle.lock().await;
-  let handle = handle.as_ref().unwrap();
-  let handle = handle.as_any();
-  if let Some(handle) = handle.downcast_ref::<FFmpegSampleProviderHandle>() {
-    let current_position = handle.get_frame_pts().unwrap();
-
-    let position = match position.chars().nth(0).context("no first position character")? {
-      '+' => current_position + Duration::from_secs(position[1..].parse::<u64>()?),
-      '-' => current_position.saturating_sub(Duration::from_secs(position[1..].parse::<u64>()?)),
-      _ => Duration::from_secs(position.parse::<u64>()?)
-    };
-
-    handle.seek(position).unwrap();
-    player.connection.sample_buffer.clear().await;
-    player.connection.rms.lock().unwrap().reset();
-
-    ctx
-      .reply(format!("Seeked to {:?} (was: {:?})", position, current_position))
-      .await?;
-  } else {
-    ctx.reply("Unsupported sample provider").await?;
-  }
+  let track = player.queue.get_current().and_then(|track| track.upgrade());
+  let total = match track {
+    Some(ref track) => {
+      let metadata = track.provider.get_metadata().await?;
+      get_metadata!(metadata, MediaMetadata::Duration(duration) => *duration)
+    }
+    None => None
+  };
+
+  let _guard = player.command_lock.lock().await;
+  let current_position = player.connection.playback_position();
+  let position = match parse_position(&position, current_position, total) {
+    // Seeking out of a clip would play parts of the media that were not requested
+    Ok(position) => track.as_ref().map_or(position, |track| track.clamp_position(position)),
+    Err(error) => {
+      ctx.reply(error.to_string()).await?;
+      return Ok(());
+    }
+  };
+
+  let approximate = match player.seek(position).await {
+    Ok(approximate) => approximate,
+    Err(error) => {
+      ctx.reply(format!("Failed to seek: {}", error)).await?;
+      return Ok(());
+    }
+  };
+
+  let note = if approximate { " (approximate, the source has no seek index)" } else { "" };
+  ctx
+    .reply(format!("Seeked to {:?}{} (was: {:?})", position, note, current_position))
+    .await?;
 
   Ok(())
 }
+
+async fn autocomplete_position(_ctx: PoiseContext<'_>, partial: &str) -> Vec<AutocompleteChoice> {
+  POSITION_SUGGESTIONS
+    .iter()
+    .filter(|(position, _)| position.starts_with(partial))
+    .map(|(position, description)| AutocompleteChoice::new(format!("{} ({})", position, description), *position))
+    .collect()
+}
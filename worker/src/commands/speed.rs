@@ -0,0 +1,62 @@
+use anyhow::Result;
+use tracing::error;
+
+use crate::filters_presets::{parse_pitch, MAX_SPEED, MIN_SPEED};
+use crate::player::FilterState;
+use crate::state::get_player_or_fail;
+use crate::util::check_dj_permission;
+use crate::{AnyError, PoiseContext};
+
+/// Change the playback speed, also changes the pitch unless adjusted with `pitch`
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
+pub async fn speed(
+  ctx: PoiseContext<'_>,
+  #[description = "Speed factor (0.25-4), `1` to reset"] speed: f64
+) -> Result<(), AnyError> {
+  if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+    ctx.reply(format!("Speed must be from {} to {}", MIN_SPEED, MAX_SPEED)).await?;
+    return Ok(());
+  }
+
+  update(ctx, |state| state.speed = speed).await
+}
+
+/// Shift the pitch without changing the speed
+#[poise::command(prefix_command, track_edits, slash_command, check = "check_dj_permission")]
+pub async fn pitch(
+  ctx: PoiseContext<'_>,
+  #[description = "Semitones (-12 to +12), `reset` or `0` to reset"] semitones: String
+) -> Result<(), AnyError> {
+  let semitones = match parse_pitch(&semitones) {
+    Ok(semitones) => semitones,
+    Err(error) => {
+      ctx.reply(error.to_string()).await?;
+      return Ok(());
+    }
+  };
+
+  update(ctx, |state| state.pitch = semitones).await
+}
+
+async fn update(ctx: PoiseContext<'_>, update: impl FnOnce(&mut FilterState)) -> Result<(), AnyError> {
+  let player = get_player_or_fail!(ctx);
+
+  let _guard = player.command_lock.lock().await;
+  let previous = player.filters.read().unwrap().clone();
+  match player.update_filters(update).await {
+    Ok(state) => {
+      ctx
+        .reply(format!(
+          "Speed: `{}x` → `{}x`, pitch: `{:+}` → `{:+}` semitones",
+          previous.speed, state.speed, previous.pitch, state.pitch
+        ))
+        .await?
+    }
+    Err(error) => {
+      error!("failed to set speed or pitch: {:?}", error);
+      ctx.reply(format!("Failed to set speed or pitch: `{}`", error)).await?
+    }
+  };
+
+  Ok(())
+}
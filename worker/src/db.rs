@@ -0,0 +1,455 @@
+use anyhow::Result;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use thiserror::Error;
+
+pub const CONFIG_KEYS: &[&str] = &[
+  "volume",
+  "dj-role",
+  "crossfade",
+  "loudness",
+  "prefix",
+  "sticky-speed",
+  "dedup",
+  "bitrate",
+  "queue-limit",
+  "dj-commands"
+];
+
+/// Commands that only read state, which can not be restricted with `dj-commands`.
+pub const OPEN_COMMANDS: &[&str] = &["help", "queue", "queue show", "debug", "debug logs"];
+
+const MAX_VOLUME_PERCENT: f32 = 200.0;
+const MAX_CROSSFADE_SECS: f32 = 10.0;
+const MAX_PREFIX_LENGTH: usize = 5;
+/// Range of voice channel bitrates in kbps.
+const BITRATE_RANGE_KBPS: std::ops::RangeInclusive<u32> = 8..=384;
+const MAX_QUEUE_LIMIT: usize = 10_000;
+
+/// Per-guild settings persisted across restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuildConfig {
+  pub guild_id: u64,
+  /// Linear gain, `1.0` is unchanged.
+  pub volume: f32,
+  /// Overrides the `DJ_ROLE_ID` environment variable.
+  pub dj_role_id: Option<u64>,
+  pub crossfade_secs: f32,
+  pub loudness_normalization: bool,
+  pub loudness_target_lufs: f32,
+  /// Text command prefix in addition to the default one.
+  pub prefix: Option<String>,
+  /// Keeps speed and pitch adjustments for the next tracks instead of resetting them.
+  pub sticky_speed: bool,
+  /// Skips tracks that are already queued, see [Queue::push](crate::player::queue::Queue::push).
+  pub dedup: bool,
+  /// Encoder bitrate in bits per second, [None] follows the bitrate of the voice channel.
+  pub bitrate: Option<u32>,
+  /// Maximum number of queued tracks, [None] if unlimited.
+  pub max_queue_length: Option<usize>,
+  /// Qualified names of commands restricted to DJs in addition to the ones that always are,
+  /// see [check_restricted_command](crate::util::check_restricted_command).
+  pub dj_commands: Vec<String>
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ConfigError {
+  #[error("Unknown key `{0}`, expected one of: {}", CONFIG_KEYS.join(", "))]
+  UnknownKey(String),
+  #[error("Unknown command `{0}` in `dj-commands`")]
+  UnknownCommand(String),
+  #[error("Invalid value `{value}` for `{key}`: {expected}")]
+  InvalidValue {
+    key: &'static str,
+    value: String,
+    expected: &'static str
+  }
+}
+
+impl GuildConfig {
+  pub fn new(guild_id: u64) -> Self {
+    Self {
+      guild_id,
+      volume: 1.0,
+      dj_role_id: None,
+      crossfade_secs: 0.0,
+      loudness_normalization: false,
+      loudness_target_lufs: -14.0,
+      prefix: None,
+      sticky_speed: false,
+      dedup: false,
+      bitrate: None,
+      max_queue_length: None,
+      dj_commands: Vec::new()
+    }
+  }
+
+  /// Returns the user-facing value of `key`, see [CONFIG_KEYS].
+  pub fn get(&self, key: &str) -> Result<String, ConfigError> {
+    Ok(match key {
+      "volume" => format!("{}%", (self.volume * 100.0).round()),
+      "dj-role" => self.dj_role_id.map_or("none".to_owned(), |id| format!("<@&{}>", id)),
+      "crossfade" => format!("{}s", self.crossfade_secs),
+      "loudness" if self.loudness_normalization => format!("{} LUFS", self.loudness_target_lufs),
+      "loudness" => "off".to_owned(),
+      "prefix" => self.prefix.clone().unwrap_or_else(|| "none".to_owned()),
+      "sticky-speed" => (if self.sticky_speed { "on" } else { "off" }).to_owned(),
+      "dedup" => (if self.dedup { "on" } else { "off" }).to_owned(),
+      "bitrate" => self.bitrate.map_or("auto".to_owned(), |bitrate| format!("{} kbps", bitrate / 1000)),
+      "queue-limit" => self.max_queue_length.map_or("off".to_owned(), |length| format!("{} tracks", length)),
+      "dj-commands" if self.dj_commands.is_empty() => "none".to_owned(),
+      "dj-commands" => self.dj_commands.join(", "),
+      _ => return Err(ConfigError::UnknownKey(key.to_owned()))
+    })
+  }
+
+  /// Parses and sets the user-facing `value` of `key`, see [CONFIG_KEYS].
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+    let value = value.trim();
+    let invalid = |key, expected| ConfigError::InvalidValue {
+      key,
+      value: value.to_owned(),
+      expected
+    };
+    let is_off = matches!(value.to_ascii_lowercase().as_str(), "off" | "none");
+
+    match key {
+      "volume" => {
+        let percent = value
+          .trim_end_matches('%')
+          .parse::<f32>()
+          .ok()
+          .filter(|percent| (0.0..=MAX_VOLUME_PERCENT).contains(percent))
+          .ok_or_else(|| invalid("volume", "expected a percentage from 0 to 200"))?;
+        self.volume = percent / 100.0;
+      }
+      "dj-role" if is_off => self.dj_role_id = None,
+      "dj-role" => {
+        let id = value
+          .strip_prefix("<@&")
+          .and_then(|it| it.strip_suffix('>'))
+          .unwrap_or(value)
+          .parse::<u64>()
+          .ok()
+          .filter(|id| *id != 0)
+          .ok_or_else(|| invalid("dj-role", "expected a role mention, role ID or `off`"))?;
+        self.dj_role_id = Some(id);
+      }
+      "crossfade" => {
+        self.crossfade_secs = value
+          .trim_end_matches('s')
+          .parse::<f32>()
+          .ok()
+          .filter(|secs| (0.0..=MAX_CROSSFADE_SECS).contains(secs))
+          .ok_or_else(|| invalid("crossfade", "expected seconds from 0 to 10"))?;
+      }
+      "loudness" if is_off => self.loudness_normalization = false,
+      "loudness" => {
+        self.loudness_target_lufs = value
+          .trim_end_matches("LUFS")
+          .trim()
+          .parse::<f32>()
+          .ok()
+          .filter(|lufs| (-70.0..=0.0).contains(lufs))
+          .ok_or_else(|| invalid("loudness", "expected `off` or a target from -70 to 0 LUFS"))?;
+        self.loudness_normalization = true;
+      }
+      "prefix" if is_off => self.prefix = None,
+      "prefix" => {
+        if value.is_empty() || value.chars().count() > MAX_PREFIX_LENGTH || value.contains(char::is_whitespace) {
+          return Err(invalid("prefix", "expected up to 5 characters without spaces, or `off`"));
+        }
+        self.prefix = Some(value.to_owned());
+      }
+      "sticky-speed" => {
+        self.sticky_speed = match value.to_ascii_lowercase().as_str() {
+          "on" | "true" => true,
+          "off" | "false" => false,
+          _ => return Err(invalid("sticky-speed", "expected `on` or `off`"))
+        };
+      }
+      "dedup" => {
+        self.dedup = match value.to_ascii_lowercase().as_str() {
+          "on" | "true" => true,
+          "off" | "false" => false,
+          _ => return Err(invalid("dedup", "expected `on` or `off`"))
+        };
+      }
+      "bitrate" if value.eq_ignore_ascii_case("auto") => self.bitrate = None,
+      "bitrate" => {
+        let kbps = value
+          .trim_end_matches("kbps")
+          .trim()
+          .parse::<u32>()
+          .ok()
+          .filter(|kbps| BITRATE_RANGE_KBPS.contains(kbps))
+          .ok_or_else(|| invalid("bitrate", "expected `auto` or kbps from 8 to 384"))?;
+        self.bitrate = Some(kbps * 1000);
+      }
+      "queue-limit" if is_off => self.max_queue_length = None,
+      "queue-limit" => {
+        self.max_queue_length = Some(
+          value
+            .parse::<usize>()
+            .ok()
+            .filter(|length| (1..=MAX_QUEUE_LIMIT).contains(length))
+            .ok_or_else(|| invalid("queue-limit", "expected a number of tracks from 1 to 10000, or `off`"))?
+        );
+      }
+      "dj-commands" if is_off => self.dj_commands.clear(),
+      "dj-commands" => {
+        let commands = value
+          .split(',')
+          .map(|command| command.split_whitespace().collect::<Vec<_>>().join(" ").to_ascii_lowercase())
+          .collect::<Vec<_>>();
+        let is_valid = |command: &String| {
+          !command.is_empty()
+            && command.chars().all(|char| char.is_ascii_alphanumeric() || char == '-' || char == ' ')
+            && !OPEN_COMMANDS.contains(&command.as_str())
+        };
+        if !commands.iter().all(is_valid) {
+          return Err(invalid(
+            "dj-commands",
+            "expected comma-separated command names other than read-only commands, or `off`"
+          ));
+        }
+        self.dj_commands = commands;
+      }
+      _ => return Err(ConfigError::UnknownKey(key.to_owned()))
+    }
+
+    Ok(())
+  }
+
+  /// Checks that every `dj-commands` entry is one of the registered `commands` qualified names.
+  pub fn check_dj_commands(&self, commands: &[String]) -> Result<(), ConfigError> {
+    match self.dj_commands.iter().find(|command| !commands.contains(command)) {
+      Some(command) => Err(ConfigError::UnknownCommand(command.clone())),
+      None => Ok(())
+    }
+  }
+}
+
+/// Opens the database at `url` (e.g. `sqlite://mosaik.db`), creating it and the schema if needed.
+pub async fn connect(url: &str) -> Result<SqlitePool> {
+  let options = url.parse::<SqliteConnectOptions>()?.create_if_missing(true);
+  // Every connection to an in-memory database is a separate database
+  let max_connections = if url.contains(":memory:") { 1 } else { 4 };
+  let pool = SqlitePoolOptions::new()
+    .max_connections(max_connections)
+    .connect_with(options)
+    .await?;
+
+  sqlx::query(
+    "CREATE TABLE IF NOT EXISTS guild_config (
+      guild_id INTEGER PRIMARY KEY NOT NULL,
+      volume REAL NOT NULL,
+      dj_role_id INTEGER,
+      crossfade_secs REAL NOT NULL,
+      loudness_normalization BOOLEAN NOT NULL,
+      loudness_target_lufs REAL NOT NULL
+    )"
+  )
+  .execute(&pool)
+  .await?;
+
+  // Added after the initial schema
+  add_column_if_missing(&pool, "prefix", "TEXT").await?;
+  add_column_if_missing(&pool, "sticky_speed", "BOOLEAN NOT NULL DEFAULT FALSE").await?;
+  add_column_if_missing(&pool, "dedup", "BOOLEAN NOT NULL DEFAULT FALSE").await?;
+  add_column_if_missing(&pool, "bitrate", "INTEGER").await?;
+  add_column_if_missing(&pool, "max_queue_length", "INTEGER").await?;
+  add_column_if_missing(&pool, "dj_commands", "TEXT NOT NULL DEFAULT ''").await?;
+
+  Ok(pool)
+}
+
+async fn add_column_if_missing(pool: &SqlitePool, name: &str, definition: &str) -> Result<()> {
+  let exists = sqlx::query("SELECT 1 FROM pragma_table_info('guild_config') WHERE name = ?")
+    .bind(name)
+    .fetch_optional(pool)
+    .await?
+    .is_some();
+  if !exists {
+    sqlx::query(&format!("ALTER TABLE guild_config ADD COLUMN {} {}", name, definition))
+      .execute(pool)
+      .await?;
+  }
+
+  Ok(())
+}
+
+/// Loads the config of `guild_id`, or the defaults if it was never saved.
+pub async fn load_config(pool: &SqlitePool, guild_id: u64) -> Result<GuildConfig> {
+  // Snowflakes fit in 63 bits, SQLite integers are signed
+  let row = sqlx::query(
+    "SELECT volume, dj_role_id, crossfade_secs, loudness_normalization, loudness_target_lufs, prefix, sticky_speed,
+      dedup, bitrate, max_queue_length, dj_commands
+    FROM guild_config WHERE guild_id = ?"
+  )
+  .bind(guild_id as i64)
+  .fetch_optional(pool)
+  .await?;
+
+  let Some(row) = row else {
+    return Ok(GuildConfig::new(guild_id));
+  };
+  Ok(GuildConfig {
+    guild_id,
+    volume: row.try_get("volume")?,
+    dj_role_id: row.try_get::<Option<i64>, _>("dj_role_id")?.map(|id| id as u64),
+    crossfade_secs: row.try_get("crossfade_secs")?,
+    loudness_normalization: row.try_get("loudness_normalization")?,
+    loudness_target_lufs: row.try_get("loudness_target_lufs")?,
+    prefix: row.try_get("prefix")?,
+    sticky_speed: row.try_get("sticky_speed")?,
+    dedup: row.try_get("dedup")?,
+    bitrate: row.try_get::<Option<i64>, _>("bitrate")?.map(|bitrate| bitrate as u32),
+    max_queue_length: row.try_get::<Option<i64>, _>("max_queue_length")?.map(|length| length as usize),
+    dj_commands: row
+      .try_get::<String, _>("dj_commands")?
+      .split(',')
+      .filter(|command| !command.is_empty())
+      .map(ToOwned::to_owned)
+      .collect()
+  })
+}
+
+pub async fn save_config(pool: &SqlitePool, config: &GuildConfig) -> Result<()> {
+  sqlx::query(
+    "INSERT INTO guild_config (
+      guild_id, volume, dj_role_id, crossfade_secs, loudness_normalization, loudness_target_lufs, prefix,
+      sticky_speed, dedup, bitrate, max_queue_length, dj_commands
+    )
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+    ON CONFLICT (guild_id) DO UPDATE SET
+      volume = excluded.volume,
+      dj_role_id = excluded.dj_role_id,
+      crossfade_secs = excluded.crossfade_secs,
+      loudness_normalization = excluded.loudness_normalization,
+      loudness_target_lufs = excluded.loudness_target_lufs,
+      prefix = excluded.prefix,
+      sticky_speed = excluded.sticky_speed,
+      dedup = excluded.dedup,
+      bitrate = excluded.bitrate,
+      max_queue_length = excluded.max_queue_length,
+      dj_commands = excluded.dj_commands"
+  )
+  .bind(config.guild_id as i64)
+  .bind(config.volume)
+  .bind(config.dj_role_id.map(|id| id as i64))
+  .bind(config.crossfade_secs)
+  .bind(config.loudness_normalization)
+  .bind(config.loudness_target_lufs)
+  .bind(&config.prefix)
+  .bind(config.sticky_speed)
+  .bind(config.dedup)
+  .bind(config.bitrate.map(i64::from))
+  .bind(config.max_queue_length.map(|length| length as i64))
+  .bind(config.dj_commands.join(","))
+  .execute(pool)
+  .await?;
+
+  Ok(())
+}
+
+#[tokio::test]
+async fn config_roundtrip() {
+  let pool = connect("sqlite::memory:").await.unwrap();
+  assert_eq!(load_config(&pool, 1).await.unwrap(), GuildConfig::new(1));
+
+  let mut config = GuildConfig {
+    guild_id: 1171104054131314708,
+    volume: 0.5,
+    dj_role_id: Some(1171104054131314709),
+    crossfade_secs: 3.0,
+    loudness_normalization: true,
+    loudness_target_lufs: -16.0,
+    prefix: Some("!".to_owned()),
+    sticky_speed: true,
+    dedup: true,
+    bitrate: Some(96_000),
+    max_queue_length: Some(100),
+    dj_commands: vec!["record".to_owned(), "queue save".to_owned()]
+  };
+  save_config(&pool, &config).await.unwrap();
+  assert_eq!(load_config(&pool, config.guild_id).await.unwrap(), config);
+
+  config.dj_role_id = None;
+  config.volume = 1.5;
+  save_config(&pool, &config).await.unwrap();
+  assert_eq!(load_config(&pool, config.guild_id).await.unwrap(), config);
+  assert_eq!(load_config(&pool, 1).await.unwrap(), GuildConfig::new(1));
+}
+
+#[test]
+fn config_set_and_get() {
+  let mut config = GuildConfig::new(1);
+
+  config.set("volume", "150%").unwrap();
+  assert_eq!(config.volume, 1.5);
+  assert_eq!(config.get("volume").unwrap(), "150%");
+  assert!(config.set("volume", "201").is_err());
+  assert!(config.set("volume", "loud").is_err());
+
+  config.set("dj-role", "<@&1171104054131314709>").unwrap();
+  assert_eq!(config.dj_role_id, Some(1171104054131314709));
+  config.set("dj-role", "off").unwrap();
+  assert_eq!(config.dj_role_id, None);
+  assert!(config.set("dj-role", "<@1>").is_err());
+
+  config.set("crossfade", "2.5").unwrap();
+  assert_eq!(config.crossfade_secs, 2.5);
+  assert!(config.set("crossfade", "11").is_err());
+
+  config.set("loudness", "-16").unwrap();
+  assert_eq!((config.loudness_normalization, config.loudness_target_lufs), (true, -16.0));
+  assert_eq!(config.get("loudness").unwrap(), "-16 LUFS");
+  config.set("loudness", "OFF").unwrap();
+  assert!(!config.loudness_normalization);
+  assert!(config.set("loudness", "5").is_err());
+
+  config.set("prefix", "!").unwrap();
+  assert_eq!(config.prefix.as_deref(), Some("!"));
+  assert!(config.set("prefix", "a b").is_err());
+  assert!(config.set("prefix", "toolong").is_err());
+
+  config.set("sticky-speed", "ON").unwrap();
+  assert!(config.sticky_speed);
+  assert_eq!(config.get("sticky-speed").unwrap(), "on");
+  assert!(config.set("sticky-speed", "maybe").is_err());
+
+  config.set("dedup", "on").unwrap();
+  assert!(config.dedup);
+  assert_eq!(config.get("dedup").unwrap(), "on");
+
+  config.set("bitrate", "128kbps").unwrap();
+  assert_eq!(config.bitrate, Some(128_000));
+  assert_eq!(config.get("bitrate").unwrap(), "128 kbps");
+  assert!(config.set("bitrate", "512").is_err());
+  config.set("bitrate", "auto").unwrap();
+  assert_eq!(config.bitrate, None);
+
+  config.set("queue-limit", "50").unwrap();
+  assert_eq!(config.max_queue_length, Some(50));
+  assert_eq!(config.get("queue-limit").unwrap(), "50 tracks");
+  assert!(config.set("queue-limit", "0").is_err());
+  config.set("queue-limit", "off").unwrap();
+  assert_eq!(config.max_queue_length, None);
+
+  config.set("dj-commands", "record, Queue  Save").unwrap();
+  assert_eq!(config.dj_commands, vec!["record".to_owned(), "queue save".to_owned()]);
+  assert_eq!(config.get("dj-commands").unwrap(), "record, queue save");
+  // Read-only commands stay open
+  assert!(config.set("dj-commands", "record, queue show").is_err());
+  assert!(config.set("dj-commands", "record,").is_err());
+  let commands = ["record".to_owned(), "queue".to_owned(), "queue save".to_owned()];
+  assert_eq!(config.check_dj_commands(&commands), Ok(()));
+  config.set("dj-commands", "record, nowplaying").unwrap();
+  assert_eq!(config.check_dj_commands(&commands), Err(ConfigError::UnknownCommand("nowplaying".to_owned())));
+  config.set("dj-commands", "none").unwrap();
+  assert!(config.dj_commands.is_empty());
+
+  assert_eq!(config.set("color", "1"), Err(ConfigError::UnknownKey("color".to_owned())));
+  assert!(ConfigError::UnknownKey("color".to_owned()).to_string().ends_with("queue-limit, dj-commands"));
+}
@@ -0,0 +1,238 @@
+use anyhow::{anyhow, Result};
+
+/// Appended to every preset, so that boosted presets never clip.
+const LIMITER: &str = "alimiter=limit=0.95:level=disabled";
+
+const DEFAULT_INTENSITY: f64 = 1.0;
+const MAX_INTENSITY: f64 = 2.0;
+
+const EQUALIZER_BANDS: [u32; 8] = [60, 150, 400, 1000, 2400, 6000, 12000, 16000];
+const EQUALIZER_MAX_GAIN: f64 = 12.0;
+
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 4.0;
+pub const MAX_PITCH_SEMITONES: f64 = 12.0;
+/// Sample rates `asetrate` may shift to, see [parse_pitch].
+const PITCH_SAMPLE_RATE_RANGE: (f64, f64) = (8000.0, 192000.0);
+
+/// Range of a single `atempo` filter, larger factors are chained.
+const ATEMPO_RANGE: (f64, f64) = (0.5, 2.0);
+
+pub struct FilterPreset {
+  pub name: &'static str,
+  pub description: &'static str,
+  build: fn(Option<&str>) -> Result<String>
+}
+
+impl FilterPreset {
+  /// Builds the FFmpeg filter graph for `parameters`, which are clamped to safe ranges.
+  pub fn build(&self, parameters: Option<&str>) -> Result<String> {
+    Ok(format!("{},{}", (self.build)(parameters)?, LIMITER))
+  }
+}
+
+pub const FILTER_PRESETS: &[FilterPreset] = &[
+  FilterPreset {
+    name: "bassboost",
+    description: "Boosts low frequencies, intensity 0-2",
+    build: bassboost
+  },
+  FilterPreset {
+    name: "nightcore",
+    description: "Raises pitch and speeds up, intensity 0-2",
+    build: nightcore
+  },
+  FilterPreset {
+    name: "vaporwave",
+    description: "Lowers pitch and slows down, intensity 0-2",
+    build: vaporwave
+  },
+  FilterPreset {
+    name: "karaoke",
+    description: "Removes center-panned vocals, intensity 0-2",
+    build: karaoke
+  },
+  FilterPreset {
+    name: "equalizer",
+    description: "8-band equalizer (60 Hz - 16 kHz), 8 comma-separated gains in dB",
+    build: equalizer
+  }
+];
+
+pub fn get_filter_preset(name: &str) -> Option<&'static FilterPreset> {
+  FILTER_PRESETS.iter().find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
+fn parse_intensity(parameters: Option<&str>) -> Result<f64> {
+  let intensity = match parameters {
+    Some(parameters) => parameters
+      .trim()
+      .parse::<f64>()
+      .map_err(|_| anyhow!("Invalid intensity `{}`, expected a number", parameters))?,
+    None => DEFAULT_INTENSITY
+  };
+  if !intensity.is_finite() {
+    return Err(anyhow!("Invalid intensity `{}`", intensity));
+  }
+
+  Ok(intensity.clamp(0.0, MAX_INTENSITY))
+}
+
+fn bassboost(parameters: Option<&str>) -> Result<String> {
+  let gain = parse_intensity(parameters)? * 6.0;
+  Ok(format!("bass=g={:.1}:f=110:w=0.6", gain))
+}
+
+fn nightcore(parameters: Option<&str>) -> Result<String> {
+  let intensity = parse_intensity(parameters)?;
+  let pitch = 1.0 + 0.15 * intensity;
+  let tempo = 1.0 + 0.05 * intensity;
+  Ok(format!("asetrate=48000*{:.3},aresample=48000,atempo={:.3}", pitch, tempo / pitch))
+}
+
+fn vaporwave(parameters: Option<&str>) -> Result<String> {
+  let pitch = 1.0 - 0.1 * parse_intensity(parameters)?;
+  Ok(format!("asetrate=48000*{:.3},aresample=48000", pitch))
+}
+
+fn karaoke(parameters: Option<&str>) -> Result<String> {
+  // Middle (mono) level can not be lower than 1/64
+  let level = (1.0 - parse_intensity(parameters)? / MAX_INTENSITY).max(0.015625);
+  Ok(format!("stereotools=mlev={:.4}", level))
+}
+
+fn equalizer(parameters: Option<&str>) -> Result<String> {
+  let usage = || anyhow!("Expected {} comma-separated gains in dB", EQUALIZER_BANDS.len());
+
+  let gains = parameters
+    .ok_or_else(usage)?
+    .split(',')
+    .map(|gain| gain.trim().parse::<f64>().ok().filter(|gain| gain.is_finite()))
+    .collect::<Option<Vec<_>>>()
+    .ok_or_else(usage)?;
+  if gains.len() != EQUALIZER_BANDS.len() {
+    return Err(usage());
+  }
+
+  Ok(
+    EQUALIZER_BANDS
+      .iter()
+      .zip(gains)
+      .map(|(frequency, gain)| {
+        format!("equalizer=f={}:t=o:w=1:g={:.1}", frequency, gain.clamp(-EQUALIZER_MAX_GAIN, EQUALIZER_MAX_GAIN))
+      })
+      .collect::<Vec<_>>()
+      .join(",")
+  )
+}
+
+/// Builds the filters that play at `speed` and shift the pitch by `semitones`, [None] if both are unchanged.
+///
+/// The pitch is shifted by resampling, which also changes the speed, so `atempo` compensates for it.
+pub fn speed_pitch_filters(speed: f64, semitones: f64) -> Option<String> {
+  let mut filters = Vec::new();
+  let mut tempo = speed;
+  if semitones != 0.0 {
+    let ratio = 2f64.powf(semitones / 12.0);
+    filters.push(format!("asetrate=48000*{:.6},aresample=48000", ratio));
+    tempo /= ratio;
+  }
+
+  let (min, max) = ATEMPO_RANGE;
+  while tempo > max {
+    filters.push(format!("atempo={:.1}", max));
+    tempo /= max;
+  }
+  while tempo < min {
+    filters.push(format!("atempo={:.1}", min));
+    tempo /= min;
+  }
+  if (tempo - 1.0).abs() > 1e-6 {
+    filters.push(format!("atempo={:.6}", tempo));
+  }
+
+  if filters.is_empty() {
+    None
+  } else {
+    Some(filters.join(","))
+  }
+}
+
+/// Parses a pitch shift in semitones, e.g. `+3` or `-1.5`. `reset` returns `0`.
+pub fn parse_pitch(value: &str) -> Result<f64> {
+  let value = value.trim();
+  if value.eq_ignore_ascii_case("reset") {
+    return Ok(0.0);
+  }
+
+  let semitones = value
+    .trim_start_matches('+')
+    .parse::<f64>()
+    .ok()
+    .filter(|semitones| semitones.abs() <= MAX_PITCH_SEMITONES)
+    .ok_or_else(|| anyhow!("Pitch must be from -{0} to +{0} semitones", MAX_PITCH_SEMITONES))?;
+
+  let (min, max) = PITCH_SAMPLE_RATE_RANGE;
+  let sample_rate = 48000.0 * 2f64.powf(semitones / 12.0);
+  if !(min..=max).contains(&sample_rate) {
+    return Err(anyhow!("Pitch shift resamples to {:.0} Hz, which is out of range", sample_rate));
+  }
+  Ok(semitones)
+}
+
+#[test]
+fn presets_are_limited_and_clamped() {
+  let bassboost = get_filter_preset("BassBoost").unwrap();
+  assert_eq!(bassboost.build(None).unwrap(), format!("bass=g=6.0:f=110:w=0.6,{}", LIMITER));
+  assert_eq!(bassboost.build(Some("100")).unwrap(), format!("bass=g=12.0:f=110:w=0.6,{}", LIMITER));
+  assert_eq!(bassboost.build(Some("-1")).unwrap(), format!("bass=g=0.0:f=110:w=0.6,{}", LIMITER));
+  assert!(bassboost.build(Some("loud")).is_err());
+  assert!(bassboost.build(Some("inf")).is_err());
+
+  for preset in FILTER_PRESETS {
+    if let Ok(graph) = preset.build(None) {
+      assert!(graph.ends_with(LIMITER), "{}", preset.name);
+    }
+  }
+}
+
+#[test]
+fn equalizer_gains() {
+  let equalizer = get_filter_preset("equalizer").unwrap();
+  let graph = equalizer.build(Some("3, 0, 0, 0, 0, 0, 0, 30")).unwrap();
+  assert!(graph.starts_with("equalizer=f=60:t=o:w=1:g=3.0,"));
+  assert!(graph.contains("equalizer=f=16000:t=o:w=1:g=12.0,"));
+
+  assert!(equalizer.build(None).is_err());
+  assert!(equalizer.build(Some("1,2,3")).is_err());
+  assert!(equalizer.build(Some("1,2,3,4,5,6,7,x")).is_err());
+}
+
+#[test]
+fn speed_and_pitch_filters() {
+  assert_eq!(speed_pitch_filters(1.0, 0.0), None);
+  assert_eq!(speed_pitch_filters(1.25, 0.0).as_deref(), Some("atempo=1.250000"));
+  // Outside of a single atempo range
+  assert_eq!(speed_pitch_filters(4.0, 0.0).as_deref(), Some("atempo=2.0,atempo=2.0"));
+  assert_eq!(speed_pitch_filters(0.3, 0.0).as_deref(), Some("atempo=0.5,atempo=0.600000"));
+
+  // An octave up plays twice as fast, compensated back to the original speed
+  assert_eq!(
+    speed_pitch_filters(1.0, 12.0).as_deref(),
+    Some("asetrate=48000*2.000000,aresample=48000,atempo=0.500000")
+  );
+  assert_eq!(
+    speed_pitch_filters(2.0, -12.0).as_deref(),
+    Some("asetrate=48000*0.500000,aresample=48000,atempo=2.0,atempo=2.000000")
+  );
+}
+
+#[test]
+fn parses_pitch() {
+  assert_eq!(parse_pitch("+3").unwrap(), 3.0);
+  assert_eq!(parse_pitch(" -1.5").unwrap(), -1.5);
+  assert_eq!(parse_pitch("reset").unwrap(), 0.0);
+  assert!(parse_pitch("13").is_err());
+  assert!(parse_pitch("NaN").is_err());
+  assert!(parse_pitch("up").is_err());
+}
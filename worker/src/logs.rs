@@ -0,0 +1,234 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serenity::all::GuildId;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Default of `MOSAIK_LOG_BUFFER_SIZE`, the number of events kept per guild.
+pub const DEFAULT_LOG_BUFFER_SIZE: usize = 500;
+
+/// Recent log events of every guild, recorded by [GuildLogLayer] from events inside spans with a `guild_id` field.
+pub struct GuildLogs {
+  capacity: usize,
+  guilds: Mutex<HashMap<u64, VecDeque<String>>>
+}
+
+impl GuildLogs {
+  /// Keeps the last `capacity` events per guild, `0` disables recording.
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      guilds: Default::default()
+    }
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Returns [None] if recording is disabled, so that the layer is not installed at all.
+  pub fn layer(self: &Arc<Self>) -> Option<GuildLogLayer> {
+    (self.capacity > 0).then(|| GuildLogLayer { logs: self.clone() })
+  }
+
+  fn push(&self, guild_id: u64, line: String) {
+    let mut guilds = self.guilds.lock().unwrap();
+    let lines = guilds.entry(guild_id).or_default();
+    if lines.len() >= self.capacity {
+      lines.pop_front();
+    }
+    lines.push_back(line);
+  }
+
+  /// Returns the last `count` events of the guild, oldest first.
+  pub fn tail(&self, guild_id: GuildId, count: usize) -> Vec<String> {
+    let guilds = self.guilds.lock().unwrap();
+    let Some(lines) = guilds.get(&guild_id.get()) else {
+      return Vec::new();
+    };
+    lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect()
+  }
+}
+
+pub struct GuildLogLayer {
+  logs: Arc<GuildLogs>
+}
+
+/// Stored in the extensions of spans with a `guild_id` field and their children.
+struct GuildSpan {
+  guild_id: u64,
+  /// Formatted span name and fields, e.g. `play{track_index=3}`.
+  context: String
+}
+
+impl<S> Layer<S> for GuildLogLayer
+where
+  S: Subscriber + for<'a> LookupSpan<'a>
+{
+  fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+    let Some(span) = ctx.span(id) else {
+      return;
+    };
+
+    let mut visitor = FieldVisitor::default();
+    attributes.record(&mut visitor);
+    // Spans outside of guilds are not formatted
+    let guild_id = visitor
+      .guild_id
+      .or_else(|| span.parent().and_then(|parent| parent.extensions().get::<GuildSpan>().map(|it| it.guild_id)));
+    let Some(guild_id) = guild_id else {
+      return;
+    };
+
+    let context = if visitor.fields.is_empty() {
+      span.name().to_owned()
+    } else {
+      format!("{}{{{}}}", span.name(), visitor.fields.trim_start())
+    };
+    span.extensions_mut().insert(GuildSpan { guild_id, context });
+  }
+
+  fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+    let Some(scope) = ctx.event_scope(event) else {
+      return;
+    };
+
+    let mut guild_id = None;
+    let mut contexts = Vec::new();
+    for span in scope.from_root() {
+      if let Some(guild) = span.extensions().get::<GuildSpan>() {
+        guild_id = Some(guild.guild_id);
+        contexts.push(guild.context.clone());
+      }
+    }
+    let Some(guild_id) = guild_id else {
+      return;
+    };
+
+    let mut visitor = FieldVisitor::default();
+    event.record(&mut visitor);
+    let metadata = event.metadata();
+    let line = format!(
+      "{} {:>5} {}: {}: {}{}",
+      format_time(SystemTime::now()),
+      metadata.level(),
+      contexts.join(":"),
+      metadata.target(),
+      visitor.message,
+      visitor.fields
+    );
+    self.logs.push(guild_id, line);
+  }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+  guild_id: Option<u64>,
+  message: String,
+  /// Formatted as ` key=value` pairs.
+  fields: String
+}
+
+impl Visit for FieldVisitor {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    match field.name() {
+      "guild_id" => self.guild_id = value.parse().ok(),
+      _ => self.record_debug(field, &value)
+    }
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+    match field.name() {
+      // Lines are already grouped by guild, so the guild ID is not repeated in fields
+      "guild_id" => self.guild_id = format!("{:?}", value).parse().ok(),
+      "message" => write!(self.message, "{:?}", value).unwrap(),
+      name => write!(self.fields, " {}={:?}", name, value).unwrap()
+    }
+  }
+}
+
+/// Formats the UTC time of day, e.g. `12:34:56.789`.
+fn format_time(time: SystemTime) -> String {
+  let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() % 86_400_000;
+  format!(
+    "{:02}:{:02}:{:02}.{:03}",
+    millis / 3_600_000,
+    millis / 60_000 % 60,
+    millis / 1000 % 60,
+    millis % 1000
+  )
+}
+
+/// Splits `lines` into code blocks of at most `limit` characters each, e.g. the message length limit.
+/// Lines longer than a block are truncated.
+pub fn to_code_blocks(lines: &[String], limit: usize) -> Vec<String> {
+  const FENCE: &str = "```\n";
+  let max_line = limit - FENCE.len() * 2;
+
+  let mut blocks = Vec::new();
+  let mut block = String::new();
+  for line in lines {
+    let line = match line.char_indices().nth(max_line - 1) {
+      Some((index, _)) => &line[..index],
+      None => line.as_str()
+    };
+    if !block.is_empty() && block.len() + line.len() + 1 > max_line {
+      blocks.push(format!("{}{}{}", FENCE, block, FENCE));
+      block.clear();
+    }
+    block.push_str(line);
+    block.push('\n');
+  }
+  if !block.is_empty() {
+    blocks.push(format!("{}{}{}", FENCE, block, FENCE));
+  }
+  blocks
+}
+
+#[test]
+fn records_events_of_guild_spans() {
+  use tracing::{debug, info_span};
+  use tracing_subscriber::layer::SubscriberExt;
+
+  let logs = Arc::new(GuildLogs::new(2));
+  let subscriber = tracing_subscriber::registry().with(logs.layer());
+  tracing::subscriber::with_default(subscriber, || {
+    debug!("outside of guilds");
+    let _player = info_span!("player", guild_id = 1u64).entered();
+    let _play = info_span!("play", track_index = 3).entered();
+    debug!(position = 5, "first");
+    debug!("second");
+    debug!("third");
+  });
+  tracing::subscriber::with_default(tracing_subscriber::registry().with(logs.layer()), || {
+    let _voice = info_span!("voice", guild_id = %3).entered();
+    debug!("recorded with a display value");
+  });
+
+  let lines = logs.tail(GuildId::new(1), 10);
+  assert_eq!(lines.len(), 2);
+  assert!(lines[0].ends_with("DEBUG player:play{track_index=3}: worker::logs: second"), "{}", lines[0]);
+  assert!(lines[1].ends_with(": third"));
+  assert!(logs.tail(GuildId::new(2), 10).is_empty());
+  assert!(!lines[0].contains("guild_id"));
+  assert_eq!(logs.tail(GuildId::new(1), 1), lines[1..]);
+  assert_eq!(logs.tail(GuildId::new(3), 10).len(), 1);
+}
+
+#[test]
+fn splits_lines_into_code_blocks() {
+  let lines = vec!["a".repeat(10), "b".repeat(10), "c".repeat(30)];
+  let blocks = to_code_blocks(&lines, 30);
+  assert_eq!(blocks, vec![
+    format!("```\n{}\n{}\n```\n", "a".repeat(10), "b".repeat(10)),
+    format!("```\n{}\n```\n", "c".repeat(21))
+  ]);
+  assert!(blocks.iter().all(|block| block.len() <= 30));
+}
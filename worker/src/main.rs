@@ -1,5 +1,9 @@
 pub mod commands;
+pub mod db;
+pub mod filters_presets;
+pub mod logs;
 pub mod player;
+pub mod playlist;
 pub mod providers;
 pub mod util;
 pub mod voice;
@@ -9,17 +13,29 @@ use std::env;
 use std::error::Error;
 use std::fmt::Write;
 use std::future::Future;
+use std::process;
 use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
+use futures_util::future;
+
 use regex::Regex;
-use serenity::all::GuildId;
+use serenity::all::{FullEvent, GuildId};
 use serenity::prelude::*;
-use tracing::{error, info};
-use tracing_subscriber::filter::EnvFilter;
+use tokio::select;
+use tokio::signal;
+use tokio::sync::Semaphore;
+use tokio::time;
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
 use tracing_subscriber::util::SubscriberInitExt;
 
+use crate::logs::{GuildLogs, DEFAULT_LOG_BUFFER_SIZE};
+use crate::playlist::FsPlaylistStorage;
+use crate::providers::HttpContext;
+use crate::util::check_restricted_command;
 use crate::voice::MosaikVoiceManager;
 
 include_and_export!(state);
@@ -128,15 +144,23 @@ async fn on_error(error: poise::FrameworkError<'_, State, AnyError>) {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+  let log_buffer_size = env::var("MOSAIK_LOG_BUFFER_SIZE")
+    .ok()
+    .and_then(|it| it.parse().ok())
+    .unwrap_or(DEFAULT_LOG_BUFFER_SIZE);
+  let logs = Arc::new(GuildLogs::new(log_buffer_size));
+  // Guild logs are recorded regardless of RUST_LOG, see the `debug logs` command
+  let guild_logs = logs.layer().map(|layer| layer.with_filter(LevelFilter::DEBUG));
   if env::var("MOSAIK_DEBUG_TRACY").map_or(false, |it| it == "1") {
     tracing_subscriber::registry()
       .with(tracing_tracy::TracyLayer::new())
       .with(tracing_subscriber::fmt::Layer::new())
+      .with(guild_logs)
       .init();
   } else {
-    tracing_subscriber::fmt()
-      .with_max_level(tracing::Level::DEBUG)
-      .with_env_filter(EnvFilter::from_default_env())
+    tracing_subscriber::registry()
+      .with(tracing_subscriber::fmt::Layer::new().with_filter(EnvFilter::from_default_env()))
+      .with(guild_logs)
       .init();
   }
   info!("hello");
@@ -145,16 +169,37 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     commands: vec![
       commands::help(),
       commands::play(),
+      commands::playnext(),
       commands::filters(),
       commands::pause(),
       commands::seek(),
       commands::queue(),
       commands::debug(),
       commands::jump(),
+      commands::search(),
+      commands::record(),
+      commands::config(),
+      commands::join(),
+      commands::announce(),
+      commands::playlist(),
+      commands::speed(),
+      commands::pitch(),
+      commands::mode(),
+      commands::crossfade(),
+      commands::cipher(),
     ],
     prefix_options: poise::PrefixFrameworkOptions {
       prefix: Some("~".into()),
       mention_as_prefix: true,
+      // Tried after the default prefix
+      dynamic_prefix: Some(|ctx| {
+        Box::pin(async move {
+          let Some(guild_id) = ctx.guild_id else {
+            return Ok(None);
+          };
+          Ok(ctx.data.config(guild_id).await?.prefix)
+        })
+      }),
       edit_tracker: Some(Arc::new(poise::EditTracker::for_timespan(Duration::from_secs(600)))),
       ..Default::default()
     },
@@ -178,21 +223,53 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         if ctx.author().id == 123456789 {
           return Ok(false);
         }
-        Ok(true)
+        check_restricted_command(ctx).await
       })
     }),
     // Enforce command checks even for owners (enforced by default)
     // Set to true to bypass checks, which is useful for testing
     skip_checks_for_owners: false,
-    event_handler: |_ctx, event, _framework, _data| {
+    event_handler: |_ctx, event, _framework, data| {
       Box::pin(async move {
         info!("Got an event in event handler: {:?}", event.snake_case_name());
+        if let FullEvent::ChannelUpdate { new, .. } = event {
+          // Follow bitrate changes of the channel the bot plays in
+          let player = data.players.read().await.get(&new.guild_id).cloned();
+          if let Some(player) = player.filter(|player| player.get_channel() == Some(new.id)) {
+            if player.channel_bitrate() != new.bitrate {
+              info!("channel {} bitrate changed to {:?}", new.id, new.bitrate);
+              player.set_channel_bitrate(new.bitrate).await?;
+            }
+          }
+        }
         Ok(())
       })
     },
     ..Default::default()
   };
 
+  let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://mosaik.db".to_owned());
+  let db = db::connect(&database_url).await?;
+
+  // Created before the client so that players can be shut down
+  let playlist_dir = env::var("PLAYLIST_DIR").unwrap_or_else(|_| "playlists".to_owned());
+  let queue_dir = env::var("QUEUE_DIR").unwrap_or_else(|_| "queues".to_owned());
+  let init_concurrency = env::var("MOSAIK_INIT_CONCURRENCY")
+    .ok()
+    .and_then(|it| it.parse().ok())
+    .unwrap_or(DEFAULT_INIT_CONCURRENCY);
+  let state = Arc::new(StateRef {
+    players: Default::default(),
+    db,
+    configs: Default::default(),
+    playlists: Box::new(FsPlaylistStorage::new(playlist_dir)),
+    saved_queues: Box::new(FsPlaylistStorage::new(queue_dir)),
+    init_permits: Arc::new(Semaphore::new(init_concurrency)),
+    logs,
+    http: HttpContext::from_env()?
+  });
+
+  let framework_state = state.clone();
   let framework = poise::Framework::builder()
     .setup(move |ctx, _ready, framework| {
       Box::pin(async move {
@@ -200,9 +277,7 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         poise::builtins::register_in_guild(ctx, &framework.options().commands, GuildId::from(1171104054131314708))
           .await?;
 
-        Ok(Arc::new(StateRef {
-          players: Default::default()
-        }))
+        Ok(framework_state)
       })
     })
     .options(options)
@@ -217,11 +292,26 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     | GatewayIntents::GUILD_MESSAGES
     | GatewayIntents::MESSAGE_CONTENT;
   let mut client = Client::builder(token, intents)
-    .voice_manager_arc(voice_manager)
+    .voice_manager_arc(voice_manager.clone())
     .framework(framework)
     .await
     .expect("Error creating client");
 
+  let shard_manager = client.shard_manager.clone();
+  tokio::spawn(async move {
+    wait_for_shutdown_signal().await;
+    info!("shutting down...");
+
+    let shutdown = async {
+      shutdown_players(&state, &voice_manager).await;
+      shard_manager.shutdown_all().await;
+    };
+    if time::timeout(SHUTDOWN_TIMEOUT, shutdown).await.is_err() {
+      error!("graceful shutdown timed out");
+      process::exit(1);
+    }
+  });
+
   if let Err(why) = client.start().await {
     println!("An error occurred while running the client: {:?}", why);
   }
@@ -229,4 +319,35 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
   Ok(())
 }
 
+/// Upper bound for leaving voice channels and closing shards before the process exits.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn wait_for_shutdown_signal() {
+  #[cfg(unix)]
+  let terminate = async {
+    let mut terminate =
+      signal::unix::signal(signal::unix::SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    terminate.recv().await;
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  select! {
+    _ = signal::ctrl_c() => info!("received SIGINT"),
+    _ = terminate => info!("received SIGTERM")
+  }
+}
+
+/// Stops every player and leaves its voice channel, so the bot does not stay visibly connected.
+async fn shutdown_players(state: &State, voice_manager: &MosaikVoiceManager) {
+  // Do not hold the lock while players are shutting down, commands may still be running
+  let players = state.players.read().await.values().cloned().collect::<Vec<_>>();
+  let results = future::join_all(players.iter().map(|player| player.shutdown(voice_manager))).await;
+  for (player, result) in players.iter().zip(results) {
+    if let Err(error) = result {
+      warn!("failed to shut down player for guild {}: {:?}", player.get_guild(), error);
+    }
+  }
+}
+
 pub static VOICE_MANAGER: OnceLock<Arc<MosaikVoiceManager>> = OnceLock::new();
@@ -1,57 +1,196 @@
 pub mod queue;
 pub mod track;
 
-use std::sync::atomic::Ordering;
+use std::env;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
-use serde_json::json;
-use serenity::all::{Cache, ChannelId, CreateMessage, GuildId, MessageBuilder};
-use serenity::constants::Opcode;
-use serenity::gateway::{ShardMessenger, ShardRunnerMessage};
-use tokio::sync::oneshot;
+use serenity::all::{Cache, ChannelId, ChannelType, CreateMessage, EditVoiceState, GuildId, MessageBuilder};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
 use tokio::time;
-use tracing::{debug, info, warn};
+use tracing::{debug, info, info_span, instrument, warn, Instrument, Span};
+use voice::constants::{CHANNEL_COUNT, DEFAULT_LIMITER_RELEASE, DEFAULT_LIMITER_THRESHOLD, SAMPLE_RATE};
+use voice::crossfade::{CrossfadeHandle, CrossfadeSampleProvider};
+use voice::provider::chain::ChainSampleProvider;
+use voice::provider::gain::GainSampleProvider;
+use voice::provider::{SampleProvider, SampleProviderHandle};
 use voice::{VoiceConnection, VoiceConnectionEvent, VoiceConnectionOptions, VoiceConnectionState};
 
+use crate::db::GuildConfig;
+use crate::filters_presets::speed_pitch_filters;
 use crate::player::queue::Queue;
-use crate::voice::MosaikVoiceManager;
-use crate::{PoiseContext, State};
+use crate::player::track::Track;
+use crate::providers::{get_metadata, FFmpegMediaProvider, MediaMetadata, MediaProvider};
+use crate::util::samples_to_duration;
+use crate::voice::clip::ClipSampleProvider;
+use crate::voice::ffmpeg::FFmpegSampleProviderHandle;
+use crate::voice::{MosaikVoiceManager, VoiceChannelChange};
+use crate::{PoiseContext, State, VOICE_MANAGER};
+
+const STAGE_SPEAKER_ATTEMPTS: usize = 5;
+const STAGE_SPEAKER_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const CROSSFADE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Default of `MOSAIK_IDLE_TIMEOUT_SECS`, how long the player stays in the channel after the queue ended.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
 pub enum PlayerEvent {
   TrackFinished(usize)
 }
 
+#[derive(Debug, Clone)]
+pub struct ActiveFilters {
+  /// Preset name or `custom`.
+  pub name: String,
+  pub graph: String
+}
+
+/// Logical filter state, combined into a single filter graph by [FilterState::graph].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterState {
+  pub filters: Option<ActiveFilters>,
+  /// Playback speed factor, `1.0` is unchanged.
+  pub speed: f64,
+  /// Pitch shift in semitones.
+  pub pitch: f64
+}
+
+impl Default for FilterState {
+  fn default() -> Self {
+    Self {
+      filters: None,
+      speed: 1.0,
+      pitch: 0.0
+    }
+  }
+}
+
+impl FilterState {
+  /// Speed and pitch adjustments come first, so that a preset limiter stays at the end of the graph.
+  pub fn graph(&self) -> Option<String> {
+    let adjustments = speed_pitch_filters(self.speed, self.pitch);
+    let filters = self.filters.as_ref().map(|filters| filters.graph.clone());
+    match (adjustments, filters) {
+      (Some(adjustments), Some(filters)) => Some(format!("{},{}", adjustments, filters)),
+      (adjustments, filters) => adjustments.or(filters)
+    }
+  }
+
+  /// Returns the state for the next track, speed and pitch are reset unless `sticky`.
+  fn for_next_track(&self, sticky: bool) -> Self {
+    if sticky {
+      self.clone()
+    } else {
+      Self {
+        filters: self.filters.clone(),
+        ..Self::default()
+      }
+    }
+  }
+}
+
 pub struct Player {
   pub state: State,
   pub connection: Arc<VoiceConnection>,
 
   pub guild_id: RwLock<GuildId>,
+  /// Parent span of player operations and tasks, so that their events carry the guild ID.
+  span: Span,
   pub context: tokio::sync::RwLock<Option<serenity::client::Context>>,
   pub text_channel_id: RwLock<Option<ChannelId>>,
   pub channel_id: RwLock<Option<ChannelId>>,
 
   pub queue: Arc<Queue>,
+  /// Persisted settings, see [crate::db::save_config].
+  pub config: RwLock<GuildConfig>,
+  /// Bitrate of the connected voice channel, used unless overridden by [GuildConfig::bitrate].
+  channel_bitrate: RwLock<Option<u32>>,
+  /// Filters applied to every played track, see [Player::update_filters].
+  pub filters: RwLock<FilterState>,
+
+  /// Serializes playback state transitions (play, stop, jump, seek). Must not be taken by the audio loop.
+  pub command_lock: Mutex<()>,
+  udp_loop_task: Mutex<Option<JoinHandle<()>>>,
+  /// Starts crossfades into the following tracks, see [Player::run_crossfade].
+  crossfade_task: Mutex<Option<JoinHandle<()>>>,
+  /// Announces title changes of the current track, see [Player::watch_metadata_updates].
+  metadata_task: Mutex<Option<JoinHandle<()>>>,
+  /// Leaves the voice channel once [Self::idle_timeout] passes after the queue ended, see [Player::start_idle_timer].
+  idle_task: std::sync::Mutex<Option<JoinHandle<()>>>,
+  /// [Duration::ZERO] disables leaving on inactivity.
+  idle_timeout: Duration,
+
+  /// Human-readable voice connection status, updated from [VoiceConnectionEvent]s.
+  pub status: RwLock<String>,
+  background_tasks_started: AtomicBool,
+  channel_changes_tx: flume::Sender<VoiceChannelChange>,
+  channel_changes_rx: flume::Receiver<VoiceChannelChange>,
 
   pub tx: flume::Sender<PlayerEvent>,
   pub rx: flume::Receiver<PlayerEvent>
 }
 
 impl Player {
-  pub fn new(state: State, guild_id: GuildId) -> Self {
+  pub fn new(state: State, guild_id: GuildId, config: GuildConfig) -> Self {
     let (tx, rx) = flume::bounded(16);
+    let (channel_changes_tx, channel_changes_rx) = flume::bounded(4);
+
+    let connection = VoiceConnection::new().unwrap();
+    connection.set_volume(config.volume);
+    match env::var("MOSAIK_FADE_OUT_MS").map(|value| value.parse::<u64>()) {
+      Ok(Ok(millis)) => connection.set_fade_out_duration(Duration::from_millis(millis)),
+      Ok(Err(error)) => warn!("invalid MOSAIK_FADE_OUT_MS value: {}", error),
+      Err(_) => {}
+    }
+    let limiter_release = env::var("MOSAIK_LIMITER_RELEASE_MS")
+      .ok()
+      .and_then(|value| value.parse().ok())
+      .map_or(DEFAULT_LIMITER_RELEASE, Duration::from_millis);
+    match env::var("MOSAIK_LIMITER_THRESHOLD").as_deref() {
+      Ok("off") => connection.set_limiter(None, limiter_release),
+      Ok(value) => match value.parse::<f32>() {
+        Ok(threshold) => connection.set_limiter(Some(threshold), limiter_release),
+        Err(error) => warn!("invalid MOSAIK_LIMITER_THRESHOLD value: {}", error)
+      },
+      Err(_) => connection.set_limiter(Some(DEFAULT_LIMITER_THRESHOLD), limiter_release)
+    }
+    let idle_timeout = env::var("MOSAIK_IDLE_TIMEOUT_SECS")
+      .ok()
+      .and_then(|value| value.parse().ok())
+      .map_or(DEFAULT_IDLE_TIMEOUT, Duration::from_secs);
+    let queue = Queue::new();
+    queue.set_dedup(config.dedup);
+    queue.set_max_length(config.max_queue_length);
 
     Self {
       state,
-      connection: Arc::new(VoiceConnection::new().unwrap()),
+      connection: Arc::new(connection),
 
       guild_id: RwLock::new(guild_id),
+      span: info_span!("player", guild_id = guild_id.get()),
       context: tokio::sync::RwLock::new(None),
       text_channel_id: RwLock::new(None),
       channel_id: RwLock::new(None),
 
-      queue: Queue::new(),
+      queue,
+      config: RwLock::new(config),
+      channel_bitrate: RwLock::new(None),
+      filters: RwLock::new(FilterState::default()),
+
+      command_lock: Mutex::new(()),
+      udp_loop_task: Mutex::new(None),
+      crossfade_task: Mutex::new(None),
+      metadata_task: Mutex::new(None),
+      idle_task: std::sync::Mutex::new(None),
+      idle_timeout,
+
+      status: RwLock::new("not connected".to_owned()),
+      background_tasks_started: AtomicBool::new(false),
+      channel_changes_tx,
+      channel_changes_rx,
 
       tx,
       rx
@@ -78,48 +217,226 @@ impl Player {
     *self.guild_id.read().unwrap()
   }
 
-  pub async fn connect(
-    self: &Arc<Self>,
-    voice_manager: &MosaikVoiceManager,
-    cache: &Cache,
-    shard: &ShardMessenger
-  ) -> Result<()> {
+  /// Replaces the config and applies it to the playback. Does not persist it.
+  pub async fn set_config(&self, config: GuildConfig) -> Result<()> {
+    self.connection.set_volume(config.volume);
+    self.queue.set_dedup(config.dedup);
+    self.queue.set_max_length(config.max_queue_length);
+    *self.config.write().unwrap() = config;
+    self.apply_bitrate().await
+  }
+
+  pub fn channel_bitrate(&self) -> Option<u32> {
+    *self.channel_bitrate.read().unwrap()
+  }
+
+  /// Updates the bitrate of the connected voice channel, e.g. after an admin changed it.
+  pub async fn set_channel_bitrate(&self, bitrate: Option<u32>) -> Result<()> {
+    *self.channel_bitrate.write().unwrap() = bitrate;
+    self.apply_bitrate().await
+  }
+
+  /// Returns the bitrate the audio should be encoded at, [None] for the encoder default.
+  fn target_bitrate(&self) -> Option<u32> {
+    self.config.read().unwrap().bitrate.or(self.channel_bitrate())
+  }
+
+  async fn apply_bitrate(&self) -> Result<()> {
+    if let Some(bitrate) = self.target_bitrate() {
+      self.connection.set_bitrate(bitrate).await?;
+    }
+    Ok(())
+  }
+
+  pub fn get_status(&self) -> String {
+    self.status.read().unwrap().clone()
+  }
+
+  fn set_status(&self, status: impl Into<String>) {
+    *self.status.write().unwrap() = status.into();
+  }
+
+  /// Sends a message to the text channel the player was last used from.
+  async fn notify(&self, content: impl Into<String>) -> Result<()> {
+    let text_channel_id = *self.text_channel_id.read().unwrap();
+    if let (Some(context), Some(text_channel_id)) = (&*self.context.read().await, text_channel_id) {
+      text_channel_id
+        .send_message(context, CreateMessage::new().content(content))
+        .await?;
+    }
+    Ok(())
+  }
+
+  /// Spawns tasks handling voice connection events, voice channel changes and finished tracks for the whole lifetime
+  /// of the player.
+  fn spawn_background_tasks(self: &Arc<Self>) {
+    if self.background_tasks_started.swap(true, Ordering::Relaxed) {
+      return;
+    }
+
+    let me = Arc::downgrade(self);
+    let changes = self.channel_changes_rx.clone();
+    let task = async move {
+      while let Ok(change) = changes.recv_async().await {
+        let Some(me) = me.upgrade() else {
+          break;
+        };
+        if let Err(error) = me.handle_channel_change(change).await {
+          warn!("failed to handle voice channel change {:?}: {:?}", change, error);
+        }
+      }
+    };
+    tokio::spawn(task.instrument(self.span.clone()));
+
+    let me = Arc::downgrade(self);
+    let events = self.connection.events.clone();
+    let task = async move {
+      while let Ok(event) = events.recv_async().await {
+        let Some(me) = me.upgrade() else {
+          break;
+        };
+        debug!("voice event: {:?}", event);
+
+        let result = match event {
+          VoiceConnectionEvent::RmsPeak(rms) => {
+            info!("rms peak: {}", rms);
+            me.notify(format!("RMS peaked at `{}`, playback was paused.", rms)).await
+          }
+          VoiceConnectionEvent::StateChanged(old, new) => {
+            info!("voice connection state changed: {:?} -> {:?}", old, new);
+            me.set_status(format!("{:?}", new));
+            Ok(())
+          }
+          VoiceConnectionEvent::GatewayClosed(code) => {
+            if code.can_reconnect() {
+              warn!("voice gateway closed: {:?}", code);
+              me.set_status(format!("gateway closed ({:?}), reconnecting", code));
+              Ok(())
+            } else {
+              warn!("voice gateway closed, cannot reconnect: {:?}", code);
+              me.set_status(format!("gateway closed ({:?})", code));
+              me.notify(format!("Voice connection closed: `{:?}`", code)).await
+            }
+          }
+          VoiceConnectionEvent::Reconnecting { attempt } => {
+            info!("reconnecting to voice gateway, attempt {}", attempt);
+            me.set_status(format!("reconnecting (attempt {})", attempt));
+            Ok(())
+          }
+          VoiceConnectionEvent::Reconnected => {
+            info!("reconnected to voice gateway");
+            me.set_status(format!("{:?}", me.connection.state.get()));
+            Ok(())
+          }
+          VoiceConnectionEvent::SampleProviderError(error) => {
+            me.notify(format!("Playback error, skipping track: `{}`", error)).await
+          }
+          VoiceConnectionEvent::SourceStalled => me.notify("Source is buffering…").await
+        };
+        if let Err(error) = result {
+          warn!("failed to handle voice event: {:?}", error);
+        }
+      }
+    };
+    tokio::spawn(task.instrument(self.span.clone()));
+
+    let me = Arc::downgrade(self);
+    let rx = self.rx.clone();
+    let task = async move {
+      while let Ok(event) = rx.recv_async().await {
+        let Some(me) = me.upgrade() else {
+          break;
+        };
+        match event {
+          PlayerEvent::TrackFinished(position) => {
+            let _guard = me.command_lock.lock().await;
+            if me.connection.state.get() == VoiceConnectionState::Playing {
+              debug!("track {} finished, but another track was already started", position);
+              continue;
+            }
+
+            let next = {
+              let mode = me.queue.mode.read().unwrap();
+              mode.seek(1, false)
+            };
+            debug!("track {} finished, next {:?}", position, next);
+
+            match next {
+              Some(next) => {
+                me.queue.set_position(next);
+                if let Err(error) = me.play().await {
+                  warn!("failed to play next track: {:?}", error);
+                }
+              }
+              None => me.start_idle_timer()
+            }
+          }
+        }
+      }
+    };
+    tokio::spawn(task.instrument(self.span.clone()));
+  }
+
+  #[instrument(parent = &self.span, skip_all)]
+  pub async fn connect(self: &Arc<Self>, voice_manager: &MosaikVoiceManager, cache: &Cache) -> Result<()> {
     let guild_id = self.get_guild();
     let channel_id = self.get_channel().context("no voice channel")?;
 
     let (tx, rx) = oneshot::channel();
     voice_manager.invalidate_state(&guild_id).await; // TODO: Invalidate as soon as disconnected
     voice_manager.callbacks.write().await.insert(guild_id, tx);
+    voice_manager
+      .channel_listeners
+      .write()
+      .await
+      .insert(guild_id, self.channel_changes_tx.clone());
 
-    // Serenity...
-    shard.send_to_shard(ShardRunnerMessage::Message(
-      serde_json::to_string(&json!({
-        "op": Opcode::VoiceStateUpdate,
-        "d": {
-          "guild_id": guild_id,
-          "channel_id": channel_id,
-          "self_mute": false,
-          "self_deaf": true
-        }
-      }))?
-      .into()
-    ));
-
-    let state = rx.await.unwrap();
+    voice_manager
+      .send_voice_state_update(guild_id, Some(channel_id), true, false)
+      .await?;
+
+    let state = rx.await.context("voice state callback dropped before the connection info arrived")?;
     debug!(?state, "got connection info");
 
+    self.spawn_background_tasks();
+
+    let is_stage = cache.channel(channel_id).map_or(false, |channel| channel.kind == ChannelType::Stage);
+
+    // Moving to another channel reconnects, so its bitrate is picked up here
+    let channel_bitrate = cache.channel(channel_id).context("no channel cached")?.bitrate;
+    *self.channel_bitrate.write().unwrap() = channel_bitrate;
+
     let options = VoiceConnectionOptions {
       user_id: cache.current_user().id.get(),
       guild_id: self.get_guild().get(),
-      bitrate: cache.channel(channel_id).context("no channel cached")?.bitrate,
+      bitrate: self.target_bitrate(),
       endpoint: state.endpoint.context("no voice endpoint")?,
-      token: state.token.unwrap(),
-      session_id: state.session_id.unwrap()
+      token: state.token.context("no voice token")?,
+      session_id: state.session_id.context("no voice session ID")?,
+      receive: env::var("MOSAIK_RECEIVE_AUDIO").map_or(false, |it| it == "1")
     };
     self.connection.connect(options).await?;
 
+    if is_stage && !self.become_stage_speaker(voice_manager, channel_id).await? {
+      warn!("bot is still suppressed in stage channel {}", channel_id);
+      let text_channel_id = *self.text_channel_id.read().unwrap();
+      if let Some(context) = &*self.context.read().await {
+        if let Some(text_channel_id) = text_channel_id {
+          if let Err(error) = text_channel_id
+            .say(
+              context,
+              "Could not become a speaker in the stage channel, accept the request to speak or invite me to speak."
+            )
+            .await
+          {
+            warn!("failed to send stage speaker notice: {:?}", error);
+          }
+        }
+      }
+    }
+
     let connection_weak = Arc::downgrade(&self.connection);
-    tokio::spawn(async move {
+    let task = async move {
       loop {
         match VoiceConnection::run_ws_loop(connection_weak.clone()).await {
           Ok(()) => {
@@ -146,66 +463,331 @@ impl Player {
       if let Some(connection) = connection_weak.upgrade() {
         connection.stop_udp_loop.store(true, Ordering::Relaxed);
       }
-    });
+    };
+    tokio::spawn(task.instrument(self.span.clone()));
 
-    let cloned = self.clone();
-    let rx = self.rx.clone();
-    tokio::spawn(async move {
-      loop {
-        match rx.recv_async().await.unwrap() {
-          PlayerEvent::TrackFinished(position) => {
-            let next = {
-              let mode = cloned.queue.mode.read().unwrap();
-              mode.seek(1, false)
-            };
-            debug!("track {} finished, next {:?}", position, next);
+    Ok(())
+  }
 
-            if let Some(next) = next {
-              cloned.queue.set_position(next);
-              cloned.play().await.unwrap();
-            }
+  /// Starts the countdown to leave the voice channel, unless it is already running or disabled.
+  pub fn start_idle_timer(self: &Arc<Self>) {
+    let mut idle_task = self.idle_task.lock().unwrap();
+    if self.idle_timeout.is_zero() || idle_task.as_ref().is_some_and(|task| !task.is_finished()) {
+      return;
+    }
+
+    debug!("queue ended, leaving in {:?} if nothing is played", self.idle_timeout);
+    // Must not keep the player alive, e.g. if it is removed
+    let me = Arc::downgrade(self);
+    let timeout = self.idle_timeout;
+    let task = async move {
+      time::sleep(timeout).await;
+      let Some(me) = me.upgrade() else {
+        return;
+      };
+      if let Err(error) = me.leave_idle().await {
+        warn!("failed to leave due to inactivity: {:?}", error);
+      }
+    };
+    *idle_task = Some(tokio::spawn(task.instrument(self.span.clone())));
+  }
+
+  /// Cancels the countdown started by [Self::start_idle_timer], e.g. when a track is played.
+  pub fn cancel_idle_timer(&self) {
+    if let Some(task) = self.idle_task.lock().unwrap().take() {
+      debug!("idle timer cancelled");
+      task.abort();
+    }
+  }
+
+  async fn leave_idle(&self) -> Result<()> {
+    let _guard = self.command_lock.lock().await;
+    if self.connection.state.get() == VoiceConnectionState::Playing || !self.connection.is_connected() {
+      return Ok(());
+    }
+
+    info!("leaving due to inactivity");
+    self.notify("Leaving due to inactivity").await?;
+    let voice_manager = VOICE_MANAGER.get().context("no voice manager")?;
+    // Leaving on purpose is not a channel change to handle
+    voice_manager.remove_channel_listener(&self.get_guild()).await;
+    voice_manager
+      .send_voice_state_update(self.get_guild(), None, false, false)
+      .await?;
+    self.connection.disconnect().await?;
+    *self.channel_id.write().unwrap() = None;
+
+    Ok(())
+  }
+
+  /// Tries to become a speaker in a stage channel, falling back to a request to speak.
+  ///
+  /// Returns `false` if the bot is still suppressed after all attempts.
+  async fn become_stage_speaker(&self, voice_manager: &MosaikVoiceManager, channel_id: ChannelId) -> Result<bool> {
+    let guild_id = self.get_guild();
+    let http = match &*self.context.read().await {
+      Some(context) => context.http.clone(),
+      None => return Err(anyhow!("no serenity context"))
+    };
+
+    for attempt in 0..STAGE_SPEAKER_ATTEMPTS {
+      match channel_id
+        .edit_own_voice_state(&http, EditVoiceState::new().suppress(false))
+        .await
+      {
+        Ok(()) => debug!("unsuppressed in stage channel {}", channel_id),
+        Err(error) => {
+          debug!("failed to unsuppress in stage channel {}: {:?}, requesting to speak", channel_id, error);
+          // The voice connection is already up, so this must not fail connecting
+          if let Err(error) = channel_id
+            .edit_own_voice_state(&http, EditVoiceState::new().request_to_speak(true))
+            .await
+          {
+            warn!("failed to request to speak in stage channel {}: {:?}", channel_id, error);
+            return Ok(false);
           }
         }
       }
-    });
+
+      time::sleep(STAGE_SPEAKER_RETRY_INTERVAL).await;
+      if !voice_manager.is_suppressed(&guild_id).await {
+        return Ok(true);
+      }
+      debug!("still suppressed in stage channel {} (attempt {})", channel_id, attempt + 1);
+    }
+
+    Ok(false)
+  }
+
+  /// Follows the bot being moved to another voice channel, or stops playback if it was kicked.
+  async fn handle_channel_change(self: &Arc<Self>, change: VoiceChannelChange) -> Result<()> {
+    match change {
+      VoiceChannelChange::Moved(channel_id) => {
+        info!("moved to voice channel {}, reconnecting", channel_id);
+        self.move_to(channel_id).await
+      }
+      VoiceChannelChange::Disconnected => {
+        info!("disconnected from voice channel");
+        let _guard = self.command_lock.lock().await;
+        let was_playing = self.connection.state.get() == VoiceConnectionState::Playing;
+        if was_playing {
+          self.stop(false).await?;
+        }
+        self.connection.disconnect().await?;
+
+        *self.channel_id.write().unwrap() = None;
+        if was_playing {
+          self.notify("Disconnected from the voice channel, playback stopped.").await?;
+        }
+        Ok(())
+      }
+    }
+  }
+
+  /// Reconnects to `channel_id`, resuming playback at the same position.
+  #[instrument(parent = &self.span, skip(self))]
+  pub async fn move_to(self: &Arc<Self>, channel_id: ChannelId) -> Result<()> {
+    let _guard = self.command_lock.lock().await;
+    let was_playing = self.connection.state.get() == VoiceConnectionState::Playing;
+    let position = self.connection.playback_position();
+    let filters = self.filters.read().unwrap().clone();
+    if was_playing {
+      // Resumed at the same position, so nothing is faded out
+      self.stop(false).await?;
+    }
+    self.connection.disconnect().await?;
+
+    self.set_channel(channel_id);
+    let context = self.context.read().await.clone().context("no context")?;
+    // Sends a new voice state update for the channel and reconnects the voice gateway
+    self.connect(VOICE_MANAGER.get().unwrap().as_ref(), &context.cache).await?;
+    if was_playing {
+      self.play().await?;
+      // Still the same track, so keep its speed and pitch
+      self.update_filters(|state| *state = filters).await?;
+      self.seek(position).await?;
+    }
 
     Ok(())
   }
 
-  pub async fn stop(self: &Arc<Self>) -> Result<()> {
+  /// If `fade_out` is set, the audio is faded out before stopping instead of being cut,
+  /// which is not wanted when the connection is lost. Callers must hold [Self::command_lock].
+  #[instrument(parent = &self.span, skip(self), fields(track_index = self.queue.position()))]
+  pub async fn stop(self: &Arc<Self>, fade_out: bool) -> Result<()> {
     if self.connection.state.get() != VoiceConnectionState::Playing {
       return Err(anyhow!("invalid player state (expected playing)"));
     }
+    self.abort_crossfade().await;
+    if let Some(task) = self.metadata_task.lock().await.take() {
+      task.abort();
+    }
+    self.connection.fade_out.store(fade_out, Ordering::Relaxed);
     self.connection.stop_udp_loop.store(true, Ordering::Relaxed);
 
     debug!("waiting for udp loop to exit...");
-    self
-      .connection
-      .state
-      .wait_for(|state| *state != VoiceConnectionState::Playing)
-      .await;
+    // The task also resets stop_udp_loop, wait for it so the next loop is not stopped immediately
+    if let Some(task) = self.udp_loop_task.lock().await.take() {
+      task.await?;
+    }
 
     Ok(())
   }
 
+  /// Stops playback and leaves the voice channel, e.g. when the worker shuts down.
+  ///
+  /// Waits for a running [Self::play] or other command through [Self::command_lock].
+  pub async fn shutdown(self: &Arc<Self>, voice_manager: &MosaikVoiceManager) -> Result<()> {
+    let _guard = self.command_lock.lock().await;
+    if self.connection.state.get() == VoiceConnectionState::Playing {
+      self.stop(true).await?;
+    }
+
+    if self.connection.is_connected() {
+      voice_manager.remove_channel_listener(&self.get_guild()).await;
+      voice_manager
+        .send_voice_state_update(self.get_guild(), None, false, false)
+        .await?;
+      self.connection.disconnect().await?;
+    }
+    *self.channel_id.write().unwrap() = None;
+
+    Ok(())
+  }
+
+  /// Plays the media at `path` before the rest of the current track, e.g. a jingle or an announcement.
+  ///
+  /// The announcement is counted in the playback position, as it is played through the same connection.
+  /// Callers must hold [Self::command_lock].
+  pub async fn play_announcement(&self, path: &str) -> Result<()> {
+    if self.connection.state.get() != VoiceConnectionState::Playing {
+      return Err(anyhow!("nothing is playing"));
+    }
+    let announcement = FFmpegMediaProvider::new(path.to_owned()).get_sample_provider().await?;
+
+    // Replaced under the lock, the playback loop fails if there is no sample provider
+    let mut sample_provider = self.connection.sample_provider.lock().unwrap();
+    let current = sample_provider.take().context("no sample provider")?;
+    let mut chain = ChainSampleProvider::new();
+    chain.push_back(current);
+    chain.push_front(announcement);
+    // The chain returns the handle of the current track, so sample_provider_handle stays valid
+    *sample_provider = Some(Box::new(chain));
+
+    Ok(())
+  }
+
+  /// Stops the current track and plays the track at `position`. Callers must hold [Self::command_lock].
+  #[instrument(parent = &self.span, skip(self))]
+  pub async fn jump(self: &Arc<Self>, position: usize) -> Result<()> {
+    if self.connection.state.get() == VoiceConnectionState::Playing {
+      self.stop(true).await?;
+    }
+    self.queue.set_position(position);
+    self.play().await
+  }
+
+  /// Seeks the current track to `position`. Callers must hold [Self::command_lock].
+  ///
+  /// Returns `true` if the position is approximate, see [FFmpegSampleProviderHandle::seek].
+  #[instrument(parent = &self.span, skip(self), fields(track_index = self.queue.position()))]
+  pub async fn seek(&self, position: Duration) -> Result<bool> {
+    let handle = self.connection.sample_provider_handle.lock().await;
+    let handle = handle.as_ref().context("no sample provider")?;
+    let handle = handle
+      .as_any()
+      .downcast_ref::<FFmpegSampleProviderHandle>()
+      .context("unsupported sample provider")?;
+    if !handle.is_seekable() {
+      return Err(anyhow!("source does not support seeking"));
+    }
+
+    let approximate = handle
+      .seek(position)
+      .map_err(|error| anyhow!("failed to seek: {}", error))?;
+    // Clear after seeking, so that samples read before the seek are dropped even if they are still being written
+    self.connection.clear_sample_buffer(position).await;
+    self.connection.rms.lock().unwrap().reset();
+
+    Ok(approximate)
+  }
+
+  /// Applies the changed filter state to the current track and keeps it for subsequent tracks.
+  /// Callers must hold [Self::command_lock].
+  pub async fn update_filters(&self, update: impl FnOnce(&mut FilterState)) -> Result<FilterState> {
+    let previous = self.filters.read().unwrap().clone();
+    let mut state = previous.clone();
+    update(&mut state);
+    {
+      let handle = self.connection.sample_provider_handle.lock().await;
+      let handle = handle.as_ref().context("nothing is playing")?;
+      apply_filters(handle.as_ref(), state.graph().as_deref())?;
+
+      if state.speed != previous.speed || state.pitch != previous.pitch {
+        // Buffered samples were filtered at the old tempo, seekable sources are read again from the played position
+        let position = self.connection.playback_position();
+        let ffmpeg = handle.as_any().downcast_ref::<FFmpegSampleProviderHandle>();
+        if let Some(ffmpeg) = ffmpeg.filter(|ffmpeg| ffmpeg.is_seekable()) {
+          ffmpeg
+            .seek(position)
+            .map_err(|error| anyhow!("failed to seek: {}", error))?;
+        }
+        self.connection.clear_sample_buffer(position).await;
+      }
+    }
+
+    self.connection.set_playback_speed(state.speed);
+    *self.filters.write().unwrap() = state.clone();
+    Ok(state)
+  }
+
+  /// Callers must hold [Self::command_lock].
+  #[instrument(parent = &self.span, skip_all, fields(track_index = self.queue.position()))]
   pub async fn play(self: &Arc<Self>) -> Result<()> {
     if self.connection.state.get() == VoiceConnectionState::Playing {
       return Err(anyhow!("invalid player state (playing)"));
     }
+    self.cancel_idle_timer();
+
+    // The previous loop may have finished on its own, but its task may not have reset stop_udp_loop yet
+    if let Some(task) = self.udp_loop_task.lock().await.take() {
+      task.await?;
+    }
+
+    self.abort_crossfade().await;
 
     debug!("playing track {} / {}", self.queue.position(), self.queue.len());
-    let track = self.queue.get_current().upgrade().unwrap();
+    let track = self
+      .queue
+      .get_current()
+      .and_then(|track| track.upgrade())
+      .ok_or_else(|| anyhow!("queue is empty"))?;
 
-    let sample_provider = track.provider.get_sample_provider().await?;
+    let filters = self.next_track_filters();
+    let mut sample_provider = self.create_sample_provider(&track, &filters).await?;
+    self.connection.set_playback_speed(filters.speed);
+    self.connection.set_start_position(track.start.unwrap_or_default());
+    *self.filters.write().unwrap() = filters;
     debug!("initializing sample provider (deadlock test)");
-    *self.connection.sample_provider_handle.lock().await = Some(sample_provider.get_handle());
-    *self.connection.sample_provider.lock().unwrap() = Some(sample_provider);
+    if self.config.read().unwrap().crossfade_secs > 0.0 {
+      let (crossfade_provider, crossfade) = CrossfadeSampleProvider::new(sample_provider);
+      sample_provider = Box::new(crossfade_provider);
+      let task = self.clone().run_crossfade(crossfade).in_current_span();
+      *self.crossfade_task.lock().await = Some(tokio::spawn(task));
+    }
+    // The crossfade provider returns the handle of the current track
+    self.connection.set_sample_provider(sample_provider).await;
     debug!("sample provider initialized (deadlock test)");
+    self.watch_metadata_updates(&track).await;
 
     let x = self.clone();
     let clone = self.connection.clone();
-    tokio::spawn(async move {
-      VoiceConnection::run_udp_loop(clone).await.unwrap();
+    let task = async move {
+      if let Err(error) = VoiceConnection::run_udp_loop(clone).await {
+        warn!("VoiceConnection::run_udp_loop error: {:?}", error);
+        x.connection.stop_udp_loop.store(false, Ordering::Relaxed);
+        return;
+      }
 
       // If stop_udp_loop is not set - send PlayerEvent::TrackFinished
       if let Err(_) = x
@@ -218,24 +800,321 @@ impl Player {
           .await
           .unwrap();
       }
-    });
+    };
+    *self.udp_loop_task.lock().await = Some(tokio::spawn(task.in_current_span()));
 
-    let clone = self.clone();
-    tokio::spawn(async move {
-      while let Ok(event) = clone.connection.events.recv_async().await {
-        info!("voice event: {:?}", event);
-        match event {
-          VoiceConnectionEvent::RmsPeak(rms) => {
-            info!("rms peak: {}", rms);
-            if let Some(context) = &*clone.context.read().await {
-              let channel_id = clone.text_channel_id.read().unwrap().unwrap();
-              channel_id.send_message(context, CreateMessage::new().content(format!("RMS peaked at `{}`, playback was paused.", rms))).await.unwrap();
+    Ok(())
+  }
+
+  /// Filter state of the next track, see [GuildConfig::sticky_speed].
+  fn next_track_filters(&self) -> FilterState {
+    let sticky = self.config.read().unwrap().sticky_speed;
+    self.filters.read().unwrap().for_next_track(sticky)
+  }
+
+  /// Creates the sample provider of `track` with `filters` applied.
+  async fn create_sample_provider(&self, track: &Track, filters: &FilterState) -> Result<Box<dyn SampleProvider>> {
+    let mut sample_provider = track.provider.get_sample_provider().await?;
+    if track.is_clipped() {
+      let start = track.start.unwrap_or_default();
+      if !start.is_zero() {
+        let handle = sample_provider.get_handle();
+        // Other sources are read from the beginning, skipping samples before the start
+        if let Some(handle) = handle.as_any().downcast_ref::<FFmpegSampleProviderHandle>() {
+          if handle.is_seekable() {
+            if let Err(error) = handle.seek(start) {
+              warn!("failed to seek to clip start {:?}: {}", start, error);
             }
           }
         }
       }
+      sample_provider = Box::new(ClipSampleProvider::new(sample_provider, start, track.end));
+    }
+    if let Some(graph) = filters.graph() {
+      if let Err(error) = apply_filters(sample_provider.get_handle().as_ref(), Some(&graph)) {
+        warn!("failed to apply filters {:?}: {:?}", filters, error);
+      }
+    }
+
+    let metadata = track.provider.get_metadata().await.unwrap_or_default();
+    if let Some(gain) = get_metadata!(metadata, MediaMetadata::NormalizedGain(gain) => *gain) {
+      debug!("normalizing track by {} dB", gain);
+      sample_provider = Box::new(GainSampleProvider::from_db(sample_provider, gain));
+    }
+    Ok(sample_provider)
+  }
+
+  /// Announces title changes of `track` while it is playing, e.g. the current song of an internet radio stream.
+  async fn watch_metadata_updates(self: &Arc<Self>, track: &Track) {
+    let task = track.provider.metadata_updates().map(|updates| {
+      // Left from a previous playback of the track
+      while updates.try_recv().is_ok() {}
+
+      let me = self.clone();
+      let task = async move {
+        while let Ok(update) = updates.recv_async().await {
+          if let MediaMetadata::Title(title) = update {
+            if let Err(error) = me.notify(format!("Now playing: {}", title)).await {
+              warn!("failed to announce title: {:?}", error);
+            }
+          }
+        }
+      };
+      tokio::spawn(task.in_current_span())
     });
 
-    Ok(())
+    if let Some(task) = mem::replace(&mut *self.metadata_task.lock().await, task) {
+      task.abort();
+    }
+  }
+
+  async fn abort_crossfade(&self) {
+    if let Some(task) = self.crossfade_task.lock().await.take() {
+      task.abort();
+    }
+  }
+
+  /// Starts the next track when the remaining duration of the current one drops below the crossfade window,
+  /// then advances the queue once the tracks switched. Tracks with unknown duration end with a hard cut.
+  async fn run_crossfade(self: Arc<Self>, crossfade: CrossfadeHandle) {
+    loop {
+      let Some(track) = self.queue.get_current().and_then(|track| track.upgrade()) else {
+        return;
+      };
+      let duration = match track.provider.get_metadata().await {
+        Ok(metadata) => get_metadata!(metadata, MediaMetadata::Duration(duration) => *duration),
+        Err(error) => {
+          warn!("failed to get track metadata: {:?}", error);
+          None
+        }
+      };
+      let Some(duration) = duration.or(track.end) else {
+        debug!("track duration is unknown, not crossfading");
+        return;
+      };
+      let duration = track.end.map_or(duration, |end| end.min(duration));
+
+      // Samples are read from the provider ahead of playback by the buffered amount
+      let mut was_playing = false;
+      let remaining = loop {
+        let window = Duration::from_secs_f32(self.config.read().unwrap().crossfade_secs);
+        if window.is_zero() || crossfade.is_closed() {
+          return;
+        }
+
+        match self.connection.state.get() {
+          VoiceConnectionState::Playing => was_playing = true,
+          // Ended or disconnected
+          _ if was_playing => return,
+          _ => {}
+        }
+
+        // Playback position and duration are in media time, the buffer and the window are in output time
+        let speed = self.filters.read().unwrap().speed;
+        let buffered = samples_to_duration(self.connection.sample_buffer.available_to_read());
+        let remaining = duration
+          .saturating_sub(self.connection.playback_position() + buffered.mul_f64(speed))
+          .div_f64(speed);
+        if was_playing && remaining <= window {
+          break remaining;
+        }
+        time::sleep(CROSSFADE_POLL_INTERVAL).await;
+      };
+
+      let Some(next_position) = self.queue.mode.read().unwrap().seek(1, false) else {
+        return;
+      };
+      let Some(next_track) = self.queue.tracks.read().unwrap().get(next_position).cloned() else {
+        return;
+      };
+      let next_filters = self.next_track_filters();
+      let next = match self.create_sample_provider(&next_track, &next_filters).await {
+        Ok(next) => next,
+        Err(error) => {
+          // The next track is loaded again after the hard cut
+          warn!("failed to load next track for crossfade: {:?}", error);
+          return;
+        }
+      };
+      let next_handle = next.get_handle();
+
+      debug!("crossfading into the next track over {:?}", remaining);
+      let length = (remaining.as_secs_f64() * SAMPLE_RATE as f64) as usize * CHANNEL_COUNT;
+      crossfade.start(next, length);
+      let Some(mixed) = crossfade.switched().await else {
+        return;
+      };
+
+      let finished = {
+        let _guard = self.command_lock.lock().await;
+        let finished = self.queue.position();
+        // Tracks may have been inserted during the crossfade
+        let position = self
+          .queue
+          .tracks
+          .read()
+          .unwrap()
+          .iter()
+          .position(|track| Arc::ptr_eq(track, &next_track));
+        self.queue.set_position(position.unwrap_or(next_position));
+
+        *self.connection.sample_provider_handle.lock().await = Some(next_handle);
+        let buffered = samples_to_duration(self.connection.sample_buffer.available_to_read());
+        self.connection.set_playback_speed(next_filters.speed);
+        self.connection.rebase_playback_position(
+          next_track.start.unwrap_or_default()
+            + samples_to_duration(mixed)
+              .saturating_sub(buffered)
+              .mul_f64(next_filters.speed)
+        );
+        *self.filters.write().unwrap() = next_filters;
+        finished
+      };
+
+      // Still playing, so the handler does not start another track
+      if self.tx.send_async(PlayerEvent::TrackFinished(finished)).await.is_err() {
+        return;
+      }
+    }
+  }
+}
+
+fn apply_filters(handle: &dyn SampleProviderHandle, graph: Option<&str>) -> Result<()> {
+  let handle = handle
+    .as_any()
+    .downcast_ref::<FFmpegSampleProviderHandle>()
+    .context("unsupported sample provider")?;
+  match graph {
+    Some(graph) => {
+      handle.init_filters(graph)?;
+      handle.set_enable_filter_graph(true)?;
+    }
+    None => handle.set_enable_filter_graph(false)?
+  }
+
+  Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_jump_and_seek_are_serialized() {
+  use std::any::Any;
+
+  use async_trait::async_trait;
+  use voice::provider::SampleProvider;
+
+  use crate::player::track::Track;
+  use crate::providers::{MediaMetadata, MediaProvider};
+  use crate::state::StateRef;
+
+  #[derive(Debug)]
+  struct IndexedMediaProvider(usize);
+  struct IndexedSampleProvider(usize);
+  struct IndexedSampleProviderHandle;
+
+  #[async_trait]
+  impl MediaProvider for IndexedMediaProvider {
+    async fn get_sample_provider(&self) -> Result<Box<dyn SampleProvider>> {
+      // Let concurrent commands interleave while the track is loading
+      tokio::task::yield_now().await;
+      Ok(Box::new(IndexedSampleProvider(self.0)))
+    }
+
+    async fn get_metadata(&self) -> Result<Vec<MediaMetadata>> {
+      Ok(Vec::new())
+    }
   }
+
+  impl SampleProvider for IndexedSampleProvider {
+    fn get_samples(&mut self) -> Result<Option<Vec<f32>>> {
+      Ok(None)
+    }
+
+    fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+      self
+    }
+
+    fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+      Box::new(IndexedSampleProviderHandle)
+    }
+  }
+
+  impl SampleProviderHandle for IndexedSampleProviderHandle {
+    fn as_any(&self) -> &(dyn Any + Sync + Send) {
+      self
+    }
+  }
+
+  const TRACKS: usize = 8;
+  const COMMANDS: usize = 64;
+
+  let state = Arc::new(StateRef::for_test().await);
+  let player = Arc::new(Player::new(state, GuildId::new(1), GuildConfig::new(1)));
+  for index in 0..TRACKS {
+    player.queue.push(Track::new(Box::new(IndexedMediaProvider(index)), None)).unwrap();
+  }
+
+  let jumps = Arc::new(std::sync::Mutex::new(Vec::new()));
+  let tasks = (0..COMMANDS)
+    .map(|index| {
+      let player = player.clone();
+      let jumps = jumps.clone();
+      tokio::spawn(async move {
+        let _guard = player.command_lock.lock().await;
+        if index % 2 == 0 {
+          let position = index * 3 % TRACKS;
+          player.jump(position).await.unwrap();
+          jumps.lock().unwrap().push(position);
+        } else {
+          // Not an FFmpeg sample provider, but seek must still not observe a half-applied jump
+          let _ = player.seek(Duration::from_secs(index as u64)).await;
+        }
+      })
+    })
+    .collect::<Vec<_>>();
+  for task in tasks {
+    task.await.unwrap();
+  }
+
+  let last_jump = *jumps.lock().unwrap().last().unwrap();
+  assert_eq!(player.queue.position(), last_jump);
+
+  let mut sample_provider = player.connection.sample_provider.lock().unwrap();
+  let sample_provider = sample_provider.as_mut().unwrap().as_any();
+  assert_eq!(sample_provider.downcast_mut::<IndexedSampleProvider>().unwrap().0, last_jump);
+}
+
+#[test]
+fn filter_state_combines_graph() {
+  let mut state = FilterState::default();
+  assert_eq!(state.graph(), None);
+
+  state.filters = Some(ActiveFilters {
+    name: "custom".to_owned(),
+    graph: "bass=g=3".to_owned()
+  });
+  state.speed = 1.5;
+  assert_eq!(state.graph().as_deref(), Some("atempo=1.500000,bass=g=3"));
+
+  // Speed and pitch are reset for the next track, the filters are kept
+  let next = state.for_next_track(false);
+  assert_eq!(next.graph().as_deref(), Some("bass=g=3"));
+  assert_eq!(state.for_next_track(true), state);
+}
+
+#[tokio::test]
+async fn idle_timer_does_not_keep_player_alive() {
+  use crate::state::StateRef;
+
+  let state = Arc::new(StateRef::for_test().await);
+  let player = Arc::new(Player::new(state, GuildId::new(1), GuildConfig::new(1)));
+
+  player.start_idle_timer();
+  player.start_idle_timer();
+  player.cancel_idle_timer();
+  assert!(player.idle_task.lock().unwrap().is_none());
+
+  player.start_idle_timer();
+  let weak = Arc::downgrade(&player);
+  drop(player);
+  assert!(weak.upgrade().is_none());
 }
@@ -1,14 +1,33 @@
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use rand::seq::SliceRandom;
+use thiserror::Error;
 
 use crate::player::track::Track;
+use crate::providers::{get_metadata, MediaMetadata};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QueueError {
+  /// The queue reached [GuildConfig::max_queue_length](crate::db::GuildConfig).
+  #[error("The queue is full ({0} tracks)")]
+  Full(usize),
+  /// Deduplication is enabled and the track is already queued.
+  #[error("This track is already in the queue")]
+  Duplicate
+}
 
 #[derive(Debug)]
 pub struct Queue {
   pub tracks: RwLock<Vec<Arc<Track>>>,
   position: AtomicUsize,
-  pub mode: RwLock<Box<dyn PlayMode>>
+  pub mode: RwLock<Box<dyn PlayMode>>,
+  /// Whether [Queue::push] and [Queue::insert] skip tracks that are already queued, see [GuildConfig::dedup](crate::db::GuildConfig).
+  dedup: AtomicBool,
+  /// Maximum number of tracks, [usize::MAX] if unlimited.
+  max_length: AtomicUsize
 }
 
 impl Queue {
@@ -16,7 +35,9 @@ impl Queue {
     let me = Self {
       tracks: RwLock::new(Vec::new()),
       position: AtomicUsize::new(0),
-      mode: RwLock::new(Box::new(UninitializedPlayMode {}))
+      mode: RwLock::new(Box::new(UninitializedPlayMode {})),
+      dedup: AtomicBool::new(false),
+      max_length: AtomicUsize::new(usize::MAX)
     };
     let me = Arc::new(me);
     me.set_mode(Box::new(NormalPlayMode::new(Arc::downgrade(&me))));
@@ -27,6 +48,34 @@ impl Queue {
     *self.mode.write().unwrap() = mode;
   }
 
+  pub fn set_dedup(&self, dedup: bool) {
+    self.dedup.store(dedup, Ordering::Relaxed);
+  }
+
+  /// Limits the number of tracks, [None] for no limit. Already queued tracks are kept.
+  pub fn set_max_length(&self, max_length: Option<usize>) {
+    self.max_length.store(max_length.unwrap_or(usize::MAX), Ordering::Relaxed);
+  }
+
+  /// Returns how many tracks can be added, [None] if there is no limit.
+  pub fn remaining_capacity(&self) -> Option<usize> {
+    let max_length = self.max_length.load(Ordering::Relaxed);
+    (max_length != usize::MAX).then(|| max_length.saturating_sub(self.len()))
+  }
+
+  /// Checks that `track` can be added to `tracks`, see [Queue::push].
+  fn check_can_add(&self, tracks: &[Arc<Track>], track: &Track) -> Result<(), QueueError> {
+    let max_length = self.max_length.load(Ordering::Relaxed);
+    if tracks.len() >= max_length {
+      return Err(QueueError::Full(max_length));
+    }
+    let is_duplicate = || track.source.is_some() && tracks.iter().any(|queued| queued.source == track.source);
+    if self.dedup.load(Ordering::Relaxed) && is_duplicate() {
+      return Err(QueueError::Duplicate);
+    }
+    Ok(())
+  }
+
   pub fn set_position(&self, position: usize) {
     self.position.store(position, Ordering::Relaxed);
   }
@@ -39,16 +88,80 @@ impl Queue {
     self.tracks.read().unwrap().len()
   }
 
-  pub fn get_current(&self) -> Weak<Track> {
+  /// Returns [None] if the queue is empty or the position is past the last track.
+  pub fn get_current(&self) -> Option<Weak<Track>> {
     let tracks = self.tracks.read().unwrap();
-    Arc::downgrade(tracks.get(self.position()).unwrap())
+    tracks.get(self.position()).map(Arc::downgrade)
   }
 
-  pub fn push(&self, track: Track) -> (Arc<Track>, usize) {
+  /// Appends `track` to the queue.
+  ///
+  /// Fails if the queue is full, or if deduplication is enabled and a track with the same [Track::source]
+  /// is already queued. Metadata is not available synchronously, use [Queue::remove_duplicates] to compare URLs.
+  pub fn push(&self, track: Track) -> Result<(Arc<Track>, usize), QueueError> {
     let mut tracks = self.tracks.write().unwrap();
+    self.check_can_add(&tracks, &track)?;
+
     let track = Arc::new(track);
     tracks.push(track.clone());
-    (track, tracks.len() - 1)
+    Ok((track, tracks.len() - 1))
+  }
+
+  /// Removes tracks with the same [MediaMetadata::Url] as an earlier track, and returns the number of removed tracks.
+  ///
+  /// Tracks without a URL are compared by [Track::source]. The current track is never removed,
+  /// and the position is adjusted so it stays current.
+  pub async fn remove_duplicates(&self) -> usize {
+    // Metadata is fetched without holding the lock, tracks are removed by identity afterwards
+    let tracks = self.tracks.read().unwrap().clone();
+    let mut urls = HashSet::new();
+    let mut duplicates = Vec::new();
+    for track in tracks {
+      let url = match track.provider.get_metadata().await {
+        Ok(metadata) => get_metadata!(metadata, MediaMetadata::Url(url) => url.clone()),
+        Err(_) => None
+      };
+      let Some(url) = url.or_else(|| track.source.clone()) else {
+        continue;
+      };
+      if !urls.insert(url) {
+        duplicates.push(track);
+      }
+    }
+
+    let mut tracks = self.tracks.write().unwrap();
+    let current = tracks.get(self.position()).cloned();
+    let before = tracks.len();
+    tracks.retain(|track| {
+      let is_current = current.as_ref().is_some_and(|current| Arc::ptr_eq(current, track));
+      is_current || !duplicates.iter().any(|duplicate| Arc::ptr_eq(duplicate, track))
+    });
+
+    if let Some(current) = current {
+      if let Some(position) = tracks.iter().position(|track| Arc::ptr_eq(track, &current)) {
+        self.set_position(position);
+      }
+    }
+    before - tracks.len()
+  }
+
+  /// Inserts `track` at `index` (clamped to the queue length), shifting the following tracks.
+  ///
+  /// If inserted at or before the current track, the position is shifted too, so the current track stays current.
+  /// Fails like [Queue::push].
+  pub fn insert(&self, index: usize, track: Track) -> Result<(Arc<Track>, usize), QueueError> {
+    let mut tracks = self.tracks.write().unwrap();
+    self.check_can_add(&tracks, &track)?;
+    let index = index.min(tracks.len());
+    let track = Arc::new(track);
+    tracks.insert(index, track.clone());
+
+    // Adjust while holding the lock, so concurrent inserts see a consistent position
+    let position = self.position();
+    if index <= position && tracks.len() > 1 {
+      self.set_position(position + 1);
+    }
+    Ok((track, index))
   }
 }
 
@@ -118,23 +231,20 @@ impl LoopPlayMode {
 }
 
 impl PlayMode for LoopPlayMode {
+  /// Wraps around both ends of the queue, so any offset is valid.
   fn seek(&self, offset: isize, _force: bool) -> Option<usize> {
-    assert_eq!(offset, 1); // TODO(Assasans): Not implemented
-
     let queue = match self.queue.upgrade() {
       Some(queue) => queue,
       None => unreachable!("queue droppped")
     };
 
-    if queue.len() < 1 {
+    let len = queue.len();
+    if len < 1 {
       return None;
     }
 
-    if queue.position() < queue.len() {
-      Some(queue.position() + 1)
-    } else {
-      Some(0)
-    }
+    let position = (queue.position() as isize + offset).rem_euclid(len as isize);
+    Some(position as usize)
   }
 }
 
@@ -143,3 +253,257 @@ impl Debug for LoopPlayMode {
     f.debug_struct("LoopPlayMode").finish()
   }
 }
+
+/// Plays tracks in random order, every track is played once before any track repeats.
+///
+/// Unlike shuffling the queue upfront, tracks added later are included too.
+pub struct RandomPlayMode {
+  queue: Weak<Queue>,
+  /// Indices of the tracks played in the current round.
+  seen: Mutex<Vec<usize>>
+}
+
+impl RandomPlayMode {
+  pub fn new(queue: Weak<Queue>) -> Self {
+    Self {
+      queue,
+      seen: Mutex::new(Vec::new())
+    }
+  }
+}
+
+impl PlayMode for RandomPlayMode {
+  /// User initiated seeks are relative like in [NormalPlayMode], automatic seeks pick a random unplayed track.
+  fn seek(&self, offset: isize, force: bool) -> Option<usize> {
+    let queue = match self.queue.upgrade() {
+      Some(queue) => queue,
+      None => unreachable!("queue droppped")
+    };
+
+    let len = queue.len();
+    let current = queue.position();
+    let mut seen = self.seen.lock().unwrap();
+    // Tracks may have been removed
+    seen.retain(|index| *index < len);
+    if current < len && !seen.contains(&current) {
+      seen.push(current);
+    }
+
+    let next = if force {
+      let position = (current as isize + offset) as usize;
+      (0..len).contains(&position).then_some(position)?
+    } else {
+      let mut unplayed = (0..len).filter(|index| !seen.contains(index)).collect::<Vec<_>>();
+      if unplayed.is_empty() {
+        // Start a new round, without repeating the current track right away
+        seen.clear();
+        unplayed = (0..len).filter(|index| *index != current || len == 1).collect();
+      }
+      *unplayed.choose(&mut rand::thread_rng())?
+    };
+
+    seen.push(next);
+    Some(next)
+  }
+}
+
+impl Debug for RandomPlayMode {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("RandomPlayMode").finish()
+  }
+}
+
+#[test]
+fn insert_keeps_current_track() {
+  use crate::providers::FFmpegMediaProvider;
+
+  let new_track = |name: &str| Track::new(Box::new(FFmpegMediaProvider::new(name.to_owned())), None);
+  let queue = Queue::new();
+
+  // Inserting into an empty queue does not move the position
+  let (first, index) = queue.insert(5, new_track("first")).unwrap();
+  assert_eq!((index, queue.position()), (0, 0));
+  queue.push(new_track("second")).unwrap();
+  queue.push(new_track("third")).unwrap();
+
+  queue.set_position(1);
+  let current = queue.get_current().unwrap().upgrade().unwrap();
+
+  // Play next
+  let (next, index) = queue.insert(queue.position() + 1, new_track("next")).unwrap();
+  assert_eq!((index, queue.position()), (2, 1));
+  // Before the current track
+  queue.insert(0, new_track("before")).unwrap();
+  assert_eq!(queue.position(), 2);
+  assert!(Arc::ptr_eq(&queue.get_current().unwrap().upgrade().unwrap(), &current));
+
+  // Automatic seek goes to the inserted track
+  let position = queue.mode.read().unwrap().seek(1, false).unwrap();
+  queue.set_position(position);
+  assert!(Arc::ptr_eq(&queue.get_current().unwrap().upgrade().unwrap(), &next));
+
+  // A batch stays contiguous and in order
+  let mut index = queue.position() + 1;
+  let batch = (0..3)
+    .map(|i| {
+      let (track, inserted) = queue.insert(index, new_track(&format!("batch {}", i))).unwrap();
+      index = inserted + 1;
+      track
+    })
+    .collect::<Vec<_>>();
+  let tracks = queue.tracks.read().unwrap();
+  for (offset, track) in batch.iter().enumerate() {
+    assert!(Arc::ptr_eq(&tracks[position + 1 + offset], track));
+  }
+  assert!(Arc::ptr_eq(&tracks[1], &first));
+  assert_eq!(tracks.len(), 8);
+}
+
+#[test]
+fn get_current_is_none_out_of_bounds() {
+  use crate::providers::FFmpegMediaProvider;
+
+  let queue = Queue::new();
+  assert!(queue.get_current().is_none());
+
+  queue.push(Track::new(Box::new(FFmpegMediaProvider::new("only".to_owned())), None)).unwrap();
+  assert!(queue.get_current().is_some());
+
+  // After the last track finished
+  queue.set_position(1);
+  assert!(queue.get_current().is_none());
+}
+
+#[test]
+fn loop_mode_wraps_around() {
+  use crate::providers::FFmpegMediaProvider;
+
+  let queue = Queue::new();
+  queue.set_mode(Box::new(LoopPlayMode::new(Arc::downgrade(&queue))));
+  let seek = |offset| queue.mode.read().unwrap().seek(offset, false);
+  assert_eq!(seek(1), None);
+
+  for name in ["first", "second", "third"] {
+    queue.push(Track::new(Box::new(FFmpegMediaProvider::new(name.to_owned())), None)).unwrap();
+  }
+
+  assert_eq!(seek(1), Some(1));
+  assert_eq!(seek(-1), Some(2));
+  assert_eq!(seek(0), Some(0));
+
+  // From the last track back to the first
+  queue.set_position(2);
+  assert_eq!(seek(1), Some(0));
+  assert_eq!(seek(-1), Some(1));
+  assert_eq!(seek(4), Some(0));
+  assert_eq!(seek(-5), Some(0));
+}
+
+#[test]
+fn random_mode_plays_every_track_once_per_round() {
+  use std::collections::HashSet;
+
+  use crate::providers::FFmpegMediaProvider;
+
+  let queue = Queue::new();
+  queue.set_mode(Box::new(RandomPlayMode::new(Arc::downgrade(&queue))));
+  assert_eq!(queue.mode.read().unwrap().seek(1, false), None);
+
+  for index in 0..5 {
+    queue.push(Track::new(Box::new(FFmpegMediaProvider::new(index.to_string())), None)).unwrap();
+  }
+  let next = || {
+    let position = queue.mode.read().unwrap().seek(1, false).unwrap();
+    queue.set_position(position);
+    position
+  };
+
+  // The first track is already playing
+  let round = (0..4).map(|_| next()).collect::<HashSet<_>>();
+  assert_eq!(round, (1..5).collect());
+
+  for _ in 0..10 {
+    // No immediate repetition when a new round starts
+    let previous = queue.position();
+    let round = (0..5).map(|_| next()).collect::<Vec<_>>();
+    assert_ne!(round[0], previous);
+    assert_eq!(round.iter().collect::<HashSet<_>>().len(), 5);
+  }
+
+  // User initiated seeks are relative
+  queue.set_position(2);
+  assert_eq!(queue.mode.read().unwrap().seek(-1, true), Some(1));
+  assert_eq!(queue.mode.read().unwrap().seek(3, true), None);
+}
+
+#[tokio::test]
+async fn removes_duplicates_keeping_current_track() {
+  use crate::providers::FFmpegMediaProvider;
+
+  let new_track = |name: &str| Track::new(Box::new(FFmpegMediaProvider::new(name.to_owned())), None);
+  let queue = Queue::new();
+  for name in ["a", "b", "a", "c", "b", "a"] {
+    queue.push(new_track(name)).unwrap();
+  }
+
+  // "c" is current
+  queue.set_position(3);
+  let current = queue.get_current().unwrap().upgrade().unwrap();
+  assert_eq!(queue.remove_duplicates().await, 3);
+  assert_eq!(queue.position(), 2);
+  assert!(Arc::ptr_eq(&queue.get_current().unwrap().upgrade().unwrap(), &current));
+
+  let sources = queue.tracks.read().unwrap().iter().map(|track| track.source.clone().unwrap()).collect::<Vec<_>>();
+  assert_eq!(sources, vec!["ffmpeg:a", "ffmpeg:b", "ffmpeg:c"]);
+  assert_eq!(queue.remove_duplicates().await, 0);
+
+  // Deduplicated on push
+  assert!(queue.push(new_track("a")).is_ok());
+  queue.set_dedup(true);
+  assert_eq!(queue.push(new_track("b")).unwrap_err(), QueueError::Duplicate);
+  assert_eq!(queue.push(new_track("d")).unwrap().1, 4);
+}
+
+#[test]
+fn enforces_max_length() {
+  use crate::providers::FFmpegMediaProvider;
+
+  let new_track = |name: &str| Track::new(Box::new(FFmpegMediaProvider::new(name.to_owned())), None);
+  let queue = Queue::new();
+  assert_eq!(queue.remaining_capacity(), None);
+
+  queue.set_max_length(Some(2));
+  assert_eq!(queue.remaining_capacity(), Some(2));
+  queue.push(new_track("first")).unwrap();
+  queue.insert(0, new_track("second")).unwrap();
+  assert_eq!(queue.remaining_capacity(), Some(0));
+  assert_eq!(queue.push(new_track("third")).unwrap_err(), QueueError::Full(2));
+  assert_eq!(queue.insert(0, new_track("third")).unwrap_err(), QueueError::Full(2));
+
+  // Lowering the limit keeps queued tracks
+  queue.set_max_length(Some(1));
+  assert_eq!(queue.len(), 2);
+  assert_eq!(queue.remaining_capacity(), Some(0));
+  queue.set_max_length(None);
+  assert!(queue.push(new_track("third")).is_ok());
+}
+
+#[test]
+fn insert_rejects_duplicates() {
+  use crate::providers::FFmpegMediaProvider;
+
+  let new_track = |name: &str| Track::new(Box::new(FFmpegMediaProvider::new(name.to_owned())), None);
+  let queue = Queue::new();
+  queue.push(new_track("a")).unwrap();
+  queue.push(new_track("b")).unwrap();
+  queue.set_position(1);
+
+  // Allowed without deduplication
+  queue.insert(2, new_track("a")).unwrap();
+  queue.set_dedup(true);
+  assert_eq!(queue.insert(2, new_track("b")).unwrap_err(), QueueError::Duplicate);
+  assert_eq!(queue.insert(0, new_track("a")).unwrap_err(), QueueError::Duplicate);
+  // A rejected track does not move the position
+  assert_eq!((queue.len(), queue.position()), (3, 1));
+  assert_eq!(queue.insert(2, new_track("c")).unwrap().1, 2);
+}
@@ -1,15 +1,166 @@
+use std::time::{Duration, Instant};
+
 use serenity::all::UserId;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::providers::{MediaMetadata, MediaProvider};
+use crate::util::format_duration;
 
-use crate::providers::MediaProvider;
+/// Failed metadata fetches are not retried for this long, see [Track::metadata].
+pub const METADATA_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+enum CachedMetadata {
+  Fetched(Vec<MediaMetadata>),
+  Failed { at: Instant }
+}
 
 #[derive(Debug)]
 pub struct Track {
   pub provider: Box<dyn MediaProvider>,
-  pub creator: Option<UserId>
+  pub creator: Option<UserId>,
+  /// Explicit source the track was created from, see [MediaProvider::source].
+  pub source: Option<String>,
+  /// Position playback starts at, see [Track::with_clip].
+  pub start: Option<Duration>,
+  /// Position playback ends at, see [Track::with_clip].
+  pub end: Option<Duration>,
+  metadata_cache: Mutex<Option<CachedMetadata>>
 }
 
 impl Track {
   pub fn new(provider: Box<dyn MediaProvider>, creator: Option<UserId>) -> Self {
-    Self { provider, creator }
+    let source = provider.source();
+    Self {
+      provider,
+      creator,
+      source,
+      start: None,
+      end: None,
+      metadata_cache: Mutex::new(None)
+    }
+  }
+
+  /// Returns the metadata of the provider, fetched once and cached.
+  ///
+  /// Empty if the fetch failed, it is retried after [METADATA_RETRY_AFTER],
+  /// so that a failing provider does not slow down every call.
+  pub async fn metadata(&self) -> Vec<MediaMetadata> {
+    // Held while fetching, so that concurrent calls fetch only once
+    let mut cache = self.metadata_cache.lock().await;
+    match &*cache {
+      Some(CachedMetadata::Fetched(metadata)) => return metadata.clone(),
+      Some(CachedMetadata::Failed { at }) if at.elapsed() < METADATA_RETRY_AFTER => return Vec::new(),
+      _ => {}
+    }
+
+    match self.provider.get_metadata().await {
+      Ok(metadata) => {
+        *cache = Some(CachedMetadata::Fetched(metadata.clone()));
+        metadata
+      }
+      Err(error) => {
+        warn!("failed to get track metadata: {:?}", error);
+        *cache = Some(CachedMetadata::Failed { at: Instant::now() });
+        Vec::new()
+      }
+    }
+  }
+
+  /// Plays only the part of the media from `start` until `end`.
+  pub fn with_clip(mut self, start: Option<Duration>, end: Option<Duration>) -> Self {
+    self.start = start;
+    self.end = end;
+    self
+  }
+
+  pub fn is_clipped(&self) -> bool {
+    self.start.is_some() || self.end.is_some()
+  }
+
+  /// Clamps a seek `position` to the clip bounds.
+  pub fn clamp_position(&self, position: Duration) -> Duration {
+    let position = position.max(self.start.unwrap_or_default());
+    match self.end {
+      Some(end) => position.min(end),
+      None => position
+    }
+  }
+
+  /// Formats the clip range, e.g. `1:23:45-1:25:00` or `1:30-`. [None] if the track is not clipped.
+  pub fn format_clip(&self) -> Option<String> {
+    if !self.is_clipped() {
+      return None;
+    }
+
+    Some(format!(
+      "{}-{}",
+      format_duration(self.start.unwrap_or_default()),
+      self.end.map(format_duration).unwrap_or_default()
+    ))
+  }
+}
+
+#[test]
+fn clamps_seek_position_to_clip() {
+  use crate::providers::FFmpegMediaProvider;
+
+  let track = Track::new(Box::new(FFmpegMediaProvider::new("track".to_owned())), None)
+    .with_clip(Some(Duration::from_secs(60)), Some(Duration::from_secs(90)));
+  assert_eq!(track.clamp_position(Duration::from_secs(10)), Duration::from_secs(60));
+  assert_eq!(track.clamp_position(Duration::from_secs(75)), Duration::from_secs(75));
+  assert_eq!(track.clamp_position(Duration::from_secs(120)), Duration::from_secs(90));
+  assert_eq!(track.format_clip().as_deref(), Some("1:00-1:30"));
+
+  let track = Track::new(Box::new(FFmpegMediaProvider::new("track".to_owned())), None);
+  assert_eq!(track.clamp_position(Duration::from_secs(120)), Duration::from_secs(120));
+  assert_eq!(track.format_clip(), None);
+}
+
+#[tokio::test]
+async fn caches_metadata_and_failures() {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  use anyhow::{anyhow, Result};
+  use async_trait::async_trait;
+  use voice::provider::SampleProvider;
+
+  #[derive(Debug)]
+  struct CountingMediaProvider {
+    calls: Arc<AtomicUsize>,
+    fail: bool
+  }
+
+  #[async_trait]
+  impl MediaProvider for CountingMediaProvider {
+    async fn get_sample_provider(&self) -> Result<Box<dyn SampleProvider>> {
+      Err(anyhow!("not playable"))
+    }
+
+    async fn get_metadata(&self) -> Result<Vec<MediaMetadata>> {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      if self.fail {
+        return Err(anyhow!("unavailable"));
+      }
+      Ok(vec![MediaMetadata::Title("Title".to_owned())])
+    }
+  }
+
+  for fail in [false, true] {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let track = Track::new(
+      Box::new(CountingMediaProvider {
+        calls: calls.clone(),
+        fail
+      }),
+      None
+    );
+
+    let expected = if fail { vec![] } else { vec![MediaMetadata::Title("Title".to_owned())] };
+    assert_eq!(track.metadata().await, expected);
+    assert_eq!(track.metadata().await, expected);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
   }
 }
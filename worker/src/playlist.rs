@@ -0,0 +1,275 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::provider_predictor::{parse_explicit_provider, PredictedProvider};
+use crate::providers::{
+  FFmpegMediaProvider, HlsMediaProvider, HttpContext, MediaProvider, SberzvukMediaProvider, VkMediaProvider,
+  YtDlpMediaProvider
+};
+
+pub const MAX_PLAYLIST_NAME_LENGTH: usize = 32;
+/// Maximum number of tracks in a saved guild queue, see [StateRef::saved_queues](crate::StateRef::saved_queues).
+pub const MAX_SAVED_QUEUE_LENGTH: usize = 200;
+
+/// A saved queue, stored as a JSON array of its entries.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Playlist {
+  pub entries: Vec<PlaylistEntry>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+  /// Provider name, e.g. `yt-dlp`, see [parse_explicit_provider].
+  pub provider: String,
+  /// Provider input, e.g. a URL or a track ID.
+  pub id: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>
+}
+
+impl PlaylistEntry {
+  /// Returns [None] if `source` is not an explicit provider source,
+  /// see [MediaProvider::source](crate::providers::MediaProvider::source).
+  pub fn from_source(source: &str, title: Option<String>) -> Option<Self> {
+    let (provider, id) = source.split_once(':')?;
+    Some(Self {
+      provider: provider.to_owned(),
+      id: id.to_owned(),
+      title
+    })
+  }
+
+  fn predicted_provider(&self) -> Result<PredictedProvider> {
+    parse_explicit_provider(&format!("{}:{}", self.provider, self.id))?
+      .map(|(provider, _)| provider)
+      .with_context(|| format!("unknown provider `{}`", self.provider))
+  }
+
+  fn wrong_provider(&self, expected: &str) -> anyhow::Error {
+    anyhow!("expected a `{}` entry, got `{}`", expected, self.provider)
+  }
+
+  /// Creates the media provider of the entry, see the [TryFrom] implementations of the providers.
+  pub fn create_provider(&self, http: &HttpContext) -> Result<Box<dyn MediaProvider>> {
+    Ok(match self.predicted_provider()? {
+      PredictedProvider::FFmpeg => Box::new(FFmpegMediaProvider::try_from(self)?),
+      PredictedProvider::YtDlp => Box::new(YtDlpMediaProvider::try_from(self)?),
+      PredictedProvider::Sberzvuk(_) => Box::new(SberzvukMediaProvider::try_from((self, http))?),
+      PredictedProvider::Vk { .. } => Box::new(VkMediaProvider::try_from((self, http))?),
+      PredictedProvider::Hls => Box::new(HlsMediaProvider::try_from((self, http))?),
+      // Never saved, tracks of playlists are saved individually
+      PredictedProvider::YtDlpPlaylist => return Err(self.wrong_provider("single track"))
+    })
+  }
+}
+
+impl TryFrom<&PlaylistEntry> for FFmpegMediaProvider {
+  type Error = anyhow::Error;
+
+  fn try_from(entry: &PlaylistEntry) -> Result<Self> {
+    match entry.predicted_provider()? {
+      PredictedProvider::FFmpeg => Ok(Self::new(entry.id.clone())),
+      _ => Err(entry.wrong_provider("ffmpeg"))
+    }
+  }
+}
+
+impl TryFrom<&PlaylistEntry> for YtDlpMediaProvider {
+  type Error = anyhow::Error;
+
+  fn try_from(entry: &PlaylistEntry) -> Result<Self> {
+    match entry.predicted_provider()? {
+      PredictedProvider::YtDlp => Ok(Self::new(entry.id.clone())),
+      _ => Err(entry.wrong_provider("yt-dlp"))
+    }
+  }
+}
+
+impl TryFrom<(&PlaylistEntry, &HttpContext)> for SberzvukMediaProvider {
+  type Error = anyhow::Error;
+
+  fn try_from((entry, http): (&PlaylistEntry, &HttpContext)) -> Result<Self> {
+    match entry.predicted_provider()? {
+      PredictedProvider::Sberzvuk(id) => Ok(Self::new(id, http.clone())),
+      _ => Err(entry.wrong_provider("zvuk"))
+    }
+  }
+}
+
+impl TryFrom<(&PlaylistEntry, &HttpContext)> for VkMediaProvider {
+  type Error = anyhow::Error;
+
+  fn try_from((entry, http): (&PlaylistEntry, &HttpContext)) -> Result<Self> {
+    match entry.predicted_provider()? {
+      PredictedProvider::Vk { owner_id, track_id } => Ok(Self::new(owner_id, track_id, http.clone())),
+      _ => Err(entry.wrong_provider("vk"))
+    }
+  }
+}
+
+impl TryFrom<(&PlaylistEntry, &HttpContext)> for HlsMediaProvider {
+  type Error = anyhow::Error;
+
+  fn try_from((entry, http): (&PlaylistEntry, &HttpContext)) -> Result<Self> {
+    match entry.predicted_provider()? {
+      PredictedProvider::Hls => Ok(Self::new(entry.id.clone(), http.clone())),
+      _ => Err(entry.wrong_provider("hls"))
+    }
+  }
+}
+
+/// Playlist storage namespaced by owner, a user ID for playlists and a guild ID for saved queues.
+#[async_trait]
+pub trait PlaylistStorage: Send + Sync {
+  async fn save(&self, owner: u64, name: &str, playlist: &Playlist) -> Result<()>;
+  /// Returns [None] if there is no playlist with the name.
+  async fn load(&self, owner: u64, name: &str) -> Result<Option<Playlist>>;
+  async fn list(&self, owner: u64) -> Result<Vec<String>>;
+  /// Returns `false` if there is no playlist with the name.
+  async fn delete(&self, owner: u64, name: &str) -> Result<bool>;
+}
+
+/// Stores playlists as `<root>/<owner ID>/<name>.json`.
+pub struct FsPlaylistStorage {
+  root: PathBuf
+}
+
+impl FsPlaylistStorage {
+  pub fn new(root: impl Into<PathBuf>) -> Self {
+    Self { root: root.into() }
+  }
+
+  fn owner_dir(&self, owner: u64) -> PathBuf {
+    self.root.join(owner.to_string())
+  }
+
+  fn path(&self, owner: u64, name: &str) -> Result<PathBuf> {
+    validate_name(name)?;
+    Ok(self.owner_dir(owner).join(format!("{}.json", name)))
+  }
+}
+
+#[async_trait]
+impl PlaylistStorage for FsPlaylistStorage {
+  async fn save(&self, owner: u64, name: &str, playlist: &Playlist) -> Result<()> {
+    let path = self.path(owner, name)?;
+    fs::create_dir_all(self.owner_dir(owner)).await?;
+    fs::write(&path, serde_json::to_vec_pretty(playlist)?)
+      .await
+      .with_context(|| format!("failed to write {}", path.display()))
+  }
+
+  async fn load(&self, owner: u64, name: &str) -> Result<Option<Playlist>> {
+    let path = self.path(owner, name)?;
+    let data = match fs::read(&path).await {
+      Ok(data) => data,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(error) => return Err(error.into())
+    };
+    let playlist = serde_json::from_slice(&data).with_context(|| format!("invalid playlist {}", path.display()))?;
+    Ok(Some(playlist))
+  }
+
+  async fn list(&self, owner: u64) -> Result<Vec<String>> {
+    let mut entries = match fs::read_dir(self.owner_dir(owner)).await {
+      Ok(entries) => entries,
+      Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error.into())
+    };
+
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      if path.extension().is_some_and(|extension| extension == "json") {
+        if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+          names.push(name.to_owned());
+        }
+      }
+    }
+    names.sort();
+    Ok(names)
+  }
+
+  async fn delete(&self, owner: u64, name: &str) -> Result<bool> {
+    match fs::remove_file(self.path(owner, name)?).await {
+      Ok(()) => Ok(true),
+      Err(error) if error.kind() == ErrorKind::NotFound => Ok(false),
+      Err(error) => Err(error.into())
+    }
+  }
+}
+
+/// Names are used as file names, so only ASCII letters, digits, `-` and `_` are allowed.
+pub fn validate_name(name: &str) -> Result<()> {
+  if name.is_empty() || name.len() > MAX_PLAYLIST_NAME_LENGTH {
+    return Err(anyhow!("Playlist name must be 1 to {} characters long", MAX_PLAYLIST_NAME_LENGTH));
+  }
+  if !name.chars().all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_') {
+    return Err(anyhow!("Playlist name may only contain letters, digits, `-` and `_`"));
+  }
+  Ok(())
+}
+
+#[tokio::test]
+async fn fs_storage_round_trip() {
+  use std::{env, process};
+
+  let root = env::temp_dir().join(format!("mosaik-playlists-{}", process::id()));
+  let storage = FsPlaylistStorage::new(&root);
+  let owner = 42;
+
+  let playlist = Playlist {
+    entries: vec![
+      PlaylistEntry::from_source("yt-dlp:https://youtu.be/dQw4w9WgXcQ", Some("Title".to_owned())).unwrap(),
+      PlaylistEntry::from_source("zvuk:126413867", None).unwrap()
+    ]
+  };
+  storage.save(owner, "mix", &playlist).await.unwrap();
+  // Entries without a title are stored as `{ "provider", "id" }`
+  let saved = std::fs::read_to_string(root.join("42").join("mix.json")).unwrap();
+  assert_eq!(
+    serde_json::from_str::<serde_json::Value>(&saved).unwrap(),
+    serde_json::json!([
+      { "provider": "yt-dlp", "id": "https://youtu.be/dQw4w9WgXcQ", "title": "Title" },
+      { "provider": "zvuk", "id": "126413867" }
+    ])
+  );
+  assert_eq!(storage.load(owner, "mix").await.unwrap(), Some(playlist));
+  assert_eq!(storage.list(owner).await.unwrap(), vec!["mix".to_owned()]);
+  // Namespaced per owner
+  assert!(storage.list(43).await.unwrap().is_empty());
+  assert_eq!(storage.load(43, "mix").await.unwrap(), None);
+
+  assert!(storage.delete(owner, "mix").await.unwrap());
+  assert!(!storage.delete(owner, "mix").await.unwrap());
+  assert_eq!(storage.load(owner, "mix").await.unwrap(), None);
+  assert!(storage.save(owner, "../escape", &Playlist::default()).await.is_err());
+
+  fs::remove_dir_all(&root).await.unwrap();
+}
+
+#[test]
+fn entries_convert_to_providers() {
+  let http = HttpContext::default();
+  let entry = |source: &str| PlaylistEntry::from_source(source, None).unwrap();
+
+  for source in ["ffmpeg:track.mp3", "yt-dlp:https://youtu.be/dQw4w9WgXcQ", "zvuk:126413867", "vk:-2001_456239017"] {
+    let provider = entry(source).create_provider(&http).unwrap();
+    assert_eq!(provider.source().as_deref(), Some(source));
+  }
+  assert!(entry("yt-dlp-playlist:https://youtube.com/playlist?list=PL").create_provider(&http).is_err());
+  assert!(entry("unknown:query").create_provider(&http).is_err());
+  // Known provider, but an invalid ID
+  assert!(entry("vk:not-an-id").create_provider(&http).is_err());
+
+  let provider = FFmpegMediaProvider::try_from(&entry("ffmpeg:track.mp3")).unwrap();
+  assert_eq!(provider.source().as_deref(), Some("ffmpeg:track.mp3"));
+  assert!(YtDlpMediaProvider::try_from(&entry("ffmpeg:track.mp3")).is_err());
+  assert!(SberzvukMediaProvider::try_from((&entry("vk:-2001_456239017"), &http)).is_err());
+}
@@ -1,5 +1,120 @@
+use std::cmp::Ordering;
+
+use anyhow::{Context, Result};
 use regex::Regex;
 
+/// Compiles the regex once and returns a `&'static Regex`.
+macro_rules! regex {
+  ($pattern:expr) => {{
+    static REGEX: ::std::sync::OnceLock<Regex> = ::std::sync::OnceLock::new();
+    REGEX.get_or_init(|| Regex::new($pattern).unwrap())
+  }};
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlKind {
+  YouTubeVideo,
+  /// `has_video` is set for links to a video opened from a playlist.
+  YouTubePlaylist { has_video: bool },
+  SoundCloudTrack,
+  SoundCloudPlaylist,
+  BandcampTrack,
+  BandcampAlbum,
+  Spotify,
+  Zvuk { track_id: i64 },
+  VkAudio { owner_id: i64, track_id: i64 },
+  /// HTTP Live Streaming playlist, a URL with an `.m3u8` path.
+  Hls,
+  /// Any other URL, possibly a direct link to a media file or stream.
+  Other,
+  NotUrl
+}
+
+/// Classifies `url` by the service it points to. Does not allocate once the regexes are compiled.
+pub fn classify_url(url: &str) -> UrlKind {
+  if regex!(r"^(?:https?://)?(?:www\.|m\.|music\.)?(?:youtube\.com|youtu\.be)/").is_match(url) {
+    let has_video = regex!(r"(?:youtube\.com/(?:watch\?(?:\S*&)?v=|shorts/)|youtu\.be/)[\w\-]+").is_match(url);
+    if regex!(r"[?&]list=[^#&]+").is_match(url) {
+      return UrlKind::YouTubePlaylist { has_video };
+    }
+    if has_video {
+      return UrlKind::YouTubeVideo;
+    }
+  }
+
+  // Regex::captures allocates even if there is no match, so IDs are split off the match manually
+  if let Some(found) = regex!(r"^(?:https?://)?(?:www\.)?(?:sber)?zvuk\.com/track/\d+").find(url) {
+    if let Some(Ok(track_id)) = found.as_str().rsplit('/').next().map(str::parse::<i64>) {
+      return UrlKind::Zvuk { track_id };
+    }
+  }
+
+  if let Some(found) = regex!(r"^(?:https?://)?(?:www\.|m\.)?vk\.com/.*audio-?\d+_\d+").find(url) {
+    if let Some((owner_id, track_id)) = found.as_str().rsplit_once("audio").and_then(|(_, id)| parse_vk_id(id)) {
+      return UrlKind::VkAudio { owner_id, track_id };
+    }
+  }
+
+  if regex!(r"^(?:https?://)?(?:www\.|m\.)?soundcloud\.com/[^/?#]+/sets/").is_match(url) {
+    return UrlKind::SoundCloudPlaylist;
+  }
+  if regex!(r"^(?:https?://)?(?:www\.|m\.)?soundcloud\.com/[^/?#]+/[^/?#]+").is_match(url) {
+    return UrlKind::SoundCloudTrack;
+  }
+
+  if regex!(r"^(?:https?://)?[\w\-]+\.bandcamp\.com/album/").is_match(url) {
+    return UrlKind::BandcampAlbum;
+  }
+  if regex!(r"^(?:https?://)?[\w\-]+\.bandcamp\.com/track/").is_match(url) {
+    return UrlKind::BandcampTrack;
+  }
+
+  if regex!(r"^(?:https?://)?open\.spotify\.com/").is_match(url) {
+    return UrlKind::Spotify;
+  }
+
+  if regex!(r"^(?:https?://)?(?:[\w\-]+\.)+[a-z]{2,}(?::\d+)?/[^?#\s]*\.m3u8(?:[?#]\S*)?$").is_match(url) {
+    return UrlKind::Hls;
+  }
+
+  if regex!(r"^(?:[a-z]+://)?(?:[\w\-]+\.)+[a-z]{2,}(?::\d+)?(?:[/?#]\S*)?$").is_match(url) {
+    return UrlKind::Other;
+  }
+
+  UrlKind::NotUrl
+}
+
+/// Parses VK audio ID in `<owner_id>_<track_id>` format.
+pub fn parse_vk_id(id: &str) -> Option<(i64, i64)> {
+  let (owner_id, track_id) = id.split_once('_')?;
+  Some((owner_id.parse().ok()?, track_id.parse().ok()?))
+}
+
+/// Parses an explicitly selected provider, e.g. `yt-dlp:<url>` or `vk:<owner_id>_<track_id>`.
+///
+/// Returns [`None`] if `source` does not start with a known provider name.
+pub fn parse_explicit_provider(source: &str) -> Result<Option<(PredictedProvider, &str)>> {
+  let (name, input) = match source.split_once(':') {
+    Some(splitted) => splitted,
+    None => return Ok(None)
+  };
+
+  let provider = match name {
+    "ffmpeg" => PredictedProvider::FFmpeg,
+    "yt-dlp" => PredictedProvider::YtDlp,
+    "yt-dlp-playlist" => PredictedProvider::YtDlpPlaylist,
+    "hls" => PredictedProvider::Hls,
+    "zvuk" => PredictedProvider::Sberzvuk(input.parse::<i64>()?),
+    "vk" => {
+      let (owner_id, track_id) = parse_vk_id(input).context("invalid VK audio ID, expected <owner_id>_<track_id>")?;
+      PredictedProvider::Vk { owner_id, track_id }
+    }
+    _ => return Ok(None)
+  };
+
+  Ok(Some((provider, input)))
+}
+
 pub struct MediaProviderPredictor {}
 
 impl MediaProviderPredictor {
@@ -7,25 +122,55 @@ impl MediaProviderPredictor {
     MediaProviderPredictor {}
   }
 
+  /// Returns predictions ranked by score, highest first. Never returns an empty vector.
   pub fn predict(&self, query: &str) -> Vec<PredictionResult> {
-    if Regex::new(r"(youtube\.com|youtu\.be)").unwrap().is_match(query) {
-      if Regex::new(r"[?&]list=([^#?&]*)").unwrap().is_match(query) {
-        return vec![PredictionResult::new(0.9, PredictedProvider::YtDlpPlaylist)];
-      }
+    let mut results = Vec::new();
 
-      if Regex::new(r"https?://(?:www\.)?youtu(?:be\.com/watch\?v=|\.be/)([\w\-_]+)").unwrap().is_match(query) {
-        return vec![PredictionResult::new(0.9, PredictedProvider::YtDlp)];
+    let kind = classify_url(query);
+    match kind {
+      UrlKind::YouTubeVideo | UrlKind::SoundCloudTrack | UrlKind::BandcampTrack => {
+        results.push(PredictionResult::new(0.8, PredictedProvider::YtDlp));
+      }
+      UrlKind::YouTubePlaylist { has_video } => {
+        results.push(PredictionResult::new(0.9, PredictedProvider::YtDlpPlaylist));
+        if has_video {
+          results.push(PredictionResult::new(0.8, PredictedProvider::YtDlp));
+        }
       }
+      UrlKind::SoundCloudPlaylist | UrlKind::BandcampAlbum => {
+        results.push(PredictionResult::new(0.8, PredictedProvider::YtDlpPlaylist));
+      }
+      // yt-dlp only supports Spotify podcasts, music is DRM-protected
+      UrlKind::Spotify => results.push(PredictionResult::new(0.3, PredictedProvider::YtDlp)),
+      UrlKind::Zvuk { track_id } => results.push(PredictionResult::new(0.9, PredictedProvider::Sberzvuk(track_id))),
+      UrlKind::VkAudio { owner_id, track_id } => {
+        results.push(PredictionResult::new(0.9, PredictedProvider::Vk { owner_id, track_id }));
+      }
+      UrlKind::Hls => results.push(PredictionResult::new(0.9, PredictedProvider::Hls)),
+      UrlKind::Other | UrlKind::NotUrl => {}
     }
-    vec![]
+
+    // Pages of recognised services are not media files, so FFmpeg is only tried after their providers
+    let fallback_score = match kind {
+      UrlKind::Other => 0.5,
+      UrlKind::NotUrl => 0.1,
+      _ => 0.2
+    };
+    results.push(PredictionResult::new(fallback_score, PredictedProvider::FFmpeg));
+
+    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    results
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PredictedProvider {
   FFmpeg,
   YtDlp,
   YtDlpPlaylist,
+  Sberzvuk(i64),
+  Vk { owner_id: i64, track_id: i64 },
+  Hls,
 }
 
 #[derive(Debug)]
@@ -39,3 +184,128 @@ impl PredictionResult {
     PredictionResult { score, provider }
   }
 }
+
+#[cfg(test)]
+fn predict_best(query: &str) -> PredictedProvider {
+  MediaProviderPredictor::new().predict(query).remove(0).provider
+}
+
+#[test]
+fn predict_youtube() {
+  assert_eq!(predict_best("https://www.youtube.com/watch?v=dQw4w9WgXcQ"), PredictedProvider::YtDlp);
+  assert_eq!(predict_best("https://youtu.be/dQw4w9WgXcQ"), PredictedProvider::YtDlp);
+  assert_eq!(
+    predict_best("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI"),
+    PredictedProvider::YtDlpPlaylist
+  );
+  assert_eq!(
+    predict_best("https://www.youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI"),
+    PredictedProvider::YtDlpPlaylist
+  );
+}
+
+#[test]
+fn predict_zvuk_and_vk() {
+  assert_eq!(predict_best("https://zvuk.com/track/126413867"), PredictedProvider::Sberzvuk(126413867));
+  assert_eq!(predict_best("https://vk.com/audio-2001_456239017"), PredictedProvider::Vk {
+    owner_id: -2001,
+    track_id: 456239017
+  });
+}
+
+#[test]
+fn predict_soundcloud_bandcamp_spotify() {
+  assert_eq!(predict_best("https://soundcloud.com/artist/track"), PredictedProvider::YtDlp);
+  assert_eq!(predict_best("https://soundcloud.com/artist/sets/album"), PredictedProvider::YtDlpPlaylist);
+  assert_eq!(predict_best("https://artist.bandcamp.com/track/song"), PredictedProvider::YtDlp);
+  assert_eq!(predict_best("https://artist.bandcamp.com/album/record"), PredictedProvider::YtDlpPlaylist);
+
+  let prediction = MediaProviderPredictor::new().predict("https://open.spotify.com/episode/4rOoJ6Egrf8K2IrywzwOMk");
+  assert_eq!(prediction[0].provider, PredictedProvider::YtDlp);
+  assert_eq!(prediction.last().unwrap().provider, PredictedProvider::FFmpeg);
+}
+
+#[test]
+fn predict_fallback_below_recognised_services() {
+  for url in [
+    "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT",
+    "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+    "https://soundcloud.com/artist/sets/album",
+    "https://example.com/live/index.m3u8"
+  ] {
+    let prediction = MediaProviderPredictor::new().predict(url);
+    let fallback = prediction.iter().find(|it| it.provider == PredictedProvider::FFmpeg).unwrap();
+    assert!(prediction
+      .iter()
+      .filter(|it| it.provider != PredictedProvider::FFmpeg)
+      .all(|it| it.score > fallback.score));
+  }
+}
+
+#[test]
+fn predict_fallback() {
+  let prediction = MediaProviderPredictor::new().predict("https://radio.example.com/stream.mp3");
+  assert_eq!(prediction.len(), 1);
+  assert_eq!(prediction[0].provider, PredictedProvider::FFmpeg);
+  assert_eq!(prediction[0].score, 0.5);
+
+  let prediction = MediaProviderPredictor::new().predict("not an url");
+  assert_eq!(prediction.len(), 1);
+  assert_eq!(prediction[0].provider, PredictedProvider::FFmpeg);
+  assert_eq!(prediction[0].score, 0.1);
+}
+
+#[test]
+fn predict_hls() {
+  assert_eq!(predict_best("https://example.com/live/index.m3u8"), PredictedProvider::Hls);
+  assert_eq!(predict_best("http://cdn.example.com:8080/a/b.m3u8?token=abc"), PredictedProvider::Hls);
+  assert_eq!(classify_url("https://example.com/stream.m3u8.mp3"), UrlKind::Other);
+  assert_eq!(
+    parse_explicit_provider("hls:https://example.com/live").unwrap(),
+    Some((PredictedProvider::Hls, "https://example.com/live"))
+  );
+}
+
+#[test]
+fn classify_youtube_edge_cases() {
+  assert_eq!(classify_url("https://youtu.be/dQw4w9WgXcQ?t=42"), UrlKind::YouTubeVideo);
+  assert_eq!(classify_url("https://youtu.be/dQw4w9WgXcQ?si=abc&t=1m30s"), UrlKind::YouTubeVideo);
+  assert_eq!(classify_url("youtu.be/dQw4w9WgXcQ"), UrlKind::YouTubeVideo);
+  assert_eq!(classify_url("https://www.youtube.com/watch?t=42&v=dQw4w9WgXcQ"), UrlKind::YouTubeVideo);
+  assert_eq!(classify_url("https://music.youtube.com/watch?v=dQw4w9WgXcQ"), UrlKind::YouTubeVideo);
+  assert_eq!(classify_url("https://www.youtube.com/shorts/dQw4w9WgXcQ"), UrlKind::YouTubeVideo);
+  assert_eq!(
+    classify_url("https://youtu.be/dQw4w9WgXcQ?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI&t=42"),
+    UrlKind::YouTubePlaylist { has_video: true }
+  );
+  assert_eq!(
+    classify_url("https://www.youtube.com/playlist?list=PLFgquLnL59alCl_2TQvOiD5Vgm1hCaGSI"),
+    UrlKind::YouTubePlaylist { has_video: false }
+  );
+  assert_eq!(classify_url("https://www.youtube.com/@channel"), UrlKind::Other);
+}
+
+#[test]
+fn classify_other() {
+  assert_eq!(classify_url("https://sber-zvuk.com/track/1"), UrlKind::Other);
+  assert_eq!(classify_url("https://vk.com/music?z=audio_playlist-2001_1"), UrlKind::Other);
+  assert_eq!(classify_url("radio.example.com:8000/live"), UrlKind::Other);
+  assert_eq!(classify_url("never gonna give you up"), UrlKind::NotUrl);
+  assert_eq!(classify_url(""), UrlKind::NotUrl);
+}
+
+#[test]
+fn explicit_provider() {
+  assert_eq!(
+    parse_explicit_provider("yt-dlp-playlist:https://example.com").unwrap(),
+    Some((PredictedProvider::YtDlpPlaylist, "https://example.com"))
+  );
+  assert_eq!(
+    parse_explicit_provider("vk:-2001_456239017").unwrap(),
+    Some((PredictedProvider::Vk { owner_id: -2001, track_id: 456239017 }, "-2001_456239017"))
+  );
+  assert!(parse_explicit_provider("vk:2001").is_err());
+  assert!(parse_explicit_provider("zvuk:abc").is_err());
+  assert_eq!(parse_explicit_provider("https://example.com").unwrap(), None);
+  assert_eq!(parse_explicit_provider("no provider").unwrap(), None);
+}
@@ -4,8 +4,12 @@ pub use yt_dlp_playlist::*;
 
 use std::fmt::Debug;
 use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
 use crate::providers::MediaProvider;
 
+pub type MediaProviderStream = BoxStream<'static, anyhow::Result<Box<dyn MediaProvider>>>;
+
 #[async_trait]
 pub trait MediaProviderFactory: Sync + Send + Debug {
   async fn init(&mut self) -> anyhow::Result<()> {
@@ -13,4 +17,13 @@ pub trait MediaProviderFactory: Sync + Send + Debug {
   }
 
   async fn get_media_providers(&self) -> anyhow::Result<Vec<Box<dyn MediaProvider>>>;
+
+  /// Yields media providers as soon as they are available, without waiting for the whole collection.
+  ///
+  /// Does not require [`MediaProviderFactory::init`] to be called.
+  async fn get_media_providers_stream(&mut self) -> anyhow::Result<MediaProviderStream> {
+    self.init().await?;
+    let providers = self.get_media_providers().await?;
+    Ok(stream::iter(providers.into_iter().map(Ok)).boxed())
+  }
 }
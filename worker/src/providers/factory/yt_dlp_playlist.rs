@@ -3,19 +3,26 @@ use std::cmp::Ordering;
 use std::process::Stdio;
 use std::time::Duration;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use debug_ignore::DebugIgnore;
+use futures_util::stream;
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::process::Command;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout};
+use tokio::task::JoinHandle;
 use tracing::debug;
 
 use voice::provider::SampleProvider;
 
-use crate::providers::YtDlpMediaProvider;
+use crate::providers::{exit_error, run_flat, CommandBuilder, YtDlpMediaProvider};
 
-use super::{MediaProvider, MediaProviderFactory};
+use super::{MediaProvider, MediaProviderFactory, MediaProviderStream};
+
+/// Number of trailing yt-dlp stderr lines included in errors.
+const STDERR_TAIL_LINES: usize = 5;
 
 #[derive(Debug)]
 pub struct YtDlpPlaylistMediaProviderFactory {
@@ -32,23 +39,7 @@ impl YtDlpPlaylistMediaProviderFactory {
 #[async_trait]
 impl MediaProviderFactory for YtDlpPlaylistMediaProviderFactory {
   async fn init(&mut self) -> Result<()> {
-    let output = Command::new("yt-dlp")
-      .args(&["--no-download", "--print-json", "--flat-playlist", &self.query])
-      .stdout(Stdio::piped())
-      .stderr(Stdio::piped())
-      .stdin(Stdio::piped())
-      .spawn()?
-      .wait_with_output()
-      .await?;
-    if !output.status.success() {
-      let stderr = String::from_utf8_lossy(&output.stderr);
-      debug!("yt-dlp media provider error: {:?}", stderr);
-      return Err(anyhow!("yt-dlp exit code {:?}: {}", output.status.code(), stderr));
-    }
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let deserializer = serde_json::Deserializer::from_str(&stdout);
-    let data = deserializer.into_iter::<Value>().flatten().collect::<Vec<_>>();
+    let data = run_flat::<Value>(&[&self.query]).await?;
 
     let data = self.data.insert(data.into());
     debug!("yt-dlp media provider initialized: {:?}", data);
@@ -73,6 +64,92 @@ impl MediaProviderFactory for YtDlpPlaylistMediaProviderFactory {
 
     Ok(providers)
   }
+
+  async fn get_media_providers_stream(&mut self) -> Result<MediaProviderStream> {
+    let mut child = CommandBuilder::new()
+      .args(["--no-download", "--print-json", "--flat-playlist", &self.query])
+      .build()
+      .stdin(Stdio::null())
+      .kill_on_drop(true)
+      .spawn()?;
+
+    let stdout = child.stdout.take().context("no yt-dlp stdout")?;
+    let mut stderr = child.stderr.take().context("no yt-dlp stderr")?;
+    // Drain stderr concurrently, otherwise yt-dlp may block on a full pipe
+    let stderr = tokio::spawn(async move {
+      let mut buffer = String::new();
+      stderr.read_to_string(&mut buffer).await.map(|_| buffer)
+    });
+
+    let playlist = PlaylistStream {
+      query: self.query.clone(),
+      child: Some(child),
+      stderr: Some(stderr),
+      lines: BufReader::new(stdout).lines()
+    };
+
+    Ok(
+      stream::unfold(playlist, |mut playlist| async move {
+        let item = playlist.next().await?;
+        Some((item, playlist))
+      })
+      .boxed()
+    )
+  }
+}
+
+struct PlaylistStream {
+  query: String,
+  child: Option<Child>,
+  stderr: Option<JoinHandle<std::io::Result<String>>>,
+  lines: Lines<BufReader<ChildStdout>>
+}
+
+impl PlaylistStream {
+  async fn next(&mut self) -> Option<Result<Box<dyn MediaProvider>>> {
+    if self.child.is_none() {
+      return None;
+    }
+
+    loop {
+      match self.lines.next_line().await {
+        Ok(Some(line)) if line.trim().is_empty() => continue,
+        Ok(Some(line)) => {
+          return Some(
+            serde_json::from_str::<Item>(&line)
+              .map(|item| {
+                debug!("item {:?} in {}", item, self.query);
+                Box::new(YtDlpMediaProvider::new(item.url)) as Box<dyn MediaProvider>
+              })
+              .map_err(|error| anyhow!(error))
+          );
+        }
+        Ok(None) => return self.finish().await.err().map(Err),
+        Err(error) => {
+          self.child = None;
+          return Some(Err(anyhow!(error)));
+        }
+      }
+    }
+  }
+
+  async fn finish(&mut self) -> Result<()> {
+    let mut child = self.child.take().context("yt-dlp already finished")?;
+    let status = child.wait().await?;
+    if status.success() {
+      return Ok(());
+    }
+
+    let stderr = match self.stderr.take() {
+      Some(stderr) => stderr.await??,
+      None => String::new()
+    };
+    let lines = stderr.lines().collect::<Vec<_>>();
+    let tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n");
+    debug!("yt-dlp playlist stream error: {:?}", stderr);
+
+    Err(exit_error(status, &tail))
+  }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
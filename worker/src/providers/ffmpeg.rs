@@ -1,32 +1,229 @@
-use anyhow::Result;
+use std::env;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use decoder::{Decoder, DecoderMetadata, ResamplerKind};
+use flume::{Receiver, Sender};
+use thiserror::Error;
+use tokio::time;
+use tracing::warn;
 use voice::provider::SampleProvider;
 
-use super::{MediaMetadata, MediaProvider};
+use super::retry::{backoff_delay, RETRY_MAX_ATTEMPTS};
+use super::{metadata, MediaMetadata, MediaProvider};
 use crate::voice::ffmpeg::FFmpegSampleProvider;
 
 #[derive(Debug)]
 pub struct FFmpegMediaProvider {
-  path: String
+  path: String,
+  metadata: Option<Vec<MediaMetadata>>,
+  /// Title changes of internet radio streams, shared by all sample providers of the track.
+  metadata_updates: (Sender<MediaMetadata>, Receiver<MediaMetadata>)
 }
 
 impl FFmpegMediaProvider {
   pub fn new(path: String) -> Self {
-    Self { path }
+    Self {
+      path,
+      metadata: None,
+      metadata_updates: flume::unbounded()
+    }
   }
 }
 
 #[async_trait]
 impl MediaProvider for FFmpegMediaProvider {
+  async fn init(&mut self) -> Result<()> {
+    let path = self.path.clone();
+    // Opening the input may read from the network
+    let info = open_blocking(open_timeout(), move || {
+      let mut decoder = Decoder::new();
+      decoder.open_input(&path)?;
+      Ok::<_, anyhow::Error>(decoder.read_metadata())
+    })
+    .await??;
+    self.metadata = Some(metadata_from_decoder(&self.path, &info));
+    Ok(())
+  }
+
   async fn get_sample_provider(&self) -> Result<Box<dyn SampleProvider>> {
-    let mut provider = FFmpegSampleProvider::new();
-    provider.open(&self.path)?;
-    // provider.init_filters("anull")?;
-    Ok(Box::new(provider))
+    let mut attempt = 0;
+    loop {
+      let path = self.path.clone();
+      let metadata_updates = self.metadata_updates.0.clone();
+      // Opening the input may read from the network
+      let result = open_blocking(open_timeout(), move || {
+        let provider = FFmpegSampleProvider::new().with_metadata_updates(metadata_updates);
+        let result = {
+          let mut decoder = provider.decoder.lock().unwrap();
+          decoder.set_resampler_kind(resampler_kind()).and_then(|_| decoder.open_input(&path))
+        };
+        result.map(|_| provider)
+      })
+      .await?;
+
+      let error = match result {
+        Ok(provider) => return Ok(Box::new(provider)),
+        Err(error) => error
+      };
+      if !error.is_transient() || attempt + 1 >= RETRY_MAX_ATTEMPTS {
+        return Err(error.into());
+      }
+
+      let delay = backoff_delay(attempt);
+      warn!("failed to open {} ({}), retrying in {:?}...", self.path, error, delay);
+      time::sleep(delay).await;
+      attempt += 1;
+    }
   }
 
   async fn get_metadata(&self) -> Result<Vec<MediaMetadata>> {
-    // TODO: Implement the logic to extract metadata from the file
-    Ok(vec![])
+    match self.metadata {
+      Some(ref metadata) => Ok(metadata.clone()),
+      None => Err(anyhow!("media provider is not initialized"))
+    }
   }
+
+  fn source(&self) -> Option<String> {
+    Some(format!("ffmpeg:{}", self.path))
+  }
+
+  fn metadata_updates(&self) -> Option<Receiver<MediaMetadata>> {
+    Some(self.metadata_updates.1.clone())
+  }
+}
+
+/// Default of `MOSAIK_OPEN_TIMEOUT`, see [open_timeout].
+pub const DEFAULT_OPEN_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SourceError {
+  #[error("Opening the source timed out after {0:?}")]
+  OpenTimeout(Duration)
+}
+
+/// Timeout of opening an input, set with `MOSAIK_OPEN_TIMEOUT` in seconds.
+pub(super) fn open_timeout() -> Duration {
+  match env::var("MOSAIK_OPEN_TIMEOUT") {
+    Ok(value) => value.parse().map(Duration::from_secs_f64).unwrap_or_else(|_| {
+      warn!("invalid MOSAIK_OPEN_TIMEOUT value {:?}, using default", value);
+      DEFAULT_OPEN_TIMEOUT
+    }),
+    Err(_) => DEFAULT_OPEN_TIMEOUT
+  }
+}
+
+/// Runs `open` on a blocking thread, fails with [SourceError::OpenTimeout] if it takes longer than `timeout`.
+///
+/// The thread is not interrupted on timeout, the opened input is dropped once it finishes.
+pub(super) async fn open_blocking<T: Send + 'static>(
+  timeout: Duration,
+  open: impl FnOnce() -> T + Send + 'static
+) -> Result<T> {
+  let result = time::timeout(timeout, tokio::task::spawn_blocking(open))
+    .await
+    .map_err(|_| SourceError::OpenTimeout(timeout))?;
+  Ok(result?)
+}
+
+/// Resampler selected with `MOSAIK_RESAMPLER` (`default`, `fast` or `high_quality`).
+pub(super) fn resampler_kind() -> ResamplerKind {
+  match env::var("MOSAIK_RESAMPLER") {
+    Ok(value) => value.parse().unwrap_or_else(|_| {
+      warn!("unknown MOSAIK_RESAMPLER value {:?}, using default", value);
+      ResamplerKind::Default
+    }),
+    Err(_) => ResamplerKind::Default
+  }
+}
+
+fn metadata_from_decoder(path: &str, info: &DecoderMetadata) -> Vec<MediaMetadata> {
+  let tag = |key: &str| info.tag(key);
+
+  // ReplayGain is relative to -18 LUFS, R128 gains of Opus (Q7.8 dB) are relative to -23 LUFS
+  let replay_gain = tag("replaygain_track_gain")
+    .and_then(|gain| gain.trim().trim_end_matches("dB").trim().parse::<f32>().ok());
+  let r128_gain = tag("r128_track_gain")
+    .and_then(|gain| gain.trim().parse::<i16>().ok())
+    .map(|gain| gain as f32 / 256.0 + 5.0);
+
+  metadata! {
+    Title => { info.title() },
+    Artist => { info.artist() },
+    Album => { tag("album") },
+    Url => { Some(path).filter(|path| path.starts_with("http://") || path.starts_with("https://")) },
+    Description => { tag("description").or_else(|| tag("comment")) },
+    Duration => { info.duration },
+    Bitrate => { info.bit_rate },
+    NormalizedGain => { replay_gain.or(r128_gain) },
+  }
+}
+
+/// Parses the title of an ICY metadata packet, e.g. `StreamTitle='Artist - Title';StreamUrl='';`.
+///
+/// Returns [None] if the packet has no title, or the title is empty.
+pub fn parse_icy_stream_title(packet: &str) -> Option<String> {
+  let start = packet.find("StreamTitle='")? + "StreamTitle='".len();
+  // Titles may contain quotes, the value ends with a quote followed by a semicolon or the end of the packet
+  let value = &packet[start..];
+  let end = value.find("';").or_else(|| value.rfind('\''))?;
+  let title = value[..end].trim();
+  (!title.is_empty()).then(|| title.to_owned())
+}
+
+#[test]
+fn parses_icy_stream_titles() {
+  assert_eq!(
+    parse_icy_stream_title("StreamTitle='Artist - Title';StreamUrl='https://example.com';").as_deref(),
+    Some("Artist - Title")
+  );
+  assert_eq!(parse_icy_stream_title("StreamTitle='Don't Stop';").as_deref(), Some("Don't Stop"));
+  assert_eq!(parse_icy_stream_title("StreamTitle='No semicolon'").as_deref(), Some("No semicolon"));
+  assert_eq!(parse_icy_stream_title("StreamTitle='';"), None);
+  assert_eq!(parse_icy_stream_title("StreamUrl='https://example.com';"), None);
+}
+
+#[test]
+fn metadata_from_ffmpeg_tags() {
+  use std::collections::HashMap;
+
+  let tagged = |tags: &[(&str, &str)]| DecoderMetadata {
+    tags: tags.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<HashMap<_, _>>(),
+    ..DecoderMetadata::default()
+  };
+
+  let info = DecoderMetadata {
+    duration: Some(Duration::from_millis(215_500)),
+    bit_rate: Some(320_000),
+    ..tagged(&[("title", "Title"), ("album_artist", "Artist"), ("album", "Album"), ("comment", " ")])
+  };
+  assert_eq!(metadata_from_decoder("/music/track.flac", &info), vec![
+    MediaMetadata::Title("Title".to_owned()),
+    MediaMetadata::Artist("Artist".to_owned()),
+    MediaMetadata::Album("Album".to_owned()),
+    MediaMetadata::Duration(Duration::from_millis(215_500)),
+    MediaMetadata::Bitrate(320_000)
+  ]);
+  // Inputs without metadata
+  assert_eq!(metadata_from_decoder("https://example.com/stream", &DecoderMetadata::default()), vec![
+    MediaMetadata::Url("https://example.com/stream".to_owned())
+  ]);
+
+  let info = tagged(&[("replaygain_track_gain", "-6.50 dB")]);
+  assert_eq!(metadata_from_decoder("/music/track.flac", &info), vec![MediaMetadata::NormalizedGain(-6.5)]);
+  let info = tagged(&[("r128_track_gain", "-768")]);
+  assert_eq!(metadata_from_decoder("/music/track.opus", &info), vec![MediaMetadata::NormalizedGain(2.0)]);
+}
+
+#[tokio::test]
+async fn open_blocking_times_out() {
+  let error = open_blocking(Duration::from_millis(50), || std::thread::sleep(Duration::from_millis(500)))
+    .await
+    .unwrap_err();
+
+  assert_eq!(
+    error.downcast_ref::<SourceError>(),
+    Some(&SourceError::OpenTimeout(Duration::from_millis(50)))
+  );
 }
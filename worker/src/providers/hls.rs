@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::time::Duration;
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use flume::{Receiver, Sender};
+use reqwest::Url;
+use tokio::time;
+use tracing::{debug, warn};
+use voice::provider::SampleProvider;
+
+use super::ffmpeg::{open_blocking, open_timeout, resampler_kind};
+use super::{metadata, send_with_retry, HttpContext, MediaMetadata, MediaProvider};
+use crate::voice::ffmpeg::FFmpegSampleProvider;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// Live playlists start this many segments before the end, as recommended by the HLS specification.
+pub const LIVE_START_SEGMENTS: usize = 3;
+/// Downloaded segments waiting to be decoded.
+const SEGMENT_BUFFER: usize = 2;
+
+/// HTTP Live Streaming playlist, either a list of variant streams or a list of media segments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HlsPlaylist {
+  Master(Vec<HlsVariant>),
+  Media(MediaPlaylist)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HlsVariant {
+  pub uri: Url,
+  /// Peak bitrate in bits per second.
+  pub bandwidth: u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaPlaylist {
+  pub target_duration: Duration,
+  pub media_sequence: u64,
+  pub segments: Vec<HlsSegment>,
+  /// Set by `#EXT-X-ENDLIST`, live playlists are refreshed until it appears.
+  pub ended: bool
+}
+
+impl MediaPlaylist {
+  /// Total duration of the segments, only meaningful for ended playlists.
+  pub fn duration(&self) -> Duration {
+    self.segments.iter().map(|segment| segment.duration).sum()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HlsSegment {
+  pub uri: Url,
+  pub duration: Duration,
+  /// Media sequence number, used to skip already downloaded segments of refreshed live playlists.
+  pub sequence: u64,
+  /// AES-128 key the segment is encrypted with, [None] if it is not encrypted.
+  pub key: Option<SegmentKey>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentKey {
+  pub uri: Url,
+  pub iv: Option<[u8; 16]>
+}
+
+impl SegmentKey {
+  /// Returns the explicit IV, or the media sequence number as a big-endian 128-bit integer.
+  pub fn iv(&self, sequence: u64) -> [u8; 16] {
+    self.iv.unwrap_or_else(|| (sequence as u128).to_be_bytes())
+  }
+}
+
+/// Parses an M3U8 playlist, relative URIs are resolved against `base`.
+pub fn parse_playlist(base: &Url, text: &str) -> Result<HlsPlaylist> {
+  let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+  if lines.next() != Some("#EXTM3U") {
+    return Err(anyhow!("not an M3U8 playlist"));
+  }
+
+  let mut variants = Vec::new();
+  let mut playlist = MediaPlaylist {
+    target_duration: Duration::ZERO,
+    media_sequence: 0,
+    segments: Vec::new(),
+    ended: false
+  };
+  let mut key = None;
+  // Attributes of the URI line that follows
+  let mut bandwidth = None;
+  let mut duration = None;
+
+  for line in lines {
+    if let Some(value) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+      let attributes = parse_attributes(value);
+      bandwidth = Some(attributes.get("BANDWIDTH").and_then(|it| it.parse().ok()).unwrap_or(0));
+    } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+      let seconds = value.split(',').next().unwrap_or_default().trim();
+      let invalid = || anyhow!("invalid segment duration {}", seconds);
+      let seconds = seconds.parse::<f64>().map_err(|_| invalid())?;
+      duration = Some(Duration::try_from_secs_f64(seconds.max(0.0)).map_err(|_| invalid())?);
+    } else if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+      playlist.target_duration = Duration::from_secs(value.parse().context("invalid target duration")?);
+    } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+      playlist.media_sequence = value.parse().context("invalid media sequence")?;
+    } else if let Some(value) = line.strip_prefix("#EXT-X-KEY:") {
+      key = parse_key(base, value)?;
+    } else if line == "#EXT-X-ENDLIST" {
+      playlist.ended = true;
+    } else if line.starts_with('#') {
+      // Comments and unsupported tags
+    } else {
+      let uri = base.join(line).with_context(|| format!("invalid URI {}", line))?;
+      if let Some(bandwidth) = bandwidth.take() {
+        variants.push(HlsVariant { uri, bandwidth });
+      } else if let Some(duration) = duration.take() {
+        playlist.segments.push(HlsSegment {
+          uri,
+          duration,
+          sequence: playlist
+            .media_sequence
+            .checked_add(playlist.segments.len() as u64)
+            .context("invalid media sequence")?,
+          key: key.clone()
+        });
+      }
+    }
+  }
+
+  if variants.is_empty() {
+    Ok(HlsPlaylist::Media(playlist))
+  } else {
+    Ok(HlsPlaylist::Master(variants))
+  }
+}
+
+/// Parses `#EXT-X-KEY` attributes, returns [None] for `METHOD=NONE`.
+fn parse_key(base: &Url, value: &str) -> Result<Option<SegmentKey>> {
+  let attributes = parse_attributes(value);
+  match attributes.get("METHOD").copied() {
+    Some("NONE") => return Ok(None),
+    Some("AES-128") => {}
+    method => return Err(anyhow!("unsupported encryption method {:?}", method))
+  }
+
+  let uri = attributes.get("URI").context("encryption key has no URI")?;
+  let iv = match attributes.get("IV") {
+    Some(iv) => {
+      let hex = iv.trim_start_matches("0x").trim_start_matches("0X");
+      let iv = u128::from_str_radix(hex, 16).with_context(|| format!("invalid IV {}", iv))?;
+      Some(iv.to_be_bytes())
+    }
+    None => None
+  };
+
+  Ok(Some(SegmentKey {
+    uri: base.join(uri).with_context(|| format!("invalid key URI {}", uri))?,
+    iv
+  }))
+}
+
+/// Parses an attribute list, e.g. `METHOD=AES-128,URI="key.bin"`. Quoted values may contain commas.
+fn parse_attributes(value: &str) -> HashMap<&str, &str> {
+  let mut attributes = HashMap::new();
+  let mut rest = value;
+  while let Some((name, value)) = rest.split_once('=') {
+    let (value, next) = match value.strip_prefix('"') {
+      Some(quoted) => {
+        let end = quoted.find('"').unwrap_or(quoted.len());
+        let next = quoted[end..].trim_start_matches('"');
+        (&quoted[..end], next.split_once(',').map_or("", |(_, next)| next))
+      }
+      None => value.split_once(',').unwrap_or((value, ""))
+    };
+    attributes.insert(name.trim(), value);
+    rest = next;
+  }
+  attributes
+}
+
+/// Decrypts an AES-128-CBC segment with PKCS#7 padding in place.
+fn decrypt_segment(data: &mut Vec<u8>, key: &[u8; 16], iv: &[u8; 16]) -> Result<()> {
+  let length = Aes128CbcDec::new(key.into(), iv.into())
+    .decrypt_padded_mut::<Pkcs7>(data)
+    .map_err(|_| anyhow!("invalid segment padding"))?
+    .len();
+  data.truncate(length);
+  Ok(())
+}
+
+async fn fetch_bytes(http: &HttpContext, url: &Url) -> Result<Vec<u8>> {
+  let response = send_with_retry(http.get(url)).await?.error_for_status()?;
+  Ok(response.bytes().await?.to_vec())
+}
+
+/// Fetches the playlist at `url`, following a master playlist to its highest bitrate variant.
+///
+/// Returns the media playlist and its URL.
+async fn fetch_media_playlist(http: &HttpContext, url: &Url) -> Result<(Url, MediaPlaylist)> {
+  let text = String::from_utf8(fetch_bytes(http, url).await?).context("playlist is not UTF-8")?;
+  match parse_playlist(url, &text)? {
+    HlsPlaylist::Media(playlist) => Ok((url.clone(), playlist)),
+    HlsPlaylist::Master(variants) => {
+      let variant = variants.into_iter().max_by_key(|variant| variant.bandwidth).unwrap();
+      debug!("selected variant {} ({} bps)", variant.uri, variant.bandwidth);
+
+      let text = String::from_utf8(fetch_bytes(http, &variant.uri).await?).context("playlist is not UTF-8")?;
+      match parse_playlist(&variant.uri, &text)? {
+        HlsPlaylist::Media(playlist) => Ok((variant.uri, playlist)),
+        HlsPlaylist::Master(_) => Err(anyhow!("variant {} is a master playlist", variant.uri))
+      }
+    }
+  }
+}
+
+/// Downloads the segments of the media playlist at `url` in order, refreshing live playlists
+/// until they end. Stops once `sender` is disconnected.
+async fn download_segments(http: HttpContext, url: Url, mut playlist: MediaPlaylist, sender: Sender<Result<Vec<u8>>>) {
+  let mut keys = HashMap::<Url, [u8; 16]>::new();
+  let mut next_sequence = if playlist.ended {
+    playlist.media_sequence
+  } else {
+    let start = playlist.segments.len().saturating_sub(LIVE_START_SEGMENTS);
+    playlist.segments.get(start).map_or(playlist.media_sequence, |segment| segment.sequence)
+  };
+
+  loop {
+    for segment in playlist.segments.iter().filter(|segment| segment.sequence >= next_sequence) {
+      let result = download_segment(&http, segment, &mut keys).await;
+      let failed = result.is_err();
+      if sender.send_async(result).await.is_err() || failed {
+        return;
+      }
+      next_sequence = segment.sequence + 1;
+    }
+
+    if playlist.ended {
+      return;
+    }
+
+    time::sleep(playlist.target_duration.max(Duration::from_secs(1))).await;
+    if sender.is_disconnected() {
+      return;
+    }
+    playlist = match fetch_media_playlist(&http, &url).await {
+      Ok((_, playlist)) => playlist,
+      Err(error) => {
+        let _ = sender.send_async(Err(error.context("failed to refresh live playlist"))).await;
+        return;
+      }
+    };
+  }
+}
+
+async fn download_segment(
+  http: &HttpContext,
+  segment: &HlsSegment,
+  keys: &mut HashMap<Url, [u8; 16]>
+) -> Result<Vec<u8>> {
+  let mut data = fetch_bytes(http, &segment.uri).await?;
+  if let Some(key) = &segment.key {
+    let value = match keys.get(&key.uri) {
+      Some(value) => *value,
+      None => {
+        let value: [u8; 16] = fetch_bytes(http, &key.uri)
+          .await?
+          .try_into()
+          .map_err(|_| anyhow!("encryption key {} is not 16 bytes long", key.uri))?;
+        keys.insert(key.uri.clone(), value);
+        value
+      }
+    };
+    decrypt_segment(&mut data, &value, &key.iv(segment.sequence))
+      .with_context(|| format!("failed to decrypt segment {}", segment.uri))?;
+  }
+  Ok(data)
+}
+
+/// Reads downloaded segments as one continuous stream, blocking until the next segment is available.
+struct HlsReader {
+  receiver: Receiver<Result<Vec<u8>>>,
+  segment: Vec<u8>,
+  position: usize
+}
+
+impl Read for HlsReader {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    while self.position >= self.segment.len() {
+      match self.receiver.recv() {
+        Ok(Ok(segment)) => {
+          self.segment = segment;
+          self.position = 0;
+        }
+        Ok(Err(error)) => {
+          warn!("failed to download HLS segment: {:?}", error);
+          return Err(io::Error::new(io::ErrorKind::Other, error));
+        }
+        // The downloader reached the end of the playlist
+        Err(_) => return Ok(0)
+      }
+    }
+
+    let length = buffer.len().min(self.segment.len() - self.position);
+    buffer[..length].copy_from_slice(&self.segment[self.position..self.position + length]);
+    self.position += length;
+    Ok(length)
+  }
+}
+
+/// Plays HTTP Live Streaming playlists, segments are downloaded (and decrypted) here and decoded by FFmpeg.
+#[derive(Debug)]
+pub struct HlsMediaProvider {
+  url: String,
+  http: HttpContext,
+  metadata: Option<Vec<MediaMetadata>>
+}
+
+impl HlsMediaProvider {
+  pub fn new(url: String, http: HttpContext) -> Self {
+    Self {
+      url,
+      http,
+      metadata: None
+    }
+  }
+}
+
+#[async_trait]
+impl MediaProvider for HlsMediaProvider {
+  async fn init(&mut self) -> Result<()> {
+    let url = Url::parse(&self.url)?;
+    let (_, playlist) = fetch_media_playlist(&self.http, &url).await?;
+    let duration = playlist.ended.then(|| playlist.duration());
+
+    self.metadata = Some(metadata! {
+      Url => { Some(&self.url) },
+      Duration => { duration },
+    });
+    Ok(())
+  }
+
+  async fn get_sample_provider(&self) -> Result<Box<dyn SampleProvider>> {
+    // Fetched again, live playlists start at the current live edge
+    let (url, playlist) = fetch_media_playlist(&self.http, &Url::parse(&self.url)?).await?;
+    let (sender, receiver) = flume::bounded(SEGMENT_BUFFER);
+    tokio::spawn(download_segments(self.http.clone(), url, playlist, sender));
+
+    // Opening the input blocks until the first segments are downloaded
+    let provider = open_blocking(open_timeout(), move || {
+      let mut provider = FFmpegSampleProvider::new();
+      provider
+        .decoder
+        .lock()
+        .unwrap()
+        .set_resampler_kind(resampler_kind())?;
+      provider.open_reader(Box::new(HlsReader {
+        receiver,
+        segment: Vec::new(),
+        position: 0
+      }))?;
+      Ok::<_, anyhow::Error>(provider)
+    })
+    .await??;
+
+    Ok(Box::new(provider))
+  }
+
+  async fn get_metadata(&self) -> Result<Vec<MediaMetadata>> {
+    match self.metadata {
+      Some(ref metadata) => Ok(metadata.clone()),
+      None => Err(anyhow!("media provider is not initialized"))
+    }
+  }
+
+  fn source(&self) -> Option<String> {
+    Some(format!("hls:{}", self.url))
+  }
+}
+
+#[test]
+fn parses_media_playlist() {
+  let base = Url::parse("https://example.com/live/audio.m3u8").unwrap();
+  let playlist = parse_playlist(&base, "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:41
+#EXTINF:5.5,
+segment41.aac
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/key?id=1,2\",IV=0x000102030405060708090a0b0c0d0e0f
+#EXTINF:6.0,Title
+/other/segment42.aac
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"
+#EXTINF:4,
+segment43.aac
+#EXT-X-ENDLIST
+")
+  .unwrap();
+
+  let HlsPlaylist::Media(playlist) = playlist else {
+    panic!("expected a media playlist");
+  };
+  assert_eq!(playlist.target_duration, Duration::from_secs(6));
+  assert!(playlist.ended);
+  assert_eq!(playlist.duration(), Duration::from_millis(15_500));
+
+  let segments = &playlist.segments;
+  assert_eq!(segments.len(), 3);
+  assert_eq!(segments[0].uri.as_str(), "https://example.com/live/segment41.aac");
+  assert_eq!(segments[0].sequence, 41);
+  assert_eq!(segments[0].key, None);
+
+  assert_eq!(segments[1].uri.as_str(), "https://example.com/other/segment42.aac");
+  let key = segments[1].key.as_ref().unwrap();
+  assert_eq!(key.uri.as_str(), "https://keys.example.com/key?id=1,2");
+  assert_eq!(key.iv(42), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+
+  // Without an explicit IV, the sequence number is used
+  let key = segments[2].key.as_ref().unwrap();
+  assert_eq!(key.uri.as_str(), "https://example.com/live/key.bin");
+  assert_eq!(key.iv(segments[2].sequence), 43u128.to_be_bytes());
+}
+
+#[test]
+fn parses_live_and_master_playlists() {
+  let base = Url::parse("https://example.com/stream/master.m3u8").unwrap();
+  let playlist = parse_playlist(&base, "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=64000,CODECS=\"mp4a.40.5\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=256000,CODECS=\"mp4a.40.2\"
+high/index.m3u8
+")
+  .unwrap();
+  assert_eq!(playlist, HlsPlaylist::Master(vec![
+    HlsVariant {
+      uri: Url::parse("https://example.com/stream/low/index.m3u8").unwrap(),
+      bandwidth: 64000
+    },
+    HlsVariant {
+      uri: Url::parse("https://example.com/stream/high/index.m3u8").unwrap(),
+      bandwidth: 256000
+    }
+  ]));
+
+  // No #EXT-X-ENDLIST, the playlist is refreshed
+  let playlist = parse_playlist(&base, "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10,\na.ts\n").unwrap();
+  let HlsPlaylist::Media(playlist) = playlist else {
+    panic!("expected a media playlist");
+  };
+  assert!(!playlist.ended);
+  assert_eq!(playlist.segments[0].sequence, 0);
+
+  assert!(parse_playlist(&base, "not a playlist").is_err());
+  assert!(parse_playlist(&base, "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"key\"\n").is_err());
+  // Remote input must not panic
+  assert!(parse_playlist(&base, "#EXTM3U\n#EXTINF:inf,\na.ts\n").is_err());
+  assert!(parse_playlist(&base, "#EXTM3U\n#EXTINF:1e30,\na.ts\n").is_err());
+  let overflow = format!("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXTINF:1,\na.ts\n#EXTINF:1,\nb.ts\n", u64::MAX);
+  assert!(parse_playlist(&base, &overflow).is_err());
+}
+
+#[test]
+fn decrypts_aes_128_segments() {
+  use aes::cipher::BlockEncryptMut;
+
+  let key = [7u8; 16];
+  let iv = 5u128.to_be_bytes();
+  let plain = b"segment data that is not block aligned";
+
+  let mut buffer = [0; 48];
+  buffer[..plain.len()].copy_from_slice(plain);
+  let encrypted = cbc::Encryptor::<aes::Aes128>::new(&key.into(), &iv.into())
+    .encrypt_padded_mut::<Pkcs7>(&mut buffer, plain.len())
+    .unwrap();
+  let mut data = encrypted.to_vec();
+  decrypt_segment(&mut data, &key, &iv).unwrap();
+  assert_eq!(data, plain);
+
+  let mut data = vec![0; 15];
+  assert!(decrypt_segment(&mut data, &key, &iv).is_err());
+}
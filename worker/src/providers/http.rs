@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method, Proxy, RequestBuilder, Url};
+
+/// Default of `MOSAIK_HTTP_TIMEOUT`, the timeout of whole requests in seconds.
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+pub const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTP client shared by all media providers, so that connections (and TLS sessions) are pooled between tracks.
+///
+/// Cloning is cheap, clones share the connection pool.
+#[derive(Clone, Debug)]
+pub struct HttpContext {
+  client: Client,
+  /// Default headers of requests to a host, e.g. API tokens.
+  host_headers: HashMap<String, HeaderMap>
+}
+
+impl HttpContext {
+  pub fn new(client: Client) -> Self {
+    Self {
+      client,
+      host_headers: HashMap::new()
+    }
+  }
+
+  /// Configured with `MOSAIK_HTTP_PROXY`, `MOSAIK_HTTP_USER_AGENT` and `MOSAIK_HTTP_TIMEOUT` (in seconds).
+  pub fn from_env() -> Result<Self> {
+    let timeout = env::var("MOSAIK_HTTP_TIMEOUT")
+      .ok()
+      .and_then(|it| it.parse().ok())
+      .map(Duration::from_secs)
+      .unwrap_or(DEFAULT_HTTP_TIMEOUT);
+
+    let mut builder = Client::builder().timeout(timeout).connect_timeout(HTTP_CONNECT_TIMEOUT);
+    if let Ok(proxy) = env::var("MOSAIK_HTTP_PROXY") {
+      builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    if let Ok(user_agent) = env::var("MOSAIK_HTTP_USER_AGENT") {
+      builder = builder.user_agent(user_agent);
+    }
+    Ok(Self::new(builder.build()?))
+  }
+
+  /// Adds a header sent with every request to `host`.
+  pub fn with_host_header(mut self, host: &str, name: HeaderName, value: HeaderValue) -> Self {
+    self.host_headers.entry(host.to_owned()).or_default().insert(name, value);
+    self
+  }
+
+  pub fn client(&self) -> &Client {
+    &self.client
+  }
+
+  pub fn request(&self, method: Method, url: impl AsRef<str>) -> RequestBuilder {
+    let url = url.as_ref();
+    let request = self.client.request(method, url);
+    let host = Url::parse(url).ok().and_then(|url| url.host_str().map(ToOwned::to_owned));
+    match host.and_then(|host| self.host_headers.get(&host)) {
+      Some(headers) => request.headers(headers.clone()),
+      None => request
+    }
+  }
+
+  pub fn get(&self, url: impl AsRef<str>) -> RequestBuilder {
+    self.request(Method::GET, url)
+  }
+
+  pub fn post(&self, url: impl AsRef<str>) -> RequestBuilder {
+    self.request(Method::POST, url)
+  }
+}
+
+impl Default for HttpContext {
+  fn default() -> Self {
+    Self::new(Client::new())
+  }
+}
+
+#[tokio::test]
+async fn reuses_connections_between_providers() {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+
+  use super::{HlsMediaProvider, MediaProvider};
+
+  const PLAYLIST: &str = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\nsegment0.aac\n#EXT-X-ENDLIST\n";
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/audio.m3u8", listener.local_addr().unwrap());
+  let connections = Arc::new(AtomicUsize::new(0));
+  let headers = Arc::new(Mutex::new(Vec::new()));
+  {
+    let connections = connections.clone();
+    let headers = headers.clone();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        connections.fetch_add(1, Ordering::SeqCst);
+        let headers = headers.clone();
+        thread::spawn(move || {
+          let mut stream = stream.unwrap();
+          let mut reader = BufReader::new(stream.try_clone().unwrap());
+          // Keep-alive: serve requests until the client closes the connection
+          loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+              return;
+            }
+            if line.trim().is_empty() {
+              let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.apple.mpegurl\r\nContent-Length: {}\r\n\r\n{}",
+                PLAYLIST.len(),
+                PLAYLIST
+              );
+              stream.write_all(response.as_bytes()).unwrap();
+            } else {
+              headers.lock().unwrap().push(line.trim().to_ascii_lowercase());
+            }
+          }
+        });
+      }
+    });
+  }
+
+  let client = Client::builder().user_agent("mosaik-test").build().unwrap();
+  let http = HttpContext::new(client).with_host_header(
+    "127.0.0.1",
+    HeaderName::from_static("x-token"),
+    HeaderValue::from_static("secret")
+  );
+  for _ in 0..2 {
+    let mut provider = HlsMediaProvider::new(url.clone(), http.clone());
+    provider.init().await.unwrap();
+  }
+
+  assert_eq!(connections.load(Ordering::SeqCst), 1);
+  let headers = headers.lock().unwrap();
+  assert_eq!(headers.iter().filter(|header| *header == "x-token: secret").count(), 2);
+  assert!(headers.contains(&"user-agent: mosaik-test".to_owned()));
+}
@@ -1,14 +1,20 @@
 use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MediaMetadata {
   Id(String),
   Title(String),
+  Artist(String),
+  Album(String),
   Url(String),
   Thumbnail(String),
   Description(String),
   Duration(Duration),
-  ViewCount(u64)
+  ViewCount(u64),
+  /// Bits per second.
+  Bitrate(u64),
+  /// Gain in dB that brings the track to the ReplayGain reference loudness (-18 LUFS).
+  NormalizedGain(f32)
 }
 
 macro_rules! metadata {
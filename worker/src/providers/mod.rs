@@ -1,16 +1,23 @@
 mod ffmpeg;
+mod hls;
+mod http;
 mod metadata;
+mod retry;
 mod sberzvuk;
 mod vk;
 mod yt_dlp;
 pub mod factory;
+pub mod search;
 
 use std::fmt::Debug;
 
 use anyhow::Result;
 use async_trait::async_trait;
 pub use ffmpeg::*;
+pub use hls::*;
+pub use http::*;
 pub use metadata::*;
+pub use retry::*;
 pub use sberzvuk::*;
 pub use vk::*;
 use voice::provider::SampleProvider;
@@ -24,4 +31,14 @@ pub trait MediaProvider: Sync + Send + Debug {
 
   async fn get_sample_provider(&self) -> Result<Box<dyn SampleProvider>>;
   async fn get_metadata(&self) -> Result<Vec<MediaMetadata>>;
+
+  /// Returns the explicit source (e.g. `yt-dlp:<url>`) that recreates this provider, used to save playlists.
+  fn source(&self) -> Option<String> {
+    None
+  }
+
+  /// Returns metadata changes while the track is playing, e.g. titles of internet radio streams.
+  fn metadata_updates(&self) -> Option<flume::Receiver<MediaMetadata>> {
+    None
+  }
 }
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use tokio::time;
+use tracing::warn;
+
+pub const RETRY_MAX_ATTEMPTS: u32 = 4;
+pub const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Returns the delay before retry number `attempt` (starting from 0).
+pub fn backoff_delay(attempt: u32) -> Duration {
+  RETRY_BASE_DELAY * 2u32.saturating_pow(attempt)
+}
+
+pub fn is_transient_status(status: StatusCode) -> bool {
+  status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_transient_error(error: &reqwest::Error) -> bool {
+  error.is_timeout() || error.is_connect()
+}
+
+/// Sends the request, retrying with exponential backoff on timeouts, connection errors and 5xx responses.
+///
+/// Requests with a streaming body cannot be cloned and are sent only once.
+pub async fn send_with_retry(request: RequestBuilder) -> Result<Response> {
+  let mut attempt = 0;
+  loop {
+    let request = match request.try_clone() {
+      Some(request) => request,
+      None => return Ok(request.send().await?)
+    };
+
+    let retry_reason = match request.send().await {
+      Ok(response) if is_transient_status(response.status()) => format!("status {}", response.status()),
+      Ok(response) => return Ok(response),
+      Err(error) if is_transient_error(&error) => error.to_string(),
+      Err(error) => return Err(anyhow!(error))
+    };
+
+    if attempt + 1 >= RETRY_MAX_ATTEMPTS {
+      return Err(anyhow!("request failed after {} attempts: {}", RETRY_MAX_ATTEMPTS, retry_reason));
+    }
+
+    let delay = backoff_delay(attempt);
+    warn!("transient request error ({}), retrying in {:?}...", retry_reason, delay);
+    time::sleep(delay).await;
+    attempt += 1;
+  }
+}
+
+#[test]
+fn backoff_delay_doubles() {
+  assert_eq!(backoff_delay(0), RETRY_BASE_DELAY);
+  assert_eq!(backoff_delay(1), RETRY_BASE_DELAY * 2);
+  assert_eq!(backoff_delay(3), RETRY_BASE_DELAY * 8);
+}
+
+#[test]
+fn transient_statuses() {
+  assert!(is_transient_status(StatusCode::BAD_GATEWAY));
+  assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+  assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+  assert!(!is_transient_status(StatusCode::FORBIDDEN));
+  assert!(!is_transient_status(StatusCode::OK));
+}
@@ -1,47 +1,61 @@
 use std::borrow::ToOwned;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use debug_ignore::DebugIgnore;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::RwLock;
 use tracing::debug;
 use voice::provider::SampleProvider;
 
-use super::{metadata, FFmpegMediaProvider, MediaMetadata, MediaProvider};
+use super::{metadata, send_with_retry, FFmpegMediaProvider, HttpContext, MediaMetadata, MediaProvider};
+
+/// Streams are refreshed this long before their reported expiry.
+pub const STREAM_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub struct SberzvukMediaProvider {
   id: i64,
+  http: HttpContext,
+  token: Option<String>,
   track: Option<DebugIgnore<GetTrack>>,
-  stream: Option<Stream>
+  stream: RwLock<Option<FetchedStream>>
+}
+
+#[derive(Debug, Clone)]
+struct FetchedStream {
+  stream: Stream,
+  fetched_at: Instant
+}
+
+impl FetchedStream {
+  fn needs_refresh(&self, now: Instant) -> bool {
+    stream_needs_refresh(self.fetched_at, self.stream.expire_delta, now)
+  }
+}
+
+/// Returns whether a stream fetched at `fetched_at`, which expires `expire_delta` seconds later,
+/// should be re-requested before being handed to the decoder at `now`.
+pub fn stream_needs_refresh(fetched_at: Instant, expire_delta: i64, now: Instant) -> bool {
+  let lifetime = Duration::from_secs(expire_delta.max(0) as u64);
+  now + STREAM_EXPIRY_MARGIN >= fetched_at + lifetime
 }
 
 impl SberzvukMediaProvider {
-  pub fn new(id: i64) -> Self {
+  pub fn new(id: i64, http: HttpContext) -> Self {
     Self {
       id,
+      http,
+      token: None,
       track: None,
-      stream: None
+      stream: RwLock::new(None)
     }
   }
-}
-
-#[async_trait]
-impl MediaProvider for SberzvukMediaProvider {
-  async fn init(&mut self) -> Result<()> {
-    let client = Client::new();
-    let profile = client
-      .get("https://zvuk.com/api/tiny/profile")
-      .send()
-      .await?
-      .json::<ProfileWrapper>()
-      .await?;
-    debug!("token: {}", profile.result.token);
 
+  async fn fetch_stream(&self, token: &str) -> Result<FetchedStream> {
     let body = serde_json::to_string(&GraphQlRequest {
       operation_name: "getStream".to_owned(),
       variables: HashMap::from([("ids".to_string(), vec![self.id].into())]),
@@ -49,17 +63,38 @@ impl MediaProvider for SberzvukMediaProvider {
     })?;
     debug!("request body: {}", body);
 
-    let response = client
-      .post("https://zvuk.com/api/v1/graphql")
-      .header("Content-Type", "application/json")
-      .header("X-Auth-Token", &profile.result.token)
-      .body(body)
-      .send()
-      .await?;
+    let response = send_with_retry(
+      self
+        .http
+        .post("https://zvuk.com/api/v1/graphql")
+        .header("Content-Type", "application/json")
+        .header("X-Auth-Token", token)
+        .body(body)
+    )
+    .await?;
     let body = response.text().await?;
     debug!("response: {}", body);
 
     let mut body = serde_json::from_str::<ResponseWrapper<GetStreamResponse>>(&body)?;
+    let content = body.data.media_contents.swap_remove(0);
+
+    Ok(FetchedStream {
+      stream: content.stream,
+      fetched_at: Instant::now()
+    })
+  }
+}
+
+#[async_trait]
+impl MediaProvider for SberzvukMediaProvider {
+  async fn init(&mut self) -> Result<()> {
+    let profile = send_with_retry(self.http.get("https://zvuk.com/api/tiny/profile"))
+      .await?
+      .json::<ProfileWrapper>()
+      .await?;
+    debug!("token: {}", profile.result.token);
+
+    let stream = self.fetch_stream(&profile.result.token).await?;
 
     self.track = Some(
       {
@@ -74,13 +109,15 @@ impl MediaProvider for SberzvukMediaProvider {
         })?;
         debug!("request body: {}", body);
 
-        let response = client
-          .post("https://zvuk.com/api/v1/graphql")
-          .header("Content-Type", "application/json")
-          .header("X-Auth-Token", &profile.result.token)
-          .body(body)
-          .send()
-          .await?;
+        let response = send_with_retry(
+          self
+            .http
+            .post("https://zvuk.com/api/v1/graphql")
+            .header("Content-Type", "application/json")
+            .header("X-Auth-Token", &profile.result.token)
+            .body(body)
+        )
+        .await?;
         let body = response.text().await?;
         debug!("response: {}", body);
 
@@ -90,18 +127,29 @@ impl MediaProvider for SberzvukMediaProvider {
       .into()
     );
 
-    let content = body.data.media_contents.swap_remove(0);
-    self.stream = Some(content.stream);
+    *self.stream.write().await = Some(stream);
+    self.token = Some(profile.result.token);
 
     Ok(())
   }
 
   async fn get_sample_provider(&self) -> Result<Box<dyn SampleProvider>> {
-    let stream = match self.stream {
-      Some(ref stream) => stream,
+    let token = match self.token {
+      Some(ref token) => token,
       None => return Err(anyhow!("media provider is not initialized"))
     };
 
+    let mut stream = self.stream.write().await;
+    let needs_refresh = match *stream {
+      Some(ref stream) => stream.needs_refresh(Instant::now()),
+      None => return Err(anyhow!("media provider is not initialized"))
+    };
+    if needs_refresh {
+      debug!("stream for {} is about to expire, refreshing", self.id);
+      *stream = Some(self.fetch_stream(token).await?);
+    }
+
+    let stream = &stream.as_ref().unwrap().stream;
     let url = stream.high.as_ref().unwrap_or(&stream.mid);
 
     let inner = FFmpegMediaProvider::new(url.clone());
@@ -112,9 +160,37 @@ impl MediaProvider for SberzvukMediaProvider {
     Ok(metadata! {
       Id => { Some(self.id.to_string()) },
       Title => { self.track.as_ref().map(|track| track.title.clone()) },
-      Duration => { self.track.as_ref().map(|track| Duration::from_secs(track.duration)) }
+      Artist => { self.track.as_ref().map(|track| format_artists(&track.artist_template, &track.artists)) },
+      Duration => { self.track.as_ref().map(|track| Duration::from_secs(track.duration)) },
+      Thumbnail => { self.track.as_ref().map(|track| format_image_url(&track.release.image.src)) }
     })
   }
+
+  fn source(&self) -> Option<String> {
+    Some(format!("zvuk:{}", self.id))
+  }
+}
+
+/// Substitutes `{index}` placeholders in Zvuk `artistTemplate` with the artist names.
+pub fn format_artists(template: &str, artists: &[Artist]) -> String {
+  if template.is_empty() {
+    return artists
+      .iter()
+      .map(|artist| artist.title.as_str())
+      .collect::<Vec<_>>()
+      .join(", ");
+  }
+
+  let mut result = template.to_owned();
+  for (index, artist) in artists.iter().enumerate() {
+    result = result.replace(&format!("{{{}}}", index), &artist.title);
+  }
+  result
+}
+
+/// Zvuk image URLs contain a `{size}` placeholder.
+pub fn format_image_url(src: &str) -> String {
+  src.replace("{size}", "600x600")
 }
 
 static GET_STREAM_QUERY: &str = r#"query getStream($ids: [ID!]!) {
@@ -368,3 +444,56 @@ pub struct Label {
   pub id: String,
   pub title: String
 }
+
+#[test]
+fn stream_refresh_decision() {
+  let fetched_at = Instant::now();
+  let hour = Duration::from_secs(60 * 60);
+  let expire_delta = hour.as_secs() as i64;
+
+  assert!(!stream_needs_refresh(fetched_at, expire_delta, fetched_at));
+  assert!(!stream_needs_refresh(fetched_at, expire_delta, fetched_at + hour / 2));
+  // Within the safety margin
+  assert!(stream_needs_refresh(fetched_at, expire_delta, fetched_at + hour - STREAM_EXPIRY_MARGIN / 2));
+  // Already expired
+  assert!(stream_needs_refresh(fetched_at, expire_delta, fetched_at + hour * 2));
+  // Invalid or missing expiry is treated as already expired
+  assert!(stream_needs_refresh(fetched_at, 0, fetched_at));
+  assert!(stream_needs_refresh(fetched_at, -1, fetched_at));
+}
+
+#[tokio::test]
+async fn metadata_from_track() {
+  let artist = |title: &str| Artist {
+    title: title.to_owned(),
+    ..Default::default()
+  };
+
+  let provider = SberzvukMediaProvider {
+    track: Some(
+      GetTrack {
+        title: "Title".to_owned(),
+        duration: 215,
+        artist_template: "{0} feat. {1}".to_owned(),
+        artists: vec![artist("First"), artist("Second")],
+        release: Release {
+          image: Image2 {
+            src: "https://cdn.zvuk.com/pic?type=release&id=1&size={size}&ext=jpg".to_owned(),
+            ..Default::default()
+          },
+          ..Default::default()
+        },
+        ..Default::default()
+      }
+      .into()
+    ),
+    ..SberzvukMediaProvider::new(42, HttpContext::default())
+  };
+  assert_eq!(provider.get_metadata().await.unwrap(), vec![
+    MediaMetadata::Id("42".to_owned()),
+    MediaMetadata::Title("Title".to_owned()),
+    MediaMetadata::Artist("First feat. Second".to_owned()),
+    MediaMetadata::Duration(Duration::from_secs(215)),
+    MediaMetadata::Thumbnail("https://cdn.zvuk.com/pic?type=release&id=1&size=600x600&ext=jpg".to_owned())
+  ]);
+}
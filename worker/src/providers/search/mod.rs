@@ -0,0 +1,27 @@
+mod yt_dlp_search;
+
+pub use yt_dlp_search::*;
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::providers::MediaProvider;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+  pub title: String,
+  pub url: String,
+  pub author: Option<String>,
+  pub duration: Option<Duration>
+}
+
+#[async_trait]
+pub trait SearchProvider: Sync + Send + Debug {
+  /// Returns at most `limit` results for `query`, best match first.
+  async fn search(&self, query: &str, limit: usize) -> anyhow::Result<Vec<SearchResult>>;
+
+  /// Creates an uninitialized media provider for a result returned by [SearchProvider::search].
+  fn get_media_provider(&self, result: &SearchResult) -> Box<dyn MediaProvider>;
+}
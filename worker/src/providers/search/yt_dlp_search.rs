@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{SearchProvider, SearchResult};
+use crate::providers::{parse_flat, run_flat, MediaProvider, YtDlpMediaProvider};
+
+#[derive(Debug)]
+pub struct YtDlpSearchProvider;
+
+impl YtDlpSearchProvider {
+  pub fn new() -> Self {
+    Self
+  }
+}
+
+#[async_trait]
+impl SearchProvider for YtDlpSearchProvider {
+  async fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchResult>> {
+    let default_search = format!("ytsearch{}", limit);
+    let items = run_flat::<SearchItem>(&["--default-search", &default_search, "--", query]).await?;
+    Ok(items.into_iter().map(SearchResult::from).collect())
+  }
+
+  fn get_media_provider(&self, result: &SearchResult) -> Box<dyn MediaProvider> {
+    Box::new(YtDlpMediaProvider::new(result.url.clone()))
+  }
+}
+
+/// Parses line-delimited `--flat-playlist --print-json` output of a `ytsearchN` query.
+pub fn parse_search_results(stdout: &str) -> Result<Vec<SearchResult>> {
+  Ok(parse_flat::<SearchItem>(stdout)?.into_iter().map(SearchResult::from).collect())
+}
+
+impl From<SearchItem> for SearchResult {
+  fn from(item: SearchItem) -> Self {
+    Self {
+      title: item.title,
+      url: item.url,
+      author: item.channel.or(item.uploader),
+      duration: item.duration.map(Duration::from_secs_f64)
+    }
+  }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchItem {
+  pub id: String,
+  pub title: String,
+  pub url: String,
+  pub channel: Option<String>,
+  pub uploader: Option<String>,
+  pub duration: Option<f64>
+}
+
+#[test]
+fn parse_flat_search_output() {
+  let stdout = concat!(
+    r#"{"_type": "url", "ie_key": "Youtube", "id": "dQw4w9WgXcQ", "url": "https://www.youtube.com/watch?v=dQw4w9WgXcQ", "title": "First", "channel": "Channel", "duration": 212.0}"#,
+    "\n",
+    r#"{"_type": "url", "ie_key": "Youtube", "id": "9bZkp7q19f0", "url": "https://www.youtube.com/watch?v=9bZkp7q19f0", "title": "Second", "uploader": "Uploader", "duration": null}"#,
+    "\n"
+  );
+
+  assert_eq!(parse_search_results(stdout).unwrap(), vec![
+    SearchResult {
+      title: "First".to_owned(),
+      url: "https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_owned(),
+      author: Some("Channel".to_owned()),
+      duration: Some(Duration::from_secs(212))
+    },
+    SearchResult {
+      title: "Second".to_owned(),
+      url: "https://www.youtube.com/watch?v=9bZkp7q19f0".to_owned(),
+      author: Some("Uploader".to_owned()),
+      duration: None
+    }
+  ]);
+}
+
+#[test]
+fn parse_empty_search_output() {
+  assert_eq!(parse_search_results("").unwrap(), vec![]);
+  assert!(parse_search_results("{\"title\": 1}").is_err());
+}
@@ -1,27 +1,29 @@
 use std::borrow::ToOwned;
 use std::env;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 use voice::provider::SampleProvider;
 
-use super::{metadata, FFmpegMediaProvider, MediaMetadata, MediaProvider};
+use super::{metadata, send_with_retry, FFmpegMediaProvider, HttpContext, MediaMetadata, MediaProvider};
 
 #[derive(Debug)]
 pub struct VkMediaProvider {
   owner_id: i64,
   track_id: i64,
+  http: HttpContext,
   track: Option<Track>
 }
 
 impl VkMediaProvider {
-  pub fn new(owner_id: i64, track_id: i64) -> Self {
+  pub fn new(owner_id: i64, track_id: i64, http: HttpContext) -> Self {
     Self {
       owner_id,
       track_id,
+      http,
       track: None
     }
   }
@@ -30,16 +32,12 @@ impl VkMediaProvider {
 #[async_trait]
 impl MediaProvider for VkMediaProvider {
   async fn init(&mut self) -> Result<()> {
-    let client = Client::new();
-    let response = client
-      .get("https://api.vk.com/method/audio.getById")
-      .query(&[
-        ("audios", format!("{}_{}", self.owner_id, self.track_id).as_str()),
-        ("access_token", &env::var("VK_ACCESS_TOKEN").unwrap()),
-        ("v", "5.221")
-      ])
-      .send()
-      .await?;
+    let response = send_with_retry(self.http.get("https://api.vk.com/method/audio.getById").query(&[
+      ("audios", format!("{}_{}", self.owner_id, self.track_id).as_str()),
+      ("access_token", &env::var("VK_ACCESS_TOKEN").unwrap()),
+      ("v", "5.221")
+    ]))
+    .await?;
     let body = response.text().await?;
     debug!("response: {}", body);
 
@@ -63,9 +61,24 @@ impl MediaProvider for VkMediaProvider {
 
   async fn get_metadata(&self) -> Result<Vec<MediaMetadata>> {
     Ok(metadata! {
-      Id => { Some(self.track_id.to_string()) }
+      Id => { Some(self.track_id.to_string()) },
+      Title => { self.track.as_ref().map(|track| track.title.clone()) },
+      Artist => { self.track.as_ref().map(|track| track.artist.clone()) },
+      Duration => { self.track.as_ref().map(|track| Duration::from_secs(track.duration.max(0) as u64)) },
+      Thumbnail => {
+        self
+          .track
+          .as_ref()
+          .and_then(|track| track.album.as_ref())
+          .and_then(|album| album.thumb.as_ref())
+          .and_then(|thumb| thumb.photo_600.as_ref().or(thumb.photo_300.as_ref()))
+      }
     })
   }
+
+  fn source(&self) -> Option<String> {
+    Some(format!("vk:{}_{}", self.owner_id, self.track_id))
+  }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -87,5 +100,61 @@ pub struct Track {
   pub track_code: String,
   pub url: String,
   pub date: i64,
-  pub genre_id: i64
+  pub genre_id: i64,
+  pub album: Option<Album>
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Album {
+  pub id: i64,
+  pub title: String,
+  pub thumb: Option<Thumb>
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Thumb {
+  pub photo_300: Option<String>,
+  pub photo_600: Option<String>
+}
+
+#[tokio::test]
+async fn metadata_from_response() {
+  let body = r#"{
+    "response": [{
+      "artist": "Artist",
+      "id": 456239017,
+      "owner_id": 2000000001,
+      "title": "Title",
+      "duration": 215,
+      "access_key": "key",
+      "is_explicit": false,
+      "is_focus_track": false,
+      "is_licensed": true,
+      "track_code": "code",
+      "url": "https://example.com/audio.mp3",
+      "date": 1700000000,
+      "genre_id": 18,
+      "album": {
+        "id": 1,
+        "title": "Album",
+        "thumb": {
+          "photo_300": "https://example.com/300.jpg",
+          "photo_600": "https://example.com/600.jpg"
+        }
+      }
+    }]
+  }"#;
+  let mut body = serde_json::from_str::<ResponseWrapper<Vec<Track>>>(body).unwrap();
+
+  let provider = VkMediaProvider {
+    track: Some(body.response.swap_remove(0)),
+    ..VkMediaProvider::new(2000000001, 456239017, HttpContext::default())
+  };
+  assert_eq!(provider.get_metadata().await.unwrap(), vec![
+    MediaMetadata::Id("456239017".to_owned()),
+    MediaMetadata::Title("Title".to_owned()),
+    MediaMetadata::Artist("Artist".to_owned()),
+    MediaMetadata::Duration(Duration::from_secs(215)),
+    MediaMetadata::Thumbnail("https://example.com/600.jpg".to_owned())
+  ]);
 }
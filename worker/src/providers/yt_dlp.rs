@@ -1,19 +1,171 @@
 use std::borrow::ToOwned;
 use std::cmp::Ordering;
-use std::process::Stdio;
+use std::env;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use debug_ignore::DebugIgnore;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use thiserror::Error;
 use tokio::process::Command;
 use tracing::debug;
 use voice::provider::SampleProvider;
 
 use super::{metadata, FFmpegMediaProvider, MediaMetadata, MediaProvider};
 
+/// Maximum length of yt-dlp stderr in errors, which are shown in Discord messages.
+pub const STDERR_ERROR_LIMIT: usize = 500;
+
+/// Options passed to every yt-dlp invocation, see [CommandBuilder].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct YtDlpOptions {
+  /// Netscape cookies file, needed for age-restricted media and to avoid sign-in checks.
+  pub cookies: Option<PathBuf>,
+  /// Passed as `--extractor-args`, e.g. `youtube:player_client=web`.
+  pub extractor_args: Vec<String>,
+  /// Passed as `--limit-rate`, e.g. `1M`.
+  pub rate_limit: Option<String>,
+  /// YouTube proof of origin token, e.g. `web+<token>`.
+  pub po_token: Option<String>,
+  pub proxy: Option<String>
+}
+
+impl YtDlpOptions {
+  /// Configured with `MOSAIK_YT_DLP_COOKIES`, `MOSAIK_YT_DLP_EXTRACTOR_ARGS` (separated by whitespace),
+  /// `MOSAIK_YT_DLP_RATE_LIMIT`, `MOSAIK_YT_DLP_PO_TOKEN` and `MOSAIK_YT_DLP_PROXY` (defaults to `MOSAIK_HTTP_PROXY`).
+  pub fn from_env() -> Self {
+    Self {
+      cookies: env::var_os("MOSAIK_YT_DLP_COOKIES").map(PathBuf::from),
+      extractor_args: env::var("MOSAIK_YT_DLP_EXTRACTOR_ARGS")
+        .map(|args| args.split_whitespace().map(ToOwned::to_owned).collect())
+        .unwrap_or_default(),
+      rate_limit: env::var("MOSAIK_YT_DLP_RATE_LIMIT").ok(),
+      po_token: env::var("MOSAIK_YT_DLP_PO_TOKEN").ok(),
+      proxy: env::var("MOSAIK_YT_DLP_PROXY").or_else(|_| env::var("MOSAIK_HTTP_PROXY")).ok()
+    }
+  }
+
+  /// Read from the environment once, see [Self::from_env].
+  pub fn global() -> &'static Self {
+    static OPTIONS: OnceLock<YtDlpOptions> = OnceLock::new();
+    OPTIONS.get_or_init(Self::from_env)
+  }
+
+  pub fn args(&self) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(cookies) = &self.cookies {
+      args.push("--cookies".to_owned());
+      args.push(cookies.to_string_lossy().into_owned());
+    }
+    for extractor_args in &self.extractor_args {
+      args.push("--extractor-args".to_owned());
+      args.push(extractor_args.clone());
+    }
+    if let Some(po_token) = &self.po_token {
+      args.push("--extractor-args".to_owned());
+      args.push(format!("youtube:po_token={}", po_token));
+    }
+    if let Some(rate_limit) = &self.rate_limit {
+      args.push("--limit-rate".to_owned());
+      args.push(rate_limit.clone());
+    }
+    if let Some(proxy) = &self.proxy {
+      args.push("--proxy".to_owned());
+      args.push(proxy.clone());
+    }
+    args
+  }
+}
+
+/// Builds yt-dlp commands with the [YtDlpOptions] before the command-specific arguments.
+#[derive(Debug, Clone)]
+pub struct CommandBuilder {
+  args: Vec<String>
+}
+
+impl CommandBuilder {
+  pub fn new() -> Self {
+    Self::with_options(YtDlpOptions::global())
+  }
+
+  pub fn with_options(options: &YtDlpOptions) -> Self {
+    Self { args: options.args() }
+  }
+
+  pub fn args<S: AsRef<str>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+    self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+    self
+  }
+
+  pub fn get_args(&self) -> &[String] {
+    &self.args
+  }
+
+  /// Returns the command with piped stdout and stderr.
+  pub fn build(&self) -> Command {
+    let mut command = Command::new("yt-dlp");
+    command.args(&self.args).stdout(Stdio::piped()).stderr(Stdio::piped());
+    command
+  }
+}
+
+/// Failed yt-dlp run, common errors are classified with user-facing messages, see [YtDlpError::classify].
+#[derive(Debug, Error)]
+pub enum YtDlpError {
+  #[error("This video is age-restricted and can not be played without yt-dlp cookies")]
+  AgeRestricted,
+  #[error("This video is not available in the region of the bot")]
+  GeoBlocked,
+  #[error("This video is private")]
+  Private,
+  #[error("YouTube requires signing in to play this video, yt-dlp cookies or a PO token are needed")]
+  SignInRequired,
+  #[error("yt-dlp failed ({status}): {stderr}")]
+  Failed { status: ExitStatus, stderr: String }
+}
+
+impl YtDlpError {
+  /// Classifies the `ERROR:` lines of `stderr`, other errors keep the end of `stderr` limited to [STDERR_ERROR_LIMIT].
+  pub fn classify(status: ExitStatus, stderr: &str) -> Self {
+    let errors = stderr
+      .lines()
+      .filter(|line| line.starts_with("ERROR:"))
+      .collect::<Vec<_>>()
+      .join("\n")
+      .to_lowercase();
+    if errors.contains("confirm your age") || errors.contains("age-restricted") {
+      return Self::AgeRestricted;
+    }
+    if errors.contains("available in your country") || errors.contains("not available from your location") {
+      return Self::GeoBlocked;
+    }
+    if errors.contains("private video") {
+      return Self::Private;
+    }
+    if errors.contains("not a bot") || errors.contains("sign in to confirm") {
+      return Self::SignInRequired;
+    }
+
+    let stderr = stderr.trim();
+    let stderr = match stderr.char_indices().rev().nth(STDERR_ERROR_LIMIT - 1) {
+      Some((index, _)) if index > 0 => format!("…{}", &stderr[index..]),
+      _ => stderr.to_owned()
+    };
+    Self::Failed { status, stderr }
+  }
+
+  /// Whether the error has a user-facing message instead of the yt-dlp output.
+  pub fn is_classified(&self) -> bool {
+    !matches!(self, Self::Failed { .. })
+  }
+}
+
 #[derive(Debug)]
 pub struct YtDlpMediaProvider {
   query: String,
@@ -29,10 +181,9 @@ impl YtDlpMediaProvider {
 #[async_trait]
 impl MediaProvider for YtDlpMediaProvider {
   async fn init(&mut self) -> Result<()> {
-    let output = Command::new("yt-dlp")
-      .args(&["--no-download", "--print-json", "--no-playlist", &self.query])
-      .stdout(Stdio::piped())
-      .stderr(Stdio::piped())
+    let output = CommandBuilder::new()
+      .args(["--no-download", "--print-json", "--no-playlist", &self.query])
+      .build()
       .stdin(Stdio::piped())
       .spawn()?
       .wait_with_output()
@@ -40,7 +191,7 @@ impl MediaProvider for YtDlpMediaProvider {
     if !output.status.success() {
       let stderr = String::from_utf8_lossy(&output.stderr);
       debug!("yt-dlp media provider error: {:?}", stderr);
-      return Err(anyhow!("yt-dlp exit code {:?}: {}", output.status.code(), stderr));
+      return Err(exit_error(output.status, &stderr));
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
@@ -112,8 +263,46 @@ impl MediaProvider for YtDlpMediaProvider {
       Title => { data["title"].as_str() },
       Url => { data["original_url"].as_str() },
       Duration => { data["duration"].as_u64().map(|it| Duration::from_secs(it)) },
+      Thumbnail => { data["thumbnail"].as_str() },
     })
   }
+
+  fn source(&self) -> Option<String> {
+    Some(format!("yt-dlp:{}", self.query))
+  }
+}
+
+/// Runs `yt-dlp --flat-playlist --print-json` with `args` and parses the item printed on each line.
+///
+/// Used for playlists and searches, where only the URLs and basic info of the entries are needed.
+pub async fn run_flat<T: DeserializeOwned>(args: &[&str]) -> Result<Vec<T>> {
+  let output = CommandBuilder::new()
+    .args(["--no-download", "--print-json", "--flat-playlist"])
+    .args(args)
+    .build()
+    .stdin(Stdio::piped())
+    .spawn()?
+    .wait_with_output()
+    .await?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("yt-dlp flat playlist error: {:?}", stderr);
+    return Err(exit_error(output.status, &stderr));
+  }
+
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  parse_flat(&stdout)
+}
+
+/// Error of a failed yt-dlp run, see [YtDlpError::classify].
+pub fn exit_error(status: ExitStatus, stderr: &str) -> anyhow::Error {
+  YtDlpError::classify(status, stderr).into()
+}
+
+/// Parses line-delimited `--flat-playlist --print-json` output.
+pub fn parse_flat<T: DeserializeOwned>(stdout: &str) -> Result<Vec<T>> {
+  let deserializer = serde_json::Deserializer::from_str(stdout);
+  Ok(deserializer.into_iter::<T>().collect::<Result<Vec<_>, _>>()?)
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -135,3 +324,104 @@ pub struct Format {
   pub vbr: Option<f64>,
   pub abr: Option<f64>
 }
+
+#[tokio::test]
+async fn metadata_keeps_thumbnail() {
+  let provider = YtDlpMediaProvider {
+    query: "https://youtu.be/dQw4w9WgXcQ".to_owned(),
+    data: Some(
+      serde_json::json!({
+        "id": "dQw4w9WgXcQ",
+        "title": "Title",
+        "thumbnail": "https://i.ytimg.com/vi/dQw4w9WgXcQ/maxresdefault.jpg"
+      })
+      .into()
+    )
+  };
+
+  let metadata = provider.get_metadata().await.unwrap();
+  assert_eq!(
+    super::get_metadata!(metadata, MediaMetadata::Thumbnail(url) => url.as_str()),
+    Some("https://i.ytimg.com/vi/dQw4w9WgXcQ/maxresdefault.jpg")
+  );
+}
+
+#[test]
+fn limits_stderr_in_exit_errors() {
+  use std::os::unix::process::ExitStatusExt;
+
+  let status = ExitStatus::from_raw(1 << 8);
+  let error = exit_error(status, "ERROR: [generic] Unsupported URL: https://example.com\n");
+  assert_eq!(
+    error.to_string(),
+    "yt-dlp failed (exit status: 1): ERROR: [generic] Unsupported URL: https://example.com"
+  );
+
+  let stderr = format!("{}ERROR: Video unavailable", "WARNING: ".repeat(100));
+  let error = exit_error(status, &stderr).to_string();
+  assert!(error.ends_with("ERROR: Video unavailable"));
+  assert_eq!(error.split_once("): ").unwrap().1.chars().count(), STDERR_ERROR_LIMIT + 1);
+}
+
+#[test]
+fn classifies_yt_dlp_errors() {
+  use std::os::unix::process::ExitStatusExt;
+
+  let classify = |stderr: &str| YtDlpError::classify(ExitStatus::from_raw(1 << 8), stderr);
+
+  assert!(matches!(
+    classify(
+      "WARNING: [youtube] Falling back to generic n function search\n\
+       ERROR: [youtube] 07FYdnEawAQ: Sign in to confirm your age. This video may be inappropriate for some users.\n"
+    ),
+    YtDlpError::AgeRestricted
+  ));
+  assert!(matches!(
+    classify(
+      "ERROR: [youtube] sJL6WA-aGkQ: Video unavailable. The uploader has not made this video available in your \
+       country\n"
+    ),
+    YtDlpError::GeoBlocked
+  ));
+  assert!(matches!(
+    classify("ERROR: [youtube] 9bZkp7q19f0: Private video. Sign in if you've been granted access to this video\n"),
+    YtDlpError::Private
+  ));
+  assert!(matches!(
+    classify(
+      "ERROR: [youtube] dQw4w9WgXcQ: Sign in to confirm you\u{2019}re not a bot. Use --cookies-from-browser or \
+       --cookies for the authentication.\n"
+    ),
+    YtDlpError::SignInRequired
+  ));
+
+  // Only errors are classified, not warnings
+  let error = classify("WARNING: private video support is experimental\nERROR: [youtube] abc: Video unavailable\n");
+  assert!(!error.is_classified());
+  assert!(error.to_string().ends_with("ERROR: [youtube] abc: Video unavailable"));
+}
+
+#[test]
+fn passes_options_to_commands() {
+  let options = YtDlpOptions {
+    cookies: Some(PathBuf::from("/etc/mosaik/cookies.txt")),
+    extractor_args: vec!["youtube:player_client=web".to_owned()],
+    rate_limit: Some("1M".to_owned()),
+    po_token: Some("web+token".to_owned()),
+    proxy: None
+  };
+  let command = CommandBuilder::with_options(&options).args(["--no-download", "query"]);
+  assert_eq!(command.get_args(), [
+    "--cookies",
+    "/etc/mosaik/cookies.txt",
+    "--extractor-args",
+    "youtube:player_client=web",
+    "--extractor-args",
+    "youtube:po_token=web+token",
+    "--limit-rate",
+    "1M",
+    "--no-download",
+    "query"
+  ]);
+  assert!(CommandBuilder::with_options(&YtDlpOptions::default()).get_args().is_empty());
+}
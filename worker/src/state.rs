@@ -1,15 +1,79 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use anyhow::Result;
 use serenity::all::GuildId;
-use tokio::sync::RwLock;
+use sqlx::SqlitePool;
+use tokio::sync::{RwLock, Semaphore};
 
+use crate::db::{load_config, GuildConfig};
+use crate::logs::GuildLogs;
 use crate::player::Player;
+use crate::playlist::PlaylistStorage;
+use crate::providers::HttpContext;
+
+/// Default number of media providers initialized at once, overridden with `MOSAIK_INIT_CONCURRENCY`.
+pub const DEFAULT_INIT_CONCURRENCY: usize = 3;
 
 pub type State = Arc<StateRef>;
 
 pub struct StateRef {
-  pub players: RwLock<HashMap<GuildId, Arc<Player>>>
+  pub players: RwLock<HashMap<GuildId, Arc<Player>>>,
+  pub db: SqlitePool,
+  /// Guild configs loaded from [Self::db], see [Self::config].
+  pub configs: RwLock<HashMap<GuildId, GuildConfig>>,
+  pub playlists: Box<dyn PlaylistStorage>,
+  /// Queues saved with the `queue save` command.
+  pub saved_queues: Box<dyn PlaylistStorage>,
+  /// Limits concurrent [`MediaProvider::init`](crate::providers::MediaProvider::init) calls,
+  /// which spawn yt-dlp processes or call external APIs.
+  pub init_permits: Arc<Semaphore>,
+  /// Recent log events per guild, see the `debug logs` command.
+  pub logs: Arc<GuildLogs>,
+  /// Shared by media providers, see [HttpContext].
+  pub http: HttpContext
+}
+
+impl StateRef {
+  /// Returns the config of `guild_id`, loading it from the database only if it is not cached.
+  pub async fn config(&self, guild_id: GuildId) -> Result<GuildConfig> {
+    if let Some(config) = self.configs.read().await.get(&guild_id) {
+      return Ok(config.clone());
+    }
+
+    let config = load_config(&self.db, guild_id.get()).await?;
+    self.configs.write().await.insert(guild_id, config.clone());
+    Ok(config)
+  }
+
+  /// Drops the cached config of `guild_id`, must be called after saving it.
+  pub async fn invalidate_config(&self, guild_id: GuildId) {
+    self.configs.write().await.remove(&guild_id);
+  }
+
+  /// State with an in-memory database and storage in a temporary directory unique to this call.
+  #[cfg(test)]
+  pub async fn for_test() -> StateRef {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let root = std::env::temp_dir().join(format!(
+      "mosaik-state-{}-{}",
+      std::process::id(),
+      NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    StateRef {
+      players: Default::default(),
+      db: crate::db::connect("sqlite::memory:").await.unwrap(),
+      configs: Default::default(),
+      playlists: Box::new(crate::playlist::FsPlaylistStorage::new(root.join("playlists"))),
+      saved_queues: Box::new(crate::playlist::FsPlaylistStorage::new(root.join("queues"))),
+      init_permits: Arc::new(Semaphore::new(DEFAULT_INIT_CONCURRENCY)),
+      logs: Arc::new(GuildLogs::new(0)),
+      http: Default::default()
+    }
+  }
 }
 
 macro_rules! get_player_or_fail {
@@ -29,3 +93,18 @@ macro_rules! get_player_or_fail {
 }
 
 pub(crate) use get_player_or_fail;
+
+#[tokio::test]
+async fn caches_configs_until_invalidated() {
+  let state = StateRef::for_test().await;
+  let guild_id = GuildId::new(1);
+  assert_eq!(state.config(guild_id).await.unwrap().prefix, None);
+
+  let mut config = GuildConfig::new(1);
+  config.prefix = Some("!".to_owned());
+  crate::db::save_config(&state.db, &config).await.unwrap();
+  assert_eq!(state.config(guild_id).await.unwrap().prefix, None);
+
+  state.invalidate_config(guild_id).await;
+  assert_eq!(state.config(guild_id).await.unwrap(), config);
+}
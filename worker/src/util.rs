@@ -1,8 +1,255 @@
+use std::env;
+use std::time::Duration;
+
+use anyhow::Context;
+use poise::CreateReply;
+use serenity::all::{ChannelId, GuildId, RoleId, UserId};
 use thiserror::Error;
+use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+use crate::db::GuildConfig;
+use crate::{AnyError, PoiseContext, State};
+
+/// Converts an interleaved sample count to its playback duration.
+pub fn samples_to_duration(samples: usize) -> Duration {
+  let frames = (samples / CHANNEL_COUNT) as u64;
+  Duration::from_nanos(frames.saturating_mul(1_000_000_000) / SAMPLE_RATE as u64)
+}
+
+/// Formats `duration` as `m:ss` or `h:mm:ss`.
+pub fn format_duration(duration: Duration) -> String {
+  let seconds = duration.as_secs();
+  let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+  if hours > 0 {
+    format!("{}:{:02}:{:02}", hours, minutes, seconds)
+  } else {
+    format!("{}:{:02}", minutes, seconds)
+  }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PositionParseError {
+  #[error("No position given")]
+  Empty,
+  #[error("Invalid position `{0}`, expected e.g. `90`, `12.5`, `1:30`, `1:02:03`, `50%`, `+30` or `-1:00`")]
+  Invalid(String),
+  #[error("Track duration is unknown, cannot seek to a percentage")]
+  UnknownDuration,
+  #[error("Invalid clip range `{0}`, expected e.g. `1:23:45-1:25:00`, `1:30-` or `-2:00`")]
+  InvalidRange(String)
+}
+
+/// Parses a seek position relative to the `current` one.
+///
+/// Supported formats are seconds (`90`, `12.5`), `mm:ss`, `hh:mm:ss` and percent of `total` (`50%`),
+/// each optionally prefixed with `+` or `-` to seek relatively. If `total` is known, the result is clamped
+/// to one second before the end.
+pub fn parse_position(input: &str, current: Duration, total: Option<Duration>) -> Result<Duration, PositionParseError> {
+  let input = input.trim();
+  let invalid = || PositionParseError::Invalid(input.to_owned());
+
+  let (sign, value) = match input.chars().next() {
+    Some('+') => (Some(true), &input[1..]),
+    Some('-') => (Some(false), &input[1..]),
+    Some(_) => (None, input),
+    None => return Err(PositionParseError::Empty)
+  };
+
+  let offset = if let Some(percent) = value.strip_suffix('%') {
+    let total = total.ok_or(PositionParseError::UnknownDuration)?;
+    let percent = parse_decimal(percent).ok_or_else(invalid)?;
+    Duration::try_from_secs_f64(total.as_secs_f64() * percent / 100.0).map_err(|_| invalid())?
+  } else {
+    parse_timestamp(value).ok_or_else(invalid)?
+  };
+
+  let position = match sign {
+    Some(true) => current.saturating_add(offset),
+    Some(false) => current.saturating_sub(offset),
+    None => offset
+  };
+
+  Ok(match total {
+    Some(total) => position.min(total.saturating_sub(Duration::from_secs(1))),
+    None => position
+  })
+}
+
+/// Parses a clip range `start-end` of timestamps, either of which may be omitted (`1:30-`, `-2:00`).
+pub fn parse_clip_range(input: &str) -> Result<(Option<Duration>, Option<Duration>), PositionParseError> {
+  let input = input.trim();
+  let invalid = || PositionParseError::InvalidRange(input.to_owned());
+
+  let (start, end) = input.split_once('-').ok_or_else(invalid)?;
+  let parse = |value: &str| match value.trim() {
+    "" => Ok(None),
+    value => parse_timestamp(value).map(Some).ok_or_else(invalid)
+  };
+  let (start, end) = (parse(start)?, parse(end)?);
 
-#[derive(Debug, Error)]
-#[error("Enum variant mismatch")]
-pub struct MismatchError;
+  match (start, end) {
+    (None, None) => Err(invalid()),
+    (Some(start), Some(end)) if end <= start => Err(invalid()),
+    range => Ok(range)
+  }
+}
+
+/// Parses `ss`, `mm:ss` or `hh:mm:ss`, where seconds may be fractional.
+fn parse_timestamp(value: &str) -> Option<Duration> {
+  let parts = value.split(':').collect::<Vec<_>>();
+  if parts.len() > 3 {
+    return None;
+  }
+
+  let (seconds, rest) = parts.split_last()?;
+  let seconds = Duration::try_from_secs_f64(parse_decimal(seconds)?).ok()?;
+  if !rest.is_empty() && seconds >= Duration::from_secs(60) {
+    return None;
+  }
+
+  let mut whole = 0u64;
+  for (index, part) in rest.iter().enumerate() {
+    if part.is_empty() || !part.chars().all(|it| it.is_ascii_digit()) {
+      return None;
+    }
+
+    let part = part.parse::<u64>().ok()?;
+    // Minutes must be below 60 if hours are given
+    if index > 0 && part >= 60 {
+      return None;
+    }
+    whole = whole.checked_mul(60)?.checked_add(part)?;
+  }
+
+  Duration::from_secs(whole.checked_mul(60)?).checked_add(seconds)
+}
+
+/// Parses a non-negative decimal number, rejecting forms like `inf`, `NaN` or `1e3` accepted by [f64::from_str].
+fn parse_decimal(value: &str) -> Option<f64> {
+  if value.is_empty() || !value.chars().all(|it| it.is_ascii_digit() || it == '.') {
+    return None;
+  }
+  value.parse::<f64>().ok()
+}
+
+/// Encodes interleaved samples as a 16-bit PCM WAV file.
+pub fn encode_wav(samples: &[f32]) -> Vec<u8> {
+  voice::wav::encode_wav(samples, SAMPLE_RATE as u32, CHANNEL_COUNT as u16)
+}
+
+/// Parses the `DJ_ROLE_ID` environment variable value, `None` means everyone is a DJ.
+pub fn parse_dj_role_id(value: Option<&str>) -> anyhow::Result<Option<RoleId>> {
+  match value.map(str::trim).filter(|value| !value.is_empty()) {
+    Some(value) => {
+      let id = value
+        .parse::<u64>()
+        .ok()
+        .filter(|id| *id != 0)
+        .with_context(|| format!("invalid DJ_ROLE_ID `{}`", value))?;
+      Ok(Some(RoleId::new(id)))
+    }
+    None => Ok(None)
+  }
+}
+
+/// Command check allowing only members with the DJ role, administrators and members alone with the bot in its
+/// voice channel.
+///
+/// The role is taken from the guild config, falling back to the `DJ_ROLE_ID` environment variable.
+/// Replies ephemerally with the required role when the check fails.
+pub async fn check_dj_permission(ctx: PoiseContext<'_>) -> Result<bool, AnyError> {
+  let Some(guild_id) = ctx.guild_id() else {
+    return Ok(true);
+  };
+  let config = ctx.data().config(guild_id).await?;
+  check_dj_role(ctx, guild_id, &config).await
+}
+
+/// [check_dj_permission] with the already loaded `config` of `guild_id`.
+async fn check_dj_role(ctx: PoiseContext<'_>, guild_id: GuildId, config: &GuildConfig) -> Result<bool, AnyError> {
+  let dj_role_id = match config.dj_role_id {
+    Some(id) => Some(RoleId::new(id)),
+    None => parse_dj_role_id(env::var("DJ_ROLE_ID").ok().as_deref())?
+  };
+  let Some(dj_role_id) = dj_role_id else {
+    return Ok(true);
+  };
+
+  let member = ctx.author_member().await.context("failed to get command author member")?;
+  let bot_id = ctx.cache().current_user().id;
+  let (allowed, role_name) = {
+    // Cache guard must not be held across awaits
+    let guild = guild_id.to_guild_cached(ctx.cache()).context("no guild cached")?;
+    let voice_states = guild
+      .voice_states
+      .values()
+      .map(|state| {
+        let is_bot = state.member.as_ref().is_some_and(|member| member.user.bot);
+        (state.user_id, state.channel_id, is_bot)
+      })
+      .collect::<Vec<_>>();
+    let allowed = member.roles.contains(&dj_role_id)
+      || guild.owner_id == member.user.id
+      || member
+        .roles
+        .iter()
+        .filter_map(|role_id| guild.roles.get(role_id))
+        .any(|role| role.permissions.administrator())
+      || is_alone_with_bot(&voice_states, bot_id, member.user.id);
+    (allowed, guild.roles.get(&dj_role_id).map(|role| role.name.clone()))
+  };
+
+  if !allowed {
+    let role = role_name.unwrap_or_else(|| dj_role_id.get().to_string());
+    ctx
+      .send(
+        CreateReply::default()
+          .content(format!("You need the `{}` role to use this command.", role))
+          .ephemeral(true)
+      )
+      .await?;
+  }
+  Ok(allowed)
+}
+
+/// Global command check enforcing [check_dj_permission] for the commands in the `dj-commands` guild setting.
+pub async fn check_restricted_command(ctx: PoiseContext<'_>) -> Result<bool, AnyError> {
+  let Some(guild_id) = ctx.guild_id() else {
+    return Ok(true);
+  };
+  let config = ctx.data().config(guild_id).await?;
+  if !config.dj_commands.contains(&ctx.command().qualified_name) {
+    return Ok(true);
+  }
+  check_dj_role(ctx, guild_id, &config).await
+}
+
+/// Qualified names of `commands` and their subcommands.
+pub fn command_names(commands: &[poise::Command<State, AnyError>]) -> Vec<String> {
+  commands
+    .iter()
+    .flat_map(|command| {
+      let mut names = vec![command.qualified_name.clone()];
+      names.extend(command_names(&command.subcommands));
+      names
+    })
+    .collect()
+}
+
+/// Whether `user_id` is the only listener besides bots in the voice channel of `bot_id`.
+///
+/// `voice_states` are the user, voice channel and whether the user is a bot.
+pub fn is_alone_with_bot(voice_states: &[(UserId, Option<ChannelId>, bool)], bot_id: UserId, user_id: UserId) -> bool {
+  let channel_of = |id: UserId| voice_states.iter().find(|state| state.0 == id).and_then(|state| state.1);
+  let Some(channel_id) = channel_of(bot_id) else {
+    return false;
+  };
+  channel_of(user_id) == Some(channel_id)
+    && voice_states
+      .iter()
+      .filter(|(_, channel, is_bot)| *channel == Some(channel_id) && !is_bot)
+      .all(|(id, _, _)| *id == user_id || *id == bot_id)
+}
 
 #[macro_export]
 macro_rules! include_and_export {
@@ -14,63 +261,126 @@ macro_rules! include_and_export {
   };
 }
 
-#[macro_export]
-macro_rules! try_unpack {
-  ($value:expr, $variant:path) => {{
-    match $value {
-      $variant(x) => Ok(x),
-      _ => Err($crate::util::MismatchError)
-    }
-  }};
+#[test]
+fn alone_with_bot_counts_as_dj() {
+  let (bot, user, other, other_bot) = (UserId::new(1), UserId::new(2), UserId::new(3), UserId::new(4));
+  let (music, general) = (Some(ChannelId::new(10)), Some(ChannelId::new(11)));
+
+  assert!(is_alone_with_bot(&[(bot, music, true), (user, music, false)], bot, user));
+  // Other bots are not listeners
+  assert!(is_alone_with_bot(&[(bot, music, true), (user, music, false), (other_bot, music, true)], bot, user));
+  assert!(!is_alone_with_bot(&[(bot, music, true), (user, music, false), (other, music, false)], bot, user));
+  // Listeners of other channels do not matter, but the user must be in the channel of the bot
+  assert!(is_alone_with_bot(&[(bot, music, true), (user, music, false), (other, general, false)], bot, user));
+  assert!(!is_alone_with_bot(&[(bot, music, true), (user, general, false)], bot, user));
+  assert!(!is_alone_with_bot(&[(user, music, false)], bot, user));
 }
 
-#[macro_export]
-macro_rules! interaction_response {
-  ($type:ident $(, $method:ident ( $( $arg:expr ),* ))*) => {{
-    use ::twilight_model::http::interaction::{InteractionResponse, InteractionResponseType};
-    use ::twilight_util::builder::InteractionResponseDataBuilder;
+#[test]
+fn format_durations() {
+  assert_eq!(format_duration(Duration::ZERO), "0:00");
+  assert_eq!(format_duration(Duration::from_millis(212_900)), "3:32");
+  assert_eq!(format_duration(Duration::from_secs(3723)), "1:02:03");
+}
 
-    let mut builder = InteractionResponseDataBuilder::new();
-    $(builder = builder.$method($($arg),*);)*
+#[test]
+fn parse_position_formats() {
+  let current = Duration::from_secs(100);
+  let total = Some(Duration::from_secs(3600));
 
-    InteractionResponse {
-      kind: InteractionResponseType::$type,
-      data: Some(builder.build())
-    }
-  }}
+  assert_eq!(parse_position("90", current, total), Ok(Duration::from_secs(90)));
+  assert_eq!(parse_position("12.5", current, total), Ok(Duration::from_millis(12500)));
+  assert_eq!(parse_position("1:30", current, total), Ok(Duration::from_secs(90)));
+  assert_eq!(parse_position("90:00", current, None), Ok(Duration::from_secs(5400)));
+  assert_eq!(parse_position("0:05.5", current, total), Ok(Duration::from_millis(5500)));
+  assert_eq!(parse_position("1:02:03", current, None), Ok(Duration::from_secs(3723)));
+  assert_eq!(parse_position(" 42 ", current, total), Ok(Duration::from_secs(42)));
 }
 
-#[macro_export]
-macro_rules! get_option {
-  ($command:expr, $name:expr) => {
-    $command.options.iter().find(|it| it.name == $name).map(|it| it.value)
-  };
+#[test]
+fn parse_position_relative() {
+  let current = Duration::from_secs(100);
+  let total = Some(Duration::from_secs(3600));
+
+  assert_eq!(parse_position("+30", current, total), Ok(Duration::from_secs(130)));
+  assert_eq!(parse_position("-10", current, total), Ok(Duration::from_secs(90)));
+  assert_eq!(parse_position("+1:30", current, total), Ok(Duration::from_secs(190)));
+  assert_eq!(parse_position("-2.5", current, total), Ok(Duration::from_millis(97500)));
+  assert_eq!(parse_position("-5:00", current, total), Ok(Duration::ZERO));
+  assert_eq!(parse_position("+10%", current, total), Ok(Duration::from_secs(460)));
 }
 
-#[macro_export]
-macro_rules! get_option_as {
-  ($command:expr, $name:expr, $type:path) => {{
-    let value = $command.options.iter().find(|it| it.name == $name).map(|it| &it.value);
-    value.map(|it| try_unpack!(it, $type))
-  }};
+#[test]
+fn parse_position_percent() {
+  let total = Some(Duration::from_secs(200));
+
+  assert_eq!(parse_position("50%", Duration::ZERO, total), Ok(Duration::from_secs(100)));
+  assert_eq!(parse_position("12.5%", Duration::ZERO, total), Ok(Duration::from_secs(25)));
+  assert_eq!(parse_position("50%", Duration::ZERO, None), Err(PositionParseError::UnknownDuration));
 }
 
-#[macro_export]
-macro_rules! reply {
-  ($state:expr, $interaction:expr, $response:expr) => {
-    $state
-      .http
-      .interaction($state.application_id)
-      .create_response($interaction.id, &$interaction.token, $response)
-  };
+#[test]
+fn parse_position_clamps_to_end() {
+  let total = Some(Duration::from_secs(200));
+
+  assert_eq!(parse_position("300", Duration::ZERO, total), Ok(Duration::from_secs(199)));
+  assert_eq!(parse_position("+5:00", Duration::from_secs(150), total), Ok(Duration::from_secs(199)));
+  assert_eq!(parse_position("150%", Duration::ZERO, total), Ok(Duration::from_secs(199)));
+  assert_eq!(parse_position("300", Duration::ZERO, None), Ok(Duration::from_secs(300)));
+
+  let overflowing = "99999999999999999999:00";
+  assert_eq!(
+    parse_position(overflowing, Duration::ZERO, None),
+    Err(PositionParseError::Invalid(overflowing.to_owned()))
+  );
 }
 
-#[macro_export]
-macro_rules! update_reply {
-  ($state:expr, $interaction:expr) => {
-    $state
-      .http
-      .interaction($state.application_id)
-      .update_response(&$interaction.token)
-  };
+#[test]
+fn parse_clip_ranges() {
+  assert_eq!(
+    parse_clip_range("1:23:45-1:25:00"),
+    Ok((Some(Duration::from_secs(5025)), Some(Duration::from_secs(5100))))
+  );
+  assert_eq!(parse_clip_range("1:30-"), Ok((Some(Duration::from_secs(90)), None)));
+  assert_eq!(parse_clip_range("-2:00"), Ok((None, Some(Duration::from_secs(120)))));
+  assert_eq!(parse_clip_range(" 10 - 12.5 "), Ok((Some(Duration::from_secs(10)), Some(Duration::from_millis(12500)))));
+
+  for input in ["", "-", "1:30", "2:00-1:00", "1:00-1:00", "a-b", "1:00-2:00-3:00"] {
+    assert_eq!(parse_clip_range(input), Err(PositionParseError::InvalidRange(input.trim().to_owned())), "{}", input);
+  }
+}
+
+#[test]
+fn parse_position_garbage() {
+  let current = Duration::from_secs(100);
+
+  assert_eq!(parse_position("", current, None), Err(PositionParseError::Empty));
+  for input in ["abc", "+", "1:2:3:4", "1:60", "1:60:00", ":30", "1::30", "1.2.3", "inf", "NaN", "1e3", "--5", "5%%"] {
+    assert_eq!(
+      parse_position(input, current, Some(Duration::from_secs(200))),
+      Err(PositionParseError::Invalid(input.to_owned())),
+      "{}",
+      input
+    );
+  }
+}
+
+#[test]
+fn dj_role_id() {
+  assert_eq!(parse_dj_role_id(None).unwrap(), None);
+  assert_eq!(parse_dj_role_id(Some(" ")).unwrap(), None);
+  assert_eq!(parse_dj_role_id(Some("123")).unwrap(), Some(RoleId::new(123)));
+  assert!(parse_dj_role_id(Some("0")).is_err());
+  assert!(parse_dj_role_id(Some("dj")).is_err());
+}
+
+#[test]
+fn wav_header() {
+  let wav = encode_wav(&[0.0, 1.0, -1.0, 2.0]);
+  assert_eq!(wav.len(), 44 + 8);
+  assert_eq!(&wav[..4], b"RIFF");
+  assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 8);
+  assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), SAMPLE_RATE as u32);
+  assert_eq!(&wav[36..40], b"data");
+  assert_eq!(&wav[44..], &[0, 0, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
 }
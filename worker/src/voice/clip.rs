@@ -0,0 +1,139 @@
+use std::any::Any;
+use std::time::Duration;
+
+use voice::provider::{SampleProvider, SampleProviderHandle};
+
+use crate::voice::ffmpeg::FFmpegSampleProviderHandle;
+
+/// Plays the part of `inner` from `start` until `end`, see [Track::with_clip](crate::player::track::Track::with_clip).
+///
+/// Seekable FFmpeg providers should be seeked to `start` beforehand, their position is taken from the decoder,
+/// so that seeks within the clip are accounted. Other providers are read from the beginning
+/// and samples before `start` are skipped.
+pub struct ClipSampleProvider {
+  inner: Box<dyn SampleProvider>,
+  start: Duration,
+  end: Option<Duration>,
+  handle: Box<dyn SampleProviderHandle>,
+  /// Interleaved samples read from `inner`, the position of providers without a decoder.
+  read: usize
+}
+
+impl ClipSampleProvider {
+  pub fn new(inner: Box<dyn SampleProvider>, start: Duration, end: Option<Duration>) -> Self {
+    let handle = inner.get_handle();
+    Self {
+      inner,
+      start,
+      end,
+      handle,
+      read: 0
+    }
+  }
+
+  fn to_duration(&self, samples: usize) -> Duration {
+    let spec = self.inner.spec();
+    Duration::from_secs_f64((samples / spec.channels) as f64 / spec.sample_rate as f64)
+  }
+
+  fn to_samples(&self, duration: Duration) -> usize {
+    let spec = self.inner.spec();
+    (duration.as_secs_f64() * spec.sample_rate as f64) as usize * spec.channels
+  }
+}
+
+impl SampleProvider for ClipSampleProvider {
+  fn get_samples(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+    let Some(mut samples) = self.inner.get_samples()? else {
+      return Ok(None);
+    };
+
+    let ffmpeg = self.handle.as_any().downcast_ref::<FFmpegSampleProviderHandle>();
+    let position = match ffmpeg.and_then(|handle| handle.get_frame_pts().ok()) {
+      Some(pts) => pts,
+      None => self.to_duration(self.read)
+    };
+    self.read += samples.len();
+
+    if let Some(end) = self.end {
+      if position >= end {
+        return Ok(None);
+      }
+      samples.truncate(self.to_samples(end - position));
+    }
+    if position < self.start {
+      let skip = self.to_samples(self.start - position).min(samples.len());
+      // Empty if the whole chunk is skipped, which requests a retry
+      samples.drain(..skip);
+    }
+
+    Ok(Some(samples))
+  }
+
+  fn spec(&self) -> voice::provider::ProviderSpec {
+    self.inner.spec()
+  }
+
+  fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+    self
+  }
+
+  fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+    self.inner.get_handle()
+  }
+}
+
+#[test]
+fn clips_provider_without_decoder() {
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+  use voice::provider::ProviderSpec;
+
+  /// Returns 10 ms chunks of the index of the chunk.
+  struct ChunkProvider {
+    chunks: usize
+  }
+  struct ChunkProviderHandle;
+
+  impl SampleProvider for ChunkProvider {
+    fn get_samples(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+      if self.chunks == 100 {
+        return Ok(None);
+      }
+      self.chunks += 1;
+      Ok(Some(vec![self.chunks as f32 - 1.0; SAMPLE_RATE / 100 * CHANNEL_COUNT]))
+    }
+
+    fn spec(&self) -> ProviderSpec {
+      ProviderSpec::default()
+    }
+
+    fn as_any(&mut self) -> &mut (dyn Any + Sync + Send) {
+      self
+    }
+
+    fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
+      Box::new(ChunkProviderHandle)
+    }
+  }
+
+  impl SampleProviderHandle for ChunkProviderHandle {
+    fn as_any(&self) -> &(dyn Any + Sync + Send) {
+      self
+    }
+  }
+
+  let mut provider = ClipSampleProvider::new(
+    Box::new(ChunkProvider { chunks: 0 }),
+    Duration::from_millis(205),
+    Some(Duration::from_millis(500))
+  );
+  let mut played = Vec::new();
+  while let Some(samples) = provider.get_samples().unwrap() {
+    played.extend(samples);
+  }
+
+  // From the middle of chunk 20 until the end of chunk 49
+  assert_eq!(played.len(), SAMPLE_RATE * 295 / 1000 * CHANNEL_COUNT);
+  assert_eq!(played.first(), Some(&20.0));
+  assert_eq!(played.last(), Some(&49.0));
+}
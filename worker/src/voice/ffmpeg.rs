@@ -1,52 +1,119 @@
 use std::any::Any;
+use std::io::Read;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::anyhow;
-use decoder::{Decoder, RawError};
-use tracing::debug;
-use voice::provider::{SampleProvider, SampleProviderHandle};
+use decoder::{estimate_byte_offset, Decoder, DecoderError, FrameResult};
+use flume::Sender;
+use tracing::{debug, warn};
+use voice::error::SampleProviderError;
+use voice::provider::{ProviderSpec, ProviderStats, SampleProvider, SampleProviderHandle};
+
+use crate::providers::{backoff_delay, parse_icy_stream_title, MediaMetadata, RETRY_MAX_ATTEMPTS};
 
 pub struct FFmpegSampleProvider {
   pub decoder: Arc<Mutex<Decoder>>,
-  flushing: bool
+  flushing: bool,
+  /// Consecutive transient read errors, the track ends after [RETRY_MAX_ATTEMPTS].
+  transient_errors: u32,
+  /// Updated after each read, so that handles do not wait for the decoder lock.
+  stats: Arc<Mutex<ProviderStats>>,
+  metadata_updates: Option<Sender<MediaMetadata>>,
+  /// Last ICY metadata packet, used to detect title changes.
+  icy_metadata: Option<String>
 }
 
 impl FFmpegSampleProvider {
   pub fn new() -> Self {
     Self {
       decoder: Arc::new(Mutex::new(Decoder::new())),
-      flushing: false
+      flushing: false,
+      transient_errors: 0,
+      stats: Default::default(),
+      metadata_updates: None,
+      icy_metadata: None
     }
   }
 
-  pub fn open(&mut self, path: &str) -> anyhow::Result<()> {
+  /// Sends [MediaMetadata::Title] to `sender` when the title of an internet radio stream changes.
+  pub fn with_metadata_updates(mut self, sender: Sender<MediaMetadata>) -> Self {
+    self.metadata_updates = Some(sender);
+    self
+  }
+
+  pub fn open(&mut self, path: &str) -> Result<(), DecoderError> {
     let mut decoder = self.decoder.lock().unwrap();
-    decoder
-      .open_input(path)
-      .map_err(|code| anyhow!("ffmpeg error {}", code))
+    decoder.open_input(path)
   }
 
-  pub fn init_filters(&mut self, description: &str) -> Result<(), RawError> {
+  /// Opens an input read from `reader`, see [Decoder::open_reader]. Blocks until the input header is read.
+  pub fn open_reader(&mut self, reader: Box<dyn Read + Send>) -> Result<(), DecoderError> {
+    let mut decoder = self.decoder.lock().unwrap();
+    decoder.open_reader(reader)
+  }
+
+  pub fn init_filters(&mut self, description: &str) -> Result<(), DecoderError> {
     let mut decoder = self.decoder.lock().unwrap();
     decoder.init_filters(description)
   }
 }
 
 impl SampleProvider for FFmpegSampleProvider {
-  fn get_samples(&mut self) -> Option<Vec<f32>> {
+  fn get_samples(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
     let mut decoder = self.decoder.lock().unwrap();
-    match decoder.read_frame(self.flushing) {
-      Some(read) => Some(read),
-      None => {
+    let read = decoder.read_frame(self.flushing);
+    {
+      let mut stats = self.stats.lock().unwrap();
+      stats.frames_decoded = decoder.frames_decoded();
+      stats.bytes_read = decoder.bytes_read();
+      if matches!(read, FrameResult::Frame(_)) {
+        stats.last_read_at = Some(Instant::now());
+      }
+    }
+    if let Some(sender) = &self.metadata_updates {
+      let packet = decoder.icy_metadata();
+      if packet != self.icy_metadata {
+        if let Some(title) = packet.as_deref().and_then(parse_icy_stream_title) {
+          debug!("stream title changed: {}", title);
+          // The receiver is kept by the media provider
+          let _ = sender.send(MediaMetadata::Title(title));
+        }
+        self.icy_metadata = packet;
+      }
+    }
+    match read {
+      FrameResult::Frame(read) => {
+        self.transient_errors = 0;
+        Ok(Some(read))
+      }
+      FrameResult::Again => Ok(Some(Vec::new())), // Request retry
+      FrameResult::Eof => {
         if !self.flushing {
           debug!("flushing decoder...");
           self.flushing = true;
-          return Some(Vec::new()); // Request retry
+          return Ok(Some(Vec::new())); // Request retry
         }
 
-        None
+        Ok(None)
+      }
+      FrameResult::Error(error) if error.is_transient() && self.transient_errors < RETRY_MAX_ATTEMPTS => {
+        let delay = backoff_delay(self.transient_errors);
+        self.transient_errors += 1;
+        warn!("transient read error: {}, retrying in {:?}", error, delay);
+        // Called from a blocking task
+        drop(decoder);
+        std::thread::sleep(delay);
+        Ok(Some(Vec::new()))
       }
+      FrameResult::Error(error) => Err(SampleProviderError::DecodeError(error.to_string()).into())
+    }
+  }
+
+  fn spec(&self) -> ProviderSpec {
+    // The decoder resamples to 48 kHz
+    ProviderSpec {
+      channels: self.decoder.lock().unwrap().output_channels(),
+      ..ProviderSpec::default()
     }
   }
 
@@ -56,40 +123,495 @@ impl SampleProvider for FFmpegSampleProvider {
 
   fn get_handle(&self) -> Box<dyn SampleProviderHandle> {
     Box::new(FFmpegSampleProviderHandle {
-      decoder: self.decoder.clone()
+      decoder: self.decoder.clone(),
+      stats: self.stats.clone()
     })
   }
 }
 
 pub struct FFmpegSampleProviderHandle {
-  pub decoder: Arc<Mutex<Decoder>>
+  pub decoder: Arc<Mutex<Decoder>>,
+  stats: Arc<Mutex<ProviderStats>>
 }
 
 impl SampleProviderHandle for FFmpegSampleProviderHandle {
   fn as_any(&self) -> &(dyn Any + Sync + Send) {
     self
   }
+
+  fn stats(&self) -> Option<ProviderStats> {
+    let stats = self.stats.lock().unwrap();
+    Some(ProviderStats::new(stats.frames_decoded, stats.bytes_read, stats.last_read_at))
+  }
 }
 
 impl FFmpegSampleProviderHandle {
-  pub fn set_enable_filter_graph(&self, enable: bool) -> Result<(), RawError> {
+  pub fn set_enable_filter_graph(&self, enable: bool) -> Result<(), DecoderError> {
     let mut decoder = self.decoder.lock().unwrap();
     decoder.set_enable_filter_graph(enable)
   }
 
-  pub fn init_filters(&self, description: &str) -> Result<(), RawError> {
+  pub fn init_filters(&self, description: &str) -> Result<(), DecoderError> {
     let mut decoder = self.decoder.lock().unwrap();
     decoder.init_filters(description)
   }
 
-  pub fn get_frame_pts(&self) -> Result<Duration, RawError> {
+  pub fn get_frame_pts(&self) -> Result<Duration, DecoderError> {
     let decoder = self.decoder.lock().unwrap();
     Ok(Duration::from_millis(decoder.get_frame_pts()))
   }
 
-  pub fn seek(&self, position: Duration) -> Result<(), RawError> {
+  pub fn is_seekable(&self) -> bool {
+    self.decoder.lock().unwrap().is_seekable()
+  }
+
+  /// Seeks to `position`. Returns `true` if the input could not be seeked by time and the position was estimated
+  /// from the input size instead, e.g. for constant bitrate streams without a seek index.
+  pub fn seek(&self, position: Duration) -> Result<bool, DecoderError> {
     let mut decoder = self.decoder.lock().unwrap();
     let base = decoder.get_decoder_time_base();
-    decoder.seek(position.as_millis() as u64 * base / 1000)
+    let pts = position.as_millis() as u64 * base / 1000;
+    let error = match decoder.seek(pts) {
+      Ok(()) => return Ok(false),
+      Err(error) => error
+    };
+    if !decoder.is_seekable() {
+      return Err(error);
+    }
+
+    let (Some(size), Some(duration)) = (decoder.input_size(), decoder.duration()) else {
+      return Err(error);
+    };
+    debug!("seeking by time failed ({}), estimating byte offset", error);
+    decoder.seek_byte(estimate_byte_offset(position, duration, size), pts)?;
+    Ok(true)
+  }
+}
+
+/// WAV file in the temporary directory, deleted on drop so that failing tests do not leave it behind.
+#[cfg(test)]
+struct TempWav(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempWav {
+  /// Writes `wav` to `mosaik-<name>-<process ID>.wav`.
+  fn new(name: &str, wav: &[u8]) -> Self {
+    let path = std::env::temp_dir().join(format!("mosaik-{}-{}.wav", name, std::process::id()));
+    std::fs::write(&path, wav).unwrap();
+    Self(path)
+  }
+
+  fn path(&self) -> &str {
+    self.0.to_str().unwrap()
+  }
+}
+
+#[cfg(test)]
+impl Drop for TempWav {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.0);
+  }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn resamples_to_48khz_end_to_end() {
+  use std::f32::consts::TAU;
+  use std::io::Cursor;
+
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE, TIMESTAMP_STEP};
+  use voice::sink::WavSink;
+  use voice::wav::{encode_wav, WAV_HEADER_SIZE};
+  use voice::{PlaybackLoopExit, VoiceConnection};
+
+  const INPUT_RATE: usize = 44100;
+  const FREQUENCY: f32 = 440.0;
+  const PACKET_SIZE: usize = TIMESTAMP_STEP * CHANNEL_COUNT;
+
+  // One second of a stereo sine
+  let input = (0..INPUT_RATE)
+    .flat_map(|index| {
+      let sample = (TAU * FREQUENCY * index as f32 / INPUT_RATE as f32).sin() * 0.5;
+      [sample, sample]
+    })
+    .collect::<Vec<_>>();
+  let file = TempWav::new("sine", &encode_wav(&input, INPUT_RATE as u32, CHANNEL_COUNT as u16));
+
+  let mut provider = FFmpegSampleProvider::new();
+  provider.open(file.path()).unwrap();
+  let connection = Arc::new(VoiceConnection::new().unwrap());
+  let mut sink = WavSink::new(Cursor::new(Vec::new())).unwrap();
+  let playback = VoiceConnection::play_to_sink(connection, Box::new(provider), &mut sink);
+  let exit = tokio::time::timeout(Duration::from_secs(10), playback).await;
+  assert_eq!(exit.expect("playback loop hung").unwrap(), PlaybackLoopExit::Finished);
+
+  // The resampler may trim or pad its delay, and the last frame is padded with silence
+  let expected = SAMPLE_RATE * CHANNEL_COUNT;
+  let samples = sink.samples();
+  assert!(
+    (expected - PACKET_SIZE..=expected + PACKET_SIZE).contains(&samples),
+    "expected about {} samples, got {}",
+    expected,
+    samples
+  );
+
+  let wav = sink.finish().unwrap().into_inner();
+  assert_eq!(wav.len(), WAV_HEADER_SIZE + samples * 2);
+  assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize, samples * 2);
+
+  // Pitch is preserved: a sine crosses zero upwards once per period
+  let left = wav[WAV_HEADER_SIZE..]
+    .chunks_exact(2 * CHANNEL_COUNT)
+    .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+    .collect::<Vec<_>>();
+  let crossings = left.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count();
+  assert!((FREQUENCY as usize - 2..=FREQUENCY as usize + 2).contains(&crossings), "{} crossings", crossings);
+}
+
+#[test]
+fn keeps_tail_of_odd_length_input() {
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+  use voice::wav::encode_wav;
+
+  // Not a multiple of any codec or resampler chunk size
+  const INPUT_RATE: usize = 44100;
+  const INPUT_FRAMES: usize = INPUT_RATE + 123;
+
+  let input = vec![0.25; INPUT_FRAMES * CHANNEL_COUNT];
+  let file = TempWav::new("tail", &encode_wav(&input, INPUT_RATE as u32, CHANNEL_COUNT as u16));
+
+  let mut provider = FFmpegSampleProvider::new();
+  provider.open(file.path()).unwrap();
+  let mut samples = 0;
+  while let Some(read) = provider.get_samples().unwrap() {
+    samples += read.len();
+  }
+
+  let expected = INPUT_FRAMES * SAMPLE_RATE / INPUT_RATE * CHANNEL_COUNT;
+  let tolerance = 2 * CHANNEL_COUNT;
+  assert!(
+    (expected - tolerance..=expected + tolerance).contains(&samples),
+    "expected about {} samples, got {}",
+    expected,
+    samples
+  );
+}
+
+#[test]
+fn resamples_with_each_resampler_kind() {
+  use decoder::ResamplerKind;
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+  use voice::wav::encode_wav;
+
+  const INPUT_RATE: usize = 44100;
+  const INPUT_FRAMES: usize = INPUT_RATE / 2;
+
+  let input = (0..INPUT_FRAMES * CHANNEL_COUNT)
+    .map(|index| (index % 100) as f32 / 100.0 - 0.5)
+    .collect::<Vec<_>>();
+  let file = TempWav::new("resampler", &encode_wav(&input, INPUT_RATE as u32, CHANNEL_COUNT as u16));
+
+  for kind in [ResamplerKind::Default, ResamplerKind::Fast, ResamplerKind::HighQuality] {
+    let mut provider = FFmpegSampleProvider::new();
+    provider.decoder.lock().unwrap().set_resampler_kind(kind).unwrap();
+    provider.open(file.path()).unwrap();
+    let mut samples = 0;
+    while let Some(read) = provider.get_samples().unwrap() {
+      samples += read.len();
+    }
+
+    let expected = INPUT_FRAMES * SAMPLE_RATE / INPUT_RATE * CHANNEL_COUNT;
+    let tolerance = 2 * CHANNEL_COUNT;
+    assert!(
+      (expected - tolerance..=expected + tolerance).contains(&samples),
+      "{:?}: expected about {} samples, got {}",
+      kind,
+      expected,
+      samples
+    );
+  }
+}
+
+/// Encodes 48 kHz stereo samples as a 32-bit IEEE float WAV file.
+#[cfg(test)]
+fn encode_float_wav(samples: &[f32]) -> Vec<u8> {
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+  let data_size = (samples.len() * 4) as u32;
+  let mut wav = Vec::new();
+  wav.extend_from_slice(b"RIFF");
+  wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+  wav.extend_from_slice(b"WAVEfmt ");
+  wav.extend_from_slice(&16u32.to_le_bytes());
+  wav.extend_from_slice(&3u16.to_le_bytes()); // IEEE float
+  wav.extend_from_slice(&(CHANNEL_COUNT as u16).to_le_bytes());
+  wav.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+  wav.extend_from_slice(&((SAMPLE_RATE * CHANNEL_COUNT * 4) as u32).to_le_bytes());
+  wav.extend_from_slice(&((CHANNEL_COUNT * 4) as u16).to_le_bytes());
+  wav.extend_from_slice(&32u16.to_le_bytes());
+  wav.extend_from_slice(b"data");
+  wav.extend_from_slice(&data_size.to_le_bytes());
+  for sample in samples {
+    wav.extend_from_slice(&sample.to_le_bytes());
+  }
+  wav
+}
+
+#[test]
+fn passes_through_48khz_float_without_resampler() {
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+  let input = (0..SAMPLE_RATE / 10 * CHANNEL_COUNT)
+    .map(|index| (index % 200) as f32 / 200.0 - 0.5)
+    .collect::<Vec<_>>();
+
+  // Already in the output format
+  let file = TempWav::new("float", &encode_float_wav(&input));
+
+  let mut provider = FFmpegSampleProvider::new();
+  provider.open(file.path()).unwrap();
+  let mut output = Vec::new();
+  while let Some(read) = provider.get_samples().unwrap() {
+    output.extend(read);
+  }
+
+  // Interleaving is kept and samples are not altered
+  assert_eq!(output, input);
+  assert!(!provider.decoder.lock().unwrap().is_resampling());
+}
+
+#[test]
+fn resumes_http_input_after_connection_drop() {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+  const DROP_AFTER: usize = 100_000;
+
+  let input = (0..SAMPLE_RATE * CHANNEL_COUNT)
+    .map(|index| (index % 300) as f32 / 300.0 - 0.5)
+    .collect::<Vec<_>>();
+  let wav = Arc::new(encode_float_wav(&input));
+
+  // Serves byte ranges, but drops the first connection starting at 0 after DROP_AFTER bytes
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/track.wav", listener.local_addr().unwrap());
+  let connections = Arc::new(AtomicUsize::new(0));
+  {
+    let wav = wav.clone();
+    let connections = connections.clone();
+    thread::spawn(move || {
+      let mut dropped = false;
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        connections.fetch_add(1, Ordering::Relaxed);
+
+        let mut start = 0;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        loop {
+          let mut line = String::new();
+          if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+            break;
+          }
+          if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+            start = range.trim().trim_end_matches('-').split('-').next().unwrap().parse().unwrap();
+          }
+        }
+
+        let length = wav.len();
+        let header = format!(
+          "HTTP/1.1 206 Partial Content\r\nContent-Type: audio/wav\r\nAccept-Ranges: bytes\r\n\
+           Content-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+          start,
+          length - 1,
+          length,
+          length - start
+        );
+        let end = if start == 0 && !dropped {
+          dropped = true;
+          DROP_AFTER
+        } else {
+          length
+        };
+        // The client may close early, e.g. after probing
+        let _ = stream.write_all(header.as_bytes()).and_then(|_| stream.write_all(&wav[start..end]));
+      }
+    });
+  }
+
+  let mut provider = FFmpegSampleProvider::new();
+  provider.open(&url).unwrap();
+  let mut output = Vec::new();
+  while let Some(read) = provider.get_samples().unwrap() {
+    output.extend(read);
   }
+
+  // The track plays to completion through a resumed connection
+  assert_eq!(output.len(), input.len());
+  assert_eq!(output, input);
+  assert!(connections.load(Ordering::Relaxed) >= 2);
+}
+
+/// Serves `wav` over HTTP on a local port, honoring range requests only if `accept_ranges` is set.
+#[cfg(test)]
+fn spawn_wav_server(wav: Arc<Vec<u8>>, accept_ranges: bool) -> String {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::thread;
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/track.wav", listener.local_addr().unwrap());
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let mut stream = stream.unwrap();
+
+      let mut start = 0;
+      let mut reader = BufReader::new(stream.try_clone().unwrap());
+      loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+          break;
+        }
+        if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+          start = range.trim().trim_end_matches('-').split('-').next().unwrap().parse().unwrap();
+        }
+      }
+
+      let length = wav.len();
+      let header = if accept_ranges {
+        format!(
+          "HTTP/1.1 206 Partial Content\r\nContent-Type: audio/wav\r\nAccept-Ranges: bytes\r\n\
+           Content-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+          start,
+          length - 1,
+          length,
+          length - start
+        )
+      } else {
+        start = 0;
+        "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\nAccept-Ranges: none\r\nConnection: close\r\n\r\n".to_owned()
+      };
+      // The client may close early, e.g. after probing or seeking
+      let _ = stream.write_all(header.as_bytes()).and_then(|_| stream.write_all(&wav[start..]));
+    }
+  });
+  url
+}
+
+#[test]
+fn seeks_http_input_with_range_requests() {
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+  const SEEK_TO: Duration = Duration::from_millis(500);
+
+  let input = (0..SAMPLE_RATE * CHANNEL_COUNT)
+    .map(|index| (index % 300) as f32 / 300.0 - 0.5)
+    .collect::<Vec<_>>();
+  let wav = Arc::new(encode_float_wav(&input));
+
+  let mut provider = FFmpegSampleProvider::new();
+  provider.open(&spawn_wav_server(wav.clone(), true)).unwrap();
+  let handle = provider.get_handle();
+  let handle = handle.as_any().downcast_ref::<FFmpegSampleProviderHandle>().unwrap();
+  assert!(handle.is_seekable());
+  // WAV is seeked precisely by time
+  assert!(!handle.seek(SEEK_TO).unwrap());
+
+  let mut output = Vec::new();
+  while let Some(read) = provider.get_samples().unwrap() {
+    output.extend(read);
+  }
+
+  // Only the part after the seek position is played, within a packet
+  let expected = input.len() - SEEK_TO.as_millis() as usize * SAMPLE_RATE / 1000 * CHANNEL_COUNT;
+  let tolerance = SAMPLE_RATE / 20 * CHANNEL_COUNT;
+  assert!(
+    (expected - tolerance..=expected + tolerance).contains(&output.len()),
+    "expected about {} samples, got {}",
+    expected,
+    output.len()
+  );
+
+  // Servers without range support are reported as not seekable
+  let provider = FFmpegSampleProvider::new();
+  provider.decoder.lock().unwrap().open_input(&spawn_wav_server(wav, false)).unwrap();
+  assert!(!provider.decoder.lock().unwrap().is_seekable());
+  assert!(provider.decoder.lock().unwrap().seek(0).is_err());
+}
+
+#[test]
+fn strips_icy_metadata_and_reports_titles() {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::thread;
+
+  use voice::constants::{CHANNEL_COUNT, SAMPLE_RATE};
+
+  const META_INTERVAL: usize = 16384;
+
+  let input = (0..SAMPLE_RATE * CHANNEL_COUNT)
+    .map(|index| (index % 400) as f32 / 400.0 - 0.5)
+    .collect::<Vec<_>>();
+  let wav = encode_float_wav(&input);
+
+  // A metadata block follows every META_INTERVAL audio bytes, titles change in the first and the tenth block
+  let mut stream = Vec::new();
+  for (index, chunk) in wav.chunks(META_INTERVAL).enumerate() {
+    stream.extend_from_slice(chunk);
+    let title = match index {
+      0 => Some("First"),
+      9 => Some("Second"),
+      _ => None
+    };
+    match title {
+      Some(title) => {
+        let mut block = format!("StreamTitle='{}';", title).into_bytes();
+        block.resize(block.len().div_ceil(16) * 16, 0);
+        stream.push((block.len() / 16) as u8);
+        stream.extend_from_slice(&block);
+      }
+      None => stream.push(0)
+    }
+  }
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/radio", listener.local_addr().unwrap());
+  thread::spawn(move || {
+    for connection in listener.incoming() {
+      let mut connection = connection.unwrap();
+      let mut reader = BufReader::new(connection.try_clone().unwrap());
+      let mut icy = false;
+      loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+          break;
+        }
+        icy |= line.to_ascii_lowercase().starts_with("icy-metadata: 1");
+      }
+      assert!(icy, "client did not request ICY metadata");
+
+      let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\nicy-metaint: {}\r\nConnection: close\r\n\r\n",
+        META_INTERVAL
+      );
+      let _ = connection.write_all(header.as_bytes()).and_then(|_| connection.write_all(&stream));
+    }
+  });
+
+  let (sender, receiver) = flume::unbounded();
+  let mut provider = FFmpegSampleProvider::new().with_metadata_updates(sender);
+  provider.open(&url).unwrap();
+  let mut output = Vec::new();
+  while let Some(read) = provider.get_samples().unwrap() {
+    output.extend(read);
+  }
+
+  // Metadata blocks are not decoded as audio
+  assert_eq!(output, input);
+  assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![
+    MediaMetadata::Title("First".to_owned()),
+    MediaMetadata::Title("Second".to_owned())
+  ]);
 }
@@ -1,12 +1,18 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use async_trait::async_trait;
+use flume::TrySendError;
 use futures_channel::mpsc::UnboundedSender;
+use serde_json::json;
 use serenity::all::{ChannelId, GuildId, ShardRunnerMessage, UserId, VoiceGatewayManager, VoiceState};
+use serenity::constants::Opcode;
+use thiserror::Error;
 use tokio::sync::oneshot::Sender;
 use tokio::sync::RwLock;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
+pub mod clip;
 pub mod ffmpeg;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -15,7 +21,9 @@ pub struct MosaikVoiceState {
   pub channel_id: Option<ChannelId>,
   pub session_id: Option<String>,
   pub endpoint: Option<String>,
-  pub token: Option<String>
+  pub token: Option<String>,
+  /// Whether the bot is suppressed (audience member) in a stage channel.
+  pub suppress: bool
 }
 
 impl MosaikVoiceState {
@@ -25,25 +33,88 @@ impl MosaikVoiceState {
       channel_id: None,
       session_id: None,
       endpoint: None,
-      token: None
+      token: None,
+      suppress: false
     }
   }
 }
 
+/// Voice channel change of the bot, not initiated by [MosaikVoiceManager::send_voice_state_update].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VoiceChannelChange {
+  Moved(ChannelId),
+  /// Kicked from the voice channel, or the channel was deleted.
+  Disconnected
+}
+
+#[derive(Debug, Error)]
+pub enum VoiceManagerError {
+  /// The shard is not registered (yet or anymore), the caller may retry later.
+  #[error("shard {0} is not registered")]
+  ShardNotRegistered(u32),
+  #[error("failed to serialize voice state update: {0}")]
+  Serialize(#[from] serde_json::Error)
+}
+
 #[derive(Debug)]
 pub struct MosaikVoiceManager {
   pub states: RwLock<HashMap<GuildId, MosaikVoiceState>>,
-  pub callbacks: RwLock<HashMap<GuildId, Sender<MosaikVoiceState>>>
+  pub callbacks: RwLock<HashMap<GuildId, Sender<MosaikVoiceState>>>,
+  /// Notified when the bot is moved or kicked from a voice channel, usually by the guild's player.
+  pub channel_listeners: RwLock<HashMap<GuildId, flume::Sender<VoiceChannelChange>>>,
+  shards: RwLock<HashMap<u32, UnboundedSender<ShardRunnerMessage>>>,
+  shard_count: AtomicU32
 }
 
 impl MosaikVoiceManager {
   pub fn new() -> Self {
     Self {
       states: Default::default(),
-      callbacks: Default::default()
+      callbacks: Default::default(),
+      channel_listeners: Default::default(),
+      shards: Default::default(),
+      shard_count: AtomicU32::new(1)
     }
   }
 
+  pub fn shard_id(&self, guild_id: GuildId) -> u32 {
+    let shard_count = self.shard_count.load(Ordering::Relaxed).max(1) as u64;
+    ((guild_id.get() >> 22) % shard_count) as u32
+  }
+
+  /// Sends a voice state update (opcode 4) through the shard responsible for `guild_id`.
+  ///
+  /// Pass [`None`] as `channel_id` to leave the voice channel.
+  pub async fn send_voice_state_update(
+    &self,
+    guild_id: GuildId,
+    channel_id: Option<ChannelId>,
+    self_deaf: bool,
+    self_mute: bool
+  ) -> Result<(), VoiceManagerError> {
+    let shard_id = self.shard_id(guild_id);
+    let message = serde_json::to_string(&json!({
+      "op": Opcode::VoiceStateUpdate,
+      "d": {
+        "guild_id": guild_id,
+        "channel_id": channel_id,
+        "self_mute": self_mute,
+        "self_deaf": self_deaf
+      }
+    }))?;
+
+    let shards = self.shards.read().await;
+    let sender = shards
+      .get(&shard_id)
+      .ok_or(VoiceManagerError::ShardNotRegistered(shard_id))?;
+    sender
+      .unbounded_send(ShardRunnerMessage::Message(message.into()))
+      .map_err(|_| VoiceManagerError::ShardNotRegistered(shard_id))?;
+    debug!(?guild_id, ?channel_id, shard_id, "sent voice state update");
+
+    Ok(())
+  }
+
   async fn run_callback_if_needed(&self, state: &MosaikVoiceState) {
     if state.session_id.is_some() && state.endpoint.is_some() && state.token.is_some() {
       let mut callbacks = self.callbacks.write().await;
@@ -54,6 +125,31 @@ impl MosaikVoiceManager {
     }
   }
 
+  async fn notify_channel_change(&self, guild_id: GuildId, change: VoiceChannelChange) {
+    info!(?guild_id, ?change, "voice channel changed");
+    let mut listeners = self.channel_listeners.write().await;
+    if let Some(listener) = listeners.get(&guild_id) {
+      match listener.try_send(change) {
+        Ok(()) => {}
+        Err(TrySendError::Full(change)) => warn!(?guild_id, ?change, "channel listener is lagging, change dropped"),
+        Err(TrySendError::Disconnected(_)) => {
+          debug!(?guild_id, "channel listener is gone");
+          listeners.remove(&guild_id);
+        }
+      }
+    }
+  }
+
+  /// Stops notifying about voice channel changes of `guild_id`, e.g. before leaving on purpose.
+  pub async fn remove_channel_listener(&self, guild_id: &GuildId) {
+    self.channel_listeners.write().await.remove(guild_id);
+  }
+
+  pub async fn is_suppressed(&self, guild_id: &GuildId) -> bool {
+    let states = self.states.read().await;
+    states.get(guild_id).map_or(false, |state| state.suppress)
+  }
+
   pub async fn invalidate_state(&self, guild_id: &GuildId) -> Option<MosaikVoiceState> {
     let mut states = self.states.write().await;
     states.remove(guild_id)
@@ -63,14 +159,17 @@ impl MosaikVoiceManager {
 #[async_trait]
 impl VoiceGatewayManager for MosaikVoiceManager {
   async fn initialise(&self, shard_count: u32, user_id: UserId) {
+    self.shard_count.store(shard_count, Ordering::Relaxed);
     info!(?user_id, ?shard_count, "voice manager initialized");
   }
 
-  async fn register_shard(&self, shard_id: u32, _sender: UnboundedSender<ShardRunnerMessage>) {
+  async fn register_shard(&self, shard_id: u32, sender: UnboundedSender<ShardRunnerMessage>) {
+    self.shards.write().await.insert(shard_id, sender);
     info!(?shard_id, "register shard");
   }
 
   async fn deregister_shard(&self, shard_id: u32) {
+    self.shards.write().await.remove(&shard_id);
     info!(?shard_id, "deregister shard");
   }
 
@@ -90,9 +189,77 @@ impl VoiceGatewayManager for MosaikVoiceManager {
     let state = states
       .entry(guild_id)
       .or_insert_with(|| MosaikVoiceState::new(guild_id));
+    // State is invalidated before connecting, so only changes made by others are detected here
+    let change = match (state.channel_id, voice_state.channel_id) {
+      (Some(old), Some(new)) if old != new => Some(VoiceChannelChange::Moved(new)),
+      (Some(_), None) => Some(VoiceChannelChange::Disconnected),
+      _ => None
+    };
     state.channel_id = voice_state.channel_id;
     state.session_id = Some(voice_state.session_id.clone());
+    state.suppress = voice_state.suppress;
     debug!("voice state update: {:?}", state);
     self.run_callback_if_needed(&state).await;
+    drop(states);
+
+    if let Some(change) = change {
+      self.notify_channel_change(guild_id, change).await;
+    }
   }
 }
+
+#[cfg(test)]
+fn voice_state(guild_id: GuildId, channel_id: Option<ChannelId>) -> VoiceState {
+  serde_json::from_value(json!({
+    "guild_id": guild_id,
+    "channel_id": channel_id,
+    "user_id": "1",
+    "session_id": "session",
+    "deaf": false,
+    "mute": false,
+    "self_deaf": true,
+    "self_mute": false,
+    "self_video": false,
+    "suppress": false,
+    "request_to_speak_timestamp": null
+  }))
+  .unwrap()
+}
+
+#[tokio::test]
+async fn state_update_notifies_when_kicked() {
+  let manager = MosaikVoiceManager::new();
+  let guild_id = GuildId::new(1);
+  let (tx, rx) = flume::unbounded();
+  manager.channel_listeners.write().await.insert(guild_id, tx);
+
+  // Initial join and unrelated updates are not changes
+  manager.state_update(guild_id, &voice_state(guild_id, Some(ChannelId::new(10)))).await;
+  manager.state_update(guild_id, &voice_state(guild_id, Some(ChannelId::new(10)))).await;
+  assert!(rx.try_recv().is_err());
+
+  manager.state_update(guild_id, &voice_state(guild_id, Some(ChannelId::new(20)))).await;
+  assert_eq!(rx.try_recv(), Ok(VoiceChannelChange::Moved(ChannelId::new(20))));
+
+  manager.state_update(guild_id, &voice_state(guild_id, None)).await;
+  assert_eq!(rx.try_recv(), Ok(VoiceChannelChange::Disconnected));
+  assert_eq!(manager.states.read().await.get(&guild_id).unwrap().channel_id, None);
+}
+
+#[tokio::test]
+async fn lagging_channel_listener_is_kept() {
+  let manager = MosaikVoiceManager::new();
+  let guild_id = GuildId::new(1);
+  let (tx, rx) = flume::bounded(1);
+  manager.channel_listeners.write().await.insert(guild_id, tx);
+
+  manager.state_update(guild_id, &voice_state(guild_id, Some(ChannelId::new(10)))).await;
+  manager.state_update(guild_id, &voice_state(guild_id, Some(ChannelId::new(20)))).await;
+  manager.state_update(guild_id, &voice_state(guild_id, Some(ChannelId::new(30)))).await;
+  assert!(manager.channel_listeners.read().await.contains_key(&guild_id));
+  assert_eq!(rx.try_recv(), Ok(VoiceChannelChange::Moved(ChannelId::new(20))));
+
+  drop(rx);
+  manager.state_update(guild_id, &voice_state(guild_id, None)).await;
+  assert!(!manager.channel_listeners.read().await.contains_key(&guild_id));
+}